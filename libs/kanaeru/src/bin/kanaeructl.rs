@@ -0,0 +1,312 @@
+//! Operations tooling for kanaeru.
+//!
+//! ```text
+//! kanaeructl quarantine list [--processor <queue>] [--all]
+//! kanaeructl quarantine show <id> [--output <file>]
+//! kanaeructl quarantine edit <id> --payload <file>
+//! kanaeructl quarantine replay <id>...
+//! kanaeructl quarantine delete <id>
+//! kanaeructl outbox parked
+//! kanaeructl outbox unpark <id>...
+//! ```
+//!
+//! The database and the broker are taken from `DATABASE_URL` and `AMQP_URL`. Built with the `cli`
+//! feature: `cargo install --path libs/kanaeru --features cli`.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use amqprs::connection::{Connection, OpenConnectionArguments};
+use clap::{Parser, Subcommand};
+use kanaeru::rabbitmq::AmqpPool;
+use kanaeru::rabbitmq::outbox::OutboxMessage;
+use kanaeru::rabbitmq::quarantine::{QUARANTINE_LIST_LIMIT, Quarantine, QuarantinedMessage};
+use kanaeru::sqlx::DatabaseProcessor;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+#[derive(Parser)]
+#[command(name = "kanaeructl", about = "Operations tooling for kanaeru")]
+struct Cli {
+    /// Postgres database holding the `kanaeru` schema
+    #[arg(long, env = "DATABASE_URL", global = true, hide_env_values = true)]
+    database_url: Option<String>,
+
+    /// AMQP broker, only needed to replay messages
+    #[arg(long, env = "AMQP_URL", global = true, hide_env_values = true)]
+    amqp_url: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect, edit and replay quarantined messages
+    #[command(subcommand)]
+    Quarantine(QuarantineCommand),
+    /// Inspect and unpark outbox messages that failed too often to be published
+    #[command(subcommand)]
+    Outbox(OutboxCommand),
+}
+
+#[derive(Subcommand)]
+enum OutboxCommand {
+    /// List parked messages, oldest first
+    Parked {
+        /// List messages with an id above this one
+        #[arg(long, default_value_t = 0)]
+        after: i64,
+        #[arg(long, default_value_t = QUARANTINE_LIST_LIMIT)]
+        limit: i64,
+    },
+    /// Put parked messages back to be published by the relay
+    Unpark {
+        #[arg(required = true)]
+        ids: Vec<i64>,
+    },
+}
+
+#[derive(Subcommand)]
+enum QuarantineCommand {
+    /// List quarantined messages, oldest first
+    List {
+        /// Only list messages of the processor consuming this queue
+        #[arg(long)]
+        processor: Option<String>,
+        /// Include messages that were replayed already
+        #[arg(long)]
+        all: bool,
+        /// List messages with an id above this one
+        #[arg(long, default_value_t = 0)]
+        after: i64,
+        #[arg(long, default_value_t = QUARANTINE_LIST_LIMIT)]
+        limit: i64,
+    },
+    /// Show a quarantined message with its headers and payload
+    Show {
+        id: i64,
+        /// Write the raw payload to this file, `-` for stdout, instead of showing it
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Replace the payload of a quarantined message
+    Edit {
+        id: i64,
+        /// File holding the new payload, `-` for stdin
+        #[arg(long)]
+        payload: PathBuf,
+    },
+    /// Publish quarantined messages again to the queue of their processor
+    Replay {
+        #[arg(required = true)]
+        ids: Vec<i64>,
+    },
+    /// Delete a quarantined message
+    Delete { id: i64 },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    match cli.command {
+        Command::Quarantine(command) => quarantine(&cli.database_url, &cli.amqp_url, command).await,
+        Command::Outbox(command) => outbox(&cli.database_url, command).await,
+    }
+}
+
+async fn outbox(database_url: &Option<String>, command: OutboxCommand) -> anyhow::Result<()> {
+    let Some(database_url) = database_url else {
+        anyhow::bail!("DATABASE_URL is not set");
+    };
+    let db = sqlx::PgPool::connect(database_url).await?;
+    match command {
+        OutboxCommand::Parked { after, limit } => {
+            let messages = OutboxMessage::list_parked(&db, after, limit).await?;
+            println!(
+                "{:>8}  {:<24}  {:<24}  {:>8}  error",
+                "id", "exchange", "routing key", "attempts"
+            );
+            for message in messages {
+                println!(
+                    "{:>8}  {:<24}  {:<24}  {:>8}  {}",
+                    message.id,
+                    message.exchange,
+                    message.routing_key,
+                    message.attempts,
+                    first_line(message.last_error.as_deref().unwrap_or_default()),
+                );
+            }
+        }
+        OutboxCommand::Unpark { ids } => {
+            for id in ids {
+                if !OutboxMessage::unpark(&db, id).await? {
+                    anyhow::bail!("No parked outbox message {id}");
+                }
+                println!("Unparked {id}");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn quarantine(
+    database_url: &Option<String>,
+    amqp_url: &Option<String>,
+    command: QuarantineCommand,
+) -> anyhow::Result<()> {
+    let Some(database_url) = database_url else {
+        anyhow::bail!("DATABASE_URL is not set");
+    };
+    let db = DatabaseProcessor::new(sqlx::PgPool::connect(database_url).await?);
+    // Connecting to the broker is deferred to replays, the other commands only need the database
+    let connect = || async {
+        let Some(amqp_url) = amqp_url else {
+            anyhow::bail!("AMQP_URL is not set");
+        };
+        let connection =
+            Connection::open(&OpenConnectionArguments::try_from(amqp_url.as_str())?).await?;
+        Ok(Quarantine::new(
+            db.clone(),
+            AmqpPool::connect(connection).await,
+        ))
+    };
+
+    match command {
+        QuarantineCommand::List {
+            processor,
+            all,
+            after,
+            limit,
+        } => {
+            let messages =
+                QuarantinedMessage::list(db.db(), processor.as_deref(), all, after, limit).await?;
+            println!(
+                "{:>8}  {:<24}  {:<14}  {:<20}  {:<8}  error",
+                "id", "processor", "class", "quarantined at", "replayed"
+            );
+            for message in messages {
+                println!(
+                    "{:>8}  {:<24}  {:<14}  {:<20}  {:<8}  {}",
+                    message.id,
+                    message.processor,
+                    message.error_class,
+                    format_time(message.quarantined_at)?,
+                    if message.replayed_at.is_some() {
+                        "yes"
+                    } else {
+                        "no"
+                    },
+                    first_line(&message.error),
+                );
+            }
+        }
+        QuarantineCommand::Show { id, output } => {
+            let message = QuarantinedMessage::find(db.db(), id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("No quarantined message {id}"))?;
+            match output {
+                Some(path) => write_payload(&path, &message.payload)?,
+                None => show(&message)?,
+            }
+        }
+        QuarantineCommand::Edit { id, payload } => {
+            let payload = read_payload(&payload)?;
+            if !QuarantinedMessage::update_payload(db.db(), id, &payload).await? {
+                anyhow::bail!("No quarantined message {id}");
+            }
+            println!("Replaced the payload of {id} with {} bytes", payload.len());
+        }
+        QuarantineCommand::Replay { ids } => {
+            let quarantine = connect().await?;
+            for id in ids {
+                quarantine.replay(id).await?;
+                println!("Replayed {id}");
+            }
+        }
+        QuarantineCommand::Delete { id } => {
+            if !QuarantinedMessage::delete(db.db(), id).await? {
+                anyhow::bail!("No quarantined message {id}");
+            }
+            println!("Deleted {id}");
+        }
+    }
+    Ok(())
+}
+
+/// RFC 3339 in UTC, to the second
+fn format_time(time: OffsetDateTime) -> anyhow::Result<String> {
+    Ok(time
+        .to_offset(time::UtcOffset::UTC)
+        .replace_nanosecond(0)?
+        .format(&Rfc3339)?)
+}
+
+fn first_line(text: &str) -> &str {
+    text.lines().next().unwrap_or_default()
+}
+
+fn show(message: &QuarantinedMessage) -> anyhow::Result<()> {
+    println!("id:             {}", message.id);
+    println!("processor:      {}", message.processor);
+    println!("exchange:       {}", message.exchange);
+    println!("routing key:    {}", message.routing_key);
+    println!(
+        "message id:     {}",
+        message.message_id.as_deref().unwrap_or("-")
+    );
+    println!(
+        "correlation id: {}",
+        message.correlation_id.as_deref().unwrap_or("-")
+    );
+    println!(
+        "content type:   {}",
+        message.content_type.as_deref().unwrap_or("-")
+    );
+    println!("quarantined at: {}", format_time(message.quarantined_at)?);
+    match message.replayed_at {
+        Some(replayed_at) => println!("replayed at:    {}", format_time(replayed_at)?),
+        None => println!("replayed at:    -"),
+    }
+    println!("error class:    {}", message.error_class);
+    println!("error:          {}", message.error);
+    println!("headers:");
+    for (name, value) in message.headers()?.as_ref() {
+        println!("  {name}: {value:?}");
+    }
+    println!("payload ({} bytes):", message.payload.len());
+    match std::str::from_utf8(&message.payload) {
+        Ok(text) => println!("{text}"),
+        Err(_) => println!("{}", hex_dump(&message.payload)),
+    }
+    Ok(())
+}
+
+fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{byte:02x}")).collect();
+            format!("{:08x}  {}", line * 16, hex.join(" "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn read_payload(path: &Path) -> anyhow::Result<Vec<u8>> {
+    if path == Path::new("-") {
+        let mut payload = Vec::new();
+        std::io::stdin().read_to_end(&mut payload)?;
+        return Ok(payload);
+    }
+    Ok(std::fs::read(path)?)
+}
+
+fn write_payload(path: &Path, payload: &[u8]) -> anyhow::Result<()> {
+    if path == Path::new("-") {
+        std::io::stdout().write_all(payload)?;
+        return Ok(());
+    }
+    Ok(std::fs::write(path, payload)?)
+}
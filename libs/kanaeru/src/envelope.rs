@@ -0,0 +1,326 @@
+//! Envelope around serialized messages and Redis values.
+//!
+//! An enveloped payload is laid out as
+//!
+//! | bytes | content |
+//! |-------|---------|
+//! | 4 | [`MAGIC`] |
+//! | 1 | [`VERSION`] |
+//! | 1 | flags, [`FLAG_ZSTD`] and [`FLAG_AES_GCM`] |
+//! | 1 | length of the content type |
+//! | n | content type |
+//! | 4 + 12 | key id and nonce, if encrypted |
+//! | rest | body |
+//!
+//! The body is compressed with zstd, then encrypted with AES-256-GCM using the whole header as
+//! associated data. Encryption keys are looked up by id in the installed [`Keyring`], so keys can
+//! be rotated while payloads encrypted with the previous key are still around.
+//!
+//! Which payloads are enveloped is decided by the [`EnvelopePolicy`] of the writer, and readers
+//! learn it out of band rather than from the bytes, since a raw rkyv or bincode payload may start
+//! with anything. An AMQP message carries the [`ENVELOPE_HEADER`] header when its content is
+//! enveloped, and is read as raw otherwise. Redis values have nowhere else to say it, so values of
+//! a type whose policy envelopes are opened with [`open_or_raw`], which still reads the raw values
+//! written before the policy was switched on. Values of a type with the raw policy are never
+//! looked into, and switching a type back to raw needs its enveloped values to expire or be
+//! rewritten first.
+//!
+//! # Magic prefixes
+//!
+//! The bytes this crate puts in front of Redis values, [`MAGIC`],
+//! [`VERSION_MAGIC`](crate::schema::VERSION_MAGIC) and the
+//! [negative cache entry](crate::redis::cache::NEGATIVE_ENTRY), all start with `0xff`
+//! followed by `KN`. `0xff` never starts UTF-8 text, so JSON or other text values can not be
+//! mistaken for a prefix. Binary values can, which is why envelopes are only looked for where
+//! the type or message says they may be present. The version prefix is looked for in every Redis
+//! value, since readers must notice values written by a newer version of their type, so a raw
+//! value of the initial version that happens to start with
+//! [`VERSION_MAGIC`](crate::schema::VERSION_MAGIC) is misread.
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use kanau::message::{DeserializeError, SerializeError};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// First bytes of an enveloped payload, see [magic prefixes](self#magic-prefixes)
+pub const MAGIC: [u8; 4] = [0xff, b'K', b'N', b'E'];
+
+/// Header of an AMQP message whose content is enveloped, set to [`VERSION`]
+pub const ENVELOPE_HEADER: &str = "x-kanaeru-envelope";
+
+/// Version of the envelope layout
+pub const VERSION: u8 = 1;
+
+/// The body is compressed with zstd
+pub const FLAG_ZSTD: u8 = 0b01;
+
+/// The body is encrypted with AES-256-GCM
+pub const FLAG_AES_GCM: u8 = 0b10;
+
+/// Payloads are not decompressed beyond this size
+pub const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+const NONCE_LEN: usize = 12;
+
+static KEYRING: RwLock<Option<Arc<Keyring>>> = RwLock::new(None);
+
+/// How a writer envelopes its payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvelopePolicy {
+    /// Whether to envelope the payload at all, it is written as is otherwise
+    pub envelope: bool,
+    /// Compress bodies of at least this many bytes with zstd
+    pub compress_above: Option<usize>,
+    /// zstd compression level
+    pub compression_level: i32,
+    /// Encrypt bodies with the current key of the installed [`Keyring`]
+    pub encrypt: bool,
+}
+
+impl EnvelopePolicy {
+    /// Write payloads as is, readable by readers that do not know about envelopes
+    pub const RAW: Self = Self {
+        envelope: false,
+        compress_above: None,
+        compression_level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        encrypt: false,
+    };
+
+    /// Envelope payloads, compressing those of at least 1 KiB
+    pub const COMPRESSED: Self = Self {
+        envelope: true,
+        compress_above: Some(1024),
+        ..Self::RAW
+    };
+
+    /// Envelope and encrypt payloads, without compressing them so their length does not depend on
+    /// how well they compress
+    pub const ENCRYPTED: Self = Self {
+        envelope: true,
+        encrypt: true,
+        ..Self::RAW
+    };
+}
+
+impl Default for EnvelopePolicy {
+    fn default() -> Self {
+        Self::RAW
+    }
+}
+
+/// AES-256-GCM keys by id
+#[derive(Clone)]
+pub struct Keyring {
+    current: u32,
+    keys: HashMap<u32, Aes256Gcm>,
+}
+
+impl Keyring {
+    /// Keyring encrypting with `key`
+    pub fn new(key_id: u32, key: &[u8; 32]) -> Self {
+        Self {
+            current: key_id,
+            keys: HashMap::new(),
+        }
+        .with_key(key_id, key)
+    }
+
+    /// Add a key that is only used to decrypt, such as the previous key after a rotation
+    pub fn with_key(mut self, key_id: u32, key: &[u8; 32]) -> Self {
+        self.keys.insert(key_id, Aes256Gcm::new(key.into()));
+        self
+    }
+
+    /// Id of the key payloads are encrypted with
+    pub fn current_key_id(&self) -> u32 {
+        self.current
+    }
+
+    /// Use `keyring` for every payload sealed or opened from now on
+    pub fn install(self) {
+        *KEYRING.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(self));
+    }
+
+    /// The installed keyring
+    pub fn installed() -> Option<Arc<Keyring>> {
+        KEYRING
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut key_ids: Vec<_> = self.keys.keys().collect();
+        key_ids.sort_unstable();
+        f.debug_struct("Keyring")
+            .field("current", &self.current)
+            .field("key_ids", &key_ids)
+            .finish()
+    }
+}
+
+/// Payload taken out of its envelope
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Opened<'a> {
+    /// Content type the payload was sealed with, none for a raw payload
+    pub content_type: Option<Cow<'a, str>>,
+    pub content: Cow<'a, [u8]>,
+}
+
+impl<'a> Opened<'a> {
+    /// A payload that was not enveloped
+    pub fn raw(content: &'a [u8]) -> Self {
+        Self {
+            content_type: None,
+            content: Cow::Borrowed(content),
+        }
+    }
+}
+
+fn serialize_error(message: &str) -> SerializeError {
+    SerializeError(anyhow::anyhow!("Failed to seal envelope: {message}"))
+}
+
+fn deserialize_error(message: &str) -> DeserializeError {
+    DeserializeError(anyhow::anyhow!("Failed to open envelope: {message}"))
+}
+
+/// Put `content` into an envelope according to `policy`
+pub fn seal(
+    content: Box<[u8]>,
+    content_type: &str,
+    policy: &EnvelopePolicy,
+) -> Result<Box<[u8]>, SerializeError> {
+    if !policy.envelope {
+        return Ok(content);
+    }
+    let content_type_len =
+        u8::try_from(content_type.len()).map_err(|_| serialize_error("content type too long"))?;
+
+    let mut flags = 0;
+    let mut body = content;
+    if policy.compress_above.is_some_and(|min| body.len() >= min) {
+        let compressed = zstd::bulk::compress(&body, policy.compression_level)
+            .map_err(|e| SerializeError(e.into()))?;
+        // Small or random payloads may not get any smaller
+        if compressed.len() < body.len() {
+            flags |= FLAG_ZSTD;
+            body = compressed.into_boxed_slice();
+        }
+    }
+    let keyring = if policy.encrypt {
+        flags |= FLAG_AES_GCM;
+        Some(Keyring::installed().ok_or_else(|| serialize_error("no keyring installed"))?)
+    } else {
+        None
+    };
+
+    let mut sealed = Vec::with_capacity(body.len() + 64);
+    sealed.extend_from_slice(&MAGIC);
+    sealed.extend_from_slice(&[VERSION, flags, content_type_len]);
+    sealed.extend_from_slice(content_type.as_bytes());
+    let Some(keyring) = keyring else {
+        sealed.extend_from_slice(&body);
+        return Ok(sealed.into_boxed_slice());
+    };
+    let cipher = keyring
+        .keys
+        .get(&keyring.current)
+        .ok_or_else(|| serialize_error("current key is missing"))?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    sealed.extend_from_slice(&keyring.current.to_be_bytes());
+    sealed.extend_from_slice(&nonce);
+    let encrypted = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &body,
+                aad: &sealed,
+            },
+        )
+        .map_err(|_| serialize_error("encryption failed"))?;
+    sealed.extend_from_slice(&encrypted);
+    Ok(sealed.into_boxed_slice())
+}
+
+/// Take a payload out of its envelope, or return it as is if it does not start with [`MAGIC`].
+///
+/// Only for payloads that may be enveloped without saying so, see the [module](self) docs.
+pub fn open_or_raw(bytes: &[u8]) -> Result<Opened<'_>, DeserializeError> {
+    if bytes.starts_with(&MAGIC) {
+        open(bytes)
+    } else {
+        Ok(Opened::raw(bytes))
+    }
+}
+
+/// Take a payload out of its envelope
+pub fn open(bytes: &[u8]) -> Result<Opened<'_>, DeserializeError> {
+    let Some(rest) = bytes.strip_prefix(&MAGIC) else {
+        return Err(deserialize_error("missing magic"));
+    };
+    let [version, flags, content_type_len, rest @ ..] = rest else {
+        return Err(deserialize_error("truncated header"));
+    };
+    if *version != VERSION {
+        return Err(deserialize_error(&format!("unknown version {version}")));
+    }
+    if flags & !(FLAG_ZSTD | FLAG_AES_GCM) != 0 {
+        return Err(deserialize_error(&format!("unknown flags {flags:#04b}")));
+    }
+    let (content_type, mut body) = rest
+        .split_at_checked(usize::from(*content_type_len))
+        .ok_or_else(|| deserialize_error("truncated content type"))?;
+    let content_type =
+        std::str::from_utf8(content_type).map_err(|_| deserialize_error("invalid content type"))?;
+
+    let mut content = Cow::Borrowed(body);
+    if flags & FLAG_AES_GCM != 0 {
+        let (key_id, rest) = body
+            .split_first_chunk::<4>()
+            .ok_or_else(|| deserialize_error("truncated key id"))?;
+        let (nonce, encrypted) = rest
+            .split_first_chunk::<NONCE_LEN>()
+            .ok_or_else(|| deserialize_error("truncated nonce"))?;
+        body = encrypted;
+        let key_id = u32::from_be_bytes(*key_id);
+        let keyring =
+            Keyring::installed().ok_or_else(|| deserialize_error("no keyring installed"))?;
+        let cipher = keyring
+            .keys
+            .get(&key_id)
+            .ok_or_else(|| deserialize_error(&format!("unknown key id {key_id}")))?;
+        let header = &bytes[..bytes.len() - body.len()];
+        let decrypted = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: body,
+                    aad: header,
+                },
+            )
+            .map_err(|_| deserialize_error("decryption failed"))?;
+        content = Cow::Owned(decrypted);
+    }
+    if flags & FLAG_ZSTD != 0 {
+        let mut decompressed = Vec::new();
+        zstd::stream::read::Decoder::new(content.as_ref())
+            .map_err(|e| DeserializeError(e.into()))?
+            .take(MAX_DECOMPRESSED_SIZE + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|e| DeserializeError(e.into()))?;
+        if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+            return Err(deserialize_error("decompressed payload too large"));
+        }
+        content = Cow::Owned(decompressed);
+    }
+    Ok(Opened {
+        content_type: Some(Cow::Borrowed(content_type)),
+        content,
+    })
+}
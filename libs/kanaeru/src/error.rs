@@ -1,5 +1,6 @@
 use thiserror::Error;
-use tonic::Status;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Status};
 
 #[derive(Debug, Error)]
 /// internal errors
@@ -41,14 +42,42 @@ pub enum Error {
 
     #[error("Trying to access a resource that does not exist")]
     NotFound,
+
+    #[error("Message was nacked by the broker")]
+    /// The broker refused to take responsibility for a published message. Publishing it again may succeed.
+    PublishNacked,
+
+    #[error("Message is unroutable: {0}")]
+    /// A mandatory message was returned by the broker because no queue is bound for its routing key
+    Unroutable(String),
+
+    #[error("Update conflicted with concurrent writes on all {0} attempts")]
+    /// An optimistic update lost the race against concurrent writes on every attempt. Retrying it later may succeed.
+    Conflict(usize),
+
+    #[error("Timed out waiting for a pooled connection")]
+    /// Every connection of a pool stayed checked out for as long as the caller was willing to wait
+    PoolTimeout,
+
+    #[error("Pool is closed")]
+    /// A connection was requested from a pool that is shutting down
+    PoolClosed,
+
+    #[error("Rate limited{}", .0.map(|d| format!(", retry after {d:?}")).unwrap_or_default())]
+    /// A rate limit was exceeded. The same call is allowed again after the given time, or never
+    /// if there is none.
+    RateLimited(Option<std::time::Duration>),
 }
 
 impl From<&Error> for Status {
     fn from(value: &Error) -> Self {
         match value {
-            Error::AmqpError(_) | Error::RedisError(_) | Error::DatabaseError(_) | Error::Io(_) => {
-                Status::internal("Internal server error")
-            }
+            Error::AmqpError(_)
+            | Error::RedisError(_)
+            | Error::DatabaseError(_)
+            | Error::Io(_)
+            | Error::PublishNacked
+            | Error::Unroutable(_) => Status::internal("Internal server error"),
             Error::SerializeError(_) | Error::DeserializeError(_) => {
                 Status::invalid_argument(value.to_string())
             }
@@ -56,6 +85,18 @@ impl From<&Error> for Status {
             Error::PermissionsDenied => Status::permission_denied("Permission denied"),
             Error::InvalidInput => Status::invalid_argument("Invalid input"),
             Error::NotFound => Status::not_found("Not found"),
+            Error::Conflict(_) => Status::aborted("Conflicting concurrent update"),
+            Error::PoolTimeout => Status::deadline_exceeded("Timed out waiting for a connection"),
+            Error::PoolClosed => Status::unavailable("Shutting down"),
+            Error::RateLimited(retry_after) => {
+                let mut metadata = MetadataMap::new();
+                if let Some(retry_after) = retry_after {
+                    // Whole seconds like the HTTP header, rounded up so retrying then is allowed
+                    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                    metadata.insert("retry-after", seconds.into());
+                }
+                Status::with_metadata(Code::ResourceExhausted, "Rate limited", metadata)
+            }
         }
     }
 }
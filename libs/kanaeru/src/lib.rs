@@ -1,10 +1,12 @@
 #![forbid(unsafe_code, clippy::unwrap_used, clippy::panic, clippy::expect_used)]
 
 pub mod cron;
+pub mod envelope;
 pub mod error;
 pub mod pool;
 pub mod rabbitmq;
 pub mod redis;
+pub mod schema;
 pub mod sqlx;
 
 pub use error::Error;
@@ -1,23 +1,393 @@
 use crossbeam_queue::ArrayQueue;
-use std::pin::Pin;
-use std::sync::Arc;
-use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify, OwnedSemaphorePermit, Semaphore, TryAcquireError, oneshot};
+use tokio::time::Instant;
 
-type ConnectionFactoryFut<T, E = anyhow::Error> =
-    Pin<Box<dyn Future<Output = Result<T, E>> + Send>>;
-type ConnectionFactory<T, E> = Pin<Box<dyn Fn() -> ConnectionFactoryFut<T, E> + Send + Sync>>;
+/// Creates and checks the connections of a [`Pool`]
+pub trait ConnectionManager: Send + Sync + 'static {
+    /// Connection type
+    type Connection: Send + 'static;
+    /// Error returned when a connection can not be created or is not usable
+    type Error: Send + 'static;
 
-struct PoolInner<T, FactoryError = anyhow::Error> {
-    idle: ArrayQueue<T>,
+    /// Create a new connection.
+    fn create(&self) -> impl Future<Output = Result<Self::Connection, Self::Error>> + Send;
+
+    /// Check an idle connection right before it is handed out.
+    ///
+    /// Connections that fail the check are dropped and the next one is tried.
+    fn is_valid(
+        &self,
+        conn: &mut Self::Connection,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let _ = conn;
+        async { Ok(()) }
+    }
+
+    /// Cheap check of whether a connection is broken, done when it is returned and by the
+    /// background reaper. Broken connections are dropped instead of being put back.
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        let _ = conn;
+        false
+    }
+}
+
+/// Default interval of the background reaper
+pub const DEFAULT_REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Settings of a [`Pool`]
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Max number of connections, both idle and checked out
+    pub capacity: usize,
+    /// Connections older than this are closed instead of being reused
+    pub max_lifetime: Option<Duration>,
+    /// Idle connections unused for longer than this are closed
+    pub idle_timeout: Option<Duration>,
+    /// How often the background reaper scans the idle connections
+    pub reap_interval: Duration,
+    /// Number of idle connections kept ready, filled at startup and refilled in the background
+    pub min_idle: usize,
+}
+
+impl PoolConfig {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            max_lifetime: None,
+            idle_timeout: None,
+            reap_interval: DEFAULT_REAP_INTERVAL,
+            min_idle: 0,
+        }
+    }
+
+    pub fn max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    pub fn reap_interval(mut self, reap_interval: Duration) -> Self {
+        self.reap_interval = reap_interval;
+        self
+    }
+
+    pub fn min_idle(mut self, min_idle: usize) -> Self {
+        self.min_idle = min_idle.min(self.capacity);
+        self
+    }
+}
+
+struct IdleConn<T> {
+    conn: T,
+    created_at: Instant,
+    idle_since: Instant,
+}
+
+/// Snapshot of the state of a [`Pool`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Max number of connections
+    pub capacity: usize,
+    /// Connections currently checked out
+    pub in_use: usize,
+    /// Connections sitting in the idle queue
+    pub idle: usize,
+    /// Callers currently waiting for capacity
+    pub waiters: usize,
+    /// Connections created by the manager since the pool was built
+    pub total_created: u64,
+    /// Failed connection attempts since the pool was built
+    pub factory_failures: u64,
+    /// Average time [`Pool::get`] and [`Pool::get_timeout`] spent waiting for capacity
+    pub average_wait: Duration,
+}
+
+#[derive(Default)]
+struct PoolCounters {
+    in_use: AtomicUsize,
+    creating: AtomicUsize,
+    waiters: AtomicUsize,
+    created: AtomicU64,
+    factory_failures: AtomicU64,
+    waits: AtomicU64,
+    wait_nanos: AtomicU64,
+}
+
+/// Counts a caller as waiting until dropped, including when the waiting future is cancelled
+struct WaiterGuard<'a>(&'a AtomicUsize);
+
+impl<'a> WaiterGuard<'a> {
+    fn new(waiters: &'a AtomicUsize) -> Self {
+        waiters.fetch_add(1, Ordering::Relaxed);
+        Self(waiters)
+    }
+}
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Idle connection taken out of the queue to be validated. Put back if the check is cancelled,
+/// e.g. because the caller gave up, since the connection was fine as far as the pool knows.
+struct Validating<'a, M: ConnectionManager> {
+    inner: &'a PoolInner<M>,
+    idle: Option<IdleConn<M::Connection>>,
+}
+
+impl<M: ConnectionManager> Validating<'_, M> {
+    /// Stop guarding the connection once the check is over
+    fn take(mut self) -> Option<IdleConn<M::Connection>> {
+        self.idle.take()
+    }
+}
+
+impl<M: ConnectionManager> Drop for Validating<'_, M> {
+    fn drop(&mut self) {
+        if let Some(idle) = self.idle.take()
+            && !self.inner.is_closed()
+            && self.inner.idle.push(idle).is_err()
+        {
+            tracing::debug!(reason = "idle queue full", "Discarding pooled connection");
+        }
+    }
+}
+
+/// Counts a connection as being created until dropped, so that [`Pool::close`] waits for it
+struct Creating<'a, M: ConnectionManager>(&'a PoolInner<M>);
+
+impl<'a, M: ConnectionManager> Creating<'a, M> {
+    fn new(inner: &'a PoolInner<M>) -> Self {
+        inner.counters.creating.fetch_add(1, Ordering::SeqCst);
+        Self(inner)
+    }
+}
+
+impl<M: ConnectionManager> Drop for Creating<'_, M> {
+    fn drop(&mut self) {
+        if self.0.counters.creating.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.released.notify_waiters();
+        }
+    }
+}
+
+struct PoolInner<M: ConnectionManager> {
+    idle: ArrayQueue<IdleConn<M::Connection>>,
     sem: Arc<Semaphore>,
-    factory: ConnectionFactory<T, FactoryError>,
+    manager: M,
+    config: PoolConfig,
+    counters: PoolCounters,
+    closed: AtomicBool,
+    /// Notified when the last checked-out connection is released or the last creation finishes
+    released: Notify,
+    /// Held while filling up to `min_idle`, so that only one fill runs at a time
+    filling: Mutex<()>,
 }
 
-pub struct Pool<T, FactoryError = anyhow::Error> {
-    inner: Arc<PoolInner<T, FactoryError>>,
+impl<M: ConnectionManager> PoolInner<M> {
+    fn is_too_old(&self, created_at: Instant, now: Instant) -> bool {
+        self.config
+            .max_lifetime
+            .is_some_and(|max| now.saturating_duration_since(created_at) >= max)
+    }
+
+    /// Why an idle connection must be dropped instead of reused, if it must.
+    fn stale_reason(
+        &self,
+        idle: &mut IdleConn<M::Connection>,
+        now: Instant,
+    ) -> Option<&'static str> {
+        if self.is_too_old(idle.created_at, now) {
+            Some("max lifetime")
+        } else if self
+            .config
+            .idle_timeout
+            .is_some_and(|max| now.saturating_duration_since(idle.idle_since) >= max)
+        {
+            Some("idle timeout")
+        } else if self.manager.has_broken(&mut idle.conn) {
+            Some("broken")
+        } else {
+            None
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Put a returned connection back to the idle queue, or drop it if it can not be reused.
+    fn recycle(&self, mut conn: M::Connection, created_at: Instant) {
+        let now = Instant::now();
+        if self.is_closed() {
+            tracing::debug!(reason = "pool closed", "Discarding pooled connection");
+            return;
+        }
+        if self.is_too_old(created_at, now) {
+            tracing::debug!(reason = "max lifetime", "Discarding pooled connection");
+            return;
+        }
+        if self.manager.has_broken(&mut conn) {
+            tracing::debug!(reason = "broken", "Discarding pooled connection");
+            return;
+        }
+        if self
+            .idle
+            .push(IdleConn {
+                conn,
+                created_at,
+                idle_since: now,
+            })
+            .is_err()
+        {
+            tracing::debug!(reason = "idle queue full", "Discarding pooled connection");
+        }
+    }
+
+    /// Drop every idle connection that is expired or no longer valid.
+    fn reap(&self) {
+        let now = Instant::now();
+        for _ in 0..self.idle.len() {
+            let Some(mut idle) = self.idle.pop() else {
+                break;
+            };
+            match self.stale_reason(&mut idle, now) {
+                Some(reason) => tracing::debug!(reason, "Discarding pooled connection"),
+                None => {
+                    let _ = self.idle.push(idle);
+                }
+            }
+        }
+    }
+
+    async fn create(&self) -> Result<M::Connection, M::Error> {
+        let _creating = Creating::new(self);
+        let created = self.manager.create().await;
+        match &created {
+            Ok(_) => {
+                self.counters.created.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!("Created new pooled connection");
+            }
+            Err(_) => {
+                self.counters
+                    .factory_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                tracing::warn!("Failed to create pooled connection");
+            }
+        }
+        created
+    }
+
+    /// Create idle connections until there are `min_idle` of them.
+    ///
+    /// Only spare capacity is used, callers waiting for a connection always win over the fill.
+    /// The caller must hold the `filling` lock.
+    async fn fill_locked(&self) -> Result<usize, M::Error> {
+        let missing = self.config.min_idle.saturating_sub(self.idle.len());
+        let mut created = 0;
+        for _ in 0..missing {
+            if self.is_closed() || self.idle.len() >= self.config.min_idle {
+                break;
+            }
+            let Ok(_permit) = self.sem.try_acquire() else {
+                break;
+            };
+            let conn = self.create().await?;
+            self.recycle(conn, Instant::now());
+            created += 1;
+        }
+        Ok(created)
+    }
+
+    async fn wait_released(&self) {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if self.counters.in_use.load(Ordering::SeqCst) == 0
+                && self.counters.creating.load(Ordering::SeqCst) == 0
+            {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    fn record_wait(&self, waited: Duration) {
+        let nanos = u64::try_from(waited.as_nanos()).unwrap_or(u64::MAX);
+        self.counters.waits.fetch_add(1, Ordering::Relaxed);
+        self.counters.wait_nanos.fetch_add(nanos, Ordering::Relaxed);
+        tracing::trace!(
+            waited_ms = waited.as_millis() as u64,
+            "Acquired pool capacity"
+        );
+    }
+
+    fn stats(&self) -> PoolStats {
+        let waits = self.counters.waits.load(Ordering::Relaxed);
+        let wait_nanos = self.counters.wait_nanos.load(Ordering::Relaxed);
+        PoolStats {
+            capacity: self.config.capacity,
+            in_use: self.counters.in_use.load(Ordering::Relaxed),
+            idle: self.idle.len(),
+            waiters: self.counters.waiters.load(Ordering::Relaxed),
+            total_created: self.counters.created.load(Ordering::Relaxed),
+            factory_failures: self.counters.factory_failures.load(Ordering::Relaxed),
+            average_wait: Duration::from_nanos(wait_nanos.checked_div(waits).unwrap_or(0)),
+        }
+    }
+
+    /// Refill up to `min_idle` in the background, unless a fill is already running.
+    fn spawn_fill(self: &Arc<Self>) {
+        if self.config.min_idle == 0 || self.is_closed() {
+            return;
+        }
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let inner = self.clone();
+        handle.spawn(async move {
+            let Ok(_filling) = inner.filling.try_lock() else {
+                return;
+            };
+            if inner.fill_locked().await.is_err() {
+                tracing::warn!("Failed to refill idle pooled connections");
+            }
+        });
+    }
+}
+
+async fn reap_loop<M: ConnectionManager>(inner: Weak<PoolInner<M>>, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+    loop {
+        ticker.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        if inner.is_closed() {
+            return;
+        }
+        inner.reap();
+        if let Ok(_filling) = inner.filling.try_lock() {
+            // Errors are already logged by `create`, the next tick tries again
+            let _ = inner.fill_locked().await;
+        }
+    }
 }
 
-impl<T, FE> Clone for Pool<T, FE> {
+pub struct Pool<M: ConnectionManager> {
+    inner: Arc<PoolInner<M>>,
+}
+
+impl<M: ConnectionManager> Clone for Pool<M> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
@@ -25,98 +395,275 @@ impl<T, FE> Clone for Pool<T, FE> {
     }
 }
 
-pub struct Pooled<T, FactoryError = anyhow::Error> {
-    inner: Arc<PoolInner<T, FactoryError>>,
+pub struct Pooled<M: ConnectionManager> {
+    inner: Arc<PoolInner<M>>,
     permit: Option<OwnedSemaphorePermit>,
-    conn: Option<T>,
+    conn: Option<M::Connection>,
+    created_at: Instant,
 }
 
-impl<T, FE> Pooled<T, FE> {
-    pub fn get_ref(&self) -> Option<&T> {
+impl<M: ConnectionManager> Pooled<M> {
+    fn new(
+        inner: Arc<PoolInner<M>>,
+        permit: OwnedSemaphorePermit,
+        conn: M::Connection,
+        created_at: Instant,
+    ) -> Self {
+        inner.counters.in_use.fetch_add(1, Ordering::SeqCst);
+        Self {
+            inner,
+            permit: Some(permit),
+            conn: Some(conn),
+            created_at,
+        }
+    }
+
+    pub fn get_ref(&self) -> Option<&M::Connection> {
         self.conn.as_ref()
     }
-    pub fn get_mut(&mut self) -> Option<&mut T> {
+    pub fn get_mut(&mut self) -> Option<&mut M::Connection> {
         self.conn.as_mut()
     }
 
+    fn release(&mut self) {
+        if self.permit.take().is_some()
+            && self.inner.counters.in_use.fetch_sub(1, Ordering::SeqCst) == 1
+        {
+            self.inner.released.notify_waiters();
+        }
+    }
+
     /// Mark the connection is disconnected.
     ///
-    /// It will drop the connection. The connection capacity will also be released.
+    /// It will drop the connection. The connection capacity will also be released, and the idle
+    /// queue is refilled up to `min_idle` in the background.
     pub fn disconnect(&mut self) {
-        self.conn.take();
-        self.permit.take();
+        if self.conn.take().is_some() {
+            tracing::debug!(reason = "disconnected", "Discarding pooled connection");
+        }
+        self.release();
+        self.inner.spawn_fill();
     }
 }
 
-impl<T, FE> Drop for Pooled<T, FE> {
+impl<M: ConnectionManager> Drop for Pooled<M> {
     fn drop(&mut self) {
         if let Some(conn) = self.conn.take() {
-            let _ = self.inner.idle.push(conn);
+            self.inner.recycle(conn, self.created_at);
         }
-        let _ = self.permit.take();
+        self.release();
     }
 }
 
-impl<T, FE> Pool<T, FE> {
-    pub fn new<F>(factory: Pin<Box<F>>, capacity: usize) -> Self
-    where
-        F: Fn() -> ConnectionFactoryFut<T, FE> + Send + Sync + 'static,
-    {
-        Self {
-            inner: Arc::new(PoolInner {
-                idle: ArrayQueue::new(capacity),
-                sem: Arc::new(Semaphore::new(capacity)),
-                factory,
-            }),
+impl<M: ConnectionManager> Pool<M> {
+    pub fn new(manager: M, capacity: usize) -> Self {
+        Self::with_config(manager, PoolConfig::new(capacity))
+    }
+
+    pub fn with_config(manager: M, config: PoolConfig) -> Self {
+        let reap_interval = config.reap_interval;
+        let inner = Arc::new(PoolInner {
+            idle: ArrayQueue::new(config.capacity),
+            sem: Arc::new(Semaphore::new(config.capacity)),
+            manager,
+            config,
+            counters: PoolCounters::default(),
+            closed: AtomicBool::new(false),
+            released: Notify::new(),
+            filling: Mutex::new(()),
+        });
+        // The reaper holds a weak reference, so it exits once every pool handle is dropped
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(reap_loop(Arc::downgrade(&inner), reap_interval));
+        } else {
+            tracing::warn!("No tokio runtime, idle connections will only be checked on checkout");
         }
+        inner.spawn_fill();
+        Self { inner }
+    }
+
+    /// Fill the idle queue up to `min_idle` and wait for it to finish.
+    ///
+    /// Returns the number of connections created.
+    pub async fn warm_up(&self) -> Result<usize, M::Error> {
+        let _filling = self.inner.filling.lock().await;
+        self.inner.fill_locked().await
+    }
+
+    /// Stop handing out connections and shut the pool down.
+    ///
+    /// Callers waiting for capacity and later checkouts get [`PoolingResult::Closed`], as do
+    /// checkouts whose connection was still being created. Then it waits up to `timeout` for
+    /// every checked-out connection to be returned and every creation to finish, and drops the
+    /// idle connections. Returns `false` if some connections were still checked out at the
+    /// deadline; they are dropped instead of recycled once they come back.
+    pub async fn close(&self, timeout: Duration) -> bool {
+        self.inner.closed.store(true, Ordering::SeqCst);
+        self.inner.sem.close();
+        let drained = tokio::time::timeout(timeout, self.inner.wait_released())
+            .await
+            .is_ok();
+        while self.inner.idle.pop().is_some() {}
+        tracing::debug!(drained, "Pool closed");
+        drained
     }
+
     pub fn idle_len(&self) -> usize {
         self.inner.idle.len()
     }
-    pub async fn factory_create(&self) -> Result<T, FE> {
-        (self.inner.factory)().await
+    pub fn config(&self) -> &PoolConfig {
+        &self.inner.config
+    }
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+    /// Take a snapshot of the pool counters
+    pub fn stats(&self) -> PoolStats {
+        self.inner.stats()
+    }
+    pub fn manager(&self) -> &M {
+        &self.inner.manager
+    }
+    /// Create a connection that is not managed by the pool and does not count toward its capacity
+    pub async fn create_unpooled(&self) -> Result<M::Connection, M::Error> {
+        self.inner.manager.create().await
     }
-    pub async fn get(&self) -> PoolingResult<T, FE> {
+
+    /// Get a connection, waiting for as long as it takes for one to become available.
+    ///
+    /// Dropping the returned future at any point is safe: a permit that was already acquired is
+    /// released, and a connection that is still being created is put into the idle queue.
+    pub async fn get(&self) -> PoolingResult<M> {
         let sem = self.inner.sem.clone();
-        let Ok(permit) = sem.acquire_owned().await else {
-            return PoolingResult::SemanticsError;
+        let started = Instant::now();
+        let acquired = {
+            let _waiting = WaiterGuard::new(&self.inner.counters.waiters);
+            sem.acquire_owned().await
         };
-        let connection = self.inner.idle.pop();
+        // The semaphore is only ever closed by `close`
+        let Ok(permit) = acquired else {
+            return PoolingResult::Closed;
+        };
+        self.inner.record_wait(started.elapsed());
+        self.checkout(permit).await
+    }
 
-        if let Some(conn) = connection {
-            PoolingResult::Ok(Pooled {
-                inner: self.inner.clone(),
-                permit: Some(permit),
-                conn: Some(conn),
-            })
-        } else {
-            let new = self.factory_create().await;
-            match new {
-                Ok(conn) => PoolingResult::Ok(Pooled {
-                    inner: self.inner.clone(),
-                    permit: Some(permit),
-                    conn: Some(conn),
-                }),
-                Err(err) => PoolingResult::FactoryErr(err),
+    /// Get a connection, giving up with [`PoolingResult::Timeout`] after `timeout`.
+    ///
+    /// The timeout covers both waiting for capacity and creating a new connection.
+    pub async fn get_timeout(&self, timeout: Duration) -> PoolingResult<M> {
+        match tokio::time::timeout(timeout, self.get()).await {
+            Ok(result) => result,
+            Err(_) => PoolingResult::Timeout,
+        }
+    }
+
+    /// Get a connection without waiting for capacity.
+    ///
+    /// Returns [`PoolingResult::Timeout`] immediately if every connection is checked out.
+    /// A new connection may still be created if there is capacity but no idle connection.
+    pub async fn try_get(&self) -> PoolingResult<M> {
+        // Not recorded as a wait, since it never waits
+        match self.inner.sem.clone().try_acquire_owned() {
+            Ok(permit) => self.checkout(permit).await,
+            Err(TryAcquireError::NoPermits) => PoolingResult::Timeout,
+            Err(TryAcquireError::Closed) => PoolingResult::Closed,
+        }
+    }
+
+    async fn checkout(&self, permit: OwnedSemaphorePermit) -> PoolingResult<M> {
+        let pooled = match self.take_or_create(permit).await {
+            PoolingResult::Ok(pooled) => pooled,
+            other => return other,
+        };
+        // The connection is counted as in use before this check, so either `close` waits for it
+        // or it is seen closed here and dropped
+        if self.inner.is_closed() {
+            drop(pooled);
+            return PoolingResult::Closed;
+        }
+        PoolingResult::Ok(pooled)
+    }
+
+    async fn take_or_create(&self, permit: OwnedSemaphorePermit) -> PoolingResult<M> {
+        let now = Instant::now();
+        while let Some(mut idle) = self.inner.idle.pop() {
+            // Stale connections are dropped here and the next one is tried
+            if let Some(reason) = self.inner.stale_reason(&mut idle, now) {
+                tracing::debug!(reason, "Discarding pooled connection");
+                continue;
+            }
+            let mut validating = Validating {
+                inner: &self.inner,
+                idle: Some(idle),
+            };
+            let Some(checked) = validating.idle.as_mut() else {
+                continue;
+            };
+            let valid = self.inner.manager.is_valid(&mut checked.conn).await.is_ok();
+            let Some(idle) = validating.take() else {
+                continue;
+            };
+            if !valid {
+                tracing::debug!(reason = "invalid", "Discarding pooled connection");
+                continue;
+            }
+            tracing::trace!("Reusing idle pooled connection");
+            return PoolingResult::Ok(Pooled::new(
+                self.inner.clone(),
+                permit,
+                idle.conn,
+                idle.created_at,
+            ));
+        }
+
+        // Create the connection in its own task, so that a caller giving up halfway does not
+        // leave a half-open connection behind. If nobody is waiting for it anymore, it is parked.
+        let (tx, rx) = oneshot::channel();
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            let created = inner.create().await;
+            if let Err((Ok(conn), _permit)) = tx.send((created, permit)) {
+                inner.recycle(conn, Instant::now());
             }
+        });
+        match rx.await {
+            Ok((Ok(conn), permit)) => PoolingResult::Ok(Pooled::new(
+                self.inner.clone(),
+                permit,
+                conn,
+                Instant::now(),
+            )),
+            Ok((Err(err), _)) => PoolingResult::FactoryErr(err),
+            Err(_) => PoolingResult::SemanticsError,
         }
     }
 }
 
-pub enum PoolingResult<T, FE> {
-    Ok(Pooled<T, FE>),
+pub enum PoolingResult<M: ConnectionManager> {
+    Ok(Pooled<M>),
     SemanticsError,
-    FactoryErr(FE),
+    FactoryErr(M::Error),
+    /// No connection became available in time
+    Timeout,
+    /// The pool has been closed
+    Closed,
 }
 
-impl<T, FE: Into<crate::Error>> From<PoolingResult<T, FE>> for Result<Pooled<T, FE>, crate::Error> {
-    fn from(result: PoolingResult<T, FE>) -> Self {
+impl<M> From<PoolingResult<M>> for Result<Pooled<M>, crate::Error>
+where
+    M: ConnectionManager,
+    M::Error: Into<crate::Error>,
+{
+    fn from(result: PoolingResult<M>) -> Self {
         match result {
             PoolingResult::Ok(succ) => Ok(succ),
             PoolingResult::FactoryErr(err) => Err(err.into()),
             PoolingResult::SemanticsError => Err(crate::Error::BusinessPanic(anyhow::anyhow!(
                 "Semaphore error"
             ))),
+            PoolingResult::Timeout => Err(crate::Error::PoolTimeout),
+            PoolingResult::Closed => Err(crate::Error::PoolClosed),
         }
     }
 }
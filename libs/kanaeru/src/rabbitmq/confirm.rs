@@ -0,0 +1,359 @@
+//! Publisher confirms and returns of unroutable messages.
+//!
+//! Every channel of [`AmqpPool`](super::AmqpPool) is put into confirm mode once, when it is
+//! opened. [`AmqpChannel::publish`] then waits for the broker to ack or nack the message.
+//! A mandatory message that can not be routed is returned by the broker before its ack. Returns
+//! carry no delivery tag, so they are matched to their publish by message id, which
+//! [`AmqpChannel::publish`] sets on messages published without one.
+
+use amqprs::callbacks::ChannelCallback;
+use amqprs::channel::{BasicPublishArguments, Channel, ConfirmSelectArguments};
+use amqprs::connection::Connection;
+use amqprs::{Ack, BasicProperties, Cancel, CloseChannel, Nack, Return};
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::error::Error;
+
+/// How long [`AmqpChannel::publish`] waits for the broker to confirm a message
+pub const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+enum Confirmation {
+    Ack,
+    Nack,
+    Returned(String),
+    Closed,
+}
+
+/// Publish waiting for its confirm
+struct Pending {
+    sender: oneshot::Sender<Confirmation>,
+    message_id: String,
+    /// Reason of the basic.return of the message, reported instead of the ack that follows it
+    returned: Option<String>,
+}
+
+struct ConfirmState {
+    /// Sequence number of the next publish, counted by the broker from 1 in confirm mode
+    next_tag: u64,
+    pending: BTreeMap<u64, Pending>,
+}
+
+#[derive(Clone)]
+struct SharedConfirmState(Arc<Mutex<ConfirmState>>);
+
+impl SharedConfirmState {
+    fn lock(&self) -> MutexGuard<'_, ConfirmState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn settle(&self, delivery_tag: u64, multiple: bool, confirmation: Confirmation) {
+        let mut state = self.lock();
+        let tags: Vec<u64> = if multiple {
+            state
+                .pending
+                .range(..=delivery_tag)
+                .map(|(tag, _)| *tag)
+                .collect()
+        } else {
+            vec![delivery_tag]
+        };
+        for tag in tags {
+            let Some(pending) = state.pending.remove(&tag) else {
+                continue;
+            };
+            let confirmation = match (pending.returned, &confirmation) {
+                (Some(reason), Confirmation::Ack) => Confirmation::Returned(reason),
+                _ => confirmation.clone(),
+            };
+            let _ = pending.sender.send(confirmation);
+        }
+    }
+
+    /// Attach a basic.return to the oldest publish of `message_id` not returned yet
+    fn returned(&self, message_id: Option<&str>, reason: String) {
+        let mut state = self.lock();
+        let pending = message_id.and_then(|message_id| {
+            state
+                .pending
+                .values_mut()
+                .find(|pending| pending.message_id == message_id && pending.returned.is_none())
+        });
+        match pending {
+            Some(pending) => pending.returned = Some(reason),
+            None => tracing::warn!(
+                message_id,
+                "Ignoring a return matching no publish: {reason}"
+            ),
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.lock();
+        for (_, pending) in std::mem::take(&mut state.pending) {
+            let _ = pending.sender.send(Confirmation::Closed);
+        }
+    }
+}
+
+struct ConfirmCallback {
+    state: SharedConfirmState,
+}
+
+impl ChannelCallback for ConfirmCallback {
+    fn close<'life0, 'life1, 'async_trait>(
+        &'life0 mut self,
+        _channel: &'life1 Channel,
+        close: CloseChannel,
+    ) -> Pin<Box<dyn Future<Output = Result<(), amqprs::error::Error>> + Send + 'async_trait>>
+    where
+        Self: 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+    {
+        Box::pin(async move {
+            tracing::warn!("Channel closed by the broker: {}", close);
+            self.state.close();
+            Ok(())
+        })
+    }
+
+    fn cancel<'life0, 'life1, 'async_trait>(
+        &'life0 mut self,
+        _channel: &'life1 Channel,
+        cancel: Cancel,
+    ) -> Pin<Box<dyn Future<Output = Result<(), amqprs::error::Error>> + Send + 'async_trait>>
+    where
+        Self: 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+    {
+        Box::pin(async move {
+            tracing::warn!("Consumer {} cancelled by the broker", cancel.consumer_tag());
+            Ok(())
+        })
+    }
+
+    fn flow<'life0, 'life1, 'async_trait>(
+        &'life0 mut self,
+        _channel: &'life1 Channel,
+        active: bool,
+    ) -> Pin<Box<dyn Future<Output = Result<bool, amqprs::error::Error>> + Send + 'async_trait>>
+    where
+        Self: 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+    {
+        Box::pin(async move { Ok(active) })
+    }
+
+    fn publish_ack<'life0, 'life1, 'async_trait>(
+        &'life0 mut self,
+        _channel: &'life1 Channel,
+        ack: Ack,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'async_trait>>
+    where
+        Self: 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+    {
+        Box::pin(async move {
+            self.state
+                .settle(ack.delivery_tag(), ack.mutiple(), Confirmation::Ack);
+        })
+    }
+
+    fn publish_nack<'life0, 'life1, 'async_trait>(
+        &'life0 mut self,
+        _channel: &'life1 Channel,
+        nack: Nack,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'async_trait>>
+    where
+        Self: 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+    {
+        Box::pin(async move {
+            self.state
+                .settle(nack.delivery_tag(), nack.multiple(), Confirmation::Nack);
+        })
+    }
+
+    fn publish_return<'life0, 'life1, 'async_trait>(
+        &'life0 mut self,
+        _channel: &'life1 Channel,
+        ret: Return,
+        basic_properties: BasicProperties,
+        _content: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'async_trait>>
+    where
+        Self: 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+    {
+        Box::pin(async move {
+            let reason = format!(
+                "{} (exchange `{}`, routing key `{}`)",
+                ret.reply_text(),
+                ret.exchange(),
+                ret.routing_key()
+            );
+            self.state
+                .returned(basic_properties.message_id().map(String::as_str), reason);
+        })
+    }
+}
+
+/// A channel in confirm mode.
+///
+/// The broker numbers every publish on the channel, so the raw [`Channel`] is not handed out:
+/// a publish that bypassed [`AmqpChannel::publish`] would shift the tags of all later ones.
+#[derive(Clone)]
+pub struct AmqpChannel {
+    channel: Channel,
+    confirms: SharedConfirmState,
+    /// Held from taking a tag until the publish is sent, so tags follow the order on the wire
+    publishing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl AmqpChannel {
+    /// Open a channel on `connection` and put it into confirm mode
+    pub async fn open(connection: &Connection) -> Result<Self, amqprs::error::Error> {
+        Self::confirm(connection.open_channel(None).await?).await
+    }
+
+    /// Put `channel` into confirm mode.
+    ///
+    /// The channel must not be in confirm mode already, since the broker numbers the publishes
+    /// of a channel from the moment it enters confirm mode.
+    pub async fn confirm(channel: Channel) -> Result<Self, amqprs::error::Error> {
+        let confirms = SharedConfirmState(Arc::new(Mutex::new(ConfirmState {
+            next_tag: 1,
+            pending: BTreeMap::new(),
+        })));
+        channel
+            .register_callback(ConfirmCallback {
+                state: confirms.clone(),
+            })
+            .await?;
+        channel
+            .confirm_select(ConfirmSelectArguments::new(false))
+            .await?;
+        Ok(Self {
+            channel,
+            confirms,
+            publishing: Arc::default(),
+        })
+    }
+
+    /// The underlying channel, to declare topology or consume on. Never publish on it.
+    pub(crate) fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.channel.is_open()
+    }
+
+    pub fn is_connection_open(&self) -> bool {
+        self.channel.is_connection_open()
+    }
+
+    /// Publish a message and wait until the broker confirms it.
+    ///
+    /// A nack is reported as [`Error::PublishNacked`] and a returned mandatory message as
+    /// [`Error::Unroutable`]. Messages without a message id are given a new one, to match their
+    /// return.
+    pub async fn publish(
+        &self,
+        mut properties: BasicProperties,
+        content: Vec<u8>,
+        args: BasicPublishArguments,
+    ) -> Result<(), Error> {
+        // A publish on a closed channel may be silently dropped and never confirmed
+        if !self.is_open() || !self.is_connection_open() {
+            return Err(Error::Io(anyhow::anyhow!("Channel is closed")));
+        }
+        let message_id = match properties.message_id() {
+            Some(message_id) => message_id.clone(),
+            None => {
+                let message_id = uuid::Uuid::now_v7().to_string();
+                properties.with_message_id(&message_id);
+                message_id
+            }
+        };
+        let (sender, receiver) = oneshot::channel();
+        let tag = {
+            let _publishing = self.publishing.lock().await;
+            let reservation = TagReservation::reserve(
+                &self.confirms,
+                Pending {
+                    sender,
+                    message_id,
+                    returned: None,
+                },
+            );
+            self.channel
+                .basic_publish(properties, content, args)
+                .await?;
+            reservation.keep()
+        };
+        match tokio::time::timeout(CONFIRM_TIMEOUT, receiver).await {
+            Ok(Ok(Confirmation::Ack)) => Ok(()),
+            Ok(Ok(Confirmation::Nack)) => Err(Error::PublishNacked),
+            Ok(Ok(Confirmation::Returned(reason))) => Err(Error::Unroutable(reason)),
+            Ok(Ok(Confirmation::Closed)) | Ok(Err(_)) => Err(Error::Io(anyhow::anyhow!(
+                "Channel closed before the message was confirmed"
+            ))),
+            Err(_) => {
+                self.confirms.lock().pending.remove(&tag);
+                Err(Error::Io(anyhow::anyhow!(
+                    "Timed out waiting for the broker to confirm the message"
+                )))
+            }
+        }
+    }
+}
+
+/// Tag taken by a publish being sent, handed back to the next publish unless the send completes.
+///
+/// Sending is a single send to the connection's writer, so a publish that failed or was dropped
+/// before completing never reached the broker, which does not count it.
+struct TagReservation<'a> {
+    confirms: &'a SharedConfirmState,
+    tag: Option<u64>,
+}
+
+impl<'a> TagReservation<'a> {
+    /// Take the next tag for `pending`. Only one reservation may exist at a time.
+    fn reserve(confirms: &'a SharedConfirmState, pending: Pending) -> Self {
+        let mut state = confirms.lock();
+        let tag = state.next_tag;
+        state.next_tag += 1;
+        state.pending.insert(tag, pending);
+        Self {
+            confirms,
+            tag: Some(tag),
+        }
+    }
+
+    /// Keep the tag once the publish was sent
+    fn keep(mut self) -> u64 {
+        self.tag.take().unwrap_or_default()
+    }
+}
+
+impl Drop for TagReservation<'_> {
+    fn drop(&mut self) {
+        let Some(tag) = self.tag else {
+            return;
+        };
+        let mut state = self.confirms.lock();
+        state.pending.remove(&tag);
+        state.next_tag = tag;
+    }
+}
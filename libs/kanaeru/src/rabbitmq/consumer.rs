@@ -0,0 +1,120 @@
+//! Prefetch, concurrency and graceful shutdown of consumers started by
+//! [`setup_consumer`](super::setup_consumer).
+
+use amqprs::channel::{BasicCancelArguments, Channel};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Default number of unacked messages the broker pushes to a consumer
+pub const CONSUMER_PREFETCH: u16 = 32;
+
+/// Settings of a consumer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsumerConfig {
+    /// Max number of unacked messages the broker pushes to the consumer, 0 for no limit
+    pub prefetch: u16,
+    /// Max number of messages processed at once. With 1, messages are processed in order of
+    /// delivery.
+    pub concurrency: u32,
+}
+
+impl ConsumerConfig {
+    pub fn new() -> Self {
+        Self {
+            prefetch: CONSUMER_PREFETCH,
+            concurrency: 1,
+        }
+    }
+
+    pub fn prefetch(mut self, prefetch: u16) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// Process up to `concurrency` messages at once. The prefetch count should be at least as
+    /// large, otherwise the broker does not push enough messages to keep every slot busy.
+    pub fn concurrency(mut self, concurrency: u32) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tracks the messages a consumer is processing
+#[derive(Clone)]
+pub(crate) struct InFlight {
+    permits: Arc<Semaphore>,
+    capacity: u32,
+}
+
+impl InFlight {
+    pub(crate) fn new(capacity: u32) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(capacity as usize)),
+            capacity,
+        }
+    }
+
+    /// Wait for a free processing slot, `None` once the consumer is shutting down
+    pub(crate) async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.permits.clone().acquire_owned().await.ok()
+    }
+
+    /// Stop handing out slots and wait for every taken slot to be released
+    async fn drain(&self) {
+        if let Ok(permits) = self.permits.acquire_many(self.capacity).await {
+            permits.forget();
+        }
+        self.permits.close();
+    }
+}
+
+/// Stops a consumer started by [`setup_consumer`](super::setup_consumer)
+pub struct ConsumerHandle {
+    channel: Channel,
+    consumer_tag: String,
+    in_flight: InFlight,
+}
+
+impl ConsumerHandle {
+    pub(crate) fn new(channel: Channel, consumer_tag: String, in_flight: InFlight) -> Self {
+        Self {
+            channel,
+            consumer_tag,
+            in_flight,
+        }
+    }
+
+    pub fn consumer_tag(&self) -> &str {
+        &self.consumer_tag
+    }
+
+    /// Cancel the consumer, wait up to `timeout` for the messages being processed, then close
+    /// the channel.
+    ///
+    /// Messages that were delivered but not processed yet are left unacked, so the broker
+    /// requeues them when the channel closes. Returns whether every in-flight message finished
+    /// before the timeout.
+    #[tracing::instrument(skip_all, fields(consumer_tag = %self.consumer_tag), err)]
+    pub async fn shutdown(self, timeout: Duration) -> Result<bool, amqprs::error::Error> {
+        self.channel
+            .basic_cancel(BasicCancelArguments::new(&self.consumer_tag))
+            .await?;
+        let drained = tokio::time::timeout(timeout, self.in_flight.drain())
+            .await
+            .is_ok();
+        if !drained {
+            tracing::warn!("Consumer did not drain in time, closing its channel anyway");
+        }
+        // Whatever is still running can no longer settle its delivery
+        self.in_flight.permits.close();
+        self.channel.close().await?;
+        Ok(drained)
+    }
+}
@@ -0,0 +1,134 @@
+//! Delayed delivery through TTL queues, without the delayed message plugin.
+//!
+//! A delayed message is published to the headers exchange [`DELAY_EXCHANGE`], tagged with its
+//! target exchange and delay. It is routed to the delay queue of that pair,
+//! `kanaeru.delay.<exchange>.<delay in ms>`, which holds every message for the same TTL and then
+//! dead-letters it to the target exchange with its original routing key.
+//!
+//! Delays are rounded up to a bucket of less than 1/128 of the delay, so that messages scheduled
+//! at arbitrary times share a bounded number of queues. Delay queues expire once unused for
+//! longer than their delay.
+
+use amqprs::channel::{Channel, ExchangeDeclareArguments, QueueDeclareArguments};
+use amqprs::{BasicProperties, FieldTable, FieldValue};
+use std::time::Duration;
+
+use super::retry::insert_field;
+use super::{AmqpBinding, AmqpConnectionManager, AmqpExchangeType, AmqpPool, bind_queue};
+use crate::error::Error;
+use crate::pool::Pooled;
+
+/// Exchange delayed messages are published to, routing them to their delay queue
+pub const DELAY_EXCHANGE: &str = "kanaeru.delay";
+
+/// Header carrying the exchange a delayed message is delivered to.
+///
+/// Unlike the other headers of kanaeru it has no `x-` prefix, because headers exchanges ignore
+/// binding arguments with that prefix.
+pub const DELAY_EXCHANGE_HEADER: &str = "kanaeru-delay-exchange";
+
+/// Header carrying the delay of a message in milliseconds, after rounding
+pub const DELAY_HEADER: &str = "kanaeru-delay";
+
+/// How long a delay queue is kept after its last message expired
+const DELAY_QUEUE_EXPIRY: Duration = Duration::from_secs(60);
+
+pub fn delay_queue_name(exchange: &str, delay: Duration) -> String {
+    format!("{DELAY_EXCHANGE}.{exchange}.{}", delay.as_millis())
+}
+
+/// Round `delay` up to its bucket, in whole milliseconds
+pub fn delay_bucket(delay: Duration) -> Duration {
+    let millis = u64::try_from(delay.as_nanos().div_ceil(1_000_000)).unwrap_or(u64::MAX);
+    let step = match millis / 128 {
+        0 => 1,
+        precision => 1 << precision.ilog2(),
+    };
+    Duration::from_millis(millis.div_ceil(step).saturating_mul(step))
+}
+
+fn millis(duration: Duration) -> i64 {
+    i64::try_from(duration.as_millis()).unwrap_or(i64::MAX)
+}
+
+/// Headers routing a message for `exchange` to the delay queue of `delay`
+fn delay_headers(exchange: &str, delay: Duration) -> FieldTable {
+    let mut headers = FieldTable::new();
+    insert_field(&mut headers, DELAY_EXCHANGE_HEADER, exchange.into());
+    insert_field(&mut headers, DELAY_HEADER, FieldValue::l(millis(delay)));
+    headers
+}
+
+/// Declare the delay queue holding messages for `exchange` during `delay`.
+///
+/// Redeclaring the queue for every message keeps it from expiring while it is in use.
+async fn declare_delay_queue(
+    channel: &Channel,
+    exchange: &str,
+    delay: Duration,
+) -> Result<(), amqprs::error::Error> {
+    channel
+        .exchange_declare(
+            ExchangeDeclareArguments::of_type(DELAY_EXCHANGE, AmqpExchangeType::Headers)
+                .durable(true)
+                .finish(),
+        )
+        .await?;
+    let queue = delay_queue_name(exchange, delay);
+    let mut arguments = FieldTable::new();
+    insert_field(
+        &mut arguments,
+        "x-message-ttl",
+        FieldValue::l(millis(delay)),
+    );
+    insert_field(
+        &mut arguments,
+        "x-expires",
+        FieldValue::l(millis(delay.saturating_add(DELAY_QUEUE_EXPIRY))),
+    );
+    // Expired messages keep their routing key, unless the queue sets one
+    insert_field(&mut arguments, "x-dead-letter-exchange", exchange.into());
+    channel
+        .queue_declare(
+            QueueDeclareArguments::durable_client_named(&queue)
+                .arguments(arguments)
+                .finish(),
+        )
+        .await?;
+    bind_queue(
+        channel,
+        &queue,
+        DELAY_EXCHANGE,
+        &[AmqpBinding::all_headers(delay_headers(exchange, delay))],
+    )
+    .await
+}
+
+/// Publish a message that is delivered to `exchange` once `delay` has passed.
+///
+/// Resolves once the message is confirmed into its delay queue. Whether the message is routable
+/// from `exchange` is only known when it is delivered, and an unroutable message is dropped then.
+pub(crate) async fn publish_delayed(
+    pool: &AmqpPool,
+    exchange: &str,
+    routing_key: &str,
+    delay: Duration,
+    mut properties: BasicProperties,
+    content: Vec<u8>,
+) -> Result<(), Error> {
+    let delay = delay_bucket(delay);
+    {
+        let channel: Result<Pooled<AmqpConnectionManager>, Error> = pool.get().await.into();
+        let channel = channel?;
+        let channel = channel
+            .get_ref()
+            .ok_or(Error::Io(anyhow::anyhow!("Channel is unexpectedly closed")))?;
+        declare_delay_queue(channel.channel(), exchange, delay).await?;
+    }
+    let mut headers = properties.headers().cloned().unwrap_or_default();
+    for (name, value) in delay_headers(exchange, delay).as_ref() {
+        insert_field(&mut headers, name.as_ref(), value.clone());
+    }
+    properties.with_headers(headers).with_persistence(true);
+    super::publish(pool, DELAY_EXCHANGE, routing_key, properties, content).await
+}
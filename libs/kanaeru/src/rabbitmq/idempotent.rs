@@ -0,0 +1,280 @@
+//! Deduplication of redelivered messages.
+//!
+//! RabbitMQ delivers messages at least once, so a processor may see a message again after it
+//! was already processed. Wrapping the processor in [`Idempotent`] claims the id of every message
+//! in redis before processing it, with `SET NX`, and skips messages whose id is already claimed.
+//! The claim is released if processing fails, and replaced by the record of the processed message
+//! otherwise.
+
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use kanau::processor::Processor;
+use std::time::Duration;
+
+use super::metadata::MessageMetadata;
+use super::policy::AckPolicy;
+use super::retry::RetryPolicy;
+use super::{AmqpBinding, AmqpMessageProcessor, AmqpMessageSend, AmqpPool};
+use crate::redis::lock::RELEASE;
+use crate::redis::{
+    KeyValue, KeyValueRead, KeyValueWrite, RedisConnection, RedisKey, encode_value, millis,
+};
+use crate::sqlx::DatabaseProcessor;
+
+/// Default time the id of a processed message is remembered
+pub const IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Default time a message is claimed for while it is processed
+pub const CLAIM_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessedMessageKey {
+    pub queue: String,
+    pub message_id: String,
+}
+
+impl From<ProcessedMessageKey> for RedisKey {
+    fn from(value: ProcessedMessageKey) -> Self {
+        let string = format!("processed_message:{}:{}", value.queue, value.message_id);
+        Self::from(string)
+    }
+}
+
+/// Unix timestamp in seconds of when a message was processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessedAt(pub u64);
+
+impl MessageSer for ProcessedAt {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(Box::new(self.0.to_be_bytes()))
+    }
+}
+
+impl MessageDe for ProcessedAt {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        let bytes: [u8; 8] = bytes.try_into().map_err(|_| {
+            DeserializeError(anyhow::anyhow!("Processed message marker is not 8 bytes"))
+        })?;
+        Ok(Self(u64::from_be_bytes(bytes)))
+    }
+}
+
+/// Record of a message processed by the processor of a queue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessedMessage {
+    pub key: ProcessedMessageKey,
+    pub processed_at: ProcessedAt,
+}
+
+impl KeyValue for ProcessedMessage {
+    type Key = ProcessedMessageKey;
+    type Value = ProcessedAt;
+
+    fn key(&self) -> Self::Key {
+        self.key.clone()
+    }
+
+    fn value(&self) -> Self::Value {
+        self.processed_at
+    }
+
+    fn into_value(self) -> Self::Value {
+        self.processed_at
+    }
+
+    fn new(key: Self::Key, value: Self::Value) -> Self {
+        Self {
+            key,
+            processed_at: value,
+        }
+    }
+}
+
+impl KeyValueRead for ProcessedMessage {}
+impl KeyValueWrite for ProcessedMessage {}
+
+/// Processor skipping messages it has already processed successfully.
+///
+/// A message is claimed before it is processed, so of two deliveries of the same message processed
+/// at the same time only one reaches the inner processor. The claim is released if the inner
+/// processor fails or is cancelled, so failed messages are retried as usual, and expires after the
+/// [claim TTL](Idempotent::claim_ttl) if the process dies while processing. Messages without a
+/// message id are always processed.
+#[derive(Clone)]
+pub struct Idempotent<P> {
+    inner: P,
+    redis: RedisConnection,
+    ttl: Duration,
+    claim_ttl: Duration,
+}
+
+impl<P> Idempotent<P> {
+    pub fn new(inner: P, redis: RedisConnection) -> Self {
+        Self {
+            inner,
+            redis,
+            ttl: IDEMPOTENCY_TTL,
+            claim_ttl: CLAIM_TTL,
+        }
+    }
+
+    /// How long the id of a processed message is remembered
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How long a message is claimed for while it is processed. A delivery of the same message
+    /// processing longer than this may be processed again by another delivery.
+    pub fn claim_ttl(mut self, claim_ttl: Duration) -> Self {
+        self.claim_ttl = claim_ttl;
+        self
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+}
+
+impl<M, P> Processor<M, Result<(), crate::error::Error>> for Idempotent<P>
+where
+    P: Processor<M, Result<(), crate::error::Error>>,
+{
+    fn process(&self, message: M) -> impl Future<Output = Result<(), crate::error::Error>> + Send {
+        self.inner.process(message)
+    }
+}
+
+impl<M, P> AmqpMessageProcessor<M> for Idempotent<P>
+where
+    M: AmqpMessageSend + MessageDe,
+    P: AmqpMessageProcessor<M> + Sync,
+{
+    const QUEUE: &'static str = P::QUEUE;
+    const RETRY: RetryPolicy = P::RETRY;
+    const ACK_POLICY: AckPolicy = P::ACK_POLICY;
+
+    fn quarantine(&self) -> Option<&DatabaseProcessor> {
+        self.inner.quarantine()
+    }
+
+    fn bindings() -> Vec<AmqpBinding> {
+        P::bindings()
+    }
+
+    fn ensure_queue(
+        pool: &AmqpPool,
+    ) -> impl Future<Output = Result<amqprs::channel::Channel, crate::error::Error>> + Send {
+        P::ensure_queue(pool)
+    }
+
+    async fn process_with_metadata(
+        &self,
+        message: M,
+        metadata: MessageMetadata,
+    ) -> Result<(), crate::error::Error> {
+        let Some(message_id) = metadata.message_id.clone() else {
+            return self.inner.process_with_metadata(message, metadata).await;
+        };
+        let key = ProcessedMessageKey {
+            queue: Self::QUEUE.to_string(),
+            message_id,
+        };
+        let mut conn = self.redis.clone();
+        let Some(claim) = Claim::take(&mut conn, key.clone(), self.claim_ttl).await? else {
+            let processed_at = ProcessedMessage::read(&mut conn, key.clone())
+                .await?
+                .map(|ProcessedAt(processed_at)| processed_at);
+            tracing::info!(
+                queue = Self::QUEUE,
+                message_id = key.message_id,
+                processed_at,
+                "Skipping message that was already processed or is being processed"
+            );
+            return Ok(());
+        };
+        if let Err(e) = self.inner.process_with_metadata(message, metadata).await {
+            claim.release().await;
+            return Err(e);
+        }
+        claim.keep();
+        if let Err(e) = ProcessedMessage::write_kv_with_ttl(&mut conn, key, now(), self.ttl).await {
+            // The claim still records the message as processed, until it expires
+            tracing::warn!("Failed to record processed message: {e}");
+        }
+        Ok(())
+    }
+}
+
+fn now() -> ProcessedAt {
+    ProcessedAt(time::OffsetDateTime::now_utc().unix_timestamp().max(0) as u64)
+}
+
+/// Claim on the id of a message being processed, released when dropped unless kept
+struct Claim {
+    key: RedisKey,
+    /// Value the claim was set to, so only this claim is released
+    token: Option<Box<[u8]>>,
+    conn: RedisConnection,
+}
+
+impl Claim {
+    /// Claim `key` for `ttl` if no one did. `None` if it is claimed already.
+    async fn take(
+        conn: &mut RedisConnection,
+        key: ProcessedMessageKey,
+        ttl: Duration,
+    ) -> Result<Option<Self>, crate::error::Error> {
+        let key: RedisKey = key.into();
+        let token = encode_value::<ProcessedMessage>(now())?;
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(token.as_ref())
+            .arg("NX")
+            .arg("PX")
+            .arg(millis(ttl))
+            .query_async(conn)
+            .await?;
+        Ok(claimed.map(|_| Self {
+            key,
+            token: Some(token),
+            conn: conn.clone(),
+        }))
+    }
+
+    /// Keep the claim once the message is processed, until it is replaced by the record
+    fn keep(mut self) {
+        self.token = None;
+    }
+
+    async fn release(mut self) {
+        let Some(token) = self.token.take() else {
+            return;
+        };
+        release(&mut self.conn, &self.key, &token).await;
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let Some(token) = self.token.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let key = self.key.clone();
+        let mut conn = self.conn.clone();
+        runtime.spawn(async move { release(&mut conn, &key, &token).await });
+    }
+}
+
+async fn release(conn: &mut RedisConnection, key: &RedisKey, token: &[u8]) {
+    let released: Result<i64, _> = RELEASE.key(key).arg(token).invoke_async(conn).await;
+    if let Err(error) = released {
+        tracing::warn!(%error, "Failed to release the claim on a message, it expires on its own");
+    }
+}
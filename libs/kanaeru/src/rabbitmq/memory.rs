@@ -0,0 +1,2162 @@
+//! In-process broker for tests, behind the `test-util` feature.
+//!
+//! [`MemoryBroker`] listens on a loopback port and speaks enough AMQP 0-9-1 for the client this
+//! crate uses, so [`AmqpMessageSend::send`](super::AmqpMessageSend::send),
+//! [`setup_consumer`](super::setup_consumer), retries, the outbox relay and RPC run against it
+//! unchanged, without a RabbitMQ server.
+//!
+//! It supports the default, direct, fanout, topic and headers exchanges, publisher confirms,
+//! mandatory returns, prefetch, ack/nack/reject with requeue, dead-lettering, message and queue
+//! TTLs, and direct reply-to. Like RabbitMQ, it only expires messages at the head of a queue.
+//! Transactions, exchange-to-exchange bindings, exclusive and auto-delete queues, priorities and
+//! persistence are not supported, and everything is lost when the broker is dropped.
+
+use amqprs::connection::{Connection, OpenConnectionArguments};
+use amqprs::{BasicProperties, FieldName, FieldTable, FieldValue, LongStr, ShortStr};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use super::AmqpPool;
+use super::retry::insert_field;
+use super::rpc::DIRECT_REPLY_TO;
+
+const PROTOCOL_HEADER: &[u8; 8] = b"AMQP\x00\x00\x09\x01";
+
+const FRAME_METHOD: u8 = 1;
+const FRAME_HEADER: u8 = 2;
+const FRAME_BODY: u8 = 3;
+const FRAME_HEARTBEAT: u8 = 8;
+const FRAME_END: u8 = 0xCE;
+/// Size of the frame header and end marker around a payload
+const FRAME_OVERHEAD: usize = 8;
+
+const FRAME_MAX: u32 = 131_072;
+const CHANNEL_MAX: u16 = 2047;
+
+/// How often queues are checked for expired messages
+const EXPIRY_INTERVAL: Duration = Duration::from_millis(10);
+
+const NO_ROUTE: u16 = 312;
+const ACCESS_REFUSED: u16 = 403;
+const NOT_FOUND: u16 = 404;
+const PRECONDITION_FAILED: u16 = 406;
+const SYNTAX_ERROR: u16 = 502;
+const COMMAND_INVALID: u16 = 503;
+const CHANNEL_ERROR: u16 = 504;
+const UNEXPECTED_FRAME: u16 = 505;
+const NOT_ALLOWED: u16 = 530;
+const NOT_IMPLEMENTED: u16 = 540;
+
+type MethodId = (u16, u16);
+
+const CONNECTION_START: MethodId = (10, 10);
+const CONNECTION_START_OK: MethodId = (10, 11);
+const CONNECTION_TUNE: MethodId = (10, 30);
+const CONNECTION_TUNE_OK: MethodId = (10, 31);
+const CONNECTION_OPEN: MethodId = (10, 40);
+const CONNECTION_OPEN_OK: MethodId = (10, 41);
+const CONNECTION_CLOSE: MethodId = (10, 50);
+const CONNECTION_CLOSE_OK: MethodId = (10, 51);
+const CHANNEL_OPEN: MethodId = (20, 10);
+const CHANNEL_OPEN_OK: MethodId = (20, 11);
+const CHANNEL_CLOSE: MethodId = (20, 40);
+const CHANNEL_CLOSE_OK: MethodId = (20, 41);
+const EXCHANGE_DECLARE: MethodId = (40, 10);
+const EXCHANGE_DECLARE_OK: MethodId = (40, 11);
+const EXCHANGE_DELETE: MethodId = (40, 20);
+const EXCHANGE_DELETE_OK: MethodId = (40, 21);
+const QUEUE_DECLARE: MethodId = (50, 10);
+const QUEUE_DECLARE_OK: MethodId = (50, 11);
+const QUEUE_BIND: MethodId = (50, 20);
+const QUEUE_BIND_OK: MethodId = (50, 21);
+const QUEUE_PURGE: MethodId = (50, 30);
+const QUEUE_PURGE_OK: MethodId = (50, 31);
+const QUEUE_DELETE: MethodId = (50, 40);
+const QUEUE_DELETE_OK: MethodId = (50, 41);
+const QUEUE_UNBIND: MethodId = (50, 50);
+const QUEUE_UNBIND_OK: MethodId = (50, 51);
+const BASIC_QOS: MethodId = (60, 10);
+const BASIC_QOS_OK: MethodId = (60, 11);
+const BASIC_CONSUME: MethodId = (60, 20);
+const BASIC_CONSUME_OK: MethodId = (60, 21);
+const BASIC_CANCEL: MethodId = (60, 30);
+const BASIC_CANCEL_OK: MethodId = (60, 31);
+const BASIC_PUBLISH: MethodId = (60, 40);
+const BASIC_RETURN: MethodId = (60, 50);
+const BASIC_DELIVER: MethodId = (60, 60);
+const BASIC_GET: MethodId = (60, 70);
+const BASIC_GET_OK: MethodId = (60, 71);
+const BASIC_GET_EMPTY: MethodId = (60, 72);
+const BASIC_ACK: MethodId = (60, 80);
+const BASIC_REJECT: MethodId = (60, 90);
+const BASIC_NACK: MethodId = (60, 120);
+const CONFIRM_SELECT: MethodId = (85, 10);
+const CONFIRM_SELECT_OK: MethodId = (85, 11);
+
+// Arguments of the methods, in wire order. Fields the broker ignores start with `_`.
+
+#[derive(Serialize)]
+struct Start {
+    version_major: u8,
+    version_minor: u8,
+    server_properties: FieldTable,
+    mechanisms: LongStr,
+    locales: LongStr,
+}
+
+#[derive(Deserialize)]
+struct StartOk {
+    _client_properties: FieldTable,
+    _mechanism: ShortStr,
+    _response: LongStr,
+    _locale: ShortStr,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Tune {
+    channel_max: u16,
+    frame_max: u32,
+    heartbeat: u16,
+}
+
+#[derive(Deserialize)]
+struct Open {
+    _virtual_host: ShortStr,
+    _capabilities: ShortStr,
+    _insist: u8,
+}
+
+#[derive(Serialize)]
+struct OpenOk {
+    known_hosts: ShortStr,
+}
+
+/// Arguments of both `connection.close` and `channel.close`
+#[derive(Serialize, Deserialize)]
+struct Close {
+    reply_code: u16,
+    reply_text: ShortStr,
+    class_id: u16,
+    method_id: u16,
+}
+
+#[derive(Serialize)]
+struct ChannelOpenOk {
+    channel_id: LongStr,
+}
+
+#[derive(Deserialize)]
+struct ExchangeDeclare {
+    _ticket: u16,
+    exchange: ShortStr,
+    kind: ShortStr,
+    bits: u8,
+    _arguments: FieldTable,
+}
+
+#[derive(Deserialize)]
+struct ExchangeDelete {
+    _ticket: u16,
+    exchange: ShortStr,
+    bits: u8,
+}
+
+#[derive(Deserialize)]
+struct QueueDeclare {
+    _ticket: u16,
+    queue: ShortStr,
+    bits: u8,
+    arguments: FieldTable,
+}
+
+#[derive(Serialize)]
+struct QueueDeclareOk {
+    queue: ShortStr,
+    message_count: u32,
+    consumer_count: u32,
+}
+
+#[derive(Deserialize)]
+struct QueueBind {
+    _ticket: u16,
+    queue: ShortStr,
+    exchange: ShortStr,
+    routing_key: ShortStr,
+    no_wait: bool,
+    arguments: FieldTable,
+}
+
+#[derive(Deserialize)]
+struct QueueUnbind {
+    _ticket: u16,
+    queue: ShortStr,
+    exchange: ShortStr,
+    routing_key: ShortStr,
+    arguments: FieldTable,
+}
+
+#[derive(Deserialize)]
+struct QueuePurge {
+    _ticket: u16,
+    queue: ShortStr,
+    no_wait: bool,
+}
+
+#[derive(Deserialize)]
+struct QueueDelete {
+    _ticket: u16,
+    queue: ShortStr,
+    bits: u8,
+}
+
+/// Arguments of `queue.purge-ok` and `queue.delete-ok`
+#[derive(Serialize)]
+struct MessageCount {
+    message_count: u32,
+}
+
+#[derive(Deserialize)]
+struct Qos {
+    _prefetch_size: u32,
+    prefetch_count: u16,
+    _global: bool,
+}
+
+#[derive(Deserialize)]
+struct Consume {
+    _ticket: u16,
+    queue: ShortStr,
+    consumer_tag: ShortStr,
+    bits: u8,
+    _arguments: FieldTable,
+}
+
+/// Arguments of `basic.consume-ok` and `basic.cancel-ok`
+#[derive(Serialize)]
+struct ConsumerTag {
+    consumer_tag: ShortStr,
+}
+
+#[derive(Deserialize)]
+struct Cancel {
+    consumer_tag: ShortStr,
+    no_wait: bool,
+}
+
+#[derive(Deserialize)]
+struct Publish {
+    _ticket: u16,
+    exchange: ShortStr,
+    routing_key: ShortStr,
+    bits: u8,
+}
+
+#[derive(Serialize)]
+struct Return {
+    reply_code: u16,
+    reply_text: ShortStr,
+    exchange: ShortStr,
+    routing_key: ShortStr,
+}
+
+#[derive(Serialize)]
+struct Deliver {
+    consumer_tag: ShortStr,
+    delivery_tag: u64,
+    redelivered: bool,
+    exchange: ShortStr,
+    routing_key: ShortStr,
+}
+
+#[derive(Deserialize)]
+struct Get {
+    _ticket: u16,
+    queue: ShortStr,
+    no_ack: bool,
+}
+
+#[derive(Serialize)]
+struct GetOk {
+    delivery_tag: u64,
+    redelivered: bool,
+    exchange: ShortStr,
+    routing_key: ShortStr,
+    message_count: u32,
+}
+
+#[derive(Serialize)]
+struct GetEmpty {
+    cluster_id: ShortStr,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Ack {
+    delivery_tag: u64,
+    multiple: bool,
+}
+
+#[derive(Deserialize)]
+struct Reject {
+    delivery_tag: u64,
+    requeue: bool,
+}
+
+#[derive(Deserialize)]
+struct Nack {
+    delivery_tag: u64,
+    bits: u8,
+}
+
+#[derive(Deserialize)]
+struct ConfirmSelect {
+    no_wait: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ContentHeader {
+    class: u16,
+    weight: u16,
+    body_size: u64,
+    properties: BasicProperties,
+}
+
+struct RawFrame {
+    kind: u8,
+    channel: u16,
+    payload: Vec<u8>,
+}
+
+impl RawFrame {
+    /// Method id and arguments of a method frame
+    fn method(&self) -> Option<(MethodId, &[u8])> {
+        let (id, arguments) = self.payload.split_first_chunk::<4>()?;
+        let class = u16::from_be_bytes([id[0], id[1]]);
+        let method = u16::from_be_bytes([id[2], id[3]]);
+        Some(((class, method), arguments))
+    }
+}
+
+fn encode_frame(kind: u8, channel: u16, payload: &[u8], out: &mut Vec<u8>) {
+    out.push(kind);
+    out.extend_from_slice(&channel.to_be_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+    out.push(FRAME_END);
+}
+
+fn method_frame<T: Serialize>(channel: u16, (class, method): MethodId, arguments: &T) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&class.to_be_bytes());
+    payload.extend_from_slice(&method.to_be_bytes());
+    if let Err(e) = amqp_serde::to_buffer(arguments, &mut payload) {
+        tracing::error!("Failed to encode method {class}.{method}: {e}");
+    }
+    let mut frame = Vec::new();
+    encode_frame(FRAME_METHOD, channel, &payload, &mut frame);
+    frame
+}
+
+/// Append the content header and body frames of a message to `out`
+fn content_frames(
+    channel: u16,
+    properties: &BasicProperties,
+    content: &[u8],
+    frame_max: usize,
+    out: &mut Vec<u8>,
+) {
+    let header = ContentHeader {
+        class: 60,
+        weight: 0,
+        body_size: content.len() as u64,
+        properties: properties.clone(),
+    };
+    match amqp_serde::to_bytes(&header) {
+        Ok(payload) => encode_frame(FRAME_HEADER, channel, &payload, out),
+        Err(e) => tracing::error!("Failed to encode content header: {e}"),
+    }
+    for chunk in content.chunks(frame_max.saturating_sub(FRAME_OVERHEAD).max(1)) {
+        encode_frame(FRAME_BODY, channel, chunk, out);
+    }
+}
+
+fn heartbeat_frame() -> Vec<u8> {
+    let mut frame = Vec::new();
+    encode_frame(FRAME_HEARTBEAT, 0, &[], &mut frame);
+    frame
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<RawFrame> {
+    let mut header = [0u8; 7];
+    reader.read_exact(&mut header).await?;
+    let size = u32::from_be_bytes([header[3], header[4], header[5], header[6]]);
+    if size > FRAME_MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame of {size} bytes exceeds frame_max"),
+        ));
+    }
+    let mut payload = vec![0u8; size as usize];
+    reader.read_exact(&mut payload).await?;
+    if reader.read_u8().await? != FRAME_END {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame does not end with the frame end marker",
+        ));
+    }
+    Ok(RawFrame {
+        kind: header[0],
+        channel: u16::from_be_bytes([header[1], header[2]]),
+        payload,
+    })
+}
+
+/// Read frames until a method frame, which must be `expected`
+async fn read_method<R, T>(reader: &mut R, expected: MethodId) -> io::Result<T>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    loop {
+        let frame = read_frame(reader).await?;
+        if frame.kind == FRAME_HEARTBEAT {
+            continue;
+        }
+        let Some((method, arguments)) = frame.method() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Expected a method frame",
+            ));
+        };
+        if method != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected method {expected:?}, received {method:?}"),
+            ));
+        }
+        return amqp_serde::from_bytes(arguments)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+    }
+}
+
+fn short(value: &str) -> ShortStr {
+    ShortStr::try_from(value).unwrap_or_default()
+}
+
+fn argument<'a>(table: &'a FieldTable, name: &str) -> Option<&'a FieldValue> {
+    table.get(&FieldName::try_from(name).ok()?)
+}
+
+fn int_argument(table: &FieldTable, name: &str) -> Option<u64> {
+    match argument(table, name)? {
+        FieldValue::b(n) => u64::try_from(*n).ok(),
+        FieldValue::B(n) => Some(u64::from(*n)),
+        FieldValue::s(n) => u64::try_from(*n).ok(),
+        FieldValue::u(n) => Some(u64::from(*n)),
+        FieldValue::I(n) => u64::try_from(*n).ok(),
+        FieldValue::i(n) => Some(u64::from(*n)),
+        FieldValue::l(n) => u64::try_from(*n).ok(),
+        _ => None,
+    }
+}
+
+fn str_argument(table: &FieldTable, name: &str) -> Option<String> {
+    match argument(table, name)? {
+        FieldValue::S(value) => Some(value.as_ref().clone()),
+        _ => None,
+    }
+}
+
+/// Properties of a dead-lettered message, which RabbitMQ strips of their expiration so the
+/// message does not expire again in its new queue
+fn without_expiration(properties: &BasicProperties) -> BasicProperties {
+    BasicProperties::new(
+        properties.content_type().cloned(),
+        properties.content_encoding().cloned(),
+        properties.headers().cloned(),
+        properties.delivery_mode(),
+        properties.priority(),
+        properties.correlation_id().cloned(),
+        properties.reply_to().cloned(),
+        None,
+        properties.message_id().cloned(),
+        properties.timestamp(),
+        properties.message_type().cloned(),
+        properties.user_id().cloned(),
+        properties.app_id().cloned(),
+        properties.cluster_id().cloned(),
+    )
+}
+
+/// Whether a topic `pattern` matches a routing key, both split into words
+fn topic_matches(pattern: &[&str], words: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => words.is_empty(),
+        Some((&"#", rest)) => {
+            (0..=words.len()).any(|skip| topic_matches(rest, words.get(skip..).unwrap_or_default()))
+        }
+        Some((&first, rest)) => match words.split_first() {
+            Some((&word, words)) => (first == "*" || first == word) && topic_matches(rest, words),
+            None => false,
+        },
+    }
+}
+
+/// Whether the headers of a message match the arguments of a headers exchange binding
+fn headers_match(arguments: &FieldTable, headers: Option<&FieldTable>) -> bool {
+    let any = matches!(
+        argument(arguments, "x-match"),
+        Some(FieldValue::S(value)) if value.as_ref().starts_with("any")
+    );
+    let mut expected = arguments
+        .as_ref()
+        .iter()
+        .filter(|(name, _)| !name.as_ref().starts_with("x-"));
+    let matches = |(name, value): (&FieldName, &FieldValue)| {
+        let actual = headers.and_then(|headers| headers.get(name));
+        match value {
+            FieldValue::V => actual.is_some(),
+            value => actual == Some(value),
+        }
+    };
+    if any {
+        expected.any(matches)
+    } else {
+        expected.all(matches)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExchangeKind {
+    Direct,
+    Fanout,
+    Topic,
+    Headers,
+}
+
+impl ExchangeKind {
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "direct" => Some(Self::Direct),
+            "fanout" => Some(Self::Fanout),
+            "topic" => Some(Self::Topic),
+            "headers" => Some(Self::Headers),
+            _ => None,
+        }
+    }
+}
+
+struct Binding {
+    queue: String,
+    routing_key: String,
+    arguments: FieldTable,
+}
+
+impl Binding {
+    fn matches(&self, kind: ExchangeKind, routing_key: &str, headers: Option<&FieldTable>) -> bool {
+        match kind {
+            ExchangeKind::Direct => self.routing_key == routing_key,
+            ExchangeKind::Fanout => true,
+            ExchangeKind::Topic => {
+                let pattern: Vec<&str> = self.routing_key.split('.').collect();
+                let words: Vec<&str> = routing_key.split('.').collect();
+                topic_matches(&pattern, &words)
+            }
+            ExchangeKind::Headers => headers_match(&self.arguments, headers),
+        }
+    }
+}
+
+struct Exchange {
+    kind: ExchangeKind,
+    bindings: Vec<Binding>,
+}
+
+#[derive(Clone)]
+struct Message {
+    exchange: String,
+    routing_key: String,
+    properties: BasicProperties,
+    content: Vec<u8>,
+    redelivered: bool,
+    expires_at: Option<Instant>,
+}
+
+/// A message held by the broker, as returned by [`MemoryBroker::messages`]
+#[derive(Debug, Clone)]
+pub struct MemoryMessage {
+    pub exchange: String,
+    pub routing_key: String,
+    pub properties: BasicProperties,
+    pub content: Vec<u8>,
+    /// Whether the message was delivered before and requeued
+    pub redelivered: bool,
+}
+
+impl From<&Message> for MemoryMessage {
+    fn from(message: &Message) -> Self {
+        Self {
+            exchange: message.exchange.clone(),
+            routing_key: message.routing_key.clone(),
+            properties: message.properties.clone(),
+            content: message.content.clone(),
+            redelivered: message.redelivered,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+struct ConsumerRef {
+    connection: u64,
+    channel: u16,
+    tag: String,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Message>,
+    consumers: Vec<ConsumerRef>,
+    /// Index of the consumer the next message is offered to first
+    next_consumer: usize,
+    arguments: FieldTable,
+    message_ttl: Option<Duration>,
+    dead_letter_exchange: Option<String>,
+    dead_letter_routing_key: Option<String>,
+}
+
+impl Queue {
+    fn new(arguments: FieldTable) -> Self {
+        Self {
+            message_ttl: int_argument(&arguments, "x-message-ttl").map(Duration::from_millis),
+            dead_letter_exchange: str_argument(&arguments, "x-dead-letter-exchange"),
+            dead_letter_routing_key: str_argument(&arguments, "x-dead-letter-routing-key"),
+            arguments,
+            ..Self::default()
+        }
+    }
+}
+
+struct Consumer {
+    queue: String,
+    no_ack: bool,
+    prefetch: u16,
+    unacked: usize,
+}
+
+impl Consumer {
+    fn ready(&self) -> bool {
+        self.no_ack || self.prefetch == 0 || self.unacked < usize::from(self.prefetch)
+    }
+}
+
+struct Unacked {
+    queue: String,
+    /// `None` for messages fetched with `basic.get`
+    consumer_tag: Option<String>,
+    message: Message,
+}
+
+/// A message being received, between its `basic.publish` and its last body frame
+struct Publishing {
+    exchange: String,
+    routing_key: String,
+    mandatory: bool,
+    properties: BasicProperties,
+    body_size: usize,
+    content: Vec<u8>,
+}
+
+#[derive(Default)]
+struct ChannelState {
+    /// Closed by the broker, waiting for the client to acknowledge
+    closing: bool,
+    confirm: bool,
+    /// Number of messages published since confirm mode was selected
+    published: u64,
+    next_delivery_tag: u64,
+    prefetch: u16,
+    consumers: HashMap<String, Consumer>,
+    unacked: BTreeMap<u64, Unacked>,
+    publishing: Option<Publishing>,
+    /// Queue behind the direct reply-to consumer of the channel
+    reply_queue: Option<String>,
+}
+
+struct ConnectionState {
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    frame_max: usize,
+    channels: HashMap<u16, ChannelState>,
+}
+
+impl ConnectionState {
+    fn send(&self, frames: Vec<u8>) {
+        // The writer is gone once the socket is, and the connection is removed right after
+        let _ = self.outgoing.send(frames);
+    }
+}
+
+/// Error closing a channel
+struct ChannelError {
+    code: u16,
+    text: String,
+    method: MethodId,
+}
+
+impl ChannelError {
+    fn new(code: u16, text: impl Into<String>, method: MethodId) -> Self {
+        Self {
+            code,
+            text: text.into(),
+            method,
+        }
+    }
+
+    fn not_found(text: impl Into<String>, method: MethodId) -> Self {
+        Self::new(NOT_FOUND, text, method)
+    }
+}
+
+enum Flow {
+    Continue,
+    Close,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    exchanges: HashMap<String, Exchange>,
+    queues: HashMap<String, Queue>,
+    connections: HashMap<u64, ConnectionState>,
+    /// Source of connection ids and of generated queue names and consumer tags
+    next_id: u64,
+}
+
+impl BrokerState {
+    fn new() -> Self {
+        let mut state = Self::default();
+        for (name, kind) in [
+            ("amq.direct", ExchangeKind::Direct),
+            ("amq.fanout", ExchangeKind::Fanout),
+            ("amq.topic", ExchangeKind::Topic),
+            ("amq.headers", ExchangeKind::Headers),
+            ("amq.match", ExchangeKind::Headers),
+        ] {
+            state.exchanges.insert(
+                name.to_string(),
+                Exchange {
+                    kind,
+                    bindings: Vec::new(),
+                },
+            );
+        }
+        state
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn send(&self, connection: u64, frames: Vec<u8>) {
+        if let Some(connection) = self.connections.get(&connection) {
+            connection.send(frames);
+        }
+    }
+
+    fn reply<T: Serialize>(&self, connection: u64, channel: u16, method: MethodId, arguments: &T) {
+        self.send(connection, method_frame(channel, method, arguments));
+    }
+
+    fn channel_mut(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        method: MethodId,
+    ) -> Result<&mut ChannelState, ChannelError> {
+        self.connections
+            .get_mut(&connection)
+            .and_then(|connection| connection.channels.get_mut(&channel))
+            .ok_or_else(|| ChannelError::new(CHANNEL_ERROR, "channel is not open", method))
+    }
+
+    fn handle_frame(&mut self, connection: u64, frame: RawFrame) -> Flow {
+        if !self.connections.contains_key(&connection) {
+            return Flow::Close;
+        }
+        if frame.kind == FRAME_HEARTBEAT {
+            return Flow::Continue;
+        }
+        if frame.channel == 0 {
+            return self.handle_connection_frame(connection, &frame);
+        }
+        let result = match frame.kind {
+            FRAME_METHOD => self.handle_method(connection, &frame),
+            FRAME_HEADER => self.handle_content_header(connection, &frame),
+            FRAME_BODY => self.handle_content_body(connection, &frame),
+            _ => Ok(()),
+        };
+        if let Err(error) = result {
+            self.fail_channel(connection, frame.channel, error);
+        }
+        self.dispatch();
+        Flow::Continue
+    }
+
+    fn handle_connection_frame(&mut self, connection: u64, frame: &RawFrame) -> Flow {
+        match frame.method() {
+            Some((CONNECTION_CLOSE, _)) => {
+                self.reply(connection, 0, CONNECTION_CLOSE_OK, &());
+            }
+            Some((CONNECTION_CLOSE_OK, _)) => {}
+            method => {
+                let (class_id, method_id) = method.map(|(id, _)| id).unwrap_or_default();
+                let close = Close {
+                    reply_code: COMMAND_INVALID,
+                    reply_text: short("COMMAND_INVALID - unexpected frame on channel 0"),
+                    class_id,
+                    method_id,
+                };
+                self.reply(connection, 0, CONNECTION_CLOSE, &close);
+            }
+        }
+        Flow::Close
+    }
+
+    fn handle_method(&mut self, connection: u64, frame: &RawFrame) -> Result<(), ChannelError> {
+        let channel = frame.channel;
+        let Some((method, arguments)) = frame.method() else {
+            return Err(ChannelError::new(
+                SYNTAX_ERROR,
+                "method frame is too short",
+                (0, 0),
+            ));
+        };
+        let closing = self
+            .connections
+            .get(&connection)
+            .and_then(|connection| connection.channels.get(&channel))
+            .map(|channel| channel.closing);
+        match (closing, method) {
+            (None, CHANNEL_OPEN) => {
+                if let Some(state) = self.connections.get_mut(&connection) {
+                    state.channels.insert(channel, ChannelState::default());
+                    state.send(method_frame(
+                        channel,
+                        CHANNEL_OPEN_OK,
+                        &ChannelOpenOk {
+                            channel_id: LongStr::default(),
+                        },
+                    ));
+                }
+                Ok(())
+            }
+            // Frames on a channel that is not open are dropped
+            (None, _) => Ok(()),
+            (Some(true), CHANNEL_CLOSE_OK) => {
+                self.remove_channel(connection, channel);
+                Ok(())
+            }
+            (Some(true), CHANNEL_CLOSE) => {
+                self.remove_channel(connection, channel);
+                self.reply(connection, channel, CHANNEL_CLOSE_OK, &());
+                Ok(())
+            }
+            // Everything else sent after the broker closed the channel is ignored
+            (Some(true), _) => Ok(()),
+            (Some(false), _) => self.handle_channel_method(connection, channel, method, arguments),
+        }
+    }
+
+    fn handle_channel_method(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        method: MethodId,
+        arguments: &[u8],
+    ) -> Result<(), ChannelError> {
+        match method {
+            CHANNEL_CLOSE => {
+                self.remove_channel(connection, channel);
+                self.reply(connection, channel, CHANNEL_CLOSE_OK, &());
+                Ok(())
+            }
+            EXCHANGE_DECLARE => {
+                self.exchange_declare(connection, channel, decode(arguments, method)?)
+            }
+            EXCHANGE_DELETE => {
+                self.exchange_delete(connection, channel, decode(arguments, method)?)
+            }
+            QUEUE_DECLARE => self.queue_declare(connection, channel, decode(arguments, method)?),
+            QUEUE_BIND => self.queue_bind(connection, channel, decode(arguments, method)?),
+            QUEUE_UNBIND => self.queue_unbind(connection, channel, decode(arguments, method)?),
+            QUEUE_PURGE => self.queue_purge(connection, channel, decode(arguments, method)?),
+            QUEUE_DELETE => self.queue_delete(connection, channel, decode(arguments, method)?),
+            BASIC_QOS => {
+                let qos: Qos = decode(arguments, method)?;
+                self.channel_mut(connection, channel, method)?.prefetch = qos.prefetch_count;
+                self.reply(connection, channel, BASIC_QOS_OK, &());
+                Ok(())
+            }
+            BASIC_CONSUME => self.basic_consume(connection, channel, decode(arguments, method)?),
+            BASIC_CANCEL => self.basic_cancel(connection, channel, decode(arguments, method)?),
+            BASIC_PUBLISH => {
+                let publish: Publish = decode(arguments, method)?;
+                self.channel_mut(connection, channel, method)?.publishing = Some(Publishing {
+                    exchange: publish.exchange.into(),
+                    routing_key: publish.routing_key.into(),
+                    mandatory: publish.bits & 0b0000_0001 != 0,
+                    properties: BasicProperties::default(),
+                    body_size: 0,
+                    content: Vec::new(),
+                });
+                Ok(())
+            }
+            BASIC_GET => self.basic_get(connection, channel, decode(arguments, method)?),
+            BASIC_ACK => {
+                let ack: Ack = decode(arguments, method)?;
+                self.take_unacked(connection, channel, ack.delivery_tag, ack.multiple, method)?;
+                Ok(())
+            }
+            BASIC_REJECT => {
+                let reject: Reject = decode(arguments, method)?;
+                let rejected =
+                    self.take_unacked(connection, channel, reject.delivery_tag, false, method)?;
+                self.settle_rejected(rejected, reject.requeue);
+                Ok(())
+            }
+            BASIC_NACK => {
+                let nack: Nack = decode(arguments, method)?;
+                let multiple = nack.bits & 0b0000_0001 != 0;
+                let requeue = nack.bits & 0b0000_0010 != 0;
+                let rejected =
+                    self.take_unacked(connection, channel, nack.delivery_tag, multiple, method)?;
+                self.settle_rejected(rejected, requeue);
+                Ok(())
+            }
+            CONFIRM_SELECT => {
+                let select: ConfirmSelect = decode(arguments, method)?;
+                self.channel_mut(connection, channel, method)?.confirm = true;
+                if !select.no_wait {
+                    self.reply(connection, channel, CONFIRM_SELECT_OK, &());
+                }
+                Ok(())
+            }
+            _ => Err(ChannelError::new(
+                NOT_IMPLEMENTED,
+                format!(
+                    "NOT_IMPLEMENTED - method {}.{} is not supported by the in-memory broker",
+                    method.0, method.1
+                ),
+                method,
+            )),
+        }
+    }
+
+    fn handle_content_header(
+        &mut self,
+        connection: u64,
+        frame: &RawFrame,
+    ) -> Result<(), ChannelError> {
+        let header: ContentHeader = decode(&frame.payload, BASIC_PUBLISH)?;
+        let state = self.channel_mut(connection, frame.channel, BASIC_PUBLISH)?;
+        if state.closing {
+            return Ok(());
+        }
+        let Some(publishing) = state.publishing.as_mut() else {
+            return Err(ChannelError::new(
+                UNEXPECTED_FRAME,
+                "UNEXPECTED_FRAME - content header without basic.publish",
+                BASIC_PUBLISH,
+            ));
+        };
+        publishing.properties = header.properties;
+        publishing.body_size = usize::try_from(header.body_size).unwrap_or(usize::MAX);
+        if publishing.body_size == 0 {
+            return self.complete_publish(connection, frame.channel);
+        }
+        Ok(())
+    }
+
+    fn handle_content_body(
+        &mut self,
+        connection: u64,
+        frame: &RawFrame,
+    ) -> Result<(), ChannelError> {
+        let state = self.channel_mut(connection, frame.channel, BASIC_PUBLISH)?;
+        if state.closing {
+            return Ok(());
+        }
+        let Some(publishing) = state.publishing.as_mut() else {
+            return Err(ChannelError::new(
+                UNEXPECTED_FRAME,
+                "UNEXPECTED_FRAME - content body without basic.publish",
+                BASIC_PUBLISH,
+            ));
+        };
+        publishing.content.extend_from_slice(&frame.payload);
+        if publishing.content.len() >= publishing.body_size {
+            return self.complete_publish(connection, frame.channel);
+        }
+        Ok(())
+    }
+
+    fn complete_publish(&mut self, connection: u64, channel: u16) -> Result<(), ChannelError> {
+        let state = self.channel_mut(connection, channel, BASIC_PUBLISH)?;
+        let Some(mut publishing) = state.publishing.take() else {
+            return Ok(());
+        };
+        if publishing.properties.reply_to().map(String::as_str) == Some(DIRECT_REPLY_TO) {
+            let Some(reply_queue) = state.reply_queue.clone() else {
+                return Err(ChannelError::new(
+                    PRECONDITION_FAILED,
+                    "PRECONDITION_FAILED - fast reply consumer does not exist",
+                    BASIC_PUBLISH,
+                ));
+            };
+            publishing.properties.with_reply_to(&reply_queue);
+        }
+        let confirm = state.confirm.then(|| {
+            state.published += 1;
+            state.published
+        });
+
+        let Some(targets) = self.route(
+            &publishing.exchange,
+            &publishing.routing_key,
+            publishing.properties.headers(),
+        ) else {
+            return Err(ChannelError::not_found(
+                format!(
+                    "NOT_FOUND - no exchange '{}' in vhost '/'",
+                    publishing.exchange
+                ),
+                BASIC_PUBLISH,
+            ));
+        };
+        if targets.is_empty() && publishing.mandatory {
+            let Some(state) = self.connections.get(&connection) else {
+                return Ok(());
+            };
+            let mut frames = method_frame(
+                channel,
+                BASIC_RETURN,
+                &Return {
+                    reply_code: NO_ROUTE,
+                    reply_text: short("NO_ROUTE"),
+                    exchange: short(&publishing.exchange),
+                    routing_key: short(&publishing.routing_key),
+                },
+            );
+            content_frames(
+                channel,
+                &publishing.properties,
+                &publishing.content,
+                state.frame_max,
+                &mut frames,
+            );
+            state.send(frames);
+        }
+        let message = Message {
+            exchange: publishing.exchange,
+            routing_key: publishing.routing_key,
+            properties: publishing.properties,
+            content: publishing.content,
+            redelivered: false,
+            expires_at: None,
+        };
+        for target in targets {
+            self.enqueue(&target, message.clone());
+        }
+        if let Some(delivery_tag) = confirm {
+            self.reply(
+                connection,
+                channel,
+                BASIC_ACK,
+                &Ack {
+                    delivery_tag,
+                    multiple: false,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn exchange_declare(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        declare: ExchangeDeclare,
+    ) -> Result<(), ChannelError> {
+        let passive = declare.bits & 0b0000_0001 != 0;
+        let no_wait = declare.bits & 0b0001_0000 != 0;
+        let name: String = declare.exchange.into();
+        match self.exchanges.get(&name) {
+            Some(exchange) => {
+                if !passive && ExchangeKind::parse(declare.kind.as_ref()) != Some(exchange.kind) {
+                    return Err(ChannelError::new(
+                        PRECONDITION_FAILED,
+                        format!(
+                            "PRECONDITION_FAILED - inequivalent arg 'type' for exchange '{name}'"
+                        ),
+                        EXCHANGE_DECLARE,
+                    ));
+                }
+            }
+            None if passive => {
+                return Err(ChannelError::not_found(
+                    format!("NOT_FOUND - no exchange '{name}' in vhost '/'"),
+                    EXCHANGE_DECLARE,
+                ));
+            }
+            None => {
+                if name.starts_with("amq.") {
+                    return Err(ChannelError::new(
+                        ACCESS_REFUSED,
+                        format!(
+                            "ACCESS_REFUSED - exchange name '{name}' contains reserved prefix 'amq.*'"
+                        ),
+                        EXCHANGE_DECLARE,
+                    ));
+                }
+                let Some(kind) = ExchangeKind::parse(declare.kind.as_ref()) else {
+                    return Err(ChannelError::new(
+                        COMMAND_INVALID,
+                        format!("COMMAND_INVALID - unknown exchange type '{}'", declare.kind),
+                        EXCHANGE_DECLARE,
+                    ));
+                };
+                self.exchanges.insert(
+                    name,
+                    Exchange {
+                        kind,
+                        bindings: Vec::new(),
+                    },
+                );
+            }
+        }
+        if !no_wait {
+            self.reply(connection, channel, EXCHANGE_DECLARE_OK, &());
+        }
+        Ok(())
+    }
+
+    fn exchange_delete(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        delete: ExchangeDelete,
+    ) -> Result<(), ChannelError> {
+        let if_unused = delete.bits & 0b0000_0001 != 0;
+        let no_wait = delete.bits & 0b0000_0010 != 0;
+        let name: String = delete.exchange.into();
+        if if_unused
+            && self
+                .exchanges
+                .get(&name)
+                .is_some_and(|exchange| !exchange.bindings.is_empty())
+        {
+            return Err(ChannelError::new(
+                PRECONDITION_FAILED,
+                format!("PRECONDITION_FAILED - exchange '{name}' in use"),
+                EXCHANGE_DELETE,
+            ));
+        }
+        self.exchanges.remove(&name);
+        if !no_wait {
+            self.reply(connection, channel, EXCHANGE_DELETE_OK, &());
+        }
+        Ok(())
+    }
+
+    fn queue_declare(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        declare: QueueDeclare,
+    ) -> Result<(), ChannelError> {
+        let passive = declare.bits & 0b0000_0001 != 0;
+        let no_wait = declare.bits & 0b0001_0000 != 0;
+        let name = if declare.queue.as_ref().is_empty() {
+            format!("amq.gen-{}", self.next_id())
+        } else {
+            let name: String = declare.queue.into();
+            if !passive && name.starts_with("amq.") {
+                return Err(ChannelError::new(
+                    ACCESS_REFUSED,
+                    format!(
+                        "ACCESS_REFUSED - queue name '{name}' contains reserved prefix 'amq.*'"
+                    ),
+                    QUEUE_DECLARE,
+                ));
+            }
+            name
+        };
+        match self.queues.get(&name) {
+            Some(queue) => {
+                if !passive && queue.arguments.as_ref() != declare.arguments.as_ref() {
+                    return Err(ChannelError::new(
+                        PRECONDITION_FAILED,
+                        format!("PRECONDITION_FAILED - inequivalent arguments for queue '{name}'"),
+                        QUEUE_DECLARE,
+                    ));
+                }
+            }
+            None if passive => {
+                return Err(ChannelError::not_found(
+                    format!("NOT_FOUND - no queue '{name}' in vhost '/'"),
+                    QUEUE_DECLARE,
+                ));
+            }
+            None => {
+                self.queues
+                    .insert(name.clone(), Queue::new(declare.arguments));
+            }
+        }
+        if !no_wait {
+            let (message_count, consumer_count) = self
+                .queues
+                .get(&name)
+                .map(|queue| (queue.messages.len(), queue.consumers.len()))
+                .unwrap_or_default();
+            self.reply(
+                connection,
+                channel,
+                QUEUE_DECLARE_OK,
+                &QueueDeclareOk {
+                    queue: short(&name),
+                    message_count: message_count as u32,
+                    consumer_count: consumer_count as u32,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn queue_bind(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        bind: QueueBind,
+    ) -> Result<(), ChannelError> {
+        let queue: String = bind.queue.into();
+        let exchange_name: String = bind.exchange.into();
+        if exchange_name.is_empty() {
+            return Err(ChannelError::new(
+                ACCESS_REFUSED,
+                "ACCESS_REFUSED - operation not permitted on the default exchange",
+                QUEUE_BIND,
+            ));
+        }
+        if !self.queues.contains_key(&queue) {
+            return Err(ChannelError::not_found(
+                format!("NOT_FOUND - no queue '{queue}' in vhost '/'"),
+                QUEUE_BIND,
+            ));
+        }
+        let Some(exchange) = self.exchanges.get_mut(&exchange_name) else {
+            return Err(ChannelError::not_found(
+                format!("NOT_FOUND - no exchange '{exchange_name}' in vhost '/'"),
+                QUEUE_BIND,
+            ));
+        };
+        let routing_key: String = bind.routing_key.into();
+        let exists = exchange.bindings.iter().any(|binding| {
+            binding.queue == queue
+                && binding.routing_key == routing_key
+                && binding.arguments.as_ref() == bind.arguments.as_ref()
+        });
+        if !exists {
+            exchange.bindings.push(Binding {
+                queue,
+                routing_key,
+                arguments: bind.arguments,
+            });
+        }
+        if !bind.no_wait {
+            self.reply(connection, channel, QUEUE_BIND_OK, &());
+        }
+        Ok(())
+    }
+
+    fn queue_unbind(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        unbind: QueueUnbind,
+    ) -> Result<(), ChannelError> {
+        let queue: String = unbind.queue.into();
+        let routing_key: String = unbind.routing_key.into();
+        if let Some(exchange) = self.exchanges.get_mut(unbind.exchange.as_ref()) {
+            exchange.bindings.retain(|binding| {
+                binding.queue != queue
+                    || binding.routing_key != routing_key
+                    || binding.arguments.as_ref() != unbind.arguments.as_ref()
+            });
+        }
+        self.reply(connection, channel, QUEUE_UNBIND_OK, &());
+        Ok(())
+    }
+
+    fn queue_purge(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        purge: QueuePurge,
+    ) -> Result<(), ChannelError> {
+        let Some(queue) = self.queues.get_mut(purge.queue.as_ref()) else {
+            return Err(ChannelError::not_found(
+                format!("NOT_FOUND - no queue '{}' in vhost '/'", purge.queue),
+                QUEUE_PURGE,
+            ));
+        };
+        let message_count = queue.messages.len() as u32;
+        queue.messages.clear();
+        if !purge.no_wait {
+            self.reply(
+                connection,
+                channel,
+                QUEUE_PURGE_OK,
+                &MessageCount { message_count },
+            );
+        }
+        Ok(())
+    }
+
+    fn queue_delete(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        delete: QueueDelete,
+    ) -> Result<(), ChannelError> {
+        let if_unused = delete.bits & 0b0000_0001 != 0;
+        let if_empty = delete.bits & 0b0000_0010 != 0;
+        let no_wait = delete.bits & 0b0000_0100 != 0;
+        let name: String = delete.queue.into();
+        if let Some(queue) = self.queues.get(&name) {
+            if if_unused && !queue.consumers.is_empty() {
+                return Err(ChannelError::new(
+                    PRECONDITION_FAILED,
+                    format!("PRECONDITION_FAILED - queue '{name}' in use"),
+                    QUEUE_DELETE,
+                ));
+            }
+            if if_empty && !queue.messages.is_empty() {
+                return Err(ChannelError::new(
+                    PRECONDITION_FAILED,
+                    format!("PRECONDITION_FAILED - queue '{name}' not empty"),
+                    QUEUE_DELETE,
+                ));
+            }
+        }
+        let message_count = self.delete_queue(&name);
+        if !no_wait {
+            self.reply(
+                connection,
+                channel,
+                QUEUE_DELETE_OK,
+                &MessageCount { message_count },
+            );
+        }
+        Ok(())
+    }
+
+    /// Delete a queue with its bindings and consumers, returning how many messages it held
+    fn delete_queue(&mut self, name: &str) -> u32 {
+        let Some(queue) = self.queues.remove(name) else {
+            return 0;
+        };
+        for exchange in self.exchanges.values_mut() {
+            exchange.bindings.retain(|binding| binding.queue != name);
+        }
+        for consumer in queue.consumers {
+            if let Some(channel) = self
+                .connections
+                .get_mut(&consumer.connection)
+                .and_then(|connection| connection.channels.get_mut(&consumer.channel))
+            {
+                channel.consumers.remove(&consumer.tag);
+            }
+        }
+        queue.messages.len() as u32
+    }
+
+    fn basic_consume(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        consume: Consume,
+    ) -> Result<(), ChannelError> {
+        let no_ack = consume.bits & 0b0000_0010 != 0;
+        let no_wait = consume.bits & 0b0000_1000 != 0;
+        let mut queue: String = consume.queue.into();
+        if queue == DIRECT_REPLY_TO {
+            if !no_ack {
+                return Err(ChannelError::new(
+                    PRECONDITION_FAILED,
+                    "PRECONDITION_FAILED - reply consumer cannot acknowledge",
+                    BASIC_CONSUME,
+                ));
+            }
+            queue = format!("{DIRECT_REPLY_TO}.{connection}.{channel}");
+            self.queues.entry(queue.clone()).or_default();
+            self.channel_mut(connection, channel, BASIC_CONSUME)?
+                .reply_queue = Some(queue.clone());
+        }
+        if !self.queues.contains_key(&queue) {
+            return Err(ChannelError::not_found(
+                format!("NOT_FOUND - no queue '{queue}' in vhost '/'"),
+                BASIC_CONSUME,
+            ));
+        }
+        let tag = if consume.consumer_tag.as_ref().is_empty() {
+            format!("amq.ctag-{}", self.next_id())
+        } else {
+            consume.consumer_tag.into()
+        };
+        let state = self.channel_mut(connection, channel, BASIC_CONSUME)?;
+        if state.consumers.contains_key(&tag) {
+            return Err(ChannelError::new(
+                NOT_ALLOWED,
+                format!("NOT_ALLOWED - attempt to reuse consumer tag '{tag}'"),
+                BASIC_CONSUME,
+            ));
+        }
+        let prefetch = state.prefetch;
+        state.consumers.insert(
+            tag.clone(),
+            Consumer {
+                queue: queue.clone(),
+                no_ack,
+                prefetch,
+                unacked: 0,
+            },
+        );
+        if let Some(queue) = self.queues.get_mut(&queue) {
+            queue.consumers.push(ConsumerRef {
+                connection,
+                channel,
+                tag: tag.clone(),
+            });
+        }
+        if !no_wait {
+            self.reply(
+                connection,
+                channel,
+                BASIC_CONSUME_OK,
+                &ConsumerTag {
+                    consumer_tag: short(&tag),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn basic_cancel(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        cancel: Cancel,
+    ) -> Result<(), ChannelError> {
+        let tag: String = cancel.consumer_tag.into();
+        let state = self.channel_mut(connection, channel, BASIC_CANCEL)?;
+        if let Some(consumer) = state.consumers.remove(&tag) {
+            self.remove_consumer(&consumer.queue, connection, channel, &tag);
+        }
+        if !cancel.no_wait {
+            self.reply(
+                connection,
+                channel,
+                BASIC_CANCEL_OK,
+                &ConsumerTag {
+                    consumer_tag: short(&tag),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn remove_consumer(&mut self, queue: &str, connection: u64, channel: u16, tag: &str) {
+        if let Some(queue) = self.queues.get_mut(queue) {
+            queue.consumers.retain(|consumer| {
+                consumer.connection != connection
+                    || consumer.channel != channel
+                    || consumer.tag != tag
+            });
+        }
+    }
+
+    fn basic_get(&mut self, connection: u64, channel: u16, get: Get) -> Result<(), ChannelError> {
+        let name: String = get.queue.into();
+        self.expire(&name);
+        let Some(queue) = self.queues.get_mut(&name) else {
+            return Err(ChannelError::not_found(
+                format!("NOT_FOUND - no queue '{name}' in vhost '/'"),
+                BASIC_GET,
+            ));
+        };
+        let message = queue.messages.pop_front();
+        let message_count = queue.messages.len() as u32;
+        let Some(state) = self.connections.get_mut(&connection) else {
+            return Ok(());
+        };
+        let frame_max = state.frame_max;
+        let Some(channel_state) = state.channels.get_mut(&channel) else {
+            return Ok(());
+        };
+        let Some(message) = message else {
+            state.send(method_frame(
+                channel,
+                BASIC_GET_EMPTY,
+                &GetEmpty {
+                    cluster_id: ShortStr::default(),
+                },
+            ));
+            return Ok(());
+        };
+        channel_state.next_delivery_tag += 1;
+        let delivery_tag = channel_state.next_delivery_tag;
+        let mut frames = method_frame(
+            channel,
+            BASIC_GET_OK,
+            &GetOk {
+                delivery_tag,
+                redelivered: message.redelivered,
+                exchange: short(&message.exchange),
+                routing_key: short(&message.routing_key),
+                message_count,
+            },
+        );
+        content_frames(
+            channel,
+            &message.properties,
+            &message.content,
+            frame_max,
+            &mut frames,
+        );
+        if !get.no_ack {
+            channel_state.unacked.insert(
+                delivery_tag,
+                Unacked {
+                    queue: name,
+                    consumer_tag: None,
+                    message,
+                },
+            );
+        }
+        state.send(frames);
+        Ok(())
+    }
+
+    /// Remove settled deliveries from the unacked messages of a channel
+    fn take_unacked(
+        &mut self,
+        connection: u64,
+        channel: u16,
+        delivery_tag: u64,
+        multiple: bool,
+        method: MethodId,
+    ) -> Result<Vec<Unacked>, ChannelError> {
+        let state = self.channel_mut(connection, channel, method)?;
+        let tags: Vec<u64> = if multiple {
+            let upper = if delivery_tag == 0 {
+                u64::MAX
+            } else {
+                delivery_tag
+            };
+            state.unacked.range(..=upper).map(|(tag, _)| *tag).collect()
+        } else if state.unacked.contains_key(&delivery_tag) {
+            vec![delivery_tag]
+        } else {
+            return Err(ChannelError::new(
+                PRECONDITION_FAILED,
+                format!("PRECONDITION_FAILED - unknown delivery tag {delivery_tag}"),
+                method,
+            ));
+        };
+        let mut taken = Vec::with_capacity(tags.len());
+        for tag in tags {
+            let Some(unacked) = state.unacked.remove(&tag) else {
+                continue;
+            };
+            if let Some(consumer) = unacked
+                .consumer_tag
+                .as_ref()
+                .and_then(|tag| state.consumers.get_mut(tag))
+            {
+                consumer.unacked = consumer.unacked.saturating_sub(1);
+            }
+            taken.push(unacked);
+        }
+        Ok(taken)
+    }
+
+    fn settle_rejected(&mut self, rejected: Vec<Unacked>, requeue: bool) {
+        if requeue {
+            self.requeue(rejected);
+        } else {
+            for unacked in rejected {
+                self.dead_letter(&unacked.queue, unacked.message, "rejected");
+            }
+        }
+    }
+
+    /// Put messages back at the head of their queues, in their original order
+    fn requeue(&mut self, messages: Vec<Unacked>) {
+        for unacked in messages.into_iter().rev() {
+            if let Some(queue) = self.queues.get_mut(&unacked.queue) {
+                let mut message = unacked.message;
+                message.redelivered = true;
+                queue.messages.push_front(message);
+            }
+        }
+    }
+
+    /// Close a channel because of an error, requeueing its unacked messages
+    fn fail_channel(&mut self, connection: u64, channel: u16, error: ChannelError) {
+        tracing::debug!(
+            connection,
+            channel,
+            code = error.code,
+            "Closing channel: {}",
+            error.text
+        );
+        self.release_channel(connection, channel);
+        let Some(state) = self.connections.get_mut(&connection) else {
+            return;
+        };
+        let Some(channel_state) = state.channels.get_mut(&channel) else {
+            return;
+        };
+        channel_state.closing = true;
+        let close = Close {
+            reply_code: error.code,
+            reply_text: short(&error.text),
+            class_id: error.method.0,
+            method_id: error.method.1,
+        };
+        state.send(method_frame(channel, CHANNEL_CLOSE, &close));
+    }
+
+    fn remove_channel(&mut self, connection: u64, channel: u16) {
+        self.release_channel(connection, channel);
+        if let Some(state) = self.connections.get_mut(&connection) {
+            state.channels.remove(&channel);
+        }
+    }
+
+    /// Cancel the consumers of a channel and requeue its unacked messages
+    fn release_channel(&mut self, connection: u64, channel: u16) {
+        let Some(state) = self
+            .connections
+            .get_mut(&connection)
+            .and_then(|state| state.channels.get_mut(&channel))
+        else {
+            return;
+        };
+        let consumers = std::mem::take(&mut state.consumers);
+        let unacked = std::mem::take(&mut state.unacked);
+        let reply_queue = state.reply_queue.take();
+        state.publishing = None;
+        for (tag, consumer) in consumers {
+            self.remove_consumer(&consumer.queue, connection, channel, &tag);
+        }
+        self.requeue(unacked.into_values().collect());
+        if let Some(reply_queue) = reply_queue {
+            self.delete_queue(&reply_queue);
+        }
+    }
+
+    fn remove_connection(&mut self, connection: u64) {
+        let channels: Vec<u16> = self
+            .connections
+            .get(&connection)
+            .map(|state| state.channels.keys().copied().collect())
+            .unwrap_or_default();
+        for channel in channels {
+            self.release_channel(connection, channel);
+        }
+        self.connections.remove(&connection);
+    }
+
+    /// Queues a message published to `exchange` is routed to, `None` if the exchange does not
+    /// exist
+    fn route(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        headers: Option<&FieldTable>,
+    ) -> Option<Vec<String>> {
+        if exchange.is_empty() {
+            return Some(
+                self.queues
+                    .contains_key(routing_key)
+                    .then(|| routing_key.to_string())
+                    .into_iter()
+                    .collect(),
+            );
+        }
+        let exchange = self.exchanges.get(exchange)?;
+        let mut targets: Vec<String> = Vec::new();
+        for binding in &exchange.bindings {
+            if binding.matches(exchange.kind, routing_key, headers)
+                && !targets.contains(&binding.queue)
+            {
+                targets.push(binding.queue.clone());
+            }
+        }
+        Some(targets)
+    }
+
+    fn enqueue(&mut self, queue: &str, mut message: Message) {
+        let Some(queue) = self.queues.get_mut(queue) else {
+            return;
+        };
+        let expiration = message
+            .properties
+            .expiration()
+            .and_then(|expiration| expiration.parse::<u64>().ok())
+            .map(Duration::from_millis);
+        let ttl = [queue.message_ttl, expiration].into_iter().flatten().min();
+        message.expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        queue.messages.push_back(message);
+    }
+
+    /// Route a message rejected or expired from `queue` to its dead-letter exchange, or drop it
+    /// if the queue has none
+    fn dead_letter(&mut self, queue: &str, message: Message, reason: &str) {
+        let Some(source) = self.queues.get(queue) else {
+            return;
+        };
+        let Some(exchange) = source.dead_letter_exchange.clone() else {
+            return;
+        };
+        let routing_key = source
+            .dead_letter_routing_key
+            .clone()
+            .unwrap_or_else(|| message.routing_key.clone());
+        let mut properties = without_expiration(&message.properties);
+        let mut headers = properties.headers().cloned().unwrap_or_default();
+        if argument(&headers, "x-first-death-queue").is_none() {
+            insert_field(&mut headers, "x-first-death-queue", queue.into());
+            insert_field(&mut headers, "x-first-death-reason", reason.into());
+            insert_field(
+                &mut headers,
+                "x-first-death-exchange",
+                message.exchange.as_str().into(),
+            );
+        }
+        properties.with_headers(headers);
+        let Some(targets) = self.route(&exchange, &routing_key, properties.headers()) else {
+            tracing::debug!(
+                queue,
+                exchange,
+                "Dropping message, dead-letter exchange does not exist"
+            );
+            return;
+        };
+        for target in targets {
+            self.enqueue(
+                &target,
+                Message {
+                    exchange: exchange.clone(),
+                    routing_key: routing_key.clone(),
+                    properties: properties.clone(),
+                    content: message.content.clone(),
+                    redelivered: false,
+                    expires_at: None,
+                },
+            );
+        }
+    }
+
+    /// Dead-letter the expired messages at the head of a queue
+    fn expire(&mut self, queue: &str) {
+        let now = Instant::now();
+        let len = self
+            .queues
+            .get(queue)
+            .map(|queue| queue.messages.len())
+            .unwrap_or_default();
+        // Bounded, in case messages are dead-lettered back into the queue already expired
+        for _ in 0..len {
+            let Some(source) = self.queues.get_mut(queue) else {
+                return;
+            };
+            let expired = source
+                .messages
+                .front()
+                .and_then(|message| message.expires_at)
+                .is_some_and(|expires_at| expires_at <= now);
+            if !expired {
+                return;
+            }
+            let Some(message) = source.messages.pop_front() else {
+                return;
+            };
+            self.dead_letter(queue, message, "expired");
+        }
+    }
+
+    /// Expire messages and push ready messages to consumers with room for them
+    fn dispatch(&mut self) {
+        let queues: Vec<String> = self.queues.keys().cloned().collect();
+        for queue in queues {
+            self.expire(&queue);
+            self.deliver(&queue);
+        }
+    }
+
+    fn deliver(&mut self, name: &str) {
+        let Self {
+            queues,
+            connections,
+            ..
+        } = self;
+        let Some(queue) = queues.get_mut(name) else {
+            return;
+        };
+        while !queue.messages.is_empty() {
+            let count = queue.consumers.len();
+            let ready = (0..count)
+                .map(|offset| (queue.next_consumer + offset) % count)
+                .find(|index| {
+                    queue
+                        .consumers
+                        .get(*index)
+                        .and_then(|target| {
+                            connections
+                                .get(&target.connection)?
+                                .channels
+                                .get(&target.channel)?
+                                .consumers
+                                .get(&target.tag)
+                        })
+                        .is_some_and(Consumer::ready)
+                });
+            let Some(index) = ready else {
+                return;
+            };
+            queue.next_consumer = index + 1;
+            let Some(target) = queue.consumers.get(index) else {
+                return;
+            };
+            let Some(connection) = connections.get_mut(&target.connection) else {
+                return;
+            };
+            let frame_max = connection.frame_max;
+            let Some(channel) = connection.channels.get_mut(&target.channel) else {
+                return;
+            };
+            let Some(consumer) = channel.consumers.get_mut(&target.tag) else {
+                return;
+            };
+            let Some(message) = queue.messages.pop_front() else {
+                return;
+            };
+            channel.next_delivery_tag += 1;
+            let delivery_tag = channel.next_delivery_tag;
+            let mut frames = method_frame(
+                target.channel,
+                BASIC_DELIVER,
+                &Deliver {
+                    consumer_tag: short(&target.tag),
+                    delivery_tag,
+                    redelivered: message.redelivered,
+                    exchange: short(&message.exchange),
+                    routing_key: short(&message.routing_key),
+                },
+            );
+            content_frames(
+                target.channel,
+                &message.properties,
+                &message.content,
+                frame_max,
+                &mut frames,
+            );
+            if !consumer.no_ack {
+                consumer.unacked += 1;
+                channel.unacked.insert(
+                    delivery_tag,
+                    Unacked {
+                        queue: name.to_string(),
+                        consumer_tag: Some(target.tag.clone()),
+                        message,
+                    },
+                );
+            }
+            connection.send(frames);
+        }
+    }
+}
+
+fn decode<T: DeserializeOwned>(arguments: &[u8], method: MethodId) -> Result<T, ChannelError> {
+    amqp_serde::from_bytes(arguments).map_err(|e| {
+        ChannelError::new(
+            SYNTAX_ERROR,
+            format!("SYNTAX_ERROR - malformed arguments: {e}"),
+            method,
+        )
+    })
+}
+
+type SharedState = Arc<Mutex<BrokerState>>;
+
+fn lock(state: &Mutex<BrokerState>) -> MutexGuard<'_, BrokerState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// AMQP broker running in the current process, for tests.
+///
+/// Dropping it disconnects every client.
+pub struct MemoryBroker {
+    address: SocketAddr,
+    state: SharedState,
+    listener: JoinHandle<()>,
+    expiry: JoinHandle<()>,
+}
+
+impl MemoryBroker {
+    /// Start a broker listening on a free loopback port
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(BrokerState::new()));
+        let listener = tokio::spawn(accept(listener, Arc::downgrade(&state)));
+        let expiry = tokio::spawn(expire(Arc::downgrade(&state)));
+        Ok(Self {
+            address,
+            state,
+            listener,
+            expiry,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Arguments to open a connection to the broker, with any credentials
+    pub fn connection_arguments(&self) -> OpenConnectionArguments {
+        OpenConnectionArguments::new(
+            &self.address.ip().to_string(),
+            self.address.port(),
+            "guest",
+            "guest",
+        )
+    }
+
+    pub async fn connect(&self) -> Result<Connection, amqprs::error::Error> {
+        Connection::open(&self.connection_arguments()).await
+    }
+
+    /// Pool of channels on a new connection to the broker
+    pub async fn pool(&self) -> Result<AmqpPool, amqprs::error::Error> {
+        Ok(AmqpPool::connect(self.connect().await?).await)
+    }
+
+    /// Number of messages in a queue waiting to be delivered, `None` if it does not exist
+    pub fn queue_len(&self, queue: &str) -> Option<usize> {
+        lock(&self.state)
+            .queues
+            .get(queue)
+            .map(|queue| queue.messages.len())
+    }
+
+    /// Number of consumers of a queue
+    pub fn consumer_count(&self, queue: &str) -> usize {
+        lock(&self.state)
+            .queues
+            .get(queue)
+            .map_or(0, |queue| queue.consumers.len())
+    }
+
+    /// Number of messages from a queue delivered to consumers and not settled yet
+    pub fn unacked_len(&self, queue: &str) -> usize {
+        lock(&self.state)
+            .connections
+            .values()
+            .flat_map(|connection| connection.channels.values())
+            .flat_map(|channel| channel.unacked.values())
+            .filter(|unacked| unacked.queue == queue)
+            .count()
+    }
+
+    /// Messages in a queue waiting to be delivered, from the head
+    pub fn messages(&self, queue: &str) -> Vec<MemoryMessage> {
+        lock(&self.state)
+            .queues
+            .get(queue)
+            .map(|queue| queue.messages.iter().map(MemoryMessage::from).collect())
+            .unwrap_or_default()
+    }
+
+    /// Drop every client connection, as a broker restart would. Unacked messages are requeued,
+    /// exchanges, queues and bindings are kept.
+    pub fn disconnect_all(&self) {
+        let mut state = lock(&self.state);
+        let connections: Vec<u64> = state.connections.keys().copied().collect();
+        for connection in connections {
+            state.remove_connection(connection);
+        }
+    }
+}
+
+impl Drop for MemoryBroker {
+    fn drop(&mut self) {
+        self.listener.abort();
+        self.expiry.abort();
+        self.disconnect_all();
+    }
+}
+
+async fn accept(listener: TcpListener, state: Weak<Mutex<BrokerState>>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        tokio::spawn(async move {
+            if let Err(e) = serve(state, stream).await {
+                tracing::debug!("In-memory broker connection failed: {e}");
+            }
+        });
+    }
+}
+
+async fn expire(state: Weak<Mutex<BrokerState>>) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        lock(&state).dispatch();
+    }
+}
+
+async fn serve(state: SharedState, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).await?;
+    if &header != PROTOCOL_HEADER {
+        writer.write_all(PROTOCOL_HEADER).await?;
+        return Ok(());
+    }
+
+    let mut capabilities = FieldTable::new();
+    for capability in [
+        "publisher_confirms",
+        "basic.nack",
+        "consumer_cancel_notify",
+        "per_consumer_qos",
+        "direct_reply_to",
+        "authentication_failure_close",
+    ] {
+        insert_field(&mut capabilities, capability, true.into());
+    }
+    let mut server_properties = FieldTable::new();
+    insert_field(
+        &mut server_properties,
+        "capabilities",
+        FieldValue::F(capabilities),
+    );
+    insert_field(&mut server_properties, "product", "kanaeru".into());
+    insert_field(
+        &mut server_properties,
+        "version",
+        env!("CARGO_PKG_VERSION").into(),
+    );
+    let start = Start {
+        version_major: 0,
+        version_minor: 9,
+        server_properties,
+        mechanisms: LongStr::try_from("PLAIN AMQPLAIN").unwrap_or_default(),
+        locales: LongStr::try_from("en_US").unwrap_or_default(),
+    };
+    writer
+        .write_all(&method_frame(0, CONNECTION_START, &start))
+        .await?;
+    // Any credentials are accepted
+    read_method::<_, StartOk>(&mut reader, CONNECTION_START_OK).await?;
+    let tune = Tune {
+        channel_max: CHANNEL_MAX,
+        frame_max: FRAME_MAX,
+        heartbeat: 0,
+    };
+    writer
+        .write_all(&method_frame(0, CONNECTION_TUNE, &tune))
+        .await?;
+    let tune_ok: Tune = read_method(&mut reader, CONNECTION_TUNE_OK).await?;
+    read_method::<_, Open>(&mut reader, CONNECTION_OPEN).await?;
+    let open_ok = OpenOk {
+        known_hosts: ShortStr::default(),
+    };
+    writer
+        .write_all(&method_frame(0, CONNECTION_OPEN_OK, &open_ok))
+        .await?;
+
+    let (outgoing, frames) = mpsc::unbounded_channel();
+    let id = {
+        let mut state = lock(&state);
+        let id = state.next_id();
+        state.connections.insert(
+            id,
+            ConnectionState {
+                outgoing,
+                frame_max: tune_ok.frame_max.clamp(4096, FRAME_MAX) as usize,
+                channels: HashMap::new(),
+            },
+        );
+        id
+    };
+    tokio::spawn(write_frames(writer, frames, tune_ok.heartbeat));
+
+    let result = async {
+        loop {
+            let frame = read_frame(&mut reader).await?;
+            if let Flow::Close = lock(&state).handle_frame(id, frame) {
+                return Ok(());
+            }
+        }
+    }
+    .await;
+    let mut state = lock(&state);
+    state.remove_connection(id);
+    state.dispatch();
+    result
+}
+
+/// Write the frames queued for a connection, and heartbeats while there are none
+async fn write_frames(
+    mut writer: OwnedWriteHalf,
+    mut frames: mpsc::UnboundedReceiver<Vec<u8>>,
+    heartbeat: u16,
+) {
+    let interval = Duration::from_secs(u64::from(heartbeat / 2).max(1));
+    loop {
+        let frame = if heartbeat == 0 {
+            frames.recv().await
+        } else {
+            match tokio::time::timeout(interval, frames.recv()).await {
+                Ok(frame) => frame,
+                Err(_) => Some(heartbeat_frame()),
+            }
+        };
+        let Some(frame) = frame else {
+            return;
+        };
+        if writer.write_all(&frame).await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Metadata stamped on every published message.
+//!
+//! Besides the message id, timestamp, content type and correlation id properties, the trace
+//! context of the publishing span is put into the `traceparent` and `tracestate` headers
+//! ([W3C Trace Context](https://www.w3.org/TR/trace-context/)). The consumer restores it as the
+//! parent of its span, so a request can be followed through every message it triggers.
+//!
+//! While a message is processed its metadata is the [current](MessageMetadata::current) one,
+//! and messages published meanwhile inherit its correlation id.
+
+use amqprs::BasicProperties;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use std::collections::HashMap;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::retry::{insert_field, str_header};
+
+const TRACEPARENT_HEADER: &str = "traceparent";
+const TRACESTATE_HEADER: &str = "tracestate";
+
+tokio::task_local! {
+    static CURRENT: MessageMetadata;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageMetadata {
+    pub message_id: Option<String>,
+    /// Shared by every message triggered by the same request
+    pub correlation_id: Option<String>,
+    /// Unix timestamp in seconds of when the message was published
+    pub timestamp: Option<u64>,
+    pub content_type: Option<String>,
+    /// W3C `traceparent` of the publishing span
+    pub traceparent: Option<String>,
+    /// W3C `tracestate` of the publishing span
+    pub tracestate: Option<String>,
+}
+
+impl MessageMetadata {
+    /// Metadata for a new message published from the current span.
+    ///
+    /// The correlation id is inherited from the message being processed, if any, or else taken
+    /// from the current trace. A message with neither starts a new correlation with its own id.
+    pub fn outgoing(content_type: &str) -> Self {
+        let message_id = uuid::Uuid::now_v7().to_string();
+        let context = tracing::Span::current().context();
+        let mut carrier = HashMap::new();
+        TraceContextPropagator::new().inject_context(&context, &mut carrier);
+        let span_context = context.span().span_context().clone();
+        let correlation_id = Self::current()
+            .and_then(|current| current.correlation_id)
+            .or_else(|| {
+                span_context
+                    .is_valid()
+                    .then(|| span_context.trace_id().to_string())
+            })
+            .unwrap_or_else(|| message_id.clone());
+        Self {
+            message_id: Some(message_id),
+            correlation_id: Some(correlation_id),
+            timestamp: Some(time::OffsetDateTime::now_utc().unix_timestamp().max(0) as u64),
+            content_type: Some(content_type.to_string()),
+            traceparent: carrier.remove(TRACEPARENT_HEADER),
+            tracestate: carrier
+                .remove(TRACESTATE_HEADER)
+                .filter(|tracestate| !tracestate.is_empty()),
+        }
+    }
+
+    /// Read the metadata of a delivered message
+    pub fn from_properties(properties: &BasicProperties) -> Self {
+        Self {
+            message_id: properties.message_id().cloned(),
+            correlation_id: properties.correlation_id().cloned(),
+            timestamp: properties.timestamp(),
+            content_type: properties.content_type().cloned(),
+            traceparent: str_header(properties, TRACEPARENT_HEADER),
+            tracestate: str_header(properties, TRACESTATE_HEADER),
+        }
+    }
+
+    /// Stamp the metadata on the properties of a message to publish
+    pub fn apply(&self, properties: &mut BasicProperties) {
+        if let Some(message_id) = &self.message_id {
+            properties.with_message_id(message_id);
+        }
+        if let Some(correlation_id) = &self.correlation_id {
+            properties.with_correlation_id(correlation_id);
+        }
+        if let Some(timestamp) = self.timestamp {
+            properties.with_timestamp(timestamp);
+        }
+        if let Some(content_type) = &self.content_type {
+            properties.with_content_type(content_type);
+        }
+        let mut headers = properties.headers().cloned().unwrap_or_default();
+        if let Some(traceparent) = &self.traceparent {
+            insert_field(
+                &mut headers,
+                TRACEPARENT_HEADER,
+                traceparent.as_str().into(),
+            );
+        }
+        if let Some(tracestate) = &self.tracestate {
+            insert_field(&mut headers, TRACESTATE_HEADER, tracestate.as_str().into());
+        }
+        properties.with_headers(headers);
+    }
+
+    /// Properties of a message to publish, with the metadata stamped on them
+    pub fn to_properties(&self) -> BasicProperties {
+        let mut properties = BasicProperties::default();
+        self.apply(&mut properties);
+        properties
+    }
+
+    /// The trace context the message was published from, to be used as the parent of the span
+    /// processing it
+    pub fn parent_context(&self) -> opentelemetry::Context {
+        let mut carrier = HashMap::new();
+        if let Some(traceparent) = &self.traceparent {
+            carrier.insert(TRACEPARENT_HEADER.to_string(), traceparent.clone());
+        }
+        if let Some(tracestate) = &self.tracestate {
+            carrier.insert(TRACESTATE_HEADER.to_string(), tracestate.clone());
+        }
+        TraceContextPropagator::new().extract(&carrier)
+    }
+
+    /// Span in which a delivered message is processed, child of the span that published it
+    pub fn consumer_span(&self, queue: &str) -> tracing::Span {
+        let span = tracing::info_span!(
+            "amqp.consume",
+            queue,
+            message_id = self.message_id.as_deref(),
+            correlation_id = self.correlation_id.as_deref(),
+        );
+        if let Err(e) = span.set_parent(self.parent_context()) {
+            tracing::debug!("Failed to restore the trace context of a message: {e}");
+        }
+        span
+    }
+
+    /// Metadata of the message being processed by the current task
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Run `f` with `self` as the [current](Self::current) metadata
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CURRENT.scope(self, f).await
+    }
+}
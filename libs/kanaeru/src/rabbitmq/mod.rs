@@ -0,0 +1,891 @@
+pub use amqprs::channel::ExchangeType as AmqpExchangeType;
+
+pub mod confirm;
+pub mod consumer;
+pub mod delay;
+pub mod idempotent;
+#[cfg(any(test, feature = "test-util"))]
+pub mod memory;
+pub mod metadata;
+pub mod outbox;
+pub mod policy;
+pub mod quarantine;
+pub mod retry;
+pub mod rpc;
+pub mod supervisor;
+
+use crate::envelope::{self, EnvelopePolicy};
+use crate::error::Error;
+use crate::pool::{ConnectionManager, PoolConfig, Pooled};
+use crate::schema::{self, Payload};
+use crate::sqlx::DatabaseProcessor;
+use amqprs::channel::{
+    BasicAckArguments, BasicConsumeArguments, BasicNackArguments, BasicQosArguments, Channel,
+    ExchangeDeclareArguments, QueueBindArguments, QueueDeclareArguments,
+};
+use amqprs::consumer::AsyncConsumer;
+use amqprs::{BasicProperties, Deliver, FieldTable, FieldValue};
+use kanau::message::{DeserializeError, MessageDe, MessageSer};
+use kanau::processor::Processor;
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use tracing::Instrument;
+
+use confirm::AmqpChannel;
+use consumer::{ConsumerConfig, ConsumerHandle, InFlight};
+use metadata::MessageMetadata;
+use policy::{AckAction, AckPolicy, ErrorClass};
+use retry::{RetryOutcome, RetryPolicy};
+
+/// Opens channels in confirm mode on one AMQP connection for [`AmqpPool`]
+///
+/// The connection can be replaced, which [`supervisor::AmqpSupervisor`] does after reconnecting.
+#[derive(Clone)]
+pub struct AmqpConnectionManager {
+    connection: Arc<RwLock<amqprs::connection::Connection>>,
+}
+
+impl AmqpConnectionManager {
+    pub fn new(connection: amqprs::connection::Connection) -> Self {
+        Self {
+            connection: Arc::new(RwLock::new(connection)),
+        }
+    }
+
+    /// The connection new channels are opened on
+    pub fn connection(&self) -> amqprs::connection::Connection {
+        self.connection
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Open a channel that is not in confirm mode, to declare topology or consume on
+    pub async fn open_channel(&self) -> Result<Channel, amqprs::error::Error> {
+        self.connection().open_channel(None).await
+    }
+
+    /// Open new channels on `connection` from now on, and return the previous connection
+    pub fn replace_connection(
+        &self,
+        connection: amqprs::connection::Connection,
+    ) -> amqprs::connection::Connection {
+        let mut current = self
+            .connection
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        std::mem::replace(&mut *current, connection)
+    }
+}
+
+impl ConnectionManager for AmqpConnectionManager {
+    type Connection = AmqpChannel;
+    type Error = amqprs::error::Error;
+
+    async fn create(&self) -> Result<AmqpChannel, amqprs::error::Error> {
+        AmqpChannel::open(&self.connection()).await
+    }
+
+    async fn is_valid(&self, channel: &mut AmqpChannel) -> Result<(), amqprs::error::Error> {
+        if self.has_broken(channel) {
+            return Err(amqprs::error::Error::ChannelUseError(
+                "Channel is closed".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn has_broken(&self, channel: &mut AmqpChannel) -> bool {
+        // Channels closed by the broker, or whose connection is gone, must never be handed out
+        !channel.is_open() || !channel.is_connection_open()
+    }
+}
+
+pub type AmqpPool = crate::pool::Pool<AmqpConnectionManager>;
+
+/// Default capacity of [`AmqpPool`]
+pub const AMQP_POOL_CAPACITY: usize = 4096;
+
+/// Default time an idle channel is kept open in [`AmqpPool`]
+pub const AMQP_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+impl AmqpPool {
+    pub async fn connect(connection: amqprs::connection::Connection) -> Self {
+        Self::connect_with_config(
+            connection,
+            PoolConfig::new(AMQP_POOL_CAPACITY).idle_timeout(AMQP_POOL_IDLE_TIMEOUT),
+        )
+        .await
+    }
+
+    pub async fn connect_with_config(
+        connection: amqprs::connection::Connection,
+        config: PoolConfig,
+    ) -> Self {
+        Self::with_config(AmqpConnectionManager::new(connection), config)
+    }
+}
+
+/// How a queue is bound to an exchange
+#[derive(Debug, Clone, PartialEq)]
+pub enum AmqpBinding {
+    /// Routing key on a direct exchange, or pattern such as `auth.user.*.#` on a topic exchange
+    RoutingKey(Cow<'static, str>),
+    /// Headers a message must carry on a headers exchange, either all or any of them
+    Headers {
+        headers: FieldTable,
+        match_all: bool,
+    },
+}
+
+impl AmqpBinding {
+    pub fn routing_key(routing_key: impl Into<Cow<'static, str>>) -> Self {
+        Self::RoutingKey(routing_key.into())
+    }
+
+    /// Match messages carrying every one of `headers`
+    pub fn all_headers(headers: FieldTable) -> Self {
+        Self::Headers {
+            headers,
+            match_all: true,
+        }
+    }
+
+    /// Match messages carrying at least one of `headers`
+    pub fn any_headers(headers: FieldTable) -> Self {
+        Self::Headers {
+            headers,
+            match_all: false,
+        }
+    }
+
+    fn arguments(&self, queue: &str, exchange: &str) -> QueueBindArguments {
+        match self {
+            Self::RoutingKey(routing_key) => QueueBindArguments::new(queue, exchange, routing_key),
+            Self::Headers { headers, match_all } => {
+                let mut arguments = headers.clone();
+                let x_match = if *match_all { "all" } else { "any" };
+                retry::insert_field(&mut arguments, "x-match", x_match.into());
+                QueueBindArguments::new(queue, exchange, "")
+                    .arguments(arguments)
+                    .finish()
+            }
+        }
+    }
+}
+
+/// Bind `queue` to `exchange` with each of `bindings`
+pub(crate) async fn bind_queue(
+    channel: &Channel,
+    queue: &str,
+    exchange: &str,
+    bindings: &[AmqpBinding],
+) -> Result<(), amqprs::error::Error> {
+    for binding in bindings {
+        channel
+            .queue_bind(binding.arguments(queue, exchange))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Trait for routing message to rabbitmq
+pub trait AmqpRouting {
+    /// Exchange name
+    const EXCHANGE: &'static str;
+
+    /// Exchange type
+    const EXCHANGE_TYPE: AmqpExchangeType;
+
+    /// Routing key, used for every message unless [`routing_key`](Self::routing_key) is
+    /// overridden
+    const ROUTING_KEY: &'static str;
+
+    /// Routing key of this message.
+    ///
+    /// Defaults to [`ROUTING_KEY`](Self::ROUTING_KEY). Override it to route by the content of
+    /// the message, e.g. `auth.user.<user_id>.session_revoked` on a topic exchange.
+    fn routing_key(&self) -> Cow<'_, str> {
+        Cow::Borrowed(Self::ROUTING_KEY)
+    }
+
+    /// Headers of this message, matched by the bindings of a headers exchange. None by default.
+    fn headers(&self) -> FieldTable {
+        FieldTable::new()
+    }
+
+    #[tracing::instrument(skip_all, err, ret)]
+    fn ensure_exchange(
+        pool: &AmqpPool,
+    ) -> impl Future<Output = Result<(), crate::error::Error>> + Send {
+        async move {
+            let channel: Result<Pooled<AmqpConnectionManager>, crate::error::Error> =
+                pool.get().await.into();
+            let channel = channel?;
+            let channel = channel
+                .get_ref()
+                .ok_or(Error::Io(anyhow::anyhow!("Channel is unexpectedly closed")))?;
+            channel
+                .channel()
+                .exchange_declare(
+                    ExchangeDeclareArguments::of_type(Self::EXCHANGE, Self::EXCHANGE_TYPE)
+                        .durable(true)
+                        .finish(),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Trait for sending message to rabbitmq
+pub trait AmqpMessageSend: MessageSer + Send + Sized + AmqpRouting {
+    /// Content type the message is serialized as
+    const CONTENT_TYPE: &'static str = schema::DEFAULT_CONTENT_TYPE;
+
+    /// How the serialized message is enveloped. Raw by default.
+    const ENVELOPE: EnvelopePolicy = EnvelopePolicy::RAW;
+
+    /// Version of the schema the message is serialized with
+    const SCHEMA_VERSION: u32 = schema::INITIAL_VERSION;
+
+    /// Decode a message written with another schema version or content type.
+    ///
+    /// Supports none by default. Override it to keep consuming messages published before the
+    /// schema changed, see [`schema`].
+    fn upcast(payload: Payload<'_>) -> Result<Self, DeserializeError> {
+        schema::no_upcast(payload)
+    }
+
+    // Allow async fn in trait because we don't want the user to override this function
+    #[allow(async_fn_in_trait)]
+    #[tracing::instrument(skip_all, level = "debug", err, ret)]
+    /// Send message to rabbitmq
+    ///
+    /// Resolves once the broker has confirmed the message. A message that no queue is bound for
+    /// fails with [`Error::Unroutable`], and one the broker refuses with [`Error::PublishNacked`].
+    ///
+    /// The message is stamped with [`MessageMetadata::outgoing`].
+    async fn send(self, pool: &AmqpPool) -> Result<(), crate::error::Error> {
+        let routing_key = self.routing_key().into_owned();
+        let mut properties = BasicProperties::default();
+        properties.with_headers(outgoing_headers(&self));
+        let bytes = seal(self)?;
+        MessageMetadata::outgoing(Self::CONTENT_TYPE).apply(&mut properties);
+        publish(
+            pool,
+            Self::EXCHANGE,
+            &routing_key,
+            properties,
+            bytes.into_vec(),
+        )
+        .await
+    }
+
+    // Allow async fn in trait because we don't want the user to override this function
+    #[allow(async_fn_in_trait)]
+    #[tracing::instrument(skip_all, level = "debug", err, ret)]
+    /// Send message to rabbitmq, to be delivered once `delay` has passed
+    ///
+    /// Resolves once the broker has confirmed the message into its delay queue, see
+    /// [`delay`] for how it is held. The delay may be rounded up by less than 1/128 of it.
+    /// The message is stamped with [`MessageMetadata::outgoing`] when it is sent, not when it is
+    /// delivered.
+    async fn send_after(self, pool: &AmqpPool, delay: Duration) -> Result<(), crate::error::Error> {
+        if delay.is_zero() {
+            return self.send(pool).await;
+        }
+        let routing_key = self.routing_key().into_owned();
+        let mut properties = BasicProperties::default();
+        properties.with_headers(outgoing_headers(&self));
+        let bytes = seal(self)?;
+        MessageMetadata::outgoing(Self::CONTENT_TYPE).apply(&mut properties);
+        delay::publish_delayed(
+            pool,
+            Self::EXCHANGE,
+            &routing_key,
+            delay,
+            properties,
+            bytes.into_vec(),
+        )
+        .await
+    }
+
+    // Allow async fn in trait because we don't want the user to override this function
+    #[allow(async_fn_in_trait)]
+    /// Send message to rabbitmq, to be delivered at `at`
+    ///
+    /// A time in the past sends the message right away. See [`send_after`](Self::send_after).
+    async fn send_at(
+        self,
+        pool: &AmqpPool,
+        at: time::OffsetDateTime,
+    ) -> Result<(), crate::error::Error> {
+        let delay = Duration::try_from(at - time::OffsetDateTime::now_utc()).unwrap_or_default();
+        self.send_after(pool, delay).await
+    }
+
+    // Allow async fn in trait because we don't want the user to override this function
+    #[allow(async_fn_in_trait)]
+    #[tracing::instrument(skip_all, level = "debug", err, ret)]
+    /// Store message in the outbox as part of `tx`
+    ///
+    /// The message is published by an [`outbox::OutboxRelay`] once `tx` commits, and never if
+    /// it rolls back. The metadata of the message is taken when it is stored, so it keeps the
+    /// trace context of the caller.
+    async fn send_in_tx(self, tx: &mut sqlx::PgTransaction<'_>) -> Result<(), crate::error::Error> {
+        let routing_key = self.routing_key().into_owned();
+        let headers = outgoing_headers(&self);
+        let bytes = seal(self)?;
+        let metadata = MessageMetadata::outgoing(Self::CONTENT_TYPE);
+        outbox::OutboxMessage::insert(
+            &mut **tx,
+            Self::EXCHANGE,
+            &routing_key,
+            &headers,
+            &bytes,
+            &metadata,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Headers of `message`, along with the version of its schema and whether it is enveloped
+pub(crate) fn outgoing_headers<M: AmqpMessageSend>(message: &M) -> FieldTable {
+    let mut headers = message.headers();
+    retry::insert_field(
+        &mut headers,
+        schema::SCHEMA_VERSION_HEADER,
+        FieldValue::i(M::SCHEMA_VERSION),
+    );
+    if M::ENVELOPE.envelope {
+        mark_enveloped(&mut headers);
+    }
+    headers
+}
+
+/// Say in `headers` that the content of the message is enveloped
+pub(crate) fn mark_enveloped(headers: &mut FieldTable) {
+    retry::insert_field(
+        headers,
+        envelope::ENVELOPE_HEADER,
+        FieldValue::i(u32::from(envelope::VERSION)),
+    );
+}
+
+/// Take the content of a message out of its envelope if its headers say it is enveloped
+pub(crate) fn open_content<'a>(
+    properties: &BasicProperties,
+    content: &'a [u8],
+) -> Result<envelope::Opened<'a>, DeserializeError> {
+    if retry::u32_header(properties, envelope::ENVELOPE_HEADER).is_some() {
+        envelope::open(content)
+    } else {
+        Ok(envelope::Opened::raw(content))
+    }
+}
+
+/// Serialize `message` and put it into the envelope of its type
+pub(crate) fn seal<M: AmqpMessageSend>(message: M) -> Result<Box<[u8]>, Error> {
+    let bytes = message.to_bytes().map_err(|e| e.into())?;
+    Ok(envelope::seal(bytes, M::CONTENT_TYPE, &M::ENVELOPE)?)
+}
+
+/// Take a delivered message out of its envelope and decode it, upcasting it if needed
+pub(crate) fn decode_delivery<M: AmqpMessageSend + MessageDe>(
+    properties: &BasicProperties,
+    content: &[u8],
+) -> Result<M, Error> {
+    let opened = open_content(properties, content)?;
+    let payload = Payload {
+        version: retry::u32_header(properties, schema::SCHEMA_VERSION_HEADER)
+            .unwrap_or(schema::INITIAL_VERSION),
+        content_type: opened
+            .content_type
+            .as_deref()
+            .or(properties.content_type().map(String::as_str)),
+        bytes: &opened.content,
+    };
+    schema::decode(payload, M::SCHEMA_VERSION, M::CONTENT_TYPE, M::upcast)
+}
+
+/// Publish a mandatory message through a pooled channel and wait for the broker to confirm it
+pub(crate) async fn publish(
+    pool: &AmqpPool,
+    exchange: &str,
+    routing_key: &str,
+    properties: BasicProperties,
+    content: Vec<u8>,
+) -> Result<(), crate::error::Error> {
+    let channel: Result<Pooled<AmqpConnectionManager>, crate::error::Error> =
+        pool.get().await.into();
+    let mut channel = channel?;
+    let published = channel
+        .get_ref()
+        .ok_or(Error::Io(anyhow::anyhow!("Channel is unexpectedly closed")))?
+        .publish(
+            properties,
+            content,
+            amqprs::channel::BasicPublishArguments::new(exchange, routing_key)
+                .mandatory(true)
+                .finish(),
+        )
+        .await;
+    if let Err(Error::AmqpError(_) | Error::Io(_)) = &published {
+        // The channel may be in an unknown state, do not hand it out again
+        channel.disconnect();
+    }
+    published
+}
+
+/// Trait for consuming message from rabbitmq
+pub trait AmqpMessageProcessor<Message: AmqpMessageSend + MessageDe>:
+    Processor<Message, Result<(), crate::error::Error>>
+{
+    const QUEUE: &'static str;
+
+    /// How messages failing with a retryable error are retried before being dead-lettered
+    const RETRY: RetryPolicy = RetryPolicy::DEFAULT;
+
+    /// How deliveries failing with each class of error are settled
+    const ACK_POLICY: AckPolicy = AckPolicy::DEFAULT;
+
+    /// Process a delivered message along with its metadata.
+    ///
+    /// Defaults to [`Processor::process`], override this to make use of the metadata.
+    fn process_with_metadata(
+        &self,
+        message: Message,
+        metadata: MessageMetadata,
+    ) -> impl Future<Output = Result<(), crate::error::Error>> + Send {
+        let _ = metadata;
+        self.process(message)
+    }
+
+    /// Database to copy messages settled with [`AckAction::Quarantine`] to.
+    ///
+    /// None by default, which dead-letters them instead.
+    fn quarantine(&self) -> Option<&DatabaseProcessor> {
+        None
+    }
+
+    /// How the queue is bound to the exchange of the messages.
+    ///
+    /// Defaults to the [`ROUTING_KEY`](AmqpRouting::ROUTING_KEY) of the messages. Override it
+    /// to subscribe to patterns on a topic exchange, or to headers on a headers exchange.
+    fn bindings() -> Vec<AmqpBinding> {
+        vec![AmqpBinding::routing_key(Message::ROUTING_KEY)]
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    /// Ensure the topology of the queue and get the channel with the queue bound
+    ///
+    /// Besides the queue itself, this declares its retry queues and dead-letter queue. A queue
+    /// declared before it had a dead-letter queue is kept as is, see [`retry`] for how to
+    /// migrate it.
+    fn ensure_queue(
+        pool: &AmqpPool,
+    ) -> impl Future<Output = Result<Channel, crate::error::Error>> + Send {
+        async move {
+            // ensure exchange first
+            Message::ensure_exchange(pool).await?;
+
+            // Declare a durable, client-named queue that dead-letters rejected messages
+            let mut channel = pool.manager().open_channel().await?;
+            let queue_arguments =
+                retry::declare_topology(&channel, Self::QUEUE, &Self::RETRY).await?;
+            let queue_arg = QueueDeclareArguments::durable_client_named(Self::QUEUE)
+                .arguments(queue_arguments)
+                .finish();
+            if let Err(error) = channel.queue_declare(queue_arg).await {
+                // The broker refuses to add the dead-letter arguments to an existing queue, and
+                // closes the channel
+                channel = pool.manager().open_channel().await?;
+                let existing = QueueDeclareArguments::new(Self::QUEUE)
+                    .passive(true)
+                    .finish();
+                if channel.queue_declare(existing).await.is_err() {
+                    return Err(error.into());
+                }
+                tracing::warn!(
+                    queue = Self::QUEUE,
+                    "Queue exists without dead-letter arguments, messages it rejects are dropped \
+                     until it is migrated: {error}"
+                );
+            }
+
+            bind_queue(&channel, Self::QUEUE, Message::EXCHANGE, &Self::bindings()).await?;
+            Ok(channel)
+        }
+    }
+}
+
+/// Consumer for rabbitmq
+pub struct AmqpMessageConsumer<
+    Message: AmqpMessageSend + MessageDe,
+    Inner: AmqpMessageProcessor<Message>,
+> {
+    inner: Arc<Inner>,
+    in_flight: InFlight,
+    concurrent: bool,
+    /// Channel the deliveries are consumed on, put into confirm mode when the first failed
+    /// delivery is republished to a retry queue
+    publisher: Arc<tokio::sync::OnceCell<AmqpChannel>>,
+    _marker: PhantomData<Message>,
+}
+
+impl<Message: AmqpMessageSend + MessageDe, Inner: AmqpMessageProcessor<Message>>
+    AmqpMessageConsumer<Message, Inner>
+{
+    /// Create a new consumer
+    pub fn new(inner: Arc<Inner>) -> Self {
+        Self::with_config(inner, &ConsumerConfig::default())
+    }
+
+    /// Create a new consumer processing up to `config.concurrency` messages at once
+    pub fn with_config(inner: Arc<Inner>, config: &ConsumerConfig) -> Self {
+        Self::with_in_flight(inner, config, InFlight::new(config.concurrency))
+    }
+
+    fn with_in_flight(inner: Arc<Inner>, config: &ConsumerConfig, in_flight: InFlight) -> Self {
+        Self {
+            inner,
+            in_flight,
+            concurrent: config.concurrency > 1,
+            publisher: Arc::default(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Process message
+    pub async fn on_message(
+        &self,
+        prop: BasicProperties,
+        content: Vec<u8>,
+    ) -> Result<(), crate::error::Error> {
+        let metadata = MessageMetadata::from_properties(&prop);
+        Self::process_message(&self.inner, &prop, metadata, &content).await
+    }
+
+    /// Process message with `metadata` as the current metadata
+    async fn process_message(
+        inner: &Inner,
+        properties: &BasicProperties,
+        metadata: MessageMetadata,
+        content: &[u8],
+    ) -> Result<(), crate::error::Error> {
+        let decoded_message = decode_delivery::<Message>(properties, content)?;
+        metadata
+            .clone()
+            .scope(inner.process_with_metadata(decoded_message, metadata))
+            .await
+    }
+}
+
+impl<M, I> AmqpMessageConsumer<M, I>
+where
+    M: AmqpMessageSend + MessageDe + Send + Sync + 'static,
+    I: AmqpMessageProcessor<M> + Send + Sync + 'static,
+    M::DeError: Send,
+{
+    /// Process a delivery in a span restored from its trace context
+    async fn handle_delivery(
+        inner: Arc<I>,
+        channel: Channel,
+        publisher: Arc<tokio::sync::OnceCell<AmqpChannel>>,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let metadata = MessageMetadata::from_properties(&basic_properties);
+        let span = metadata.consumer_span(I::QUEUE);
+        Self::settle_delivery(
+            inner,
+            channel,
+            &publisher,
+            deliver,
+            basic_properties,
+            content,
+            metadata,
+        )
+        .instrument(span)
+        .await
+    }
+
+    /// Process a delivery and settle it according to the result
+    async fn settle_delivery(
+        inner: Arc<I>,
+        channel: Channel,
+        publisher: &tokio::sync::OnceCell<AmqpChannel>,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+        metadata: MessageMetadata,
+    ) {
+        let delivery_tag = deliver.delivery_tag();
+        let error = match Self::process_message(&inner, &basic_properties, metadata, &content).await
+        {
+            Ok(_) => {
+                ack(&channel, BasicAckArguments::new(delivery_tag, false), 5).await;
+                return;
+            }
+            Err(e) => e,
+        };
+        let class = ErrorClass::of(&error);
+        let action = I::ACK_POLICY.action(class);
+        tracing::error!(
+            queue = I::QUEUE,
+            error_class = class.as_str(),
+            ack_action = action.as_str(),
+            "Failed to process message: {error}"
+        );
+        match action {
+            AckAction::Ack => {
+                ack(&channel, BasicAckArguments::new(delivery_tag, false), 5).await;
+            }
+            AckAction::Requeue => {
+                nack(
+                    &channel,
+                    BasicNackArguments::new(delivery_tag, false, true),
+                    5,
+                )
+                .await;
+            }
+            AckAction::RetryLater => {
+                retry_later(
+                    &channel,
+                    publisher,
+                    delivery_tag,
+                    I::QUEUE,
+                    &I::RETRY,
+                    &basic_properties,
+                    &content,
+                )
+                .await;
+            }
+            AckAction::DeadLetter => {
+                nack(
+                    &channel,
+                    BasicNackArguments::new(delivery_tag, false, false),
+                    5,
+                )
+                .await;
+            }
+            AckAction::Quarantine => {
+                let quarantined = match inner.quarantine() {
+                    Some(db) => quarantine::QuarantinedMessage::insert(
+                        db.db(),
+                        I::QUEUE,
+                        deliver.exchange(),
+                        deliver.routing_key(),
+                        &basic_properties,
+                        &content,
+                        &error.to_string(),
+                        class,
+                    )
+                    .await
+                    .map_err(|e| e.to_string()),
+                    None => Err("the processor has no quarantine".to_string()),
+                };
+                match quarantined {
+                    Ok(id) => {
+                        tracing::info!(queue = I::QUEUE, id, "Quarantined message");
+                        ack(&channel, BasicAckArguments::new(delivery_tag, false), 5).await;
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            queue = I::QUEUE,
+                            "Dead-lettering message instead of quarantining it: {e}"
+                        );
+                        nack(
+                            &channel,
+                            BasicNackArguments::new(delivery_tag, false, false),
+                            5,
+                        )
+                        .await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<M, I> AsyncConsumer for AmqpMessageConsumer<M, I>
+where
+    M: AmqpMessageSend + MessageDe + Send + Sync + 'static,
+    I: AmqpMessageProcessor<M> + Send + Sync + 'static,
+    M::DeError: Send,
+{
+    fn consume<'life0, 'life1, 'async_trait>(
+        &'life0 mut self,
+        channel: &'life1 Channel,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'async_trait>>
+    where
+        Self: 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+    {
+        Box::pin(async move {
+            let Some(permit) = self.in_flight.acquire().await else {
+                // Shutting down, the delivery is requeued once the channel is closed
+                return;
+            };
+            let delivery = Self::handle_delivery(
+                self.inner.clone(),
+                channel.clone(),
+                self.publisher.clone(),
+                deliver,
+                basic_properties,
+                content,
+            );
+            if self.concurrent {
+                tokio::spawn(async move {
+                    delivery.await;
+                    drop(permit);
+                });
+            } else {
+                delivery.await;
+                drop(permit);
+            }
+        })
+    }
+}
+
+/// Move a failed delivery to its next retry queue, or dead-letter it once it is out of attempts.
+///
+/// The delivery is only acked once the broker has confirmed its copy in the retry queue.
+async fn retry_later(
+    channel: &Channel,
+    publisher: &tokio::sync::OnceCell<AmqpChannel>,
+    delivery_tag: u64,
+    queue: &str,
+    policy: &RetryPolicy,
+    properties: &BasicProperties,
+    content: &[u8],
+) {
+    let publisher = publisher
+        .get_or_try_init(|| AmqpChannel::confirm(channel.clone()))
+        .await
+        .map_err(Error::from);
+    let scheduled = match publisher {
+        Ok(publisher) => retry::schedule(publisher, queue, policy, properties, content).await,
+        Err(e) => Err(e),
+    };
+    match scheduled {
+        Ok(RetryOutcome::Scheduled { attempt, delay }) => {
+            tracing::warn!(
+                queue,
+                attempt,
+                delay_ms = delay.as_millis() as u64,
+                "Message scheduled for retry"
+            );
+            ack(channel, BasicAckArguments::new(delivery_tag, false), 5).await;
+        }
+        Ok(RetryOutcome::Exhausted) => {
+            tracing::error!(queue, "Message ran out of attempts, dead-lettering it");
+            nack(
+                channel,
+                BasicNackArguments::new(delivery_tag, false, false),
+                5,
+            )
+            .await;
+        }
+        Err(e) => {
+            // Without a retry queue, requeueing is the only way not to lose the message
+            tracing::error!(queue, "Failed to schedule retry: {e}");
+            nack(
+                channel,
+                BasicNackArguments::new(delivery_tag, false, true),
+                5,
+            )
+            .await;
+        }
+    }
+}
+
+/// Ack message with retry
+pub async fn ack(channel: &Channel, arg: BasicAckArguments, max_retries: u32) {
+    let mut retries = 0;
+    while retries < max_retries {
+        match channel.basic_ack(arg.clone()).await {
+            Ok(_) => return,
+            Err(e) => {
+                tracing::error!("Failed to ack message: {e}");
+                retries += 1;
+            }
+        }
+    }
+
+    if retries == max_retries {
+        tracing::error!("Failed to ack message after {} retries", max_retries);
+    }
+}
+
+pub async fn nack(channel: &Channel, arg: BasicNackArguments, max_retries: u32) {
+    let mut retries = 0;
+    while retries < max_retries {
+        match channel.basic_nack(arg.clone()).await {
+            Ok(_) => return,
+            Err(e) => {
+                tracing::error!("Failed to nack message: {e}");
+                retries += 1;
+            }
+        }
+    }
+}
+
+/// bind consumer for a message type
+///
+/// Uses the default [`ConsumerConfig`]: a prefetch of [`consumer::CONSUMER_PREFETCH`] messages
+/// processed one at a time. The consumer stops for good if the connection is lost, see
+/// [`supervisor::AmqpSupervisor::register_consumer`] to have it started again.
+pub async fn setup_consumer<M, H>(
+    channel: &Channel,
+    hook: Arc<H>,
+) -> Result<ConsumerHandle, amqprs::error::Error>
+where
+    M: AmqpMessageSend + MessageDe + Send + Sync + 'static,
+    M::DeError: Send,
+    H: AmqpMessageProcessor<M> + Send + Sync + 'static,
+{
+    setup_consumer_with_config::<M, H>(channel, hook, ConsumerConfig::default()).await
+}
+
+/// bind consumer for a message type, with the prefetch and concurrency of `config`
+pub async fn setup_consumer_with_config<M, H>(
+    channel: &Channel,
+    hook: Arc<H>,
+    config: ConsumerConfig,
+) -> Result<ConsumerHandle, amqprs::error::Error>
+where
+    M: AmqpMessageSend + MessageDe + Send + Sync + 'static,
+    M::DeError: Send,
+    H: AmqpMessageProcessor<M> + Send + Sync + 'static,
+{
+    let queue = H::QUEUE;
+    bind_queue(channel, queue, M::EXCHANGE, &H::bindings()).await?;
+    channel
+        .basic_qos(BasicQosArguments::new(0, config.prefetch, false))
+        .await?;
+    let in_flight = InFlight::new(config.concurrency);
+    let consumer_tag = channel
+        .basic_consume(
+            AmqpMessageConsumer::<M, H>::with_in_flight(hook, &config, in_flight.clone()),
+            BasicConsumeArguments::new(queue, "")
+                .manual_ack(true)
+                .finish(),
+        )
+        .await?;
+    Ok(ConsumerHandle::new(
+        channel.clone(),
+        consumer_tag,
+        in_flight,
+    ))
+}
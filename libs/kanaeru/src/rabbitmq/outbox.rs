@@ -0,0 +1,420 @@
+//! Transactional outbox.
+//!
+//! [`AmqpMessageSend::send_in_tx`](super::AmqpMessageSend::send_in_tx) stores a message in
+//! `kanaeru.outbox` as part of the caller's transaction, so the message exists if and only if
+//! the transaction commits. [`OutboxRelay`] then publishes pending messages with confirms and
+//! marks them as published.
+//!
+//! A relay claims a batch by pushing `next_attempt_at` of its messages into the future, so
+//! several relays can work through the outbox at once, and publishes the batch outside of any
+//! transaction. A relay that dies mid-batch leaves its messages to be claimed again once the
+//! claim expires, so a message may be published twice but never lost.
+//!
+//! A message that fails to publish is retried with exponential backoff, and parked once it has
+//! failed [`OUTBOX_MAX_ATTEMPTS`] times, so a poisoned message never blocks the ones after it.
+//! Parked messages are left alone until [`OutboxRelay::unpark`] puts them back.
+
+use amqprs::{BasicProperties, FieldTable};
+use kanau::message::DeserializeError;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+use super::AmqpPool;
+use super::metadata::MessageMetadata;
+use crate::error::Error;
+use crate::sqlx::DatabaseProcessor;
+
+/// Default number of messages [`OutboxRelay`] publishes per transaction
+pub const OUTBOX_BATCH_SIZE: i64 = 100;
+
+/// Default time [`OutboxRelay`] waits before polling again once the outbox is drained
+pub const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default number of failed publishes after which [`OutboxRelay`] parks a message
+pub const OUTBOX_MAX_ATTEMPTS: i32 = 10;
+
+/// Delay before the first retry of a failed message, doubled on every further attempt
+pub const OUTBOX_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between two retries of a failed message
+pub const OUTBOX_RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Default time a batch stays claimed by a relay before other relays may claim it again
+pub const OUTBOX_CLAIM_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Encode headers as on the wire to store them, `None` for no headers
+pub(crate) fn encode_headers(headers: &FieldTable) -> Result<Option<Vec<u8>>, sqlx::Error> {
+    if headers.as_ref().is_empty() {
+        return Ok(None);
+    }
+    let headers = amqp_serde::to_bytes(headers).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    Ok(Some(headers))
+}
+
+/// Decode headers stored by [`encode_headers`]
+pub(crate) fn decode_headers(headers: Option<&[u8]>) -> Result<FieldTable, DeserializeError> {
+    match headers {
+        Some(headers) => amqp_serde::from_bytes(headers)
+            .map_err(|e| DeserializeError(anyhow::anyhow!("Malformed stored headers: {e}"))),
+        None => Ok(FieldTable::new()),
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, sqlx::FromRow, Debug)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub exchange: String,
+    pub routing_key: String,
+    pub payload: Vec<u8>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: OffsetDateTime,
+    pub published_at: Option<OffsetDateTime>,
+    pub message_id: Option<String>,
+    pub correlation_id: Option<String>,
+    pub content_type: Option<String>,
+    pub traceparent: Option<String>,
+    pub tracestate: Option<String>,
+    /// AMQP field table of the headers, encoded as on the wire. `None` for no headers.
+    pub headers: Option<Vec<u8>>,
+    /// When the message may be claimed next, by a retry or once the claim of a relay expires
+    pub next_attempt_at: OffsetDateTime,
+    pub parked_at: Option<OffsetDateTime>,
+}
+
+impl OutboxMessage {
+    pub async fn insert(
+        conn: impl sqlx::PgExecutor<'_>,
+        exchange: &str,
+        routing_key: &str,
+        headers: &FieldTable,
+        payload: &[u8],
+        metadata: &MessageMetadata,
+    ) -> Result<i64, sqlx::Error> {
+        let headers = encode_headers(headers)?;
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO kanaeru.outbox
+                (exchange, routing_key, payload, message_id, correlation_id, content_type, traceparent, tracestate, headers)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id
+            "#,
+            exchange,
+            routing_key,
+            payload,
+            metadata.message_id,
+            metadata.correlation_id,
+            metadata.content_type,
+            metadata.traceparent,
+            metadata.tracestate,
+            headers,
+        )
+        .fetch_one(conn)
+        .await?;
+        Ok(row.id)
+    }
+
+    /// Claim up to `limit` due messages, oldest first, for `claim_for`.
+    ///
+    /// The claimed messages are not due again until the claim expires, so other relays skip them.
+    pub async fn claim_due(
+        conn: impl sqlx::PgExecutor<'_>,
+        limit: i64,
+        claim_for: Duration,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            Self,
+            r#"
+            UPDATE kanaeru.outbox
+            SET next_attempt_at = NOW() + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id
+                FROM kanaeru.outbox
+                WHERE published_at IS NULL AND parked_at IS NULL AND next_attempt_at <= NOW()
+                ORDER BY id
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, exchange, routing_key, payload, attempts, last_error, created_at, published_at,
+                message_id, correlation_id, content_type, traceparent, tracestate, headers,
+                next_attempt_at, parked_at
+            "#,
+            limit,
+            claim_for.as_secs_f64(),
+        )
+        .fetch_all(conn)
+        .await
+        .map(|mut messages| {
+            // RETURNING does not keep the order of the subquery
+            messages.sort_by_key(|message| message.id);
+            messages
+        })
+    }
+
+    /// Metadata the message was stored with, timestamped with when it was stored
+    pub fn metadata(&self) -> MessageMetadata {
+        MessageMetadata {
+            message_id: self.message_id.clone(),
+            correlation_id: self.correlation_id.clone(),
+            timestamp: u64::try_from(self.created_at.unix_timestamp()).ok(),
+            content_type: self.content_type.clone(),
+            traceparent: self.traceparent.clone(),
+            tracestate: self.tracestate.clone(),
+        }
+    }
+
+    /// Headers the message was stored with
+    pub fn headers(&self) -> Result<FieldTable, DeserializeError> {
+        decode_headers(self.headers.as_deref())
+    }
+
+    /// Properties to publish the message with: its headers, stamped with its metadata
+    pub fn properties(&self) -> Result<BasicProperties, DeserializeError> {
+        let mut properties = BasicProperties::default();
+        properties.with_headers(self.headers()?);
+        self.metadata().apply(&mut properties);
+        Ok(properties)
+    }
+
+    pub async fn mark_published(
+        conn: impl sqlx::PgExecutor<'_>,
+        id: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE kanaeru.outbox SET published_at = NOW(), attempts = attempts + 1 WHERE id = $1",
+            id
+        )
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Record a failed publish and retry the message after `retry_in`
+    pub async fn mark_failed(
+        conn: impl sqlx::PgExecutor<'_>,
+        id: i64,
+        error: &str,
+        retry_in: Duration,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE kanaeru.outbox
+            SET attempts = attempts + 1, last_error = $2, next_attempt_at = NOW() + make_interval(secs => $3)
+            WHERE id = $1
+            "#,
+            id,
+            error,
+            retry_in.as_secs_f64(),
+        )
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Record a failed publish and stop retrying the message
+    pub async fn park(
+        conn: impl sqlx::PgExecutor<'_>,
+        id: i64,
+        error: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE kanaeru.outbox SET attempts = attempts + 1, last_error = $2, parked_at = NOW() WHERE id = $1",
+            id,
+            error,
+        )
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Put a parked message back to be published right away, with its attempts reset
+    pub async fn unpark(conn: impl sqlx::PgExecutor<'_>, id: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE kanaeru.outbox
+            SET parked_at = NULL, attempts = 0, next_attempt_at = NOW()
+            WHERE id = $1 AND parked_at IS NOT NULL
+            "#,
+            id,
+        )
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Parked messages, oldest first, starting after `after_id`
+    pub async fn list_parked(
+        conn: impl sqlx::PgExecutor<'_>,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            Self,
+            r#"
+            SELECT id, exchange, routing_key, payload, attempts, last_error, created_at, published_at,
+                message_id, correlation_id, content_type, traceparent, tracestate, headers,
+                next_attempt_at, parked_at
+            FROM kanaeru.outbox
+            WHERE parked_at IS NOT NULL AND id > $1
+            ORDER BY id
+            LIMIT $2
+            "#,
+            after_id,
+            limit,
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    pub async fn delete_published_before(
+        conn: impl sqlx::PgExecutor<'_>,
+        time_before: OffsetDateTime,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM kanaeru.outbox WHERE published_at < $1",
+            time_before,
+        )
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+/// Publishes the messages stored by
+/// [`AmqpMessageSend::send_in_tx`](super::AmqpMessageSend::send_in_tx)
+#[derive(Clone)]
+pub struct OutboxRelay {
+    db: DatabaseProcessor,
+    mq: AmqpPool,
+    batch_size: i64,
+    poll_interval: Duration,
+    max_attempts: i32,
+    claim_timeout: Duration,
+}
+
+impl OutboxRelay {
+    pub fn new(db: DatabaseProcessor, mq: AmqpPool) -> Self {
+        Self {
+            db,
+            mq,
+            batch_size: OUTBOX_BATCH_SIZE,
+            poll_interval: OUTBOX_POLL_INTERVAL,
+            max_attempts: OUTBOX_MAX_ATTEMPTS,
+            claim_timeout: OUTBOX_CLAIM_TIMEOUT,
+        }
+    }
+
+    pub fn batch_size(mut self, batch_size: i64) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn max_attempts(mut self, max_attempts: i32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// How long a batch stays claimed. Keep it well above the time a batch takes to publish,
+    /// or other relays publish its messages again.
+    pub fn claim_timeout(mut self, claim_timeout: Duration) -> Self {
+        self.claim_timeout = claim_timeout;
+        self
+    }
+
+    /// Publish one batch of due messages and return how many were published.
+    ///
+    /// Messages are published in the order they were stored, except that a failing message is
+    /// retried later while the messages after it go ahead.
+    #[tracing::instrument(skip_all, level = "debug", err, ret)]
+    pub async fn relay_once(&self) -> Result<usize, Error> {
+        let messages =
+            OutboxMessage::claim_due(self.db.db(), self.batch_size, self.claim_timeout).await?;
+        let mut published = 0;
+        for message in messages {
+            let result = match message.properties() {
+                Ok(properties) => {
+                    super::publish(
+                        &self.mq,
+                        &message.exchange,
+                        &message.routing_key,
+                        properties,
+                        message.payload.clone(),
+                    )
+                    .await
+                }
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(()) => {
+                    OutboxMessage::mark_published(self.db.db(), message.id).await?;
+                    published += 1;
+                }
+                Err(e) => self.record_failure(&message, &e).await?,
+            }
+        }
+        Ok(published)
+    }
+
+    async fn record_failure(&self, message: &OutboxMessage, error: &Error) -> Result<(), Error> {
+        let OutboxMessage {
+            id,
+            exchange,
+            routing_key,
+            attempts,
+            ..
+        } = message;
+        let attempts = attempts.saturating_add(1);
+        // Stored headers that do not decode never will
+        if attempts >= self.max_attempts || matches!(error, Error::DeserializeError(_)) {
+            tracing::error!(
+                id,
+                exchange,
+                routing_key,
+                attempts,
+                "Parking outbox message: {error}"
+            );
+            OutboxMessage::park(self.db.db(), *id, &error.to_string()).await?;
+        } else {
+            let retry_in = OUTBOX_RETRY_BASE_DELAY
+                .saturating_mul(2u32.saturating_pow(attempts.unsigned_abs() - 1))
+                .min(OUTBOX_RETRY_MAX_DELAY);
+            tracing::warn!(
+                id,
+                exchange,
+                routing_key,
+                attempts,
+                "Failed to relay outbox message, retrying in {retry_in:?}: {error}"
+            );
+            OutboxMessage::mark_failed(self.db.db(), *id, &error.to_string(), retry_in).await?;
+        }
+        Ok(())
+    }
+
+    /// Relay pending messages until the returned future is dropped.
+    pub async fn run(&self) {
+        loop {
+            match self.relay_once().await {
+                // A full batch means more messages are probably waiting
+                Ok(published) if published as i64 >= self.batch_size => continue,
+                Ok(_) => {}
+                Err(e) => tracing::error!("Outbox relay failed: {e}"),
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Put a parked message back to be published on the next batch
+    pub async fn unpark(&self, id: i64) -> Result<bool, Error> {
+        Ok(OutboxMessage::unpark(self.db.db(), id).await?)
+    }
+
+    /// Delete messages published before `time_before` and return how many were deleted
+    pub async fn purge_published(&self, time_before: OffsetDateTime) -> Result<u64, Error> {
+        Ok(OutboxMessage::delete_published_before(self.db.db(), time_before).await?)
+    }
+}
@@ -0,0 +1,155 @@
+//! How failed deliveries are settled.
+//!
+//! Every error returned by a processor falls into an [`ErrorClass`], and the processor's
+//! [`AckPolicy`] decides which [`AckAction`] settles the delivery for that class.
+
+use std::fmt;
+
+use crate::error::Error;
+
+/// How a delivery that failed to process is settled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckAction {
+    /// Ack the delivery, dropping the message
+    Ack,
+    /// Nack the delivery and requeue it for immediate redelivery
+    Requeue,
+    /// Schedule the message to a retry queue, dead-lettering it once the retries run out
+    RetryLater,
+    /// Reject the delivery without requeueing, which dead-letters it to `q.dead`
+    DeadLetter,
+    /// Copy the message to the [quarantine](super::quarantine) and ack the delivery. Dead-letters
+    /// it if the processor has no quarantine or the copy fails.
+    Quarantine,
+}
+
+impl AckAction {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            AckAction::Ack => "ack",
+            AckAction::Requeue => "requeue",
+            AckAction::RetryLater => "retry_later",
+            AckAction::DeadLetter => "dead_letter",
+            AckAction::Quarantine => "quarantine",
+        }
+    }
+}
+
+impl fmt::Display for AckAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Class of an error returned by a processor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Database, Redis or IO errors, update conflicts or rate limits which are likely to go away
+    Transient,
+    /// A message published while processing was nacked or returned by the broker
+    Publish,
+    /// Errors from the AMQP client
+    Amqp,
+    /// The message could not be serialized or deserialized
+    Malformed,
+    /// The message was refused as invalid, not found, not permitted or over a limit it never fits
+    Rejected,
+    /// The business logic failed in a way retrying cannot solve
+    BusinessPanic,
+}
+
+impl ErrorClass {
+    pub fn of(error: &Error) -> Self {
+        match error {
+            Error::DatabaseError(_)
+            | Error::RedisError(_)
+            | Error::Io(_)
+            | Error::PoolTimeout
+            | Error::PoolClosed
+            | Error::Conflict(_)
+            | Error::RateLimited(Some(_)) => ErrorClass::Transient,
+            Error::PublishNacked | Error::Unroutable(_) => ErrorClass::Publish,
+            Error::AmqpError(_) => ErrorClass::Amqp,
+            Error::SerializeError(_) | Error::DeserializeError(_) => ErrorClass::Malformed,
+            Error::InvalidInput
+            | Error::NotFound
+            | Error::PermissionsDenied
+            | Error::RateLimited(None) => ErrorClass::Rejected,
+            Error::BusinessPanic(_) => ErrorClass::BusinessPanic,
+        }
+    }
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            ErrorClass::Transient => "transient",
+            ErrorClass::Publish => "publish",
+            ErrorClass::Amqp => "amqp",
+            ErrorClass::Malformed => "malformed",
+            ErrorClass::Rejected => "rejected",
+            ErrorClass::BusinessPanic => "business_panic",
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Action taken for each [`ErrorClass`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AckPolicy {
+    pub transient: AckAction,
+    pub publish: AckAction,
+    pub amqp: AckAction,
+    pub malformed: AckAction,
+    pub rejected: AckAction,
+    pub business_panic: AckAction,
+}
+
+impl AckPolicy {
+    /// Retry errors that may go away, drop messages that will never succeed
+    pub const DEFAULT: Self = Self {
+        transient: AckAction::RetryLater,
+        publish: AckAction::RetryLater,
+        amqp: AckAction::RetryLater,
+        malformed: AckAction::Ack,
+        rejected: AckAction::Ack,
+        business_panic: AckAction::Ack,
+    };
+
+    /// Like [`AckPolicy::DEFAULT`], but keep messages that will never succeed in the
+    /// [quarantine](super::quarantine) to be inspected and replayed
+    pub const QUARANTINE_FAILURES: Self = Self {
+        malformed: AckAction::Quarantine,
+        rejected: AckAction::Quarantine,
+        business_panic: AckAction::Quarantine,
+        ..Self::DEFAULT
+    };
+
+    /// Like [`AckPolicy::DEFAULT`], but keep messages that will never succeed in `q.dead`
+    pub const DEAD_LETTER_FAILURES: Self = Self {
+        malformed: AckAction::DeadLetter,
+        rejected: AckAction::DeadLetter,
+        business_panic: AckAction::DeadLetter,
+        ..Self::DEFAULT
+    };
+
+    pub const fn action(&self, class: ErrorClass) -> AckAction {
+        match class {
+            ErrorClass::Transient => self.transient,
+            ErrorClass::Publish => self.publish,
+            ErrorClass::Amqp => self.amqp,
+            ErrorClass::Malformed => self.malformed,
+            ErrorClass::Rejected => self.rejected,
+            ErrorClass::BusinessPanic => self.business_panic,
+        }
+    }
+}
+
+impl Default for AckPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
@@ -0,0 +1,274 @@
+//! Quarantine of poison messages.
+//!
+//! Deliveries settled with [`AckAction::Quarantine`](super::policy::AckAction::Quarantine) are
+//! copied to `kanaeru.quarantine` with their original bytes, headers, metadata, error and the
+//! queue of the processor that failed on them, and then acked. [`Quarantine`] lists, inspects
+//! and edits them, and replays them to the queue of their processor once the bug is fixed.
+//!
+//! Processors opt in with an [`ACK_POLICY`](super::AmqpMessageProcessor::ACK_POLICY) such as
+//! [`AckPolicy::QUARANTINE_FAILURES`](super::policy::AckPolicy::QUARANTINE_FAILURES) and a
+//! database in [`quarantine`](super::AmqpMessageProcessor::quarantine).
+
+use amqprs::{BasicProperties, FieldName, FieldTable, FieldValue};
+use kanau::message::DeserializeError;
+use time::OffsetDateTime;
+
+use super::AmqpPool;
+use super::metadata::MessageMetadata;
+use super::outbox::{decode_headers, encode_headers};
+use super::policy::ErrorClass;
+use super::retry::{ATTEMPT_HEADER, insert_field};
+use crate::error::Error;
+use crate::sqlx::DatabaseProcessor;
+
+/// Header of a replayed message carrying the id it had in the quarantine
+pub const QUARANTINE_ID_HEADER: &str = "x-kanaeru-quarantine-id";
+
+/// Default number of messages [`Quarantine::list`] returns
+pub const QUARANTINE_LIST_LIMIT: i64 = 100;
+
+#[derive(Clone, PartialEq, Eq, sqlx::FromRow, Debug)]
+pub struct QuarantinedMessage {
+    pub id: i64,
+    /// Queue of the processor that failed on the message
+    pub processor: String,
+    pub exchange: String,
+    pub routing_key: String,
+    pub payload: Vec<u8>,
+    /// AMQP field table of the headers, encoded as on the wire. `None` for no headers.
+    pub headers: Option<Vec<u8>>,
+    pub message_id: Option<String>,
+    pub correlation_id: Option<String>,
+    pub content_type: Option<String>,
+    pub traceparent: Option<String>,
+    pub tracestate: Option<String>,
+    pub error: String,
+    pub error_class: String,
+    pub quarantined_at: OffsetDateTime,
+    pub replayed_at: Option<OffsetDateTime>,
+}
+
+impl QuarantinedMessage {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn insert(
+        conn: impl sqlx::PgExecutor<'_>,
+        processor: &str,
+        exchange: &str,
+        routing_key: &str,
+        properties: &BasicProperties,
+        payload: &[u8],
+        error: &str,
+        error_class: ErrorClass,
+    ) -> Result<i64, sqlx::Error> {
+        let metadata = MessageMetadata::from_properties(properties);
+        let headers = match properties.headers() {
+            Some(headers) => encode_headers(headers)?,
+            None => None,
+        };
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO kanaeru.quarantine
+                (processor, exchange, routing_key, payload, headers, message_id, correlation_id,
+                 content_type, traceparent, tracestate, error, error_class)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            RETURNING id
+            "#,
+            processor,
+            exchange,
+            routing_key,
+            payload,
+            headers,
+            metadata.message_id,
+            metadata.correlation_id,
+            metadata.content_type,
+            metadata.traceparent,
+            metadata.tracestate,
+            error,
+            error_class.as_str(),
+        )
+        .fetch_one(conn)
+        .await?;
+        Ok(row.id)
+    }
+
+    /// Messages with an id above `after_id`, oldest first, optionally only those of `processor`
+    /// and those not replayed yet
+    pub async fn list(
+        conn: impl sqlx::PgExecutor<'_>,
+        processor: Option<&str>,
+        include_replayed: bool,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            Self,
+            r#"
+            SELECT id, processor, exchange, routing_key, payload, headers, message_id,
+                correlation_id, content_type, traceparent, tracestate, error, error_class,
+                quarantined_at, replayed_at
+            FROM kanaeru.quarantine
+            WHERE id > $1
+                AND ($2::TEXT IS NULL OR processor = $2)
+                AND ($3 OR replayed_at IS NULL)
+            ORDER BY id
+            LIMIT $4
+            "#,
+            after_id,
+            processor,
+            include_replayed,
+            limit,
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    pub async fn find(
+        conn: impl sqlx::PgExecutor<'_>,
+        id: i64,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            Self,
+            r#"
+            SELECT id, processor, exchange, routing_key, payload, headers, message_id,
+                correlation_id, content_type, traceparent, tracestate, error, error_class,
+                quarantined_at, replayed_at
+            FROM kanaeru.quarantine
+            WHERE id = $1
+            "#,
+            id,
+        )
+        .fetch_optional(conn)
+        .await
+    }
+
+    pub async fn update_payload(
+        conn: impl sqlx::PgExecutor<'_>,
+        id: i64,
+        payload: &[u8],
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE kanaeru.quarantine SET payload = $2 WHERE id = $1",
+            id,
+            payload,
+        )
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn mark_replayed(
+        conn: impl sqlx::PgExecutor<'_>,
+        id: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE kanaeru.quarantine SET replayed_at = NOW() WHERE id = $1",
+            id
+        )
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete(conn: impl sqlx::PgExecutor<'_>, id: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!("DELETE FROM kanaeru.quarantine WHERE id = $1", id)
+            .execute(conn)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Metadata the message was delivered with
+    pub fn metadata(&self) -> MessageMetadata {
+        MessageMetadata {
+            message_id: self.message_id.clone(),
+            correlation_id: self.correlation_id.clone(),
+            timestamp: u64::try_from(self.quarantined_at.unix_timestamp()).ok(),
+            content_type: self.content_type.clone(),
+            traceparent: self.traceparent.clone(),
+            tracestate: self.tracestate.clone(),
+        }
+    }
+
+    /// Headers the message was delivered with
+    pub fn headers(&self) -> Result<FieldTable, DeserializeError> {
+        decode_headers(self.headers.as_deref())
+    }
+
+    /// Properties to replay the message with: its headers, starting over from the first attempt,
+    /// stamped with its metadata
+    pub fn replay_properties(&self) -> Result<BasicProperties, DeserializeError> {
+        let mut headers = self.headers()?;
+        if let Ok(name) = FieldName::try_from(ATTEMPT_HEADER) {
+            headers.remove(&name);
+        }
+        insert_field(&mut headers, QUARANTINE_ID_HEADER, FieldValue::l(self.id));
+        let mut properties = BasicProperties::default();
+        properties.with_headers(headers).with_persistence(true);
+        self.metadata().apply(&mut properties);
+        Ok(properties)
+    }
+}
+
+/// Inspects, edits and replays quarantined messages
+#[derive(Clone)]
+pub struct Quarantine {
+    db: DatabaseProcessor,
+    mq: AmqpPool,
+}
+
+impl Quarantine {
+    pub fn new(db: DatabaseProcessor, mq: AmqpPool) -> Self {
+        Self { db, mq }
+    }
+
+    /// Up to `limit` messages with an id above `after_id`, oldest first
+    pub async fn list(
+        &self,
+        processor: Option<&str>,
+        include_replayed: bool,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<QuarantinedMessage>, Error> {
+        Ok(
+            QuarantinedMessage::list(self.db.db(), processor, include_replayed, after_id, limit)
+                .await?,
+        )
+    }
+
+    pub async fn get(&self, id: i64) -> Result<QuarantinedMessage, Error> {
+        QuarantinedMessage::find(self.db.db(), id)
+            .await?
+            .ok_or(Error::NotFound)
+    }
+
+    /// Replace the payload of a message, e.g. to fix what its processor failed on
+    pub async fn edit_payload(&self, id: i64, payload: &[u8]) -> Result<(), Error> {
+        if !QuarantinedMessage::update_payload(self.db.db(), id, payload).await? {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Publish a message again to the queue of its processor, and only there
+    #[tracing::instrument(skip(self), err)]
+    pub async fn replay(&self, id: i64) -> Result<(), Error> {
+        let message = self.get(id).await?;
+        let properties = message.replay_properties()?;
+        super::publish(
+            &self.mq,
+            "",
+            &message.processor,
+            properties,
+            message.payload,
+        )
+        .await?;
+        QuarantinedMessage::mark_replayed(self.db.db(), id).await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, id: i64) -> Result<(), Error> {
+        if !QuarantinedMessage::delete(self.db.db(), id).await? {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+//! Delayed retries with exponential backoff and dead-lettering.
+//!
+//! Every processor queue `q` gets one retry queue per retry, `q.retry.1`, `q.retry.2`, ...
+//! Each retry queue holds messages for its own TTL and then dead-letters them back to `q`
+//! through the default exchange. Messages that run out of attempts are rejected from `q`,
+//! which dead-letters them to [`DEAD_LETTER_EXCHANGE`] and into `q.dead`.
+//!
+//! A failed delivery is only acked once the broker has confirmed its copy in the retry queue,
+//! and requeued if the copy could not be published.
+//!
+//! # Migrating existing queues
+//!
+//! `q` is declared with `x-dead-letter-exchange` and `x-dead-letter-routing-key` arguments, and
+//! RabbitMQ refuses to redeclare a queue with other arguments than it was created with. A queue
+//! created before it had retries is therefore kept as is, with a warning, and the messages it
+//! rejects are dropped instead of dead-lettered. Either
+//!
+//! - apply a policy doing the same, which takes effect right away, e.g. for a queue `orders`:
+//!   `rabbitmqctl set_policy --apply-to queues orders-dead-letter '^orders$'
+//!   '{"dead-letter-exchange":"kanaeru.dead-letter","dead-letter-routing-key":"orders"}'`
+//! - or drain and delete the queue, so that it is declared again with the arguments.
+
+use amqprs::channel::{
+    BasicPublishArguments, Channel, ExchangeDeclareArguments, QueueBindArguments,
+    QueueDeclareArguments,
+};
+use amqprs::{BasicProperties, FieldName, FieldTable, FieldValue};
+use std::time::Duration;
+
+use super::AmqpExchangeType;
+use super::confirm::AmqpChannel;
+use crate::error::Error;
+
+/// Exchange receiving messages that ran out of attempts, routed by the name of their queue
+pub const DEAD_LETTER_EXCHANGE: &str = "kanaeru.dead-letter";
+
+/// Header carrying the number of the current attempt, starting from 1
+pub const ATTEMPT_HEADER: &str = "x-kanaeru-attempt";
+
+/// How a processor retries messages that failed with a retryable error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts before a message is dead-lettered, including the first delivery
+    pub max_attempts: u32,
+    /// Delay of the first retry, doubled for every later retry
+    pub base_delay: Duration,
+    /// Upper bound of the delay
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub const DEFAULT: Self = Self {
+        max_attempts: 5,
+        base_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(300),
+    };
+
+    /// Delay before the `retry`-th retry, starting from 1
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    /// Number of retry queues, one per retry
+    pub fn retries(&self) -> u32 {
+        self.max_attempts.saturating_sub(1)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+pub fn retry_queue_name(queue: &str, retry: u32) -> String {
+    format!("{queue}.retry.{retry}")
+}
+
+pub fn dead_letter_queue_name(queue: &str) -> String {
+    format!("{queue}.dead")
+}
+
+pub(crate) fn insert_field(table: &mut FieldTable, name: &str, value: FieldValue) {
+    if let Ok(name) = FieldName::try_from(name) {
+        // `insert` does not account for the size of a replaced value, so remove it first
+        table.remove(&name);
+        table.insert(name, value);
+    }
+}
+
+/// Value of a string header of a delivered message
+pub(crate) fn str_header(properties: &BasicProperties, name: &str) -> Option<String> {
+    let name = FieldName::try_from(name).ok()?;
+    match properties.headers()?.get(&name)? {
+        FieldValue::S(value) => Some(value.as_ref().clone()),
+        _ => None,
+    }
+}
+
+/// Value of an unsigned integer header of a delivered message
+pub(crate) fn u32_header(properties: &BasicProperties, name: &str) -> Option<u32> {
+    let name = FieldName::try_from(name).ok()?;
+    match properties.headers()?.get(&name)? {
+        FieldValue::i(n) => Some(*n),
+        FieldValue::I(n) => u32::try_from(*n).ok(),
+        FieldValue::l(n) => u32::try_from(*n).ok(),
+        _ => None,
+    }
+}
+
+/// Number of the attempt the delivered message is on, 1 for the first delivery
+pub fn attempt(properties: &BasicProperties) -> u32 {
+    u32_header(properties, ATTEMPT_HEADER).unwrap_or(1).max(1)
+}
+
+/// Declare the dead-letter and retry queues of `queue`.
+///
+/// Returns the arguments the main queue must be declared with, so that rejected messages are
+/// dead-lettered.
+pub(crate) async fn declare_topology(
+    channel: &Channel,
+    queue: &str,
+    policy: &RetryPolicy,
+) -> Result<FieldTable, amqprs::error::Error> {
+    channel
+        .exchange_declare(
+            ExchangeDeclareArguments::of_type(DEAD_LETTER_EXCHANGE, AmqpExchangeType::Direct)
+                .durable(true)
+                .finish(),
+        )
+        .await?;
+    let dead_queue = dead_letter_queue_name(queue);
+    channel
+        .queue_declare(QueueDeclareArguments::durable_client_named(&dead_queue))
+        .await?;
+    channel
+        .queue_bind(QueueBindArguments::new(
+            &dead_queue,
+            DEAD_LETTER_EXCHANGE,
+            queue,
+        ))
+        .await?;
+
+    for retry in 1..=policy.retries() {
+        let ttl = i64::try_from(policy.delay(retry).as_millis()).unwrap_or(i64::MAX);
+        let mut arguments = FieldTable::new();
+        insert_field(&mut arguments, "x-message-ttl", FieldValue::l(ttl));
+        // Expired messages go back to the main queue through the default exchange
+        insert_field(&mut arguments, "x-dead-letter-exchange", "".into());
+        insert_field(&mut arguments, "x-dead-letter-routing-key", queue.into());
+        channel
+            .queue_declare(
+                QueueDeclareArguments::durable_client_named(&retry_queue_name(queue, retry))
+                    .arguments(arguments)
+                    .finish(),
+            )
+            .await?;
+    }
+
+    let mut main_arguments = FieldTable::new();
+    insert_field(
+        &mut main_arguments,
+        "x-dead-letter-exchange",
+        DEAD_LETTER_EXCHANGE.into(),
+    );
+    insert_field(
+        &mut main_arguments,
+        "x-dead-letter-routing-key",
+        queue.into(),
+    );
+    Ok(main_arguments)
+}
+
+/// What happened to a message that failed with a retryable error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryOutcome {
+    /// A copy was published to a retry queue, the delivery can be acked
+    Scheduled { attempt: u32, delay: Duration },
+    /// No attempts left, the delivery must be rejected to dead-letter it
+    Exhausted,
+}
+
+/// Publish a copy of a failed message to the next retry queue of `queue`, and wait for the
+/// broker to confirm it.
+pub(crate) async fn schedule(
+    publisher: &AmqpChannel,
+    queue: &str,
+    policy: &RetryPolicy,
+    properties: &BasicProperties,
+    content: &[u8],
+) -> Result<RetryOutcome, Error> {
+    let attempt = attempt(properties);
+    if attempt >= policy.max_attempts {
+        return Ok(RetryOutcome::Exhausted);
+    }
+    let mut properties = properties.clone();
+    let mut headers = properties.headers().cloned().unwrap_or_default();
+    insert_field(&mut headers, ATTEMPT_HEADER, FieldValue::i(attempt + 1));
+    properties.with_headers(headers).with_persistence(true);
+    publisher
+        .publish(
+            properties,
+            content.to_vec(),
+            BasicPublishArguments::new("", &retry_queue_name(queue, attempt))
+                .mandatory(true)
+                .finish(),
+        )
+        .await?;
+    Ok(RetryOutcome::Scheduled {
+        attempt: attempt + 1,
+        delay: policy.delay(attempt),
+    })
+}
@@ -0,0 +1,439 @@
+//! Request/reply over RabbitMQ.
+//!
+//! A request is published like any other message, with `reply_to` set to the
+//! [direct reply-to](https://www.rabbitmq.com/docs/direct-reply-to) pseudo-queue of the
+//! [`AmqpRpcClient`] channel. The server publishes its reply there, with the id of the request
+//! as correlation id. Requests expire after [`AmqpRpcRequest::TIMEOUT`], so a server never
+//! works on a request the client has given up on.
+//!
+//! Errors returned by the server are sent back in the [`RPC_ERROR_HEADER`] header and turned
+//! into the matching [`Error`] on the client. Replies are enveloped like their requests, with
+//! the [`ENVELOPE`](AmqpMessageSend::ENVELOPE) of the request type.
+
+use amqprs::channel::{
+    BasicConsumeArguments, BasicPublishArguments, BasicQosArguments, Channel,
+    ExchangeDeclareArguments, QueueDeclareArguments,
+};
+use amqprs::connection::Connection;
+use amqprs::consumer::AsyncConsumer;
+use amqprs::{BasicProperties, Deliver};
+use kanau::message::{MessageDe, MessageSer};
+use kanau::processor::Processor;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::Instrument;
+
+use super::confirm::AmqpChannel;
+use super::consumer::{ConsumerConfig, ConsumerHandle, InFlight};
+use super::metadata::MessageMetadata;
+use super::retry::{insert_field, str_header};
+use super::{
+    AmqpBinding, AmqpMessageSend, AmqpPool, ack, bind_queue, decode_delivery, mark_enveloped,
+    open_content, outgoing_headers, seal,
+};
+use crate::envelope;
+use crate::error::Error;
+use crate::schema::DEFAULT_CONTENT_TYPE;
+
+/// Pseudo-queue through which replies are sent straight to the requesting channel
+pub const DIRECT_REPLY_TO: &str = "amq.rabbitmq.reply-to";
+
+/// Header of a reply carrying the kind of error the server failed with. The body of such a
+/// reply is the error message.
+pub const RPC_ERROR_HEADER: &str = "x-kanaeru-rpc-error";
+
+/// Default time to wait for a reply
+pub const RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request answered by an [`AmqpRpcProcessor`]
+pub trait AmqpRpcRequest: AmqpMessageSend {
+    /// Reply to the request
+    type Response: MessageSer + MessageDe + Send;
+
+    /// How long the client waits for a reply
+    const TIMEOUT: Duration = RPC_TIMEOUT;
+
+    /// Send request and wait for the reply
+    fn call(
+        self,
+        client: &AmqpRpcClient,
+    ) -> impl Future<Output = Result<Self::Response, Error>> + Send {
+        client.call(self)
+    }
+}
+
+type PendingReplies = HashMap<String, oneshot::Sender<(BasicProperties, Vec<u8>)>>;
+
+#[derive(Clone, Default)]
+struct SharedPendingReplies(Arc<Mutex<PendingReplies>>);
+
+impl SharedPendingReplies {
+    fn lock(&self) -> MutexGuard<'_, PendingReplies> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Forgets a pending reply once the call is over, whether it got a reply or not
+struct PendingGuard<'a> {
+    pending: &'a SharedPendingReplies,
+    message_id: &'a str,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().remove(self.message_id);
+    }
+}
+
+struct ReplyConsumer {
+    pending: SharedPendingReplies,
+}
+
+impl AsyncConsumer for ReplyConsumer {
+    fn consume<'life0, 'life1, 'async_trait>(
+        &'life0 mut self,
+        _channel: &'life1 Channel,
+        _deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'async_trait>>
+    where
+        Self: 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+    {
+        Box::pin(async move {
+            let sender = basic_properties
+                .correlation_id()
+                .and_then(|id| self.pending.lock().remove(id));
+            match sender {
+                Some(sender) => {
+                    let _ = sender.send((basic_properties, content));
+                }
+                None => tracing::warn!(
+                    correlation_id = basic_properties.correlation_id(),
+                    "Dropping reply to an unknown or timed out request"
+                ),
+            }
+        })
+    }
+}
+
+/// Sends requests and receives their replies on one channel
+pub struct AmqpRpcClient {
+    channel: AmqpChannel,
+    pending: SharedPendingReplies,
+}
+
+impl AmqpRpcClient {
+    /// Open the channel of the client and start consuming its replies.
+    ///
+    /// The client is bound to `connection` and fails every call once it is lost. Connect a new
+    /// client then, for example to [`AmqpSupervisor::connection`].
+    ///
+    /// [`AmqpSupervisor::connection`]: super::supervisor::AmqpSupervisor::connection
+    pub async fn connect(connection: &Connection) -> Result<Self, amqprs::error::Error> {
+        let channel = AmqpChannel::open(connection).await?;
+        let pending = SharedPendingReplies::default();
+        channel
+            .channel()
+            .basic_consume(
+                ReplyConsumer {
+                    pending: pending.clone(),
+                },
+                // Direct reply-to must be consumed without acks
+                BasicConsumeArguments::new(DIRECT_REPLY_TO, "")
+                    .manual_ack(false)
+                    .finish(),
+            )
+            .await?;
+        Ok(Self { channel, pending })
+    }
+
+    /// Send a request and wait up to [`AmqpRpcRequest::TIMEOUT`] for its reply
+    #[tracing::instrument(skip_all, level = "debug", err)]
+    pub async fn call<R: AmqpRpcRequest>(&self, request: R) -> Result<R::Response, Error> {
+        let routing_key = request.routing_key().into_owned();
+        let mut properties = BasicProperties::default();
+        properties.with_headers(outgoing_headers(&request));
+        let bytes = seal(request)?;
+        let metadata = MessageMetadata::outgoing(R::CONTENT_TYPE);
+        let message_id = metadata.message_id.clone().unwrap_or_default();
+        metadata.apply(&mut properties);
+        properties
+            .with_reply_to(DIRECT_REPLY_TO)
+            .with_expiration(&R::TIMEOUT.as_millis().to_string());
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().insert(message_id.clone(), sender);
+        let _pending = PendingGuard {
+            pending: &self.pending,
+            message_id: &message_id,
+        };
+        self.channel
+            .publish(
+                properties,
+                bytes.into_vec(),
+                BasicPublishArguments::new(R::EXCHANGE, &routing_key)
+                    .mandatory(true)
+                    .finish(),
+            )
+            .await?;
+
+        let (properties, content) = match tokio::time::timeout(R::TIMEOUT, receiver).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) => {
+                return Err(Error::Io(anyhow::anyhow!(
+                    "Reply channel closed before the reply arrived"
+                )));
+            }
+            Err(_) => {
+                return Err(Error::Io(anyhow::anyhow!(
+                    "Timed out waiting for the reply to a request"
+                )));
+            }
+        };
+        if let Some(kind) = str_header(&properties, RPC_ERROR_HEADER) {
+            return Err(remote_error(&kind, String::from_utf8_lossy(&content)));
+        }
+        let opened = open_content(&properties, &content)?;
+        R::Response::from_bytes(&opened.content).map_err(|e| Error::DeserializeError(e.into()))
+    }
+}
+
+fn error_kind(error: &Error) -> &'static str {
+    match error {
+        Error::InvalidInput | Error::SerializeError(_) | Error::DeserializeError(_) => {
+            "invalid_input"
+        }
+        Error::NotFound => "not_found",
+        Error::PermissionsDenied => "permission_denied",
+        Error::BusinessPanic(_) => "business_panic",
+        _ => "io",
+    }
+}
+
+fn remote_error(kind: &str, message: impl std::fmt::Display) -> Error {
+    match kind {
+        "invalid_input" => Error::InvalidInput,
+        "not_found" => Error::NotFound,
+        "permission_denied" => Error::PermissionsDenied,
+        "business_panic" => Error::BusinessPanic(anyhow::anyhow!("Remote: {message}")),
+        _ => Error::Io(anyhow::anyhow!("Remote: {message}")),
+    }
+}
+
+/// Trait for answering requests from rabbitmq
+pub trait AmqpRpcProcessor<Request: AmqpRpcRequest + MessageDe>:
+    Processor<Request, Result<Request::Response, Error>>
+{
+    const QUEUE: &'static str;
+
+    /// How the request queue is bound to the exchange of the requests.
+    ///
+    /// Defaults to the [`ROUTING_KEY`](super::AmqpRouting::ROUTING_KEY) of the requests.
+    fn bindings() -> Vec<AmqpBinding> {
+        vec![AmqpBinding::routing_key(Request::ROUTING_KEY)]
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    /// Ensure the request queue and get the channel with the queue bound
+    fn ensure_queue(pool: &AmqpPool) -> impl Future<Output = Result<Channel, Error>> + Send {
+        async move {
+            // Replies are published without confirms, so the channel is not in confirm mode
+            let channel = pool.manager().open_channel().await?;
+            channel
+                .exchange_declare(
+                    ExchangeDeclareArguments::of_type(Request::EXCHANGE, Request::EXCHANGE_TYPE)
+                        .durable(true)
+                        .finish(),
+                )
+                .await?;
+            // Requests expire with the timeout of their caller, there is nothing to retry
+            channel
+                .queue_declare(QueueDeclareArguments::durable_client_named(Self::QUEUE))
+                .await?;
+            bind_queue(&channel, Self::QUEUE, Request::EXCHANGE, &Self::bindings()).await?;
+            Ok(channel)
+        }
+    }
+}
+
+/// Consumer answering requests with an [`AmqpRpcProcessor`]
+pub struct AmqpRpcConsumer<Request: AmqpRpcRequest + MessageDe, Inner: AmqpRpcProcessor<Request>> {
+    inner: Arc<Inner>,
+    in_flight: InFlight,
+    concurrent: bool,
+    _marker: PhantomData<Request>,
+}
+
+impl<R, I> AmqpRpcConsumer<R, I>
+where
+    R: AmqpRpcRequest + MessageDe + Send + Sync + 'static,
+    I: AmqpRpcProcessor<R> + Send + Sync + 'static,
+    R::DeError: Send,
+{
+    async fn handle_request(
+        inner: Arc<I>,
+        channel: Channel,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let metadata = MessageMetadata::from_properties(&basic_properties);
+        let span = metadata.consumer_span(I::QUEUE);
+        async move {
+            let response = match decode_delivery::<R>(&basic_properties, &content) {
+                Ok(request) => metadata.clone().scope(inner.process(request)).await,
+                Err(e) => Err(e),
+            }
+            .and_then(|response| {
+                let bytes = response.to_bytes().map_err(|e| e.into())?;
+                Ok(envelope::seal(bytes, DEFAULT_CONTENT_TYPE, &R::ENVELOPE)?)
+            });
+            match basic_properties.reply_to() {
+                Some(reply_to) => {
+                    reply(
+                        &channel,
+                        reply_to,
+                        &metadata,
+                        response,
+                        R::ENVELOPE.envelope,
+                    )
+                    .await
+                }
+                None => tracing::warn!("Request has no reply_to, dropping its reply"),
+            }
+            ack(
+                &channel,
+                amqprs::channel::BasicAckArguments::new(deliver.delivery_tag(), false),
+                5,
+            )
+            .await;
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+async fn reply(
+    channel: &Channel,
+    reply_to: &str,
+    metadata: &MessageMetadata,
+    response: Result<Box<[u8]>, Error>,
+    enveloped: bool,
+) {
+    let mut properties = BasicProperties::default();
+    if let Some(message_id) = &metadata.message_id {
+        properties.with_correlation_id(message_id);
+    }
+    let content = match response {
+        Ok(bytes) => {
+            if enveloped {
+                let mut headers = amqprs::FieldTable::new();
+                mark_enveloped(&mut headers);
+                properties.with_headers(headers);
+            }
+            bytes.into_vec()
+        }
+        Err(e) => {
+            tracing::warn!("Replying with error: {e}");
+            let mut headers = amqprs::FieldTable::new();
+            insert_field(&mut headers, RPC_ERROR_HEADER, error_kind(&e).into());
+            properties.with_headers(headers);
+            e.to_string().into_bytes()
+        }
+    };
+    if let Err(e) = channel
+        .basic_publish(
+            properties,
+            content,
+            BasicPublishArguments::new("", reply_to),
+        )
+        .await
+    {
+        tracing::error!("Failed to publish reply: {e}");
+    }
+}
+
+impl<R, I> AsyncConsumer for AmqpRpcConsumer<R, I>
+where
+    R: AmqpRpcRequest + MessageDe + Send + Sync + 'static,
+    I: AmqpRpcProcessor<R> + Send + Sync + 'static,
+    R::DeError: Send,
+{
+    fn consume<'life0, 'life1, 'async_trait>(
+        &'life0 mut self,
+        channel: &'life1 Channel,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'async_trait>>
+    where
+        Self: 'async_trait,
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+    {
+        Box::pin(async move {
+            let Some(permit) = self.in_flight.acquire().await else {
+                // Shutting down, the request is requeued once the channel is closed
+                return;
+            };
+            let request = Self::handle_request(
+                self.inner.clone(),
+                channel.clone(),
+                deliver,
+                basic_properties,
+                content,
+            );
+            if self.concurrent {
+                tokio::spawn(async move {
+                    request.await;
+                    drop(permit);
+                });
+            } else {
+                request.await;
+                drop(permit);
+            }
+        })
+    }
+}
+
+/// bind request consumer for a request type, with the prefetch and concurrency of `config`
+pub async fn setup_rpc_server<R, H>(
+    channel: &Channel,
+    hook: Arc<H>,
+    config: ConsumerConfig,
+) -> Result<ConsumerHandle, amqprs::error::Error>
+where
+    R: AmqpRpcRequest + MessageDe + Send + Sync + 'static,
+    R::DeError: Send,
+    H: AmqpRpcProcessor<R> + Send + Sync + 'static,
+{
+    channel
+        .basic_qos(BasicQosArguments::new(0, config.prefetch, false))
+        .await?;
+    let in_flight = InFlight::new(config.concurrency);
+    let consumer_tag = channel
+        .basic_consume(
+            AmqpRpcConsumer::<R, H> {
+                inner: hook,
+                in_flight: in_flight.clone(),
+                concurrent: config.concurrency > 1,
+                _marker: PhantomData,
+            },
+            BasicConsumeArguments::new(H::QUEUE, "")
+                .manual_ack(true)
+                .finish(),
+        )
+        .await?;
+    Ok(ConsumerHandle::new(
+        channel.clone(),
+        consumer_tag,
+        in_flight,
+    ))
+}
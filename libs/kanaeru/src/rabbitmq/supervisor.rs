@@ -0,0 +1,305 @@
+//! Connection recovery.
+//!
+//! [`AmqpSupervisor`] owns the connection behind an [`AmqpPool`]. When the connection is lost,
+//! it reconnects with exponential backoff, hands the new connection to the pool, and declares
+//! again every exchange, queue and consumer registered with it, in the order they were
+//! registered. Channels opened on the lost connection are dropped by the pool as broken.
+//!
+//! Only what is registered is recovered. A consumer started with
+//! [`setup_consumer`](super::setup_consumer) or an [`AmqpRpcClient`](super::rpc::AmqpRpcClient)
+//! stays bound to the connection it was started on and stops for good once that connection is
+//! lost. Start consumers with [`AmqpSupervisor::register_consumer`] instead, and connect RPC
+//! clients again to [`AmqpSupervisor::connection`] once their calls fail.
+
+use amqprs::connection::{Connection, OpenConnectionArguments};
+use kanau::message::MessageDe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::Duration;
+
+use super::consumer::{ConsumerConfig, ConsumerHandle};
+use super::rpc::{AmqpRpcProcessor, AmqpRpcRequest, setup_rpc_server};
+use super::{
+    AMQP_POOL_CAPACITY, AMQP_POOL_IDLE_TIMEOUT, AmqpConnectionManager, AmqpMessageProcessor,
+    AmqpMessageSend, AmqpPool, AmqpRouting, setup_consumer_with_config,
+};
+use crate::error::Error;
+use crate::pool::PoolConfig;
+
+/// Delay before the first reconnection attempt, doubled after every failed attempt
+pub const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound of the delay between reconnection attempts
+pub const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How often the supervisor checks the connection, besides being woken up by network failures
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long consumers started before a declaration failed may finish their messages
+const ABORTED_CONSUMER_TIMEOUT: Duration = Duration::from_secs(5);
+
+type DeclareFuture = Pin<Box<dyn Future<Output = Result<Option<ConsumerHandle>, Error>> + Send>>;
+
+/// Declares part of the topology, and returns the handle of the consumer it started, if any
+type Declare = Box<dyn Fn(AmqpPool) -> DeclareFuture + Send + Sync>;
+
+struct Registration {
+    name: &'static str,
+    declare: Declare,
+}
+
+struct SupervisorInner {
+    args: OpenConnectionArguments,
+    pool: AmqpPool,
+    /// Held while declaring, so registering never races with a reconnection
+    registrations: tokio::sync::Mutex<Vec<Registration>>,
+    consumers: Mutex<Vec<ConsumerHandle>>,
+    closed: AtomicBool,
+}
+
+impl SupervisorInner {
+    /// Declare everything registered, replacing the handles of the consumers.
+    ///
+    /// If a declaration fails, the consumers started before it are shut down, since the
+    /// declarations are retried from the start on a new connection.
+    async fn declare_all(&self) -> Result<(), Error> {
+        let registrations = self.registrations.lock().await;
+        let mut consumers = Vec::new();
+        for registration in registrations.iter() {
+            match (registration.declare)(self.pool.clone()).await {
+                Ok(handle) => consumers.extend(handle),
+                Err(e) => {
+                    tracing::error!(registration = registration.name, "Failed to declare: {e}");
+                    shutdown_consumers(consumers, ABORTED_CONSUMER_TIMEOUT).await;
+                    return Err(e);
+                }
+            }
+        }
+        *self
+            .consumers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = consumers;
+        Ok(())
+    }
+
+    async fn reconnect(&self) {
+        let mut attempt = 0u32;
+        while !self.closed.load(Ordering::Acquire) {
+            match Connection::open(&self.args).await {
+                Ok(connection) => {
+                    self.pool.manager().replace_connection(connection);
+                    if self.declare_all().await.is_ok() {
+                        tracing::info!(attempt, "Reconnected to the broker");
+                        return;
+                    }
+                    // The next attempt starts over on a new connection
+                    if let Err(e) = self.pool.manager().connection().close().await {
+                        tracing::debug!("Failed to close the connection: {e}");
+                    }
+                }
+                Err(e) => tracing::warn!(attempt, "Failed to reconnect to the broker: {e}"),
+            }
+            let factor = 2u32.saturating_pow(attempt);
+            tokio::time::sleep(
+                RECONNECT_BASE_DELAY
+                    .saturating_mul(factor)
+                    .min(RECONNECT_MAX_DELAY),
+            )
+            .await;
+            attempt = attempt.saturating_add(1);
+        }
+    }
+}
+
+/// Keeps the connection of an [`AmqpPool`] alive, along with the topology and consumers
+/// registered with it
+#[derive(Clone)]
+pub struct AmqpSupervisor {
+    inner: Arc<SupervisorInner>,
+}
+
+impl AmqpSupervisor {
+    pub async fn connect(args: OpenConnectionArguments) -> Result<Self, Error> {
+        Self::connect_with_config(
+            args,
+            PoolConfig::new(AMQP_POOL_CAPACITY).idle_timeout(AMQP_POOL_IDLE_TIMEOUT),
+        )
+        .await
+    }
+
+    /// Open a connection and start supervising it. The pool is created with `config`.
+    pub async fn connect_with_config(
+        args: OpenConnectionArguments,
+        config: PoolConfig,
+    ) -> Result<Self, Error> {
+        let connection = Connection::open(&args).await?;
+        let pool = AmqpPool::with_config(AmqpConnectionManager::new(connection), config);
+        let inner = Arc::new(SupervisorInner {
+            args,
+            pool,
+            registrations: tokio::sync::Mutex::new(Vec::new()),
+            consumers: Mutex::new(Vec::new()),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(supervise(Arc::downgrade(&inner)));
+        Ok(Self { inner })
+    }
+
+    /// Pool of channels on the supervised connection
+    pub fn pool(&self) -> &AmqpPool {
+        &self.inner.pool
+    }
+
+    /// The current connection, replaced by a new one after every reconnection
+    pub fn connection(&self) -> Connection {
+        self.inner.pool.manager().connection()
+    }
+
+    /// Declare the exchange of `M` now and after every reconnection
+    pub async fn register_exchange<M: AmqpRouting + 'static>(&self) -> Result<(), Error> {
+        self.register(
+            M::EXCHANGE,
+            Box::new(|pool| {
+                Box::pin(async move {
+                    M::ensure_exchange(&pool).await?;
+                    Ok(None)
+                })
+            }),
+        )
+        .await
+    }
+
+    /// Declare the queue of `H` and start consuming it, now and after every reconnection
+    pub async fn register_consumer<M, H>(
+        &self,
+        hook: Arc<H>,
+        config: ConsumerConfig,
+    ) -> Result<(), Error>
+    where
+        M: AmqpMessageSend + MessageDe + Send + Sync + 'static,
+        M::DeError: Send,
+        H: AmqpMessageProcessor<M> + Send + Sync + 'static,
+    {
+        self.register(
+            H::QUEUE,
+            Box::new(move |pool| {
+                let hook = hook.clone();
+                Box::pin(async move {
+                    let channel = H::ensure_queue(&pool).await?;
+                    let handle = setup_consumer_with_config::<M, H>(&channel, hook, config).await?;
+                    Ok(Some(handle))
+                })
+            }),
+        )
+        .await
+    }
+
+    /// Declare the request queue of `H` and start answering it, now and after every
+    /// reconnection
+    pub async fn register_rpc_server<R, H>(
+        &self,
+        hook: Arc<H>,
+        config: ConsumerConfig,
+    ) -> Result<(), Error>
+    where
+        R: AmqpRpcRequest + MessageDe + Send + Sync + 'static,
+        R::DeError: Send,
+        H: AmqpRpcProcessor<R> + Send + Sync + 'static,
+    {
+        self.register(
+            H::QUEUE,
+            Box::new(move |pool| {
+                let hook = hook.clone();
+                Box::pin(async move {
+                    let channel = H::ensure_queue(&pool).await?;
+                    let handle = setup_rpc_server::<R, H>(&channel, hook, config).await?;
+                    Ok(Some(handle))
+                })
+            }),
+        )
+        .await
+    }
+
+    async fn register(&self, name: &'static str, declare: Declare) -> Result<(), Error> {
+        let mut registrations = self.inner.registrations.lock().await;
+        let handle = declare(self.inner.pool.clone()).await?;
+        registrations.push(Registration { name, declare });
+        if let Some(handle) = handle {
+            self.inner
+                .consumers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(handle);
+        }
+        Ok(())
+    }
+
+    /// Stop supervising, shut the consumers down, close the pool and then the connection.
+    ///
+    /// Each step waits up to `timeout`. Returns whether everything was drained in time.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.inner.closed.store(true, Ordering::Release);
+        let consumers = std::mem::take(
+            &mut *self
+                .inner
+                .consumers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        let mut drained = shutdown_consumers(consumers, timeout).await;
+        drained &= self.inner.pool.close(timeout).await;
+        if let Err(e) = self.inner.pool.manager().connection().close().await {
+            tracing::debug!("Failed to close the connection: {e}");
+        }
+        drained
+    }
+}
+
+/// Shut `consumers` down one after the other and return whether they all drained in time
+async fn shutdown_consumers(consumers: Vec<ConsumerHandle>, timeout: Duration) -> bool {
+    let mut drained = true;
+    for consumer in consumers {
+        match consumer.shutdown(timeout).await {
+            Ok(consumer_drained) => drained &= consumer_drained,
+            Err(e) => {
+                tracing::warn!("Failed to shut a consumer down: {e}");
+                drained = false;
+            }
+        }
+    }
+    drained
+}
+
+/// Reconnect whenever the connection is lost, until the supervisor is shut down or dropped
+async fn supervise(inner: Weak<SupervisorInner>) {
+    loop {
+        let connection = {
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+            if inner.closed.load(Ordering::Acquire) {
+                return;
+            }
+            inner.pool.manager().connection()
+        };
+        if connection.is_open() {
+            tokio::select! {
+                _ = connection.listen_network_io_failure() => {}
+                _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+            }
+        }
+        if connection.is_open() {
+            continue;
+        }
+        drop(connection);
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        if inner.closed.load(Ordering::Acquire) {
+            return;
+        }
+        tracing::warn!("Connection to the broker lost, reconnecting");
+        inner.reconnect().await;
+    }
+}
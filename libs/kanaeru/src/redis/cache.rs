@@ -0,0 +1,301 @@
+//! Cache-aside reads of [`KeyValue`](super::KeyValue)s.
+//!
+//! [`CachedKeyValue::get_or_load`] reads a value from redis and, on a miss, loads it with the
+//! given loader, usually from Postgres, and writes it back. TTLs are lengthened by a random
+//! jitter so entries cached together do not expire together. Values the loader does not find
+//! are cached as a [`NEGATIVE_ENTRY`], so repeated reads of a missing key do not reach the
+//! database either.
+//!
+//! Concurrent misses of the same key of the same entity in a process are collapsed into a single
+//! load whose result is handed to every caller, as long as they read the same database of the
+//! same redis server. With [`CachedKeyValue::LOAD_LOCK_TTL`] set, a short redis lock
+//! collapses misses across processes as well: a process that does not get the lock waits for
+//! the value to be written by the one holding it, and only loads the value itself once the lock
+//! is gone or it waited for as long as the lock lasts.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::Duration;
+
+use kanau::message::{MessageDe, MessageSer};
+use rand::Rng;
+use redis::AsyncCommands;
+use redis::aio::ConnectionLike;
+
+use super::lock::RELEASE;
+use super::{
+    KeyValueRead, KeyValueWrite, RedisConnection, RedisKey, decode_value, encode_value, millis,
+};
+use crate::error::Error;
+
+/// Value cached for a key the loader did not find, see
+/// [magic prefixes](crate::envelope#magic-prefixes)
+pub const NEGATIVE_ENTRY: [u8; 4] = [0xff, b'K', b'N', b'0'];
+
+/// Suffix of the key of the redis lock taken while loading a value
+pub const LOAD_LOCK_SUFFIX: &[u8] = b":load-lock";
+
+/// How often a process waiting on another one's load checks for the value
+const LOAD_LOCK_POLL_INTERVAL: Duration = Duration::from_millis(25);
+
+/// Result of a load, an `Option<Value>` of the loaded type
+type Loaded = Arc<dyn Any + Send + Sync>;
+
+/// Load of a key in progress in this process. Callers missing the key queue on `result`, the
+/// first one loads the value and leaves it there for the others.
+#[derive(Default)]
+struct Flight {
+    result: tokio::sync::Mutex<Option<Loaded>>,
+}
+
+/// What a [`Flight`] loads: a key of an entity, in a database of a redis server
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct FlightKey {
+    run_id: String,
+    db: i64,
+    entity: TypeId,
+    key: RedisKey,
+}
+
+impl FlightKey {
+    /// Key of the flight loading `key` of `T` through `conn`. The server is told apart by the
+    /// `run_id` it reports, which is unique to each run of a redis server.
+    async fn new<T: 'static>(conn: &mut RedisConnection, key: &RedisKey) -> Result<Self, Error> {
+        let info: redis::InfoDict = redis::cmd("INFO").arg("server").query_async(conn).await?;
+        Ok(Self {
+            run_id: info.get("run_id").unwrap_or_default(),
+            db: conn.get_db(),
+            entity: TypeId::of::<T>(),
+            key: key.clone(),
+        })
+    }
+}
+
+static FLIGHTS: LazyLock<Mutex<HashMap<FlightKey, Arc<Flight>>>> = LazyLock::new(Default::default);
+
+/// Membership of a caller in a [`Flight`]. The flight is forgotten as soon as one of its callers
+/// is done, since the value is in redis by then, and later misses start a new one.
+struct Boarding {
+    key: FlightKey,
+    flight: Arc<Flight>,
+}
+
+impl Boarding {
+    fn board(key: FlightKey) -> Self {
+        let flight = FLIGHTS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        Self { key, flight }
+    }
+}
+
+impl Drop for Boarding {
+    fn drop(&mut self) {
+        let mut flights = FLIGHTS.lock().unwrap_or_else(PoisonError::into_inner);
+        if flights
+            .get(&self.key)
+            .is_some_and(|flight| Arc::ptr_eq(flight, &self.flight))
+        {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+/// `ttl` lengthened by a random fraction of itself of up to `jitter`, which is capped at 1
+pub fn jittered(ttl: Duration, jitter: f64) -> Duration {
+    if jitter.is_nan() || jitter <= 0.0 {
+        return ttl;
+    }
+    ttl + ttl.mul_f64(rand::rng().random_range(0.0..=jitter.min(1.0)))
+}
+
+fn load_lock_key(key: &RedisKey) -> RedisKey {
+    let mut lock_key = Vec::with_capacity(key.0.len() + LOAD_LOCK_SUFFIX.len());
+    lock_key.extend_from_slice(&key.0);
+    lock_key.extend_from_slice(LOAD_LOCK_SUFFIX);
+    lock_key.into()
+}
+
+/// Cache-aside reads for [`KeyValue`](super::KeyValue)s whose source of truth is elsewhere.
+///
+/// Implement it with `impl CachedKeyValue for Entity {}`, overriding the constants to tune it.
+pub trait CachedKeyValue: KeyValueRead + KeyValueWrite
+where
+    Self::Key: Send,
+    Self::Value: MessageSer + MessageDe + Clone + Send + 'static,
+{
+    /// TTL of cached values, before jitter
+    const CACHE_TTL: Duration = Duration::from_secs(300);
+
+    /// TTL of cached misses, before jitter. Misses are not cached if `None`.
+    const NEGATIVE_TTL: Option<Duration> = Some(Duration::from_secs(30));
+
+    /// Fraction of the TTL that is randomly added to it
+    const TTL_JITTER: f64 = 0.1;
+
+    /// TTL of the redis lock taken while loading a value, so that only one process loads it.
+    /// Only misses in the same process are collapsed if `None`.
+    ///
+    /// It should be longer than the loader usually takes, since waiting processes give up on the
+    /// value once it expires.
+    const LOAD_LOCK_TTL: Option<Duration> = None;
+
+    /// Read the value of `key`, loading it with `load` and caching it on a miss.
+    ///
+    /// Errors of the loader are returned as is and not cached. Failing to cache a loaded value is
+    /// only logged, since the value is loaded by then.
+    fn get_or_load<F, Fut>(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        load: F,
+    ) -> impl Future<Output = Result<Option<Self::Value>, Error>> + Send
+    where
+        Self: 'static,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<Option<Self::Value>, Error>> + Send,
+    {
+        async move {
+            let key: RedisKey = key.into();
+            let cached: Option<Vec<u8>> = conn.get(&key).await?;
+            if let Some(bytes) = cached {
+                return decode_value::<Self>(&bytes);
+            }
+
+            let boarding = Boarding::board(FlightKey::new::<Self>(conn, &key).await?);
+            let mut result = boarding.flight.result.lock().await;
+            if let Some(value) = result
+                .as_ref()
+                .and_then(|loaded| loaded.downcast_ref::<Option<Self::Value>>())
+            {
+                return Ok(value.clone());
+            }
+            // A previous flight may have cached the value between the read and boarding, or
+            // failed to load it
+            let cached: Option<Vec<u8>> = conn.get(&key).await?;
+            if let Some(bytes) = cached {
+                return decode_value::<Self>(&bytes);
+            }
+
+            let value = load_locked::<Self, F, Fut>(conn, &key, load).await?;
+            *result = Some(Arc::new(value.clone()));
+            Ok(value)
+        }
+    }
+
+    /// Cache `value` for `key`, or a miss if it is `None`
+    fn cache(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        value: Option<Self::Value>,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            store::<Self>(conn, &key, value).await
+        }
+    }
+}
+
+async fn store<T>(
+    conn: &mut RedisConnection,
+    key: &RedisKey,
+    value: Option<T::Value>,
+) -> Result<(), Error>
+where
+    T: CachedKeyValue,
+    T::Key: Send,
+    T::Value: MessageSer + MessageDe + Clone + Send + 'static,
+{
+    let (bytes, ttl) = match value {
+        Some(value) => (encode_value::<T>(value)?, T::CACHE_TTL),
+        None => match T::NEGATIVE_TTL {
+            Some(ttl) => (Box::from(NEGATIVE_ENTRY.as_slice()), ttl),
+            None => return Ok(()),
+        },
+    };
+    let ttl = jittered(ttl, T::TTL_JITTER);
+    let _: () = conn.pset_ex(key, bytes.as_ref(), millis(ttl)).await?;
+    Ok(())
+}
+
+/// Load and cache the value of `key`, under the redis lock if [`CachedKeyValue::LOAD_LOCK_TTL`]
+/// is set
+async fn load_locked<T, F, Fut>(
+    conn: &mut RedisConnection,
+    key: &RedisKey,
+    load: F,
+) -> Result<Option<T::Value>, Error>
+where
+    T: CachedKeyValue,
+    T::Key: Send,
+    T::Value: MessageSer + MessageDe + Clone + Send + 'static,
+    F: FnOnce() -> Fut + Send,
+    Fut: Future<Output = Result<Option<T::Value>, Error>> + Send,
+{
+    let mut token = None;
+    if let Some(lock_ttl) = T::LOAD_LOCK_TTL {
+        let lock_key = load_lock_key(key);
+        let new_token = uuid::Uuid::new_v4().to_string();
+        let acquired: Option<String> = redis::cmd("SET")
+            .arg(&lock_key)
+            .arg(&new_token)
+            .arg("NX")
+            .arg("PX")
+            .arg(millis(lock_ttl))
+            .query_async(conn)
+            .await?;
+        if acquired.is_some() {
+            token = Some((lock_key, new_token));
+        } else if let Some(value) = wait_for_load::<T>(conn, key, &lock_key, lock_ttl).await? {
+            return Ok(value);
+        }
+    }
+
+    let value = load().await;
+    if let Ok(value) = &value
+        && let Err(error) = store::<T>(conn, key, value.clone()).await
+    {
+        tracing::warn!(%error, "Failed to cache a loaded value");
+    }
+    if let Some((lock_key, token)) = token {
+        let released: Result<i64, _> = RELEASE.key(&lock_key).arg(&token).invoke_async(conn).await;
+        if let Err(error) = released {
+            tracing::warn!(%error, "Failed to release a load lock, it expires on its own");
+        }
+    }
+    value
+}
+
+/// Wait for the process holding the load lock of `key` to cache its value. `None` if the lock is
+/// gone without a value being cached, or still there after `lock_ttl`, e.g. because it was taken
+/// again by another process.
+async fn wait_for_load<T>(
+    conn: &mut RedisConnection,
+    key: &RedisKey,
+    lock_key: &RedisKey,
+    lock_ttl: Duration,
+) -> Result<Option<Option<T::Value>>, Error>
+where
+    T: CachedKeyValue,
+    T::Key: Send,
+    T::Value: MessageSer + MessageDe + Clone + Send + 'static,
+{
+    let deadline = tokio::time::Instant::now() + lock_ttl;
+    loop {
+        if tokio::time::Instant::now() >= deadline {
+            return Ok(None);
+        }
+        tokio::time::sleep(LOAD_LOCK_POLL_INTERVAL).await;
+        let cached: Option<Vec<u8>> = conn.get(key).await?;
+        if let Some(bytes) = cached {
+            return decode_value::<T>(&bytes).map(Some);
+        }
+        let locked: bool = conn.exists(lock_key).await?;
+        if !locked {
+            return Ok(None);
+        }
+    }
+}
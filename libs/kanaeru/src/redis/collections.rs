@@ -0,0 +1,521 @@
+//! Typed redis collections.
+//!
+//! Where a [`KeyValue`](super::KeyValue) stores its value as one blob, so changing part of it is
+//! a read-modify-write, the collections here map to the redis SET, HASH, ZSET and LIST types and
+//! change single members with a single atomic command.
+//!
+//! Collections follow the [`KeyValue`](super::KeyValue) conventions: an entity type implements
+//! the trait of its collection, its key converts into a [`RedisKey`], and its members are
+//! serialized with [`MessageSer`] and [`MessageDe`]. Members are written as serialized, without
+//! an envelope or version prefix, since redis compares them by their bytes and an encrypted
+//! member would never compare equal.
+
+use kanau::message::{MessageDe, MessageSer};
+use redis::AsyncCommands;
+
+use super::{RedisConnection, RedisKey};
+use crate::error::Error;
+
+fn encode<T: MessageSer>(member: T) -> Result<Box<[u8]>, Error> {
+    member
+        .to_bytes()
+        .map_err(|e| Error::SerializeError(e.into()))
+}
+
+fn decode<T: MessageDe>(bytes: &[u8]) -> Result<T, Error> {
+    T::from_bytes(bytes).map_err(|e| Error::DeserializeError(e.into()))
+}
+
+fn decode_all<T: MessageDe>(members: Vec<Vec<u8>>) -> Result<Vec<T>, Error> {
+    members.iter().map(|bytes| decode(bytes)).collect()
+}
+
+/// Key of a redis collection, shared by every collection type.
+pub trait RedisCollection: Send + Sync {
+    /// Key type.
+    type Key: Into<RedisKey> + Send + Sync + Sized;
+
+    /// Delete the whole collection.
+    fn delete(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let _: () = conn.del(key).await?;
+            Ok(())
+        }
+    }
+
+    /// Expire the whole collection after `ttl`. Returns false if it does not exist.
+    fn expire(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        ttl: std::time::Duration,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            let millis = i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX);
+            Ok(conn.pexpire(key, millis).await?)
+        }
+    }
+}
+
+/// Unordered collection of unique members, stored as a redis SET.
+pub trait RedisSet: RedisCollection {
+    /// Member type.
+    type Member: MessageSer + MessageDe + Send + Sync + Sized;
+
+    /// Add a member. Returns false if it was a member already.
+    fn add(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        member: Self::Member,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let member = encode(member)?;
+            let added: usize = conn.sadd(key, member.as_ref()).await?;
+            Ok(added > 0)
+        }
+    }
+
+    /// Add members. Returns how many were not members already.
+    fn add_all(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        members: Vec<Self::Member>,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        async {
+            if members.is_empty() {
+                return Ok(0);
+            }
+            let key: RedisKey = key.into();
+            let mut cmd = redis::cmd("SADD");
+            cmd.arg(key);
+            for member in members {
+                cmd.arg(encode(member)?.as_ref());
+            }
+            Ok(cmd.query_async(conn).await?)
+        }
+    }
+
+    /// Remove a member. Returns false if it was not a member.
+    fn remove(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        member: Self::Member,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let member = encode(member)?;
+            let removed: usize = conn.srem(key, member.as_ref()).await?;
+            Ok(removed > 0)
+        }
+    }
+
+    /// Whether `member` is a member.
+    fn contains(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        member: Self::Member,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let member = encode(member)?;
+            Ok(conn.sismember(key, member.as_ref()).await?)
+        }
+    }
+
+    /// All members, in no particular order.
+    fn members(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+    ) -> impl Future<Output = Result<Vec<Self::Member>, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let members: Vec<Vec<u8>> = conn.smembers(key).await?;
+            decode_all(members)
+        }
+    }
+
+    /// Number of members.
+    fn len(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            Ok(conn.scard(key).await?)
+        }
+    }
+}
+
+/// Map of fields to values, stored as a redis HASH.
+pub trait RedisHash: RedisCollection {
+    /// Field type.
+    type Field: MessageSer + MessageDe + Send + Sync + Sized;
+    /// Value type.
+    type Value: MessageSer + MessageDe + Send + Sync + Sized;
+
+    /// Set the value of a field. Returns false if the field existed and was overwritten.
+    fn set(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        field: Self::Field,
+        value: Self::Value,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let field = encode(field)?;
+            let value = encode(value)?;
+            let added: usize = conn.hset(key, field.as_ref(), value.as_ref()).await?;
+            Ok(added > 0)
+        }
+    }
+
+    /// Set the value of a field unless it exists. Returns false if it existed.
+    fn set_if_absent(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        field: Self::Field,
+        value: Self::Value,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let field = encode(field)?;
+            let value = encode(value)?;
+            Ok(conn.hset_nx(key, field.as_ref(), value.as_ref()).await?)
+        }
+    }
+
+    /// Value of a field.
+    fn get(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        field: Self::Field,
+    ) -> impl Future<Output = Result<Option<Self::Value>, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let field = encode(field)?;
+            let value: Option<Vec<u8>> = conn.hget(key, field.as_ref()).await?;
+            value.map(|bytes| decode(&bytes)).transpose()
+        }
+    }
+
+    /// Remove a field. Returns false if it did not exist.
+    fn remove(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        field: Self::Field,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let field = encode(field)?;
+            let removed: usize = conn.hdel(key, field.as_ref()).await?;
+            Ok(removed > 0)
+        }
+    }
+
+    /// Whether a field exists.
+    fn contains(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        field: Self::Field,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let field = encode(field)?;
+            Ok(conn.hexists(key, field.as_ref()).await?)
+        }
+    }
+
+    /// All fields and their values, in no particular order.
+    #[allow(clippy::type_complexity)]
+    fn entries(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+    ) -> impl Future<Output = Result<Vec<(Self::Field, Self::Value)>, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let entries: Vec<(Vec<u8>, Vec<u8>)> = conn.hgetall(key).await?;
+            entries
+                .iter()
+                .map(|(field, value)| Ok((decode(field)?, decode(value)?)))
+                .collect()
+        }
+    }
+
+    /// Number of fields.
+    fn len(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            Ok(conn.hlen(key).await?)
+        }
+    }
+}
+
+/// Collection of unique members ordered by score, stored as a redis ZSET.
+///
+/// Members with the same score are ordered by their serialized bytes.
+pub trait RedisSortedSet: RedisCollection {
+    /// Member type.
+    type Member: MessageSer + MessageDe + Send + Sync + Sized;
+
+    /// Add a member, or update its score. Returns false if it was a member already.
+    fn add(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        member: Self::Member,
+        score: f64,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            let member = encode(member)?;
+            let added: usize = conn.zadd(key, member.as_ref(), score).await?;
+            Ok(added > 0)
+        }
+    }
+
+    /// Add `by` to the score of a member, adding it with a score of `by` if it is not one.
+    /// Returns the new score.
+    fn increment(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        member: Self::Member,
+        by: f64,
+    ) -> impl Future<Output = Result<f64, Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            let member = encode(member)?;
+            Ok(conn.zincr(key, member.as_ref(), by).await?)
+        }
+    }
+
+    /// Remove a member. Returns false if it was not a member.
+    fn remove(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        member: Self::Member,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let member = encode(member)?;
+            let removed: usize = conn.zrem(key, member.as_ref()).await?;
+            Ok(removed > 0)
+        }
+    }
+
+    /// Score of a member, `None` if it is not one.
+    fn score(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        member: Self::Member,
+    ) -> impl Future<Output = Result<Option<f64>, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let member = encode(member)?;
+            Ok(conn.zscore(key, member.as_ref()).await?)
+        }
+    }
+
+    /// Whether `member` is a member.
+    fn contains(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        member: Self::Member,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async { Ok(Self::score(conn, key, member).await?.is_some()) }
+    }
+
+    /// Members and scores by rank, lowest score first, from `start` to `stop` inclusive.
+    /// Negative ranks count from the highest score, `-1` being the last member.
+    fn range(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        start: isize,
+        stop: isize,
+    ) -> impl Future<Output = Result<Vec<(Self::Member, f64)>, Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            let members: Vec<(Vec<u8>, f64)> = conn.zrange_withscores(key, start, stop).await?;
+            members
+                .iter()
+                .map(|(member, score)| Ok((decode(member)?, *score)))
+                .collect()
+        }
+    }
+
+    /// Members and scores with a score between `min` and `max` inclusive, lowest score first.
+    fn range_by_score(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        min: f64,
+        max: f64,
+    ) -> impl Future<Output = Result<Vec<(Self::Member, f64)>, Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            let members: Vec<(Vec<u8>, f64)> = conn.zrangebyscore_withscores(key, min, max).await?;
+            members
+                .iter()
+                .map(|(member, score)| Ok((decode(member)?, *score)))
+                .collect()
+        }
+    }
+
+    /// Remove the members with a score between `min` and `max` inclusive. Returns how many were
+    /// removed.
+    fn remove_by_score(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        min: f64,
+        max: f64,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            Ok(conn.zrembyscore(key, min, max).await?)
+        }
+    }
+
+    /// Number of members.
+    fn len(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            Ok(conn.zcard(key).await?)
+        }
+    }
+}
+
+/// Ordered sequence of items, stored as a redis LIST.
+pub trait RedisList: RedisCollection {
+    /// Item type.
+    type Item: MessageSer + MessageDe + Send + Sync + Sized;
+
+    /// Append an item. Returns the new length.
+    fn push_back(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        item: Self::Item,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let item = encode(item)?;
+            Ok(conn.rpush(key, item.as_ref()).await?)
+        }
+    }
+
+    /// Prepend an item. Returns the new length.
+    fn push_front(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        item: Self::Item,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let item = encode(item)?;
+            Ok(conn.lpush(key, item.as_ref()).await?)
+        }
+    }
+
+    /// Remove and return the last item.
+    fn pop_back(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+    ) -> impl Future<Output = Result<Option<Self::Item>, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let item: Option<Vec<u8>> = conn.rpop(key, None).await?;
+            item.map(|bytes| decode(&bytes)).transpose()
+        }
+    }
+
+    /// Remove and return the first item.
+    fn pop_front(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+    ) -> impl Future<Output = Result<Option<Self::Item>, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let item: Option<Vec<u8>> = conn.lpop(key, None).await?;
+            item.map(|bytes| decode(&bytes)).transpose()
+        }
+    }
+
+    /// Remove occurrences of `item`: the first `count` if positive, the last `-count` if
+    /// negative, all of them if zero. Returns how many were removed.
+    fn remove(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        item: Self::Item,
+        count: isize,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            let item = encode(item)?;
+            Ok(conn.lrem(key, count, item.as_ref()).await?)
+        }
+    }
+
+    /// Whether `item` is in the list.
+    fn contains(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        item: Self::Item,
+    ) -> impl Future<Output = Result<bool, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let item = encode(item)?;
+            let position: Option<usize> = conn
+                .lpos(key, item.as_ref(), redis::LposOptions::default())
+                .await?;
+            Ok(position.is_some())
+        }
+    }
+
+    /// Items from index `start` to `stop` inclusive. Negative indexes count from the end, `-1`
+    /// being the last item.
+    fn range(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        start: isize,
+        stop: isize,
+    ) -> impl Future<Output = Result<Vec<Self::Item>, Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            let items: Vec<Vec<u8>> = conn.lrange(key, start, stop).await?;
+            decode_all(items)
+        }
+    }
+
+    /// Keep only the items from index `start` to `stop` inclusive, e.g. to cap the length of the
+    /// list.
+    fn trim(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        start: isize,
+        stop: isize,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            let _: () = conn.ltrim(key, start, stop).await?;
+            Ok(())
+        }
+    }
+
+    /// Number of items.
+    fn len(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            Ok(conn.llen(key).await?)
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Distributed locks on redis keys.
+//!
+//! A [`RedisLock`] is taken with `SET NX PX` and a random token, so only its owner can renew or
+//! release it, and it expires on its own if its owner dies. While a [`RedisLockGuard`] is held,
+//! a background task renews its lease, and reports through [`RedisLockGuard::is_held`] and
+//! [`RedisLockGuard::lost`] if it could not, e.g. because redis was unreachable for longer than
+//! the lease.
+//!
+//! Every acquisition also gets a fencing token, a number that grows with each acquisition of the
+//! same lock. A lock can be lost without its owner noticing in time, e.g. during a long pause, so
+//! writes to other systems made under the lock should carry the token and be refused by them if
+//! a higher one was seen already.
+
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use super::cache::jittered;
+use super::{RedisConnection, RedisKey, millis};
+use crate::error::Error;
+
+/// Lease of a lock if not set with [`RedisLock::lease`]
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(30);
+
+/// Suffix of the key counting the acquisitions of a lock, whose value is the fencing token
+pub const FENCE_SUFFIX: &[u8] = b":fence";
+
+/// Takes `KEYS[1]` with token `ARGV[1]` for `ARGV[2]` milliseconds if it is free, and returns the
+/// next fencing token from `KEYS[2]`
+static ACQUIRE: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r#"
+        if redis.call("SET", KEYS[1], ARGV[1], "NX", "PX", ARGV[2]) then
+            return redis.call("INCR", KEYS[2])
+        end
+        return false
+        "#,
+    )
+});
+
+/// Extends `KEYS[1]` to `ARGV[2]` milliseconds only if it still holds token `ARGV[1]`
+static RENEW: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r#"
+        if redis.call("GET", KEYS[1]) == ARGV[1] then
+            return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+        end
+        return 0
+        "#,
+    )
+});
+
+/// Deletes `KEYS[1]` only if it still holds token `ARGV[1]`, so a lock that expired and was taken
+/// by someone else is left alone
+pub(crate) static RELEASE: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r#"
+        if redis.call("GET", KEYS[1]) == ARGV[1] then
+            return redis.call("DEL", KEYS[1])
+        end
+        return 0
+        "#,
+    )
+});
+
+/// Lock on a redis key shared by every process using the same key
+#[derive(Debug, Clone)]
+pub struct RedisLock {
+    key: RedisKey,
+    lease: Duration,
+    retry_interval: Duration,
+}
+
+impl RedisLock {
+    pub fn new(key: impl Into<RedisKey>) -> Self {
+        Self {
+            key: key.into(),
+            lease: DEFAULT_LEASE,
+            retry_interval: Duration::from_millis(100),
+        }
+    }
+
+    /// How long the lock is held after its owner stops renewing it. Renewals happen every third
+    /// of it.
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// How often [`RedisLock::acquire`] tries to take the lock while it is held by someone else,
+    /// before jitter
+    pub fn retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+
+    fn fence_key(&self) -> RedisKey {
+        let mut fence_key = Vec::with_capacity(self.key.0.len() + FENCE_SUFFIX.len());
+        fence_key.extend_from_slice(&self.key.0);
+        fence_key.extend_from_slice(FENCE_SUFFIX);
+        fence_key.into()
+    }
+
+    /// Take the lock if it is free
+    pub async fn try_acquire(
+        &self,
+        conn: &mut RedisConnection,
+    ) -> Result<Option<RedisLockGuard>, Error> {
+        let token = uuid::Uuid::new_v4().to_string();
+        let fence: Option<u64> = ACQUIRE
+            .key(&self.key)
+            .key(self.fence_key())
+            .arg(&token)
+            .arg(millis(self.lease))
+            .invoke_async(conn)
+            .await?;
+        let Some(fence) = fence else {
+            return Ok(None);
+        };
+        let (held_tx, held) = tokio::sync::watch::channel(true);
+        let renewal = tokio::spawn(renew(
+            conn.clone(),
+            self.key.clone(),
+            token.clone(),
+            self.lease,
+            held_tx,
+        ));
+        Ok(Some(RedisLockGuard {
+            key: self.key.clone(),
+            token,
+            fence,
+            conn: conn.clone(),
+            renewal: Some(renewal),
+            held,
+        }))
+    }
+
+    /// Take the lock, waiting up to `wait` for it to be free. `None` if it was not.
+    pub async fn acquire(
+        &self,
+        conn: &mut RedisConnection,
+        wait: Duration,
+    ) -> Result<Option<RedisLockGuard>, Error> {
+        let deadline = Instant::now() + wait;
+        loop {
+            if let Some(guard) = self.try_acquire(conn).await? {
+                return Ok(Some(guard));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            tokio::time::sleep(jittered(self.retry_interval, 0.5).min(remaining)).await;
+        }
+    }
+}
+
+/// Renew the lease of a lock every third of it, until the lock is found taken by someone else
+/// or could not be renewed for a whole lease
+async fn renew(
+    mut conn: RedisConnection,
+    key: RedisKey,
+    token: String,
+    lease: Duration,
+    held: tokio::sync::watch::Sender<bool>,
+) {
+    let mut renewed_at = Instant::now();
+    loop {
+        tokio::time::sleep(lease / 3).await;
+        let renewed: Result<bool, _> = RENEW
+            .key(&key)
+            .arg(&token)
+            .arg(millis(lease))
+            .invoke_async(&mut conn)
+            .await;
+        match renewed {
+            Ok(true) => renewed_at = Instant::now(),
+            Ok(false) => {
+                tracing::warn!("Lock was taken by someone else before its lease was renewed");
+                break;
+            }
+            Err(error) if renewed_at.elapsed() >= lease => {
+                tracing::warn!(%error, "Lock expired before its lease could be renewed");
+                break;
+            }
+            Err(error) => tracing::warn!(%error, "Failed to renew the lease of a lock"),
+        }
+    }
+    held.send_replace(false);
+}
+
+/// Held [`RedisLock`], released when dropped.
+///
+/// Dropping the guard releases the lock in the background. Use [`RedisLockGuard::release`] to
+/// wait for the release.
+pub struct RedisLockGuard {
+    key: RedisKey,
+    token: String,
+    fence: u64,
+    conn: RedisConnection,
+    renewal: Option<tokio::task::JoinHandle<()>>,
+    held: tokio::sync::watch::Receiver<bool>,
+}
+
+impl RedisLockGuard {
+    /// Fencing token of this acquisition, higher than those of every earlier acquisition of the
+    /// lock
+    pub fn fencing_token(&self) -> u64 {
+        self.fence
+    }
+
+    /// Whether the lease is still renewed. Once false, someone else may hold the lock.
+    pub fn is_held(&self) -> bool {
+        *self.held.borrow()
+    }
+
+    /// Wait until the lease could not be renewed, e.g. to abort the critical section with
+    /// `tokio::select!`
+    pub async fn lost(&self) {
+        let mut held = self.held.clone();
+        // The sender is only dropped once it reported the loss
+        let _ = held.wait_for(|held| !held).await;
+    }
+
+    /// Release the lock. Returns false if it was not held anymore.
+    pub async fn release(mut self) -> Result<bool, Error> {
+        if let Some(renewal) = self.renewal.take() {
+            renewal.abort();
+        }
+        let released: i64 = RELEASE
+            .key(&self.key)
+            .arg(&self.token)
+            .invoke_async(&mut self.conn)
+            .await?;
+        Ok(released > 0)
+    }
+}
+
+impl Drop for RedisLockGuard {
+    fn drop(&mut self) {
+        let Some(renewal) = self.renewal.take() else {
+            return;
+        };
+        renewal.abort();
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let key = self.key.clone();
+        let token = std::mem::take(&mut self.token);
+        let mut conn = self.conn.clone();
+        runtime.spawn(async move {
+            let released: Result<i64, _> =
+                RELEASE.key(&key).arg(&token).invoke_async(&mut conn).await;
+            if let Err(error) = released {
+                tracing::warn!(%error, "Failed to release a lock, it expires with its lease");
+            }
+        });
+    }
+}
@@ -0,0 +1,331 @@
+pub mod cache;
+pub mod collections;
+pub mod lock;
+pub mod rate_limit;
+
+use std::sync::LazyLock;
+
+use kanau::message::{DeserializeError, MessageDe, MessageSer};
+use redis::AsyncCommands;
+
+use crate::envelope::{self, EnvelopePolicy};
+use crate::pool::{ConnectionManager, Pool};
+use crate::schema::{self, Payload};
+
+pub use cache::CachedKeyValue;
+pub use collections::{RedisCollection, RedisHash, RedisList, RedisSet, RedisSortedSet};
+pub use lock::{RedisLock, RedisLockGuard};
+pub use rate_limit::{RateLimit, RateLimitKey, RateLimiter, SlidingWindow, TokenBucket};
+
+/// Type alias for redis multiplexed connection.
+pub type RedisConnection = redis::aio::MultiplexedConnection;
+
+/// Opens multiplexed connections on a redis client for [`RedisPool`]
+#[derive(Debug, Clone)]
+pub struct RedisConnectionManager {
+    client: redis::Client,
+}
+
+impl RedisConnectionManager {
+    pub fn new(client: redis::Client) -> Self {
+        Self { client }
+    }
+}
+
+impl ConnectionManager for RedisConnectionManager {
+    type Connection = RedisConnection;
+    type Error = redis::RedisError;
+
+    async fn create(&self) -> Result<RedisConnection, redis::RedisError> {
+        self.client.get_multiplexed_async_connection().await
+    }
+
+    async fn is_valid(&self, conn: &mut RedisConnection) -> Result<(), redis::RedisError> {
+        redis::cmd("PING").query_async(conn).await
+    }
+}
+
+/// Pool of redis connections
+pub type RedisPool = Pool<RedisConnectionManager>;
+
+/// Redis key wrapper used by [`KeyValue`] trait.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RedisKey(pub Box<[u8]>);
+
+impl From<String> for RedisKey {
+    fn from(v: String) -> Self {
+        Self(v.into_bytes().into_boxed_slice())
+    }
+}
+
+impl From<&str> for RedisKey {
+    fn from(v: &str) -> Self {
+        Self(v.as_bytes().to_vec().into_boxed_slice())
+    }
+}
+
+impl From<Vec<u8>> for RedisKey {
+    fn from(v: Vec<u8>) -> Self {
+        Self(v.into_boxed_slice())
+    }
+}
+
+impl From<&[u8]> for RedisKey {
+    fn from(v: &[u8]) -> Self {
+        Self(v.to_vec().into_boxed_slice())
+    }
+}
+
+impl From<[u8; 16]> for RedisKey {
+    fn from(v: [u8; 16]) -> Self {
+        Self(v.to_vec().into_boxed_slice())
+    }
+}
+
+impl From<uuid::Uuid> for RedisKey {
+    fn from(v: uuid::Uuid) -> Self {
+        Self(v.as_bytes().to_vec().into_boxed_slice())
+    }
+}
+
+impl redis::ToRedisArgs for RedisKey {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + redis::RedisWrite,
+    {
+        self.0.as_ref().write_redis_args(out)
+    }
+}
+
+/// Abstraction for key-value pairs stored in redis.
+#[allow(unused)]
+pub trait KeyValue: Sized + Send + Sync {
+    /// Key type.
+    type Key: Into<RedisKey> + Send + Sync + Sized;
+    /// Value type.
+    type Value: Send + Sync + Sized;
+
+    /// Content type the value is serialized as.
+    const CONTENT_TYPE: &'static str = schema::DEFAULT_CONTENT_TYPE;
+
+    /// How the serialized value is enveloped. Raw by default.
+    const ENVELOPE: EnvelopePolicy = EnvelopePolicy::RAW;
+
+    /// Version of the schema the value is serialized with
+    const SCHEMA_VERSION: u32 = schema::INITIAL_VERSION;
+
+    /// Decode a value written with another schema version or content type.
+    ///
+    /// Supports none by default. Override it to keep reading values written before the schema
+    /// changed, see [`schema`].
+    fn upcast(payload: Payload<'_>) -> Result<Self::Value, DeserializeError> {
+        schema::no_upcast(payload)
+    }
+
+    /// Get key from the pair.
+    fn key(&self) -> Self::Key;
+    /// Get value by cloning.
+    fn value(&self) -> Self::Value;
+    /// Consume pair and return value.
+    fn into_value(self) -> Self::Value;
+    /// Create new pair from key and value.
+    fn new(key: Self::Key, value: Self::Value) -> Self;
+
+    /// Delete value by key.
+    fn delete(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+    ) -> impl Future<Output = Result<(), crate::error::Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let _: () = conn.del(key).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Helper trait for reading value from redis.
+pub trait KeyValueRead: KeyValue
+where
+    Self::Value: MessageDe,
+{
+    /// Read value by key.
+    fn read(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+    ) -> impl Future<Output = Result<Option<Self::Value>, crate::error::Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let data: Option<Vec<u8>> = conn.get(key).await?;
+            match data {
+                Some(bytes) => decode_value::<Self>(&bytes),
+                None => Ok(None),
+            }
+        }
+    }
+}
+
+#[allow(unused)]
+/// Helper trait for writing value to redis.
+pub trait KeyValueWrite: KeyValue + Send
+where
+    Self::Value: MessageSer,
+    Self::Key: Send,
+    Self::Value: Send,
+{
+    /// How many times [`KeyValueWrite::update`] tries to apply its change before giving up with
+    /// [`Error::Conflict`](crate::error::Error::Conflict).
+    const UPDATE_ATTEMPTS: usize = 5;
+
+    /// Write current pair into redis.
+    fn write(
+        &self,
+        conn: &mut RedisConnection,
+    ) -> impl Future<Output = Result<(), crate::error::Error>> + Send {
+        async { Self::write_kv(conn, self.key(), self.value()).await }
+    }
+
+    /// Write provided key and value into redis.
+    fn write_kv(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        value: Self::Value,
+    ) -> impl Future<Output = Result<(), crate::error::Error>> + Send {
+        async {
+            let key: RedisKey = key.into();
+            let bytes = encode_value::<Self>(value)?;
+            let _: () = conn.set(key, bytes.as_ref()).await?;
+            Ok(())
+        }
+    }
+
+    /// Write current pair into redis with TTL.
+    fn write_with_ttl(
+        &self,
+        conn: &mut RedisConnection,
+        ttl: std::time::Duration,
+    ) -> impl Future<Output = Result<(), crate::error::Error>> + Send {
+        async move { Self::write_kv_with_ttl(conn, self.key(), self.value(), ttl).await }
+    }
+
+    /// Write provided key and value into redis with TTL.
+    fn write_kv_with_ttl(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        value: Self::Value,
+        ttl: std::time::Duration,
+    ) -> impl Future<Output = Result<(), crate::error::Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            let bytes = encode_value::<Self>(value)?;
+            let _: () = conn.set_ex(key, bytes.as_ref(), ttl.as_secs()).await?;
+            Ok(())
+        }
+    }
+
+    /// Atomically replace the value of `key` with what `change` makes of it, keeping its TTL.
+    /// Returns the new value.
+    ///
+    /// The value is read, changed and written back only if it was not written in between, and
+    /// re-read and changed again otherwise, so `change` may run more than once. Gives up with
+    /// [`Error::Conflict`](crate::error::Error::Conflict) after [`KeyValueWrite::UPDATE_ATTEMPTS`]
+    /// lost races, and fails with [`Error::NotFound`](crate::error::Error::NotFound) if there is
+    /// no value. Errors returned by `change` abort the update.
+    ///
+    /// The check is a compare-and-swap of the stored bytes in a script rather than
+    /// `WATCH`/`MULTI`/`EXEC`, since a watch would be shared by every user of the multiplexed
+    /// connection.
+    fn update<F>(
+        conn: &mut RedisConnection,
+        key: Self::Key,
+        mut change: F,
+    ) -> impl Future<Output = Result<Self::Value, crate::error::Error>> + Send
+    where
+        Self: KeyValueRead,
+        Self::Value: MessageDe + Clone,
+        F: FnMut(Self::Value) -> Result<Self::Value, crate::error::Error> + Send,
+    {
+        async move {
+            let key: RedisKey = key.into();
+            for _ in 0..Self::UPDATE_ATTEMPTS {
+                let current: Option<Vec<u8>> = conn.get(&key).await?;
+                let Some(current) = current else {
+                    return Err(crate::error::Error::NotFound);
+                };
+                let Some(value) = decode_value::<Self>(&current)? else {
+                    return Err(crate::error::Error::NotFound);
+                };
+                let value = change(value)?;
+                let bytes = encode_value::<Self>(value.clone())?;
+                let swapped: bool = COMPARE_AND_SWAP
+                    .key(&key)
+                    .arg(current.as_slice())
+                    .arg(bytes.as_ref())
+                    .invoke_async(conn)
+                    .await?;
+                if swapped {
+                    return Ok(value);
+                }
+            }
+            Err(crate::error::Error::Conflict(Self::UPDATE_ATTEMPTS))
+        }
+    }
+}
+
+/// Sets `KEYS[1]` to `ARGV[2]`, keeping its TTL, only if it still holds `ARGV[1]`
+static COMPARE_AND_SWAP: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r#"
+        if redis.call("GET", KEYS[1]) ~= ARGV[1] then
+            return 0
+        end
+        redis.call("SET", KEYS[1], ARGV[2], "KEEPTTL")
+        return 1
+        "#,
+    )
+});
+
+/// Decode a value read from redis. A [negative cache entry](cache::NEGATIVE_ENTRY) is read as no
+/// value.
+pub(crate) fn decode_value<T: KeyValue>(
+    bytes: &[u8],
+) -> Result<Option<T::Value>, crate::error::Error>
+where
+    T::Value: MessageDe,
+{
+    if bytes == cache::NEGATIVE_ENTRY {
+        return Ok(None);
+    }
+    // Values of a raw type are never looked into, see the envelope docs
+    let opened = if T::ENVELOPE.envelope {
+        envelope::open_or_raw(bytes)?
+    } else {
+        envelope::Opened::raw(bytes)
+    };
+    let (version, bytes) = schema::strip_version(&opened.content)?;
+    let payload = Payload {
+        version,
+        content_type: opened.content_type.as_deref(),
+        bytes,
+    };
+    let val = schema::decode(payload, T::SCHEMA_VERSION, T::CONTENT_TYPE, T::upcast)?;
+    Ok(Some(val))
+}
+
+/// Encode a value to be written to redis
+pub(crate) fn encode_value<T: KeyValue>(value: T::Value) -> Result<Box<[u8]>, crate::error::Error>
+where
+    T::Value: MessageSer,
+{
+    let bytes =
+        MessageSer::to_bytes(value).map_err(|e| crate::error::Error::SerializeError(e.into()))?;
+    let bytes = schema::prefix_version(bytes, T::SCHEMA_VERSION);
+    Ok(envelope::seal(bytes, T::CONTENT_TYPE, &T::ENVELOPE)?)
+}
+
+/// `duration` in whole milliseconds, at least 1 since redis refuses a TTL of 0
+pub(crate) fn millis(duration: std::time::Duration) -> u64 {
+    u64::try_from(duration.as_millis())
+        .unwrap_or(u64::MAX)
+        .max(1)
+}
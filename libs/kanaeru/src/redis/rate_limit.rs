@@ -0,0 +1,300 @@
+//! Rate limiters backed by redis.
+//!
+//! Both limiters check and update their state in a single script, so concurrent requests in any
+//! number of processes never overshoot the limit, and they use the clock of the redis server, so
+//! processes with skewed clocks agree on it.
+//!
+//! - [`SlidingWindow`] allows `limit` hits in any window of its length. It keeps the time of
+//!   every hit in the window in a ZSET, so it is exact but takes memory in proportion to the
+//!   limit. Suited to low limits such as login attempts or OTP requests.
+//! - [`TokenBucket`] allows bursts of up to `capacity` hits, refilled by one token every
+//!   interval. It keeps two numbers per key whatever the limit. Suited to request rates.
+//!
+//! Limits are kept per [`RateLimitKey`], made up of the name of the limit and whatever it is
+//! applied per, e.g. the IP address, the account or the route.
+//!
+//! Hits costing more than a limit ever allows, e.g. any hit on a limit of 0, are denied without
+//! a `retry_after` and without being counted.
+
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use redis::AsyncCommands;
+
+use super::{RedisConnection, RedisKey, millis};
+use crate::error::Error;
+
+/// Prefix of the keys of rate limits
+pub const RATE_LIMIT_KEY_PREFIX: &str = "rate_limit";
+
+/// Drops the hits of `KEYS[1]` older than `ARGV[1]` milliseconds, and records `ARGV[3]` hits
+/// named after `ARGV[4]` if that keeps it within `ARGV[2]` hits. Returns whether they were
+/// recorded, the hits left and the milliseconds until enough hits leave the window.
+static SLIDING_WINDOW: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r#"
+        local window = tonumber(ARGV[1])
+        local limit = tonumber(ARGV[2])
+        local cost = tonumber(ARGV[3])
+        local time = redis.call("TIME")
+        local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+        redis.call("ZREMRANGEBYSCORE", KEYS[1], "-inf", now - window)
+        local count = redis.call("ZCARD", KEYS[1])
+        if count + cost <= limit then
+            for i = 1, cost do
+                redis.call("ZADD", KEYS[1], now, ARGV[4] .. ":" .. i)
+            end
+            redis.call("PEXPIRE", KEYS[1], window)
+            return {1, limit - count - cost, 0}
+        end
+        -- The hit that has to leave the window for `cost` more to fit
+        local blocking = count + cost - limit - 1
+        local oldest = redis.call("ZRANGE", KEYS[1], blocking, blocking, "WITHSCORES")
+        return {0, limit - count, tonumber(oldest[2]) + window - now}
+        "#,
+    )
+});
+
+/// Refills the bucket of `KEYS[1]` with a token every `ARGV[2]` milliseconds up to `ARGV[1]`
+/// tokens, and takes `ARGV[3]` tokens if there are enough. Returns whether they were taken, the
+/// tokens left and the milliseconds until there are enough.
+static TOKEN_BUCKET: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r#"
+        local capacity = tonumber(ARGV[1])
+        local interval = tonumber(ARGV[2])
+        local cost = tonumber(ARGV[3])
+        local time = redis.call("TIME")
+        local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+        local state = redis.call("HMGET", KEYS[1], "tokens", "at")
+        local tokens = tonumber(state[1]) or capacity
+        local at = tonumber(state[2]) or now
+        tokens = math.min(capacity, tokens + math.max(0, now - at) / interval)
+
+        local allowed = 0
+        local retry_after = 0
+        if tokens >= cost then
+            tokens = tokens - cost
+            allowed = 1
+        else
+            retry_after = math.ceil((cost - tokens) * interval)
+        end
+        redis.call("HSET", KEYS[1], "tokens", tostring(tokens), "at", now)
+        -- A bucket left alone this long is full, which is the same as no bucket
+        redis.call("PEXPIRE", KEYS[1], math.ceil((capacity - tokens) * interval) + 1)
+        return {allowed, math.floor(tokens), retry_after}
+        "#,
+    )
+});
+
+/// Key of a rate limit: its name and what it is applied per, such as
+/// `RateLimitKey::new("login").ip(ip).account(email)`.
+///
+/// `%`, `:` and `=` are percent-encoded in every component, so values containing the separators,
+/// such as IPv6 addresses, cannot make two keys collide.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RateLimitKey(String);
+
+impl RateLimitKey {
+    pub fn new(limit: &str) -> Self {
+        let mut key = format!("{RATE_LIMIT_KEY_PREFIX}:");
+        escape_into(&mut key, limit);
+        Self(key)
+    }
+
+    /// Apply the limit per `value` of `dimension`, in addition to the previous ones
+    pub fn per(mut self, dimension: &str, value: impl Display) -> Self {
+        self.0.push(':');
+        escape_into(&mut self.0, dimension);
+        self.0.push('=');
+        escape_into(&mut self.0, &value.to_string());
+        self
+    }
+
+    /// Apply the limit per IP address
+    pub fn ip(self, ip: IpAddr) -> Self {
+        self.per("ip", ip)
+    }
+
+    /// Apply the limit per account
+    pub fn account(self, account: impl Display) -> Self {
+        self.per("account", account)
+    }
+
+    /// Apply the limit per route or RPC method
+    pub fn route(self, route: &str) -> Self {
+        self.per("route", route)
+    }
+}
+
+fn escape_into(key: &mut String, component: &str) {
+    for c in component.chars() {
+        match c {
+            '%' => key.push_str("%25"),
+            ':' => key.push_str("%3A"),
+            '=' => key.push_str("%3D"),
+            c => key.push(c),
+        }
+    }
+}
+
+impl From<RateLimitKey> for RedisKey {
+    fn from(value: RateLimitKey) -> Self {
+        Self::from(value.0)
+    }
+}
+
+/// Outcome of a hit on a rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Whether the hit is within the limit. Denied hits are not counted.
+    pub allowed: bool,
+    /// Hits left within the limit
+    pub remaining: u32,
+    /// How long until the hit would be allowed, zero if it was and `None` if it never will be
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimit {
+    /// The limit, or [`Error::RateLimited`] if the hit was denied
+    pub fn into_result(self) -> Result<Self, Error> {
+        if self.allowed {
+            Ok(self)
+        } else {
+            Err(Error::RateLimited(self.retry_after))
+        }
+    }
+
+    /// Denial of hits costing more than the limit ever allows, with the hits left as they are
+    fn never(self) -> Self {
+        Self {
+            allowed: false,
+            retry_after: None,
+            ..self
+        }
+    }
+
+    fn from_script((allowed, remaining, retry_after): (i64, i64, i64)) -> Self {
+        Self {
+            allowed: allowed == 1,
+            remaining: u32::try_from(remaining.max(0)).unwrap_or(u32::MAX),
+            retry_after: Some(Duration::from_millis(
+                u64::try_from(retry_after.max(0)).unwrap_or(0),
+            )),
+        }
+    }
+}
+
+/// Limit on how often something may happen per [`RateLimitKey`]
+pub trait RateLimiter: Send + Sync {
+    /// Record `cost` hits on the limit of `key` if they are within it
+    fn hit(
+        &self,
+        conn: &mut RedisConnection,
+        key: RateLimitKey,
+        cost: u32,
+    ) -> impl Future<Output = Result<RateLimit, Error>> + Send;
+
+    /// Record a hit on the limit of `key`, failing with [`Error::RateLimited`] if it is not
+    /// within it
+    fn check(
+        &self,
+        conn: &mut RedisConnection,
+        key: RateLimitKey,
+    ) -> impl Future<Output = Result<RateLimit, Error>> + Send {
+        async move { self.hit(conn, key, 1).await?.into_result() }
+    }
+
+    /// Forget the hits on the limit of `key`, e.g. after a successful login
+    fn reset(
+        &self,
+        conn: &mut RedisConnection,
+        key: RateLimitKey,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async move {
+            let key: RedisKey = key.into();
+            let _: () = conn.del(key).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Allows `limit` hits in any window of `window`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlidingWindow {
+    limit: u32,
+    window: Duration,
+}
+
+impl SlidingWindow {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self { limit, window }
+    }
+}
+
+impl RateLimiter for SlidingWindow {
+    async fn hit(
+        &self,
+        conn: &mut RedisConnection,
+        key: RateLimitKey,
+        cost: u32,
+    ) -> Result<RateLimit, Error> {
+        // Hits that can never fit are not tried, only the hits left are read
+        let never = cost > self.limit;
+        let key: RedisKey = key.into();
+        // Names the hits, which have to be unique within the ZSET
+        let hit_id = uuid::Uuid::new_v4().to_string();
+        let result = SLIDING_WINDOW
+            .key(key)
+            .arg(millis(self.window))
+            .arg(self.limit)
+            .arg(if never { 0 } else { cost })
+            .arg(hit_id)
+            .invoke_async(conn)
+            .await?;
+        let limit = RateLimit::from_script(result);
+        Ok(if never { limit.never() } else { limit })
+    }
+}
+
+/// Allows bursts of up to `capacity` hits, refilled by one every `refill_interval`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenBucket {
+    capacity: u32,
+    refill_interval: Duration,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill_interval: Duration) -> Self {
+        Self {
+            capacity,
+            refill_interval,
+        }
+    }
+}
+
+impl RateLimiter for TokenBucket {
+    async fn hit(
+        &self,
+        conn: &mut RedisConnection,
+        key: RateLimitKey,
+        cost: u32,
+    ) -> Result<RateLimit, Error> {
+        // Hits that can never fit are not tried, only the tokens left are read
+        let never = cost > self.capacity;
+        let key: RedisKey = key.into();
+        let result = TOKEN_BUCKET
+            .key(key)
+            .arg(self.capacity)
+            .arg(millis(self.refill_interval))
+            .arg(if never { 0 } else { cost })
+            .invoke_async(conn)
+            .await?;
+        let limit = RateLimit::from_script(result);
+        Ok(if never { limit.never() } else { limit })
+    }
+}
@@ -0,0 +1,118 @@
+//! Versioned schemas of messages and Redis values.
+//!
+//! Every message type and Redis value declares the version of the schema it is serialized with.
+//! AMQP messages carry it in the [`SCHEMA_VERSION_HEADER`] header and their content type in the
+//! `content_type` property. Redis values carry it as a [`VERSION_MAGIC`] prefix followed by the
+//! version as a big-endian `u32`, and their content type in the [envelope](crate::envelope) if
+//! they are enveloped.
+//!
+//! Payloads written before versioning have neither, and are taken as [`INITIAL_VERSION`]. Redis
+//! values of the initial version are still written without a prefix, so they stay readable by
+//! code that does not know about versions.
+//!
+//! A payload of the current version and content type is decoded with
+//! [`MessageDe::from_bytes`]. Any other payload is handed to the `upcast` function of its type,
+//! which can decode older versions or other codecs into the current type. A payload of a newer
+//! version than the reader knows is an [`Error::Io`], so it is retried until a newer reader
+//! takes it instead of being dropped as malformed.
+
+use kanau::message::{DeserializeError, MessageDe};
+
+use crate::error::Error;
+
+/// Version of payloads that do not declare one
+pub const INITIAL_VERSION: u32 = 1;
+
+/// Header of an AMQP message carrying the version of its schema
+pub const SCHEMA_VERSION_HEADER: &str = "x-kanaeru-schema-version";
+
+/// First bytes of a versioned Redis value, see
+/// [magic prefixes](crate::envelope#magic-prefixes)
+pub const VERSION_MAGIC: [u8; 4] = [0xff, b'K', b'N', b'V'];
+
+/// Content type of payloads that do not declare one
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Content type of messages serialized with rkyv
+pub const RKYV_CONTENT_TYPE: &str = "application/x-rkyv";
+
+/// Content type of messages serialized with bincode
+pub const BINCODE_CONTENT_TYPE: &str = "application/x-bincode";
+
+/// Content type of messages serialized with serde_json
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Serialized payload along with the schema it was written with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Payload<'a> {
+    pub version: u32,
+    /// Content type the payload was written as, if it is known
+    pub content_type: Option<&'a str>,
+    pub bytes: &'a [u8],
+}
+
+impl Payload<'_> {
+    /// Error for a payload that can not be upcast
+    pub fn unsupported(&self) -> DeserializeError {
+        DeserializeError(anyhow::anyhow!(
+            "Unsupported schema version {} with content type {}",
+            self.version,
+            self.content_type.unwrap_or("unknown")
+        ))
+    }
+}
+
+/// Upcast function that supports no other version or content type
+pub fn no_upcast<T>(payload: Payload<'_>) -> Result<T, DeserializeError> {
+    Err(payload.unsupported())
+}
+
+/// Prefix `bytes` with `version`, unless it is the initial version
+pub fn prefix_version(bytes: Box<[u8]>, version: u32) -> Box<[u8]> {
+    if version == INITIAL_VERSION {
+        return bytes;
+    }
+    let mut prefixed = Vec::with_capacity(VERSION_MAGIC.len() + 4 + bytes.len());
+    prefixed.extend_from_slice(&VERSION_MAGIC);
+    prefixed.extend_from_slice(&version.to_be_bytes());
+    prefixed.extend_from_slice(&bytes);
+    prefixed.into_boxed_slice()
+}
+
+/// Split the version prefix from `bytes`, taking unprefixed bytes as the initial version
+pub fn strip_version(bytes: &[u8]) -> Result<(u32, &[u8]), DeserializeError> {
+    let Some(rest) = bytes.strip_prefix(&VERSION_MAGIC) else {
+        return Ok((INITIAL_VERSION, bytes));
+    };
+    let (version, rest) = rest
+        .split_first_chunk::<4>()
+        .ok_or_else(|| DeserializeError(anyhow::anyhow!("Truncated schema version")))?;
+    Ok((u32::from_be_bytes(*version), rest))
+}
+
+/// Decode `payload` into the current schema, which is `version` serialized as `content_type`.
+///
+/// A payload without a content type, or with the [default](DEFAULT_CONTENT_TYPE) one, is taken
+/// as `content_type`.
+pub fn decode<T: MessageDe>(
+    payload: Payload<'_>,
+    version: u32,
+    content_type: &str,
+    upcast: impl FnOnce(Payload<'_>) -> Result<T, DeserializeError>,
+) -> Result<T, Error> {
+    if payload.version > version {
+        return Err(Error::Io(anyhow::anyhow!(
+            "Schema version {} is newer than the supported version {version}",
+            payload.version
+        )));
+    }
+    let current = payload.version == version
+        && payload
+            .content_type
+            .filter(|payload_type| *payload_type != DEFAULT_CONTENT_TYPE)
+            .is_none_or(|payload_type| payload_type == content_type);
+    if current {
+        return T::from_bytes(payload.bytes).map_err(|e| Error::DeserializeError(e.into()));
+    }
+    upcast(payload).map_err(Error::DeserializeError)
+}
@@ -0,0 +1,363 @@
+//! Needs a redis server in `KANAERU_TEST_REDIS_URL`. Keys are random, so any instance does.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use kanaeru::redis::cache::{LOAD_LOCK_SUFFIX, NEGATIVE_ENTRY, jittered};
+use kanaeru::redis::{
+    CachedKeyValue, KeyValue, KeyValueRead, KeyValueWrite, RedisConnection, RedisKey,
+};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use redis::AsyncCommands;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Count(u32);
+
+impl MessageSer for Count {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(Box::new(self.0.to_be_bytes()))
+    }
+}
+
+impl MessageDe for Count {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        let bytes = <[u8; 4]>::try_from(bytes)
+            .map_err(|_| DeserializeError(anyhow::anyhow!("Count is not 4 bytes")))?;
+        Ok(Self(u32::from_be_bytes(bytes)))
+    }
+}
+
+macro_rules! cached {
+    ($name:ident) => {
+        struct $name {
+            key: String,
+            value: Count,
+        }
+
+        impl KeyValue for $name {
+            type Key = String;
+            type Value = Count;
+
+            fn key(&self) -> String {
+                self.key.clone()
+            }
+
+            fn value(&self) -> Count {
+                self.value
+            }
+
+            fn into_value(self) -> Count {
+                self.value
+            }
+
+            fn new(key: String, value: Count) -> Self {
+                Self { key, value }
+            }
+        }
+
+        impl KeyValueRead for $name {}
+
+        impl KeyValueWrite for $name {}
+    };
+}
+
+cached!(Profile);
+
+impl CachedKeyValue for Profile {
+    const CACHE_TTL: Duration = Duration::from_secs(60);
+    const NEGATIVE_TTL: Option<Duration> = Some(Duration::from_secs(10));
+    const TTL_JITTER: f64 = 0.5;
+}
+
+cached!(Uncached);
+
+impl CachedKeyValue for Uncached {
+    const NEGATIVE_TTL: Option<Duration> = None;
+}
+
+cached!(Locked);
+
+impl CachedKeyValue for Locked {
+    const LOAD_LOCK_TTL: Option<Duration> = Some(Duration::from_millis(500));
+}
+
+async fn connection() -> Option<RedisConnection> {
+    let Ok(url) = std::env::var("KANAERU_TEST_REDIS_URL") else {
+        eprintln!("KANAERU_TEST_REDIS_URL is not set, skipping");
+        return None;
+    };
+    let client = redis::Client::open(url).unwrap();
+    Some(client.get_multiplexed_async_connection().await.unwrap())
+}
+
+fn random_key() -> String {
+    format!("test:cache:{}", uuid::Uuid::new_v4())
+}
+
+fn lock_key(key: &str) -> RedisKey {
+    [key.as_bytes(), LOAD_LOCK_SUFFIX].concat().into()
+}
+
+#[test]
+fn jitter_lengthens_ttls_up_to_the_fraction() {
+    let ttl = Duration::from_secs(100);
+    assert_eq!(jittered(ttl, 0.0), ttl);
+    assert_eq!(jittered(ttl, -1.0), ttl);
+    assert_eq!(jittered(ttl, f64::NAN), ttl);
+
+    let samples: Vec<Duration> = (0..200).map(|_| jittered(ttl, 0.25)).collect();
+    assert!(
+        samples
+            .iter()
+            .all(|sample| (ttl..=ttl.mul_f64(1.25)).contains(sample))
+    );
+    assert!(samples.iter().any(|sample| *sample != samples[0]));
+
+    // Capped at doubling the TTL
+    assert!((0..200).all(|_| jittered(ttl, 10.0) <= ttl * 2));
+}
+
+#[tokio::test]
+async fn values_are_loaded_once_and_cached_with_jitter() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+    let loads = AtomicUsize::new(0);
+    let load = || async {
+        loads.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(Count(7)))
+    };
+
+    let value = Profile::get_or_load(&mut conn, key.clone(), load).await;
+    assert_eq!(value.unwrap(), Some(Count(7)));
+    let value = Profile::get_or_load(&mut conn, key.clone(), load).await;
+    assert_eq!(value.unwrap(), Some(Count(7)));
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        Profile::read(&mut conn, key.clone()).await.unwrap(),
+        Some(Count(7))
+    );
+
+    let ttl: i64 = conn.pttl(&key).await.unwrap();
+    assert!((50_000..=90_000).contains(&ttl), "{ttl}");
+}
+
+#[tokio::test]
+async fn concurrent_misses_are_collapsed_into_one_load() {
+    let Some(conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+    let loads = Arc::new(AtomicUsize::new(0));
+
+    let callers: Vec<_> = (0..16)
+        .map(|_| {
+            let mut conn = conn.clone();
+            let key = key.clone();
+            let loads = loads.clone();
+            tokio::spawn(async move {
+                Profile::get_or_load(&mut conn, key, || async move {
+                    loads.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Ok(Some(Count(3)))
+                })
+                .await
+            })
+        })
+        .collect();
+    for caller in callers {
+        assert_eq!(caller.await.unwrap().unwrap(), Some(Count(3)));
+    }
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn misses_of_other_entities_with_the_same_key_are_loaded_on_their_own() {
+    let Some(conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+    let loads = Arc::new(AtomicUsize::new(0));
+    let load = |value| {
+        let loads = loads.clone();
+        move || async move {
+            loads.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(Some(Count(value)))
+        }
+    };
+
+    let profile = {
+        let mut conn = conn.clone();
+        let key = key.clone();
+        let load = load(1);
+        tokio::spawn(async move { Profile::get_or_load(&mut conn, key, load).await })
+    };
+    let uncached = {
+        let mut conn = conn.clone();
+        let load = load(2);
+        tokio::spawn(async move { Uncached::get_or_load(&mut conn, key, load).await })
+    };
+    assert_eq!(profile.await.unwrap().unwrap(), Some(Count(1)));
+    assert_eq!(uncached.await.unwrap().unwrap(), Some(Count(2)));
+    assert_eq!(loads.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn failed_loads_are_not_cached() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+
+    let value = Profile::get_or_load(&mut conn, key.clone(), || async {
+        Err(kanaeru::Error::NotFound)
+    })
+    .await;
+    assert!(matches!(value, Err(kanaeru::Error::NotFound)));
+    let exists: bool = conn.exists(&key).await.unwrap();
+    assert!(!exists);
+
+    let value = Profile::get_or_load(&mut conn, key, || async { Ok(Some(Count(1))) }).await;
+    assert_eq!(value.unwrap(), Some(Count(1)));
+}
+
+#[tokio::test]
+async fn misses_are_cached_as_negative_entries() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+    let loads = AtomicUsize::new(0);
+    let load = || async {
+        loads.fetch_add(1, Ordering::SeqCst);
+        Ok(None)
+    };
+
+    assert_eq!(
+        Profile::get_or_load(&mut conn, key.clone(), load)
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        Profile::get_or_load(&mut conn, key.clone(), load)
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+    let raw: Vec<u8> = conn.get(&key).await.unwrap();
+    assert_eq!(raw, NEGATIVE_ENTRY);
+    // Read as no value by plain reads as well
+    assert_eq!(Profile::read(&mut conn, key.clone()).await.unwrap(), None);
+    let ttl: i64 = conn.pttl(&key).await.unwrap();
+    assert!((5_000..=15_000).contains(&ttl), "{ttl}");
+
+    // Caching a value replaces the negative entry
+    Profile::cache(&mut conn, key.clone(), Some(Count(2)))
+        .await
+        .unwrap();
+    assert_eq!(
+        Profile::get_or_load(&mut conn, key, load).await.unwrap(),
+        Some(Count(2))
+    );
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn misses_are_not_cached_without_negative_ttl() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+    let loads = AtomicUsize::new(0);
+    let load = || async {
+        loads.fetch_add(1, Ordering::SeqCst);
+        Ok(None)
+    };
+
+    assert_eq!(
+        Uncached::get_or_load(&mut conn, key.clone(), load)
+            .await
+            .unwrap(),
+        None
+    );
+    let exists: bool = conn.exists(&key).await.unwrap();
+    assert!(!exists);
+    assert_eq!(
+        Uncached::get_or_load(&mut conn, key, load).await.unwrap(),
+        None
+    );
+    assert_eq!(loads.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn loads_of_other_processes_are_waited_for() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+    // Another process holds the load lock and caches the value after a while
+    let _: () = conn.pset_ex(lock_key(&key), "other", 5_000).await.unwrap();
+    let other = {
+        let mut conn = conn.clone();
+        let key = key.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Locked::cache(&mut conn, key, Some(Count(9))).await.unwrap();
+        })
+    };
+
+    let loads = AtomicUsize::new(0);
+    let value = Locked::get_or_load(&mut conn, key.clone(), || async {
+        loads.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(Count(1)))
+    })
+    .await;
+    other.await.unwrap();
+    assert_eq!(value.unwrap(), Some(Count(9)));
+    assert_eq!(loads.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn values_are_loaded_once_the_other_process_is_gone() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+
+    // The lock is released without a value being cached
+    let key = random_key();
+    let _: () = conn.pset_ex(lock_key(&key), "other", 5_000).await.unwrap();
+    let release = {
+        let mut conn = conn.clone();
+        let lock_key = lock_key(&key);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let _: () = conn.del(lock_key).await.unwrap();
+        })
+    };
+    let value = Locked::get_or_load(&mut conn, key.clone(), || async { Ok(Some(Count(4))) }).await;
+    release.await.unwrap();
+    assert_eq!(value.unwrap(), Some(Count(4)));
+    // The lock taken for the load is released
+    let locked: bool = conn.exists(lock_key(&key)).await.unwrap();
+    assert!(!locked);
+
+    // The lock outlives its TTL, e.g. because it was taken again, and is given up on
+    let key = random_key();
+    let _: () = conn.pset_ex(lock_key(&key), "other", 30_000).await.unwrap();
+    let started = Instant::now();
+    let value = Locked::get_or_load(&mut conn, key, || async { Ok(Some(Count(5))) }).await;
+    assert_eq!(value.unwrap(), Some(Count(5)));
+    let waited = started.elapsed();
+    assert!(waited >= Duration::from_millis(500), "{waited:?}");
+    assert!(waited < Duration::from_secs(5), "{waited:?}");
+}
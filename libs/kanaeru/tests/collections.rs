@@ -0,0 +1,388 @@
+//! Needs a redis server in `KANAERU_TEST_REDIS_URL`. Keys are random, so any instance does.
+
+use std::time::Duration;
+
+use kanaeru::redis::{
+    RedisCollection, RedisConnection, RedisHash, RedisList, RedisSet, RedisSortedSet,
+};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use redis::AsyncCommands;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Id(u32);
+
+impl MessageSer for Id {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(Box::new(self.0.to_be_bytes()))
+    }
+}
+
+impl MessageDe for Id {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        let bytes = <[u8; 4]>::try_from(bytes)
+            .map_err(|_| DeserializeError(anyhow::anyhow!("Id is not 4 bytes")))?;
+        Ok(Self(u32::from_be_bytes(bytes)))
+    }
+}
+
+struct Members;
+
+impl RedisCollection for Members {
+    type Key = String;
+}
+
+impl RedisSet for Members {
+    type Member = Id;
+}
+
+struct Owners;
+
+impl RedisCollection for Owners {
+    type Key = String;
+}
+
+impl RedisHash for Owners {
+    type Field = Id;
+    type Value = Id;
+}
+
+struct Leaderboard;
+
+impl RedisCollection for Leaderboard {
+    type Key = String;
+}
+
+impl RedisSortedSet for Leaderboard {
+    type Member = Id;
+}
+
+struct Queue;
+
+impl RedisCollection for Queue {
+    type Key = String;
+}
+
+impl RedisList for Queue {
+    type Item = Id;
+}
+
+async fn connection() -> Option<RedisConnection> {
+    let Ok(url) = std::env::var("KANAERU_TEST_REDIS_URL") else {
+        eprintln!("KANAERU_TEST_REDIS_URL is not set, skipping");
+        return None;
+    };
+    let client = redis::Client::open(url).unwrap();
+    Some(client.get_multiplexed_async_connection().await.unwrap())
+}
+
+fn random_key() -> String {
+    format!("test:collections:{}", uuid::Uuid::new_v4())
+}
+
+#[tokio::test]
+async fn sets_add_and_remove_single_members() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+
+    assert!(Members::add(&mut conn, key.clone(), Id(1)).await.unwrap());
+    assert!(!Members::add(&mut conn, key.clone(), Id(1)).await.unwrap());
+    assert_eq!(
+        Members::add_all(&mut conn, key.clone(), vec![Id(1), Id(2), Id(3)])
+            .await
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        Members::add_all(&mut conn, key.clone(), Vec::new())
+            .await
+            .unwrap(),
+        0
+    );
+    assert!(
+        Members::contains(&mut conn, key.clone(), Id(2))
+            .await
+            .unwrap()
+    );
+    assert_eq!(Members::len(&mut conn, key.clone()).await.unwrap(), 3);
+
+    assert!(
+        Members::remove(&mut conn, key.clone(), Id(2))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !Members::remove(&mut conn, key.clone(), Id(2))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !Members::contains(&mut conn, key.clone(), Id(2))
+            .await
+            .unwrap()
+    );
+    let mut members = Members::members(&mut conn, key.clone()).await.unwrap();
+    members.sort();
+    assert_eq!(members, [Id(1), Id(3)]);
+}
+
+#[tokio::test]
+async fn hashes_set_fields_one_by_one() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+
+    assert!(
+        Owners::set(&mut conn, key.clone(), Id(1), Id(10))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !Owners::set(&mut conn, key.clone(), Id(1), Id(11))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !Owners::set_if_absent(&mut conn, key.clone(), Id(1), Id(12))
+            .await
+            .unwrap()
+    );
+    assert!(
+        Owners::set_if_absent(&mut conn, key.clone(), Id(2), Id(20))
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        Owners::get(&mut conn, key.clone(), Id(1)).await.unwrap(),
+        Some(Id(11))
+    );
+    assert_eq!(
+        Owners::get(&mut conn, key.clone(), Id(3)).await.unwrap(),
+        None
+    );
+    assert!(
+        Owners::contains(&mut conn, key.clone(), Id(2))
+            .await
+            .unwrap()
+    );
+    assert_eq!(Owners::len(&mut conn, key.clone()).await.unwrap(), 2);
+
+    assert!(Owners::remove(&mut conn, key.clone(), Id(2)).await.unwrap());
+    assert!(!Owners::remove(&mut conn, key.clone(), Id(2)).await.unwrap());
+    assert_eq!(
+        Owners::entries(&mut conn, key.clone()).await.unwrap(),
+        [(Id(1), Id(11))]
+    );
+}
+
+#[tokio::test]
+async fn sorted_sets_order_members_by_score() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+
+    assert!(
+        Leaderboard::add(&mut conn, key.clone(), Id(1), 3.0)
+            .await
+            .unwrap()
+    );
+    assert!(
+        Leaderboard::add(&mut conn, key.clone(), Id(2), 1.0)
+            .await
+            .unwrap()
+    );
+    assert!(
+        Leaderboard::add(&mut conn, key.clone(), Id(3), 2.0)
+            .await
+            .unwrap()
+    );
+    // Updating the score of a member does not add it again
+    assert!(
+        !Leaderboard::add(&mut conn, key.clone(), Id(3), 5.0)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        Leaderboard::increment(&mut conn, key.clone(), Id(2), 1.5)
+            .await
+            .unwrap(),
+        2.5
+    );
+    assert_eq!(
+        Leaderboard::increment(&mut conn, key.clone(), Id(4), 4.0)
+            .await
+            .unwrap(),
+        4.0
+    );
+    assert_eq!(
+        Leaderboard::score(&mut conn, key.clone(), Id(1))
+            .await
+            .unwrap(),
+        Some(3.0)
+    );
+    assert_eq!(
+        Leaderboard::score(&mut conn, key.clone(), Id(5))
+            .await
+            .unwrap(),
+        None
+    );
+    assert!(
+        Leaderboard::contains(&mut conn, key.clone(), Id(4))
+            .await
+            .unwrap()
+    );
+
+    assert_eq!(
+        Leaderboard::range(&mut conn, key.clone(), 0, -1)
+            .await
+            .unwrap(),
+        [(Id(2), 2.5), (Id(1), 3.0), (Id(4), 4.0), (Id(3), 5.0)]
+    );
+    assert_eq!(
+        Leaderboard::range(&mut conn, key.clone(), -2, -1)
+            .await
+            .unwrap(),
+        [(Id(4), 4.0), (Id(3), 5.0)]
+    );
+    assert_eq!(
+        Leaderboard::range_by_score(&mut conn, key.clone(), 3.0, 4.0)
+            .await
+            .unwrap(),
+        [(Id(1), 3.0), (Id(4), 4.0)]
+    );
+
+    assert_eq!(
+        Leaderboard::remove_by_score(&mut conn, key.clone(), 0.0, 3.0)
+            .await
+            .unwrap(),
+        2
+    );
+    assert!(
+        Leaderboard::remove(&mut conn, key.clone(), Id(4))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !Leaderboard::remove(&mut conn, key.clone(), Id(4))
+            .await
+            .unwrap()
+    );
+    assert_eq!(Leaderboard::len(&mut conn, key.clone()).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn lists_keep_items_in_order() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+
+    assert_eq!(
+        Queue::push_back(&mut conn, key.clone(), Id(2))
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        Queue::push_back(&mut conn, key.clone(), Id(3))
+            .await
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        Queue::push_front(&mut conn, key.clone(), Id(1))
+            .await
+            .unwrap(),
+        3
+    );
+    assert_eq!(
+        Queue::push_back(&mut conn, key.clone(), Id(2))
+            .await
+            .unwrap(),
+        4
+    );
+    assert_eq!(
+        Queue::range(&mut conn, key.clone(), 0, -1).await.unwrap(),
+        [Id(1), Id(2), Id(3), Id(2)]
+    );
+    assert!(
+        Queue::contains(&mut conn, key.clone(), Id(3))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !Queue::contains(&mut conn, key.clone(), Id(4))
+            .await
+            .unwrap()
+    );
+
+    // Only the last occurrence
+    assert_eq!(
+        Queue::remove(&mut conn, key.clone(), Id(2), -1)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        Queue::range(&mut conn, key.clone(), 0, -1).await.unwrap(),
+        [Id(1), Id(2), Id(3)]
+    );
+
+    Queue::push_back(&mut conn, key.clone(), Id(4))
+        .await
+        .unwrap();
+    Queue::trim(&mut conn, key.clone(), 1, -1).await.unwrap();
+    assert_eq!(Queue::len(&mut conn, key.clone()).await.unwrap(), 3);
+    assert_eq!(
+        Queue::pop_front(&mut conn, key.clone()).await.unwrap(),
+        Some(Id(2))
+    );
+    assert_eq!(
+        Queue::pop_back(&mut conn, key.clone()).await.unwrap(),
+        Some(Id(4))
+    );
+    assert_eq!(
+        Queue::pop_back(&mut conn, key.clone()).await.unwrap(),
+        Some(Id(3))
+    );
+    assert_eq!(
+        Queue::pop_front(&mut conn, key.clone()).await.unwrap(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn collections_are_expired_and_deleted_whole() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+
+    assert!(
+        !Members::expire(&mut conn, key.clone(), Duration::from_secs(60))
+            .await
+            .unwrap()
+    );
+    Members::add_all(&mut conn, key.clone(), vec![Id(1), Id(2)])
+        .await
+        .unwrap();
+    assert!(
+        Members::expire(&mut conn, key.clone(), Duration::from_secs(60))
+            .await
+            .unwrap()
+    );
+    let ttl: i64 = conn.pttl(&key).await.unwrap();
+    assert!((1..=60_000).contains(&ttl), "{ttl}");
+
+    Members::delete(&mut conn, key.clone()).await.unwrap();
+    let exists: bool = conn.exists(&key).await.unwrap();
+    assert!(!exists);
+    assert_eq!(Members::len(&mut conn, key).await.unwrap(), 0);
+}
@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use amqprs::BasicProperties;
+use amqprs::channel::{
+    BasicPublishArguments, ExchangeDeclareArguments, QueueBindArguments, QueueDeclareArguments,
+};
+use kanaeru::rabbitmq::confirm::AmqpChannel;
+use kanaeru::rabbitmq::memory::MemoryBroker;
+
+const EXCHANGE: &str = "test.confirm";
+const QUEUE: &str = "test.confirm.routed";
+
+async fn declare(broker: &MemoryBroker) {
+    let connection = broker.connect().await.unwrap();
+    let channel = connection.open_channel(None).await.unwrap();
+    channel
+        .exchange_declare(ExchangeDeclareArguments::new(EXCHANGE, "direct"))
+        .await
+        .unwrap();
+    channel
+        .queue_declare(QueueDeclareArguments::durable_client_named(QUEUE))
+        .await
+        .unwrap();
+    channel
+        .queue_bind(QueueBindArguments::new(QUEUE, EXCHANGE, "routed"))
+        .await
+        .unwrap();
+    channel.close().await.unwrap();
+}
+
+fn mandatory(routing_key: &str) -> BasicPublishArguments {
+    BasicPublishArguments::new(EXCHANGE, routing_key)
+        .mandatory(true)
+        .finish()
+}
+
+#[tokio::test]
+async fn returned_message_fails_and_the_next_one_is_confirmed() {
+    let broker = MemoryBroker::start().await.unwrap();
+    declare(&broker).await;
+    let connection = broker.connect().await.unwrap();
+    let channel = AmqpChannel::open(&connection).await.unwrap();
+
+    let returned = channel
+        .publish(BasicProperties::default(), vec![0], mandatory("nowhere"))
+        .await;
+    let Err(kanaeru::Error::Unroutable(reason)) = returned else {
+        panic!("expected the message to be returned, got {returned:?}");
+    };
+    assert!(reason.contains("nowhere"), "{reason}");
+    channel
+        .publish(BasicProperties::default(), vec![1], mandatory("routed"))
+        .await
+        .unwrap();
+    assert_eq!(broker.queue_len(QUEUE), Some(1));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_publishes_get_their_own_confirms() {
+    let broker = MemoryBroker::start().await.unwrap();
+    declare(&broker).await;
+    let connection = broker.connect().await.unwrap();
+    let channel = Arc::new(AmqpChannel::open(&connection).await.unwrap());
+
+    let publishes: Vec<_> = (0..=255u8)
+        .map(|i| {
+            let channel = channel.clone();
+            tokio::spawn(async move {
+                let routing_key = if i % 2 == 0 {
+                    "routed".to_owned()
+                } else {
+                    format!("nowhere-{i}")
+                };
+                let result = channel
+                    .publish(BasicProperties::default(), vec![i], mandatory(&routing_key))
+                    .await;
+                (routing_key, result)
+            })
+        })
+        .collect();
+    for publish in publishes {
+        let (routing_key, result) = publish.await.unwrap();
+        match result {
+            Ok(()) => assert_eq!(routing_key, "routed"),
+            Err(kanaeru::Error::Unroutable(reason)) => {
+                // The return is matched to the publish it belongs to, not a neighbour
+                assert!(reason.contains(&format!("`{routing_key}`")), "{reason}");
+            }
+            Err(e) => panic!("publish to {routing_key} failed: {e:?}"),
+        }
+    }
+    assert_eq!(broker.queue_len(QUEUE), Some(128));
+}
+
+#[tokio::test]
+async fn publish_on_a_closed_channel_fails() {
+    let broker = MemoryBroker::start().await.unwrap();
+    declare(&broker).await;
+    let connection = broker.connect().await.unwrap();
+    let channel = AmqpChannel::open(&connection).await.unwrap();
+    connection.close().await.unwrap();
+
+    assert!(!channel.is_open() || !channel.is_connection_open());
+    assert!(
+        channel
+            .publish(BasicProperties::default(), vec![0], mandatory("routed"))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn messages_without_a_message_id_are_given_one() {
+    let broker = MemoryBroker::start().await.unwrap();
+    declare(&broker).await;
+    let connection = broker.connect().await.unwrap();
+    let channel = AmqpChannel::open(&connection).await.unwrap();
+
+    channel
+        .publish(BasicProperties::default(), vec![0], mandatory("routed"))
+        .await
+        .unwrap();
+    let mut properties = BasicProperties::default();
+    properties.with_message_id("order-1");
+    channel
+        .publish(properties, vec![1], mandatory("routed"))
+        .await
+        .unwrap();
+
+    let messages = broker.messages(QUEUE);
+    assert!(messages[0].properties.message_id().is_some());
+    assert_eq!(
+        messages[1].properties.message_id().map(String::as_str),
+        Some("order-1")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn dropped_publishes_keep_later_confirms_matched() {
+    let broker = MemoryBroker::start().await.unwrap();
+    declare(&broker).await;
+    let connection = broker.connect().await.unwrap();
+    let channel = Arc::new(AmqpChannel::open(&connection).await.unwrap());
+
+    // Publishes dropped while waiting for the channel, being sent or waiting for their confirm
+    let publishes: Vec<_> = (0..=255u8)
+        .map(|i| {
+            let channel = channel.clone();
+            tokio::spawn(async move {
+                let _ = channel
+                    .publish(BasicProperties::default(), vec![i], mandatory("routed"))
+                    .await;
+            })
+        })
+        .collect();
+    for (i, publish) in publishes.iter().enumerate() {
+        if i % 3 == 0 {
+            publish.abort();
+        }
+    }
+    for publish in publishes {
+        let _ = publish.await;
+    }
+
+    let returned = channel
+        .publish(BasicProperties::default(), vec![0], mandatory("nowhere"))
+        .await;
+    assert!(
+        matches!(returned, Err(kanaeru::Error::Unroutable(_))),
+        "{returned:?}"
+    );
+    channel
+        .publish(BasicProperties::default(), vec![1], mandatory("routed"))
+        .await
+        .unwrap();
+}
@@ -0,0 +1,143 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use kanaeru::rabbitmq::consumer::{ConsumerConfig, ConsumerHandle};
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::{
+    AmqpExchangeType, AmqpMessageProcessor, AmqpMessageSend, AmqpPool, AmqpRouting,
+    setup_consumer_with_config,
+};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use kanau::processor::Processor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Task(u8);
+
+impl MessageSer for Task {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(Box::new([self.0]))
+    }
+}
+
+impl MessageDe for Task {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        match bytes {
+            [id] => Ok(Self(*id)),
+            _ => Err(DeserializeError(anyhow::anyhow!("Task is not 1 byte"))),
+        }
+    }
+}
+
+impl AmqpRouting for Task {
+    const EXCHANGE: &'static str = "test.task";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "task.queued";
+}
+
+impl AmqpMessageSend for Task {}
+
+/// Takes `delay` per task and records how many tasks it processed at once
+struct Worker {
+    delay: Duration,
+    active: AtomicUsize,
+    max_active: AtomicUsize,
+    finished: AtomicUsize,
+}
+
+impl Worker {
+    fn new(delay: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            delay,
+            active: AtomicUsize::new(0),
+            max_active: AtomicUsize::new(0),
+            finished: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl Processor<Task, Result<(), kanaeru::Error>> for Worker {
+    async fn process(&self, _: Task) -> Result<(), kanaeru::Error> {
+        let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_active.fetch_max(active, Ordering::SeqCst);
+        tokio::time::sleep(self.delay).await;
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        self.finished.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl AmqpMessageProcessor<Task> for Worker {
+    const QUEUE: &'static str = "test.task.queued";
+}
+
+async fn consume(pool: &AmqpPool, worker: &Arc<Worker>, concurrency: u32) -> ConsumerHandle {
+    let channel = Worker::ensure_queue(pool).await.unwrap();
+    let config = ConsumerConfig::new().prefetch(16).concurrency(concurrency);
+    setup_consumer_with_config::<Task, _>(&channel, worker.clone(), config)
+        .await
+        .unwrap()
+}
+
+async fn eventually(condition: impl Fn() -> bool) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn at_most_concurrency_tasks_are_processed_at_once() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let worker = Worker::new(Duration::from_millis(100));
+    let _consumer = consume(&pool, &worker, 3).await;
+
+    for i in 0..9 {
+        Task(i).send(&pool).await.unwrap();
+    }
+    eventually(|| worker.finished.load(Ordering::SeqCst) == 9).await;
+    assert_eq!(worker.max_active.load(Ordering::SeqCst), 3);
+    eventually(|| broker.unacked_len(Worker::QUEUE) == 0).await;
+}
+
+#[tokio::test]
+async fn shutdown_waits_for_tasks_in_flight() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let worker = Worker::new(Duration::from_millis(300));
+    let consumer = consume(&pool, &worker, 2).await;
+
+    Task(1).send(&pool).await.unwrap();
+    Task(2).send(&pool).await.unwrap();
+    eventually(|| worker.active.load(Ordering::SeqCst) == 2).await;
+
+    assert!(consumer.shutdown(Duration::from_secs(5)).await.unwrap());
+    assert_eq!(worker.finished.load(Ordering::SeqCst), 2);
+    assert_eq!(broker.consumer_count(Worker::QUEUE), 0);
+    assert_eq!(broker.queue_len(Worker::QUEUE), Some(0));
+}
+
+#[tokio::test]
+async fn tasks_unfinished_at_the_shutdown_timeout_are_requeued() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let worker = Worker::new(Duration::from_secs(2));
+    let consumer = consume(&pool, &worker, 2).await;
+
+    Task(1).send(&pool).await.unwrap();
+    Task(2).send(&pool).await.unwrap();
+    eventually(|| worker.active.load(Ordering::SeqCst) == 2).await;
+
+    assert!(!consumer.shutdown(Duration::from_millis(100)).await.unwrap());
+    assert_eq!(worker.finished.load(Ordering::SeqCst), 0);
+    // Closing the channel gives the unacked deliveries back to the queue
+    eventually(|| broker.queue_len(Worker::QUEUE) == Some(2)).await;
+}
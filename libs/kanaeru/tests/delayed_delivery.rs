@@ -0,0 +1,171 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use kanaeru::rabbitmq::consumer::ConsumerHandle;
+use kanaeru::rabbitmq::delay::{delay_bucket, delay_queue_name};
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::{
+    AmqpExchangeType, AmqpMessageProcessor, AmqpMessageSend, AmqpRouting, setup_consumer,
+};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use kanau::processor::Processor;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Reminder(u8);
+
+impl MessageSer for Reminder {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(Box::new([self.0]))
+    }
+}
+
+impl MessageDe for Reminder {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        match bytes {
+            [id] => Ok(Self(*id)),
+            _ => Err(DeserializeError(anyhow::anyhow!("Reminder is not 1 byte"))),
+        }
+    }
+}
+
+impl AmqpRouting for Reminder {
+    const EXCHANGE: &'static str = "test.reminder";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "reminder.due";
+}
+
+impl AmqpMessageSend for Reminder {}
+
+struct Recorder(mpsc::UnboundedSender<(Reminder, Instant)>);
+
+impl Processor<Reminder, Result<(), kanaeru::Error>> for Recorder {
+    async fn process(&self, message: Reminder) -> Result<(), kanaeru::Error> {
+        let _ = self.0.send((message, Instant::now()));
+        Ok(())
+    }
+}
+
+impl AmqpMessageProcessor<Reminder> for Recorder {
+    const QUEUE: &'static str = "test.reminder.due";
+}
+
+struct Setup {
+    broker: MemoryBroker,
+    pool: kanaeru::rabbitmq::AmqpPool,
+    receiver: mpsc::UnboundedReceiver<(Reminder, Instant)>,
+    _consumer: ConsumerHandle,
+}
+
+async fn setup() -> Setup {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let channel = Recorder::ensure_queue(&pool).await.unwrap();
+    let consumer = setup_consumer::<Reminder, _>(&channel, Arc::new(Recorder(sender)))
+        .await
+        .unwrap();
+    Setup {
+        broker,
+        pool,
+        receiver,
+        _consumer: consumer,
+    }
+}
+
+async fn receive(
+    receiver: &mut mpsc::UnboundedReceiver<(Reminder, Instant)>,
+) -> (Reminder, Instant) {
+    tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn delayed_messages_are_delivered_in_order_of_due_time() {
+    let Setup {
+        broker,
+        pool,
+        mut receiver,
+        _consumer,
+    } = setup().await;
+    let delays = [
+        (Reminder(1), Duration::from_millis(600)),
+        (Reminder(2), Duration::from_millis(200)),
+        (Reminder(3), Duration::from_millis(400)),
+        (Reminder(4), Duration::from_millis(200)),
+    ];
+
+    let sent_at = Instant::now();
+    for (reminder, delay) in delays {
+        reminder.send_after(&pool, delay).await.unwrap();
+    }
+    let queue = delay_queue_name(Reminder::EXCHANGE, Duration::from_millis(200));
+    assert_eq!(broker.queue_len(&queue), Some(2));
+
+    let mut received = Vec::new();
+    for _ in delays {
+        let (reminder, at) = receive(&mut receiver).await;
+        received.push(reminder);
+        let (_, delay) = delays.iter().find(|(sent, _)| *sent == reminder).unwrap();
+        let elapsed = at - sent_at;
+        // Only the lower bound holds on a loaded machine, the order covers the rest
+        assert!(
+            elapsed >= *delay,
+            "{reminder:?} delivered after {elapsed:?}"
+        );
+    }
+    assert_eq!(
+        received,
+        [Reminder(2), Reminder(4), Reminder(3), Reminder(1)]
+    );
+    assert_eq!(broker.queue_len(&queue), Some(0));
+}
+
+#[tokio::test]
+async fn messages_scheduled_in_the_past_are_sent_right_away() {
+    let Setup {
+        broker: _broker,
+        pool,
+        mut receiver,
+        _consumer,
+    } = setup().await;
+
+    let sent_at = Instant::now();
+    let at = time::OffsetDateTime::now_utc() + time::Duration::milliseconds(300);
+    Reminder(1).send_at(&pool, at).await.unwrap();
+    let past = time::OffsetDateTime::now_utc() - time::Duration::hours(1);
+    Reminder(2).send_at(&pool, past).await.unwrap();
+
+    // Sent second, but delivered ahead of the message still due
+    let (reminder, _) = receive(&mut receiver).await;
+    assert_eq!(reminder, Reminder(2));
+    let (reminder, at) = receive(&mut receiver).await;
+    assert_eq!(reminder, Reminder(1));
+    assert!(at - sent_at >= Duration::from_millis(290));
+}
+
+#[test]
+fn delays_are_rounded_up_by_less_than_a_128th() {
+    assert_eq!(delay_bucket(Duration::ZERO), Duration::ZERO);
+    assert_eq!(
+        delay_bucket(Duration::from_micros(1500)),
+        Duration::from_millis(2)
+    );
+    assert_eq!(
+        delay_bucket(Duration::from_millis(200)),
+        Duration::from_millis(200)
+    );
+    for millis in [1_000, 60_000, 86_400_000, 30 * 86_400_000] {
+        let delay = Duration::from_millis(millis + 1);
+        let bucket = delay_bucket(delay);
+        assert!(bucket >= delay);
+        assert!(bucket - delay < delay / 128);
+    }
+}
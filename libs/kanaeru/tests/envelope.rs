@@ -0,0 +1,231 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use kanaeru::envelope::{
+    self, EnvelopePolicy, FLAG_AES_GCM, FLAG_ZSTD, Keyring, MAGIC, MAX_DECOMPRESSED_SIZE,
+};
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::{
+    AmqpExchangeType, AmqpMessageProcessor, AmqpMessageSend, AmqpRouting, setup_consumer,
+};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use kanau::processor::Processor;
+use tokio::sync::mpsc;
+
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Offset of the flags in the envelope header
+const FLAGS: usize = 5;
+
+fn compressible() -> Box<[u8]> {
+    b"kanaeru ".repeat(512).into_boxed_slice()
+}
+
+#[test]
+fn compressible_payloads_are_compressed() {
+    let content = compressible();
+    let sealed =
+        envelope::seal(content.clone(), CONTENT_TYPE, &EnvelopePolicy::COMPRESSED).unwrap();
+    assert!(sealed.starts_with(&MAGIC));
+    assert_eq!(sealed[FLAGS], FLAG_ZSTD);
+    assert!(sealed.len() < content.len() / 10);
+
+    let opened = envelope::open(&sealed).unwrap();
+    assert_eq!(opened.content_type.as_deref(), Some(CONTENT_TYPE));
+    assert_eq!(opened.content.as_ref(), content.as_ref());
+
+    // Below the threshold the body is left as is
+    let sealed = envelope::seal(
+        Box::new([1, 2, 3]),
+        CONTENT_TYPE,
+        &EnvelopePolicy::COMPRESSED,
+    )
+    .unwrap();
+    assert_eq!(sealed[FLAGS], 0);
+    assert_eq!(envelope::open(&sealed).unwrap().content.as_ref(), [1, 2, 3]);
+}
+
+#[test]
+fn decompression_is_capped() {
+    let at_cap = vec![0; MAX_DECOMPRESSED_SIZE as usize].into_boxed_slice();
+    let sealed = envelope::seal(at_cap, CONTENT_TYPE, &EnvelopePolicy::COMPRESSED).unwrap();
+    assert_eq!(
+        envelope::open(&sealed).unwrap().content.len() as u64,
+        MAX_DECOMPRESSED_SIZE
+    );
+
+    let over_cap = vec![0; MAX_DECOMPRESSED_SIZE as usize + 1].into_boxed_slice();
+    let sealed = envelope::seal(over_cap, CONTENT_TYPE, &EnvelopePolicy::COMPRESSED).unwrap();
+    let error = envelope::open(&sealed).unwrap_err();
+    assert!(error.0.to_string().contains("too large"), "{}", error.0);
+}
+
+// The keyring is global, so everything encrypted is in this one test
+#[test]
+fn encrypted_payloads_survive_key_rotation() {
+    let first_key = [1; 32];
+    let second_key = [2; 32];
+    Keyring::new(1, &first_key).install();
+
+    let content = compressible();
+    let policy = EnvelopePolicy {
+        compress_above: Some(1024),
+        ..EnvelopePolicy::ENCRYPTED
+    };
+    let sealed = envelope::seal(content.clone(), CONTENT_TYPE, &policy).unwrap();
+    assert_eq!(sealed[FLAGS], FLAG_ZSTD | FLAG_AES_GCM);
+    assert!(!sealed.windows(8).any(|window| window == b"kanaeru "));
+    assert_eq!(
+        envelope::open(&sealed).unwrap().content.as_ref(),
+        content.as_ref()
+    );
+
+    // The header is authenticated along with the body
+    let mut tampered = sealed.to_vec();
+    tampered[FLAGS + 2] ^= 1;
+    assert!(envelope::open(&tampered).is_err());
+
+    Keyring::new(2, &second_key)
+        .with_key(1, &first_key)
+        .install();
+    assert_eq!(
+        envelope::open(&sealed).unwrap().content.as_ref(),
+        content.as_ref()
+    );
+    let resealed = envelope::seal(content.clone(), CONTENT_TYPE, &policy).unwrap();
+    assert_eq!(
+        envelope::open(&resealed).unwrap().content.as_ref(),
+        content.as_ref()
+    );
+
+    // Once the first key is dropped, what it encrypted can no longer be read
+    Keyring::new(2, &second_key).install();
+    let error = envelope::open(&sealed).unwrap_err();
+    assert!(
+        error.0.to_string().contains("unknown key id 1"),
+        "{}",
+        error.0
+    );
+    assert!(envelope::open(&resealed).is_ok());
+}
+
+#[test]
+fn raw_payloads_are_only_looked_into_when_asked() {
+    let raw = [&MAGIC[..], b"not an envelope"].concat();
+    assert!(envelope::open(b"{}").is_err());
+    assert!(envelope::open(&raw).is_err());
+    let opened = envelope::open_or_raw(b"{}").unwrap();
+    assert_eq!(opened.content_type, None);
+    assert_eq!(opened.content.as_ref(), b"{}");
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Blob(Vec<u8>);
+
+impl MessageSer for Blob {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(self.0.into_boxed_slice())
+    }
+}
+
+impl MessageDe for Blob {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        Ok(Self(bytes.to_vec()))
+    }
+}
+
+impl AmqpRouting for Blob {
+    const EXCHANGE: &'static str = "test.blob";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "blob.stored";
+}
+
+impl AmqpMessageSend for Blob {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CompressedBlob(Vec<u8>);
+
+impl MessageSer for CompressedBlob {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(self.0.into_boxed_slice())
+    }
+}
+
+impl MessageDe for CompressedBlob {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        Ok(Self(bytes.to_vec()))
+    }
+}
+
+impl AmqpRouting for CompressedBlob {
+    const EXCHANGE: &'static str = "test.blob";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "blob.compressed";
+}
+
+impl AmqpMessageSend for CompressedBlob {
+    const ENVELOPE: EnvelopePolicy = EnvelopePolicy::COMPRESSED;
+}
+
+struct Recorder(mpsc::UnboundedSender<Vec<u8>>);
+
+impl Processor<Blob, Result<(), kanaeru::Error>> for Recorder {
+    async fn process(&self, blob: Blob) -> Result<(), kanaeru::Error> {
+        let _ = self.0.send(blob.0);
+        Ok(())
+    }
+}
+
+impl AmqpMessageProcessor<Blob> for Recorder {
+    const QUEUE: &'static str = "test.blob.stored";
+}
+
+impl Processor<CompressedBlob, Result<(), kanaeru::Error>> for Recorder {
+    async fn process(&self, blob: CompressedBlob) -> Result<(), kanaeru::Error> {
+        let _ = self.0.send(blob.0);
+        Ok(())
+    }
+}
+
+impl AmqpMessageProcessor<CompressedBlob> for Recorder {
+    const QUEUE: &'static str = "test.blob.compressed";
+}
+
+#[tokio::test]
+async fn messages_say_whether_they_are_enveloped() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let (sender, mut received) = mpsc::unbounded_channel();
+    let recorder = Arc::new(Recorder(sender));
+    let channel = <Recorder as AmqpMessageProcessor<Blob>>::ensure_queue(&pool)
+        .await
+        .unwrap();
+    let _raw = setup_consumer::<Blob, _>(&channel, recorder.clone())
+        .await
+        .unwrap();
+    let channel = <Recorder as AmqpMessageProcessor<CompressedBlob>>::ensure_queue(&pool)
+        .await
+        .unwrap();
+    let _compressed = setup_consumer::<CompressedBlob, _>(&channel, recorder)
+        .await
+        .unwrap();
+
+    // A raw payload that starts like an envelope is still delivered as is
+    let raw = [&MAGIC[..], &[1, 0, 0, 1, 2, 3]].concat();
+    Blob(raw.clone()).send(&pool).await.unwrap();
+    let blob = tokio::time::timeout(Duration::from_secs(5), received.recv()).await;
+    assert_eq!(blob.unwrap(), Some(raw));
+
+    let content = compressible().into_vec();
+    CompressedBlob(content.clone()).send(&pool).await.unwrap();
+    let blob = tokio::time::timeout(Duration::from_secs(5), received.recv()).await;
+    assert_eq!(blob.unwrap(), Some(content));
+}
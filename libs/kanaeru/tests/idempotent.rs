@@ -0,0 +1,244 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use amqprs::BasicProperties;
+use amqprs::channel::{BasicPublishArguments, QueueDeclareArguments};
+use kanaeru::rabbitmq::idempotent::{Idempotent, ProcessedMessage, ProcessedMessageKey};
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::metadata::MessageMetadata;
+use kanaeru::rabbitmq::{
+    AmqpBinding, AmqpExchangeType, AmqpMessageProcessor, AmqpMessageSend, AmqpRouting,
+};
+use kanaeru::redis::{KeyValueRead, RedisConnection};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use kanau::processor::Processor;
+
+struct OrderEvent;
+
+impl MessageSer for OrderEvent {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(Box::new([]))
+    }
+}
+
+impl MessageDe for OrderEvent {
+    type DeError = DeserializeError;
+
+    fn from_bytes(_: &[u8]) -> Result<Self, DeserializeError> {
+        Ok(Self)
+    }
+}
+
+impl AmqpRouting for OrderEvent {
+    const EXCHANGE: &'static str = "test.order";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Topic;
+    const ROUTING_KEY: &'static str = "order.placed";
+}
+
+impl AmqpMessageSend for OrderEvent {}
+
+struct AllOrderEvents;
+
+impl Processor<OrderEvent, Result<(), kanaeru::Error>> for AllOrderEvents {
+    async fn process(&self, _: OrderEvent) -> Result<(), kanaeru::Error> {
+        Ok(())
+    }
+}
+
+impl AmqpMessageProcessor<OrderEvent> for AllOrderEvents {
+    const QUEUE: &'static str = "test.order.all";
+
+    fn bindings() -> Vec<AmqpBinding> {
+        vec![AmqpBinding::routing_key("order.*")]
+    }
+}
+
+#[tokio::test]
+async fn idempotent_processor_keeps_the_bindings_of_the_inner_one() {
+    assert_eq!(
+        <Idempotent<AllOrderEvents> as AmqpMessageProcessor<OrderEvent>>::bindings(),
+        AllOrderEvents::bindings()
+    );
+
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let channel =
+        <Idempotent<AllOrderEvents> as AmqpMessageProcessor<OrderEvent>>::ensure_queue(&pool)
+            .await
+            .unwrap();
+    channel
+        .basic_publish(
+            BasicProperties::default(),
+            vec![],
+            BasicPublishArguments::new(OrderEvent::EXCHANGE, "order.cancelled"),
+        )
+        .await
+        .unwrap();
+    // Waits for the publish to be handled
+    channel
+        .queue_declare(
+            QueueDeclareArguments::durable_client_named(AllOrderEvents::QUEUE)
+                .passive(true)
+                .finish(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(broker.queue_len(AllOrderEvents::QUEUE), Some(1));
+}
+
+/// Counts the orders it processes, failing the first `failures` of them
+struct Orders {
+    delay: Duration,
+    failures: AtomicUsize,
+    processed: AtomicUsize,
+}
+
+impl Orders {
+    fn new(delay: Duration, failures: usize) -> Self {
+        Self {
+            delay,
+            failures: AtomicUsize::new(failures),
+            processed: AtomicUsize::new(0),
+        }
+    }
+
+    fn processed(&self) -> usize {
+        self.processed.load(Ordering::SeqCst)
+    }
+}
+
+impl Processor<OrderEvent, Result<(), kanaeru::Error>> for Orders {
+    async fn process(&self, _: OrderEvent) -> Result<(), kanaeru::Error> {
+        tokio::time::sleep(self.delay).await;
+        self.processed.fetch_add(1, Ordering::SeqCst);
+        let failing = self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |failures| {
+                failures.checked_sub(1)
+            })
+            .is_ok();
+        if failing {
+            return Err(kanaeru::Error::Io(anyhow::anyhow!("Order failed")));
+        }
+        Ok(())
+    }
+}
+
+impl AmqpMessageProcessor<OrderEvent> for Orders {
+    const QUEUE: &'static str = "test.order.placed";
+}
+
+/// Needs a redis server in `KANAERU_TEST_REDIS_URL`. Message ids are random, so any instance does.
+async fn connection() -> Option<RedisConnection> {
+    let Ok(url) = std::env::var("KANAERU_TEST_REDIS_URL") else {
+        eprintln!("KANAERU_TEST_REDIS_URL is not set, skipping");
+        return None;
+    };
+    let client = redis::Client::open(url).unwrap();
+    Some(client.get_multiplexed_async_connection().await.unwrap())
+}
+
+fn delivery() -> MessageMetadata {
+    MessageMetadata {
+        message_id: Some(uuid::Uuid::new_v4().to_string()),
+        ..MessageMetadata::default()
+    }
+}
+
+async fn is_recorded(conn: &mut RedisConnection, metadata: &MessageMetadata) -> bool {
+    let key = ProcessedMessageKey {
+        queue: Orders::QUEUE.to_string(),
+        message_id: metadata.message_id.clone().unwrap(),
+    };
+    ProcessedMessage::read(conn, key).await.unwrap().is_some()
+}
+
+#[tokio::test]
+async fn processed_messages_are_recorded_and_skipped() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let orders = Idempotent::new(Orders::new(Duration::ZERO, 0), conn.clone());
+    let metadata = delivery();
+
+    orders
+        .process_with_metadata(OrderEvent, metadata.clone())
+        .await
+        .unwrap();
+    assert!(is_recorded(&mut conn, &metadata).await);
+    orders
+        .process_with_metadata(OrderEvent, metadata)
+        .await
+        .unwrap();
+    assert_eq!(orders.inner().processed(), 1);
+
+    // Messages without an id can not be told apart
+    for _ in 0..2 {
+        orders
+            .process_with_metadata(OrderEvent, MessageMetadata::default())
+            .await
+            .unwrap();
+    }
+    assert_eq!(orders.inner().processed(), 3);
+}
+
+#[tokio::test]
+async fn failed_messages_are_not_recorded() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let orders = Idempotent::new(Orders::new(Duration::ZERO, 1), conn.clone());
+    let metadata = delivery();
+
+    assert!(
+        orders
+            .process_with_metadata(OrderEvent, metadata.clone())
+            .await
+            .is_err()
+    );
+    assert!(!is_recorded(&mut conn, &metadata).await);
+    orders
+        .process_with_metadata(OrderEvent, metadata.clone())
+        .await
+        .unwrap();
+    assert_eq!(orders.inner().processed(), 2);
+    assert!(is_recorded(&mut conn, &metadata).await);
+}
+
+#[tokio::test]
+async fn concurrent_deliveries_are_processed_once() {
+    let Some(conn) = connection().await else {
+        return;
+    };
+    let orders = Idempotent::new(Orders::new(Duration::from_millis(100), 0), conn);
+    let metadata = delivery();
+
+    let (first, second) = tokio::join!(
+        orders.process_with_metadata(OrderEvent, metadata.clone()),
+        orders.process_with_metadata(OrderEvent, metadata),
+    );
+    first.unwrap();
+    second.unwrap();
+    assert_eq!(orders.inner().processed(), 1);
+}
+
+#[tokio::test]
+async fn cancelled_processing_releases_the_claim() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let orders = Idempotent::new(Orders::new(Duration::from_secs(5), 0), conn.clone());
+    let metadata = delivery();
+
+    let cancelled = tokio::time::timeout(
+        Duration::from_millis(50),
+        orders.process_with_metadata(OrderEvent, metadata.clone()),
+    )
+    .await;
+    assert!(cancelled.is_err());
+    // The claim is released in the background
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!is_recorded(&mut conn, &metadata).await);
+}
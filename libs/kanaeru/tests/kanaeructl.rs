@@ -0,0 +1,209 @@
+//! Runs `kanaeructl`, so needs the `cli` feature, and a Postgres database with the migrations
+//! applied in `KANAERU_TEST_DATABASE_URL`. Only the rows these tests insert are touched.
+#![cfg(feature = "cli")]
+
+use std::process::Output;
+
+use amqprs::channel::QueueDeclareArguments;
+use amqprs::{BasicProperties, FieldTable};
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::metadata::MessageMetadata;
+use kanaeru::rabbitmq::outbox::OutboxMessage;
+use kanaeru::rabbitmq::policy::ErrorClass;
+use kanaeru::rabbitmq::quarantine::QuarantinedMessage;
+
+const PROCESSOR: &str = "test.kanaeructl.processed";
+
+/// Run `kanaeructl` against the test database and `broker`, off the runtime serving the broker
+async fn kanaeructl(url: &str, broker: Option<&MemoryBroker>, args: &[&str]) -> Output {
+    let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_kanaeructl"));
+    command
+        .args(args)
+        .env("DATABASE_URL", url)
+        .env_remove("AMQP_URL");
+    if let Some(broker) = broker {
+        command.env(
+            "AMQP_URL",
+            format!("amqp://guest:guest@{}", broker.address()),
+        );
+    }
+    tokio::task::spawn_blocking(move || command.output().unwrap())
+        .await
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn quarantined_messages_are_shown_edited_replayed_and_deleted() {
+    let Ok(url) = std::env::var("KANAERU_TEST_DATABASE_URL") else {
+        eprintln!("KANAERU_TEST_DATABASE_URL is not set, skipping");
+        return;
+    };
+    let db = sqlx::PgPool::connect(&url).await.unwrap();
+    sqlx::query("DELETE FROM kanaeru.quarantine WHERE processor = $1")
+        .bind(PROCESSOR)
+        .execute(&db)
+        .await
+        .unwrap();
+    let mut properties = BasicProperties::default();
+    properties.with_message_id("order-1");
+    let id = QuarantinedMessage::insert(
+        &db,
+        PROCESSOR,
+        "test.order",
+        "order.placed",
+        &properties,
+        b"{\"order\":1}",
+        "Order not found\nwhile processing",
+        ErrorClass::Rejected,
+    )
+    .await
+    .unwrap();
+    let id_arg = id.to_string();
+
+    let listed = stdout(
+        &kanaeructl(
+            &url,
+            None,
+            &["quarantine", "list", "--processor", PROCESSOR],
+        )
+        .await,
+    );
+    let row = listed
+        .lines()
+        .find(|line| line.trim_start().starts_with(&id_arg))
+        .unwrap();
+    assert!(row.contains("rejected"), "{row}");
+    assert!(row.ends_with("Order not found"), "{row}");
+
+    let shown = stdout(&kanaeructl(&url, None, &["quarantine", "show", &id_arg]).await);
+    assert!(shown.contains("message id:     order-1"), "{shown}");
+    assert!(shown.contains("replayed at:    -"), "{shown}");
+    assert!(shown.contains("{\"order\":1}"), "{shown}");
+
+    let payload = std::env::temp_dir().join(format!("kanaeructl-{}", uuid::Uuid::new_v4()));
+    std::fs::write(&payload, b"{\"order\":2}").unwrap();
+    let payload_arg = payload.to_str().unwrap();
+    stdout(
+        &kanaeructl(
+            &url,
+            None,
+            &["quarantine", "edit", &id_arg, "--payload", payload_arg],
+        )
+        .await,
+    );
+    std::fs::remove_file(&payload).unwrap();
+
+    // Replays need the broker
+    let replayed = kanaeructl(&url, None, &["quarantine", "replay", &id_arg]).await;
+    assert!(!replayed.status.success());
+    let broker = MemoryBroker::start().await.unwrap();
+    let connection = broker.connect().await.unwrap();
+    let channel = connection.open_channel(None).await.unwrap();
+    channel
+        .queue_declare(QueueDeclareArguments::durable_client_named(PROCESSOR))
+        .await
+        .unwrap();
+    stdout(&kanaeructl(&url, Some(&broker), &["quarantine", "replay", &id_arg]).await);
+    let messages = broker.messages(PROCESSOR);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].content, b"{\"order\":2}");
+
+    let listed = stdout(
+        &kanaeructl(
+            &url,
+            None,
+            &["quarantine", "list", "--processor", PROCESSOR],
+        )
+        .await,
+    );
+    assert!(!listed.contains(&format!(" {id_arg} ")), "{listed}");
+    let listed = stdout(
+        &kanaeructl(
+            &url,
+            None,
+            &["quarantine", "list", "--processor", PROCESSOR, "--all"],
+        )
+        .await,
+    );
+    assert!(listed.contains("yes"), "{listed}");
+
+    stdout(&kanaeructl(&url, None, &["quarantine", "delete", &id_arg]).await);
+    assert!(QuarantinedMessage::find(&db, id).await.unwrap().is_none());
+    assert!(
+        !kanaeructl(&url, None, &["quarantine", "delete", &id_arg])
+            .await
+            .status
+            .success()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn parked_outbox_messages_are_listed_and_unparked() {
+    let Ok(url) = std::env::var("KANAERU_TEST_DATABASE_URL") else {
+        eprintln!("KANAERU_TEST_DATABASE_URL is not set, skipping");
+        return;
+    };
+    let db = sqlx::PgPool::connect(&url).await.unwrap();
+    let id = OutboxMessage::insert(
+        &db,
+        "test.kanaeructl",
+        "parked",
+        &FieldTable::new(),
+        &[1],
+        &MessageMetadata::outgoing("application/octet-stream"),
+    )
+    .await
+    .unwrap();
+    sqlx::query(
+        "UPDATE kanaeru.outbox SET parked_at = NOW(), attempts = 5, last_error = 'NO_ROUTE' WHERE id = $1",
+    )
+    .bind(id)
+    .execute(&db)
+    .await
+    .unwrap();
+    let id_arg = id.to_string();
+
+    let parked = stdout(
+        &kanaeructl(
+            &url,
+            None,
+            &["outbox", "parked", "--after", &(id - 1).to_string()],
+        )
+        .await,
+    );
+    let row = parked
+        .lines()
+        .find(|line| line.trim_start().starts_with(&id_arg))
+        .unwrap();
+    assert!(row.contains("test.kanaeructl"), "{row}");
+    assert!(row.ends_with("NO_ROUTE"), "{row}");
+
+    stdout(&kanaeructl(&url, None, &["outbox", "unpark", &id_arg]).await);
+    let parked: bool =
+        sqlx::query_scalar("SELECT parked_at IS NOT NULL FROM kanaeru.outbox WHERE id = $1")
+            .bind(id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert!(!parked);
+    assert!(
+        !kanaeructl(&url, None, &["outbox", "unpark", &id_arg])
+            .await
+            .status
+            .success()
+    );
+    sqlx::query("DELETE FROM kanaeru.outbox WHERE id = $1")
+        .bind(id)
+        .execute(&db)
+        .await
+        .unwrap();
+}
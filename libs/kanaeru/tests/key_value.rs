@@ -0,0 +1,172 @@
+//! Needs a redis server in `KANAERU_TEST_REDIS_URL`. Keys are random, so any instance does.
+
+use std::time::Duration;
+
+use kanaeru::redis::{KeyValue, KeyValueRead, KeyValueWrite, RedisConnection};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use redis::{AsyncCommands, Commands};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Count(u32);
+
+impl MessageSer for Count {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(Box::new(self.0.to_be_bytes()))
+    }
+}
+
+impl MessageDe for Count {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        let bytes = <[u8; 4]>::try_from(bytes)
+            .map_err(|_| DeserializeError(anyhow::anyhow!("Count is not 4 bytes")))?;
+        Ok(Self(u32::from_be_bytes(bytes)))
+    }
+}
+
+struct Counter {
+    key: String,
+    value: Count,
+}
+
+impl KeyValue for Counter {
+    type Key = String;
+    type Value = Count;
+
+    fn key(&self) -> String {
+        self.key.clone()
+    }
+
+    fn value(&self) -> Count {
+        self.value
+    }
+
+    fn into_value(self) -> Count {
+        self.value
+    }
+
+    fn new(key: String, value: Count) -> Self {
+        Self { key, value }
+    }
+}
+
+impl KeyValueRead for Counter {}
+
+impl KeyValueWrite for Counter {
+    const UPDATE_ATTEMPTS: usize = 3;
+}
+
+fn client() -> Option<redis::Client> {
+    let Ok(url) = std::env::var("KANAERU_TEST_REDIS_URL") else {
+        eprintln!("KANAERU_TEST_REDIS_URL is not set, skipping");
+        return None;
+    };
+    Some(redis::Client::open(url).unwrap())
+}
+
+async fn counter(client: &redis::Client, value: u32) -> (RedisConnection, String) {
+    let mut conn = client.get_multiplexed_async_connection().await.unwrap();
+    let key = format!("test:counter:{}", uuid::Uuid::new_v4());
+    Counter::new(key.clone(), Count(value))
+        .write_with_ttl(&mut conn, Duration::from_secs(60))
+        .await
+        .unwrap();
+    (conn, key)
+}
+
+#[tokio::test]
+async fn updates_keep_the_ttl() {
+    let Some(client) = client() else {
+        return;
+    };
+    let (mut conn, key) = counter(&client, 1).await;
+
+    let value = Counter::update(&mut conn, key.clone(), |Count(n)| Ok(Count(n + 1))).await;
+    assert_eq!(value.unwrap(), Count(2));
+    assert_eq!(
+        Counter::read(&mut conn, key.clone()).await.unwrap(),
+        Some(Count(2))
+    );
+    let ttl: i64 = conn.pttl(&key).await.unwrap();
+    assert!((1..=60_000).contains(&ttl), "{ttl}");
+}
+
+#[tokio::test]
+async fn updates_racing_a_write_are_retried_on_the_new_value() {
+    let Some(client) = client() else {
+        return;
+    };
+    let (mut conn, key) = counter(&client, 1).await;
+    // Another writer changes the value while the first change is made
+    let mut other = client.get_connection().unwrap();
+    let mut changes = Vec::new();
+
+    let value = Counter::update(&mut conn, key.clone(), |Count(n)| {
+        changes.push(n);
+        if changes.len() == 1 {
+            let _: () = other.set(&key, 10u32.to_be_bytes().as_slice()).unwrap();
+        }
+        Ok(Count(n + 1))
+    })
+    .await;
+    assert_eq!(value.unwrap(), Count(11));
+    assert_eq!(changes, [1, 10]);
+    assert_eq!(
+        Counter::read(&mut conn, key).await.unwrap(),
+        Some(Count(11))
+    );
+}
+
+#[tokio::test]
+async fn updates_losing_every_race_conflict() {
+    let Some(client) = client() else {
+        return;
+    };
+    let (mut conn, key) = counter(&client, 1).await;
+    let mut other = client.get_connection().unwrap();
+    let mut changes = 0;
+
+    let value = Counter::update(&mut conn, key.clone(), |Count(n)| {
+        changes += 1;
+        let _: () = other.set(&key, (n + 100).to_be_bytes().as_slice()).unwrap();
+        Ok(Count(n + 1))
+    })
+    .await;
+    assert!(
+        matches!(value, Err(kanaeru::Error::Conflict(3))),
+        "{value:?}"
+    );
+    assert_eq!(changes, 3);
+    // Only the other writer's values were written
+    assert_eq!(
+        Counter::read(&mut conn, key).await.unwrap(),
+        Some(Count(301))
+    );
+}
+
+#[tokio::test]
+async fn updates_of_missing_values_and_failed_changes_write_nothing() {
+    let Some(client) = client() else {
+        return;
+    };
+    let (mut conn, key) = counter(&client, 1).await;
+
+    let missing = format!("{key}:missing");
+    let value = Counter::update(&mut conn, missing.clone(), |_| unreachable!()).await;
+    assert!(matches!(value, Err(kanaeru::Error::NotFound)), "{value:?}");
+    let exists: bool = conn.exists(&missing).await.unwrap();
+    assert!(!exists);
+
+    let value = Counter::update(&mut conn, key.clone(), |_| {
+        Err(kanaeru::Error::InvalidInput)
+    })
+    .await;
+    assert!(
+        matches!(value, Err(kanaeru::Error::InvalidInput)),
+        "{value:?}"
+    );
+    assert_eq!(Counter::read(&mut conn, key).await.unwrap(), Some(Count(1)));
+}
@@ -0,0 +1,112 @@
+//! Needs a redis server in `KANAERU_TEST_REDIS_URL`. Keys are random, so any instance does.
+
+use std::time::Duration;
+
+use kanaeru::redis::{RedisConnection, RedisLock};
+use redis::AsyncCommands;
+
+async fn connection() -> Option<RedisConnection> {
+    let Ok(url) = std::env::var("KANAERU_TEST_REDIS_URL") else {
+        eprintln!("KANAERU_TEST_REDIS_URL is not set, skipping");
+        return None;
+    };
+    let client = redis::Client::open(url).unwrap();
+    Some(client.get_multiplexed_async_connection().await.unwrap())
+}
+
+fn random_key() -> String {
+    format!("test:lock:{}", uuid::Uuid::new_v4())
+}
+
+#[tokio::test]
+async fn locks_are_exclusive_and_fenced() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let lock = RedisLock::new(random_key());
+
+    let first = lock.try_acquire(&mut conn).await.unwrap().unwrap();
+    assert!(first.is_held());
+    assert!(lock.try_acquire(&mut conn).await.unwrap().is_none());
+    let first_fence = first.fencing_token();
+    assert!(first.release().await.unwrap());
+
+    let second = lock.try_acquire(&mut conn).await.unwrap().unwrap();
+    assert!(second.fencing_token() > first_fence);
+    let second_fence = second.fencing_token();
+    // Dropping the guard releases the lock as well
+    drop(second);
+    let third = lock
+        .acquire(&mut conn, Duration::from_secs(1))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(third.fencing_token() > second_fence);
+    assert!(third.release().await.unwrap());
+}
+
+#[tokio::test]
+async fn acquire_waits_for_the_lock_to_be_free() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let lock = RedisLock::new(random_key()).retry_interval(Duration::from_millis(20));
+    let held = lock.try_acquire(&mut conn).await.unwrap().unwrap();
+
+    assert!(
+        lock.acquire(&mut conn, Duration::from_millis(100))
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    let waiting = {
+        let lock = lock.clone();
+        let mut conn = conn.clone();
+        tokio::spawn(async move { lock.acquire(&mut conn, Duration::from_secs(5)).await })
+    };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let fence = held.fencing_token();
+    assert!(held.release().await.unwrap());
+    let acquired = waiting.await.unwrap().unwrap().unwrap();
+    assert!(acquired.fencing_token() > fence);
+}
+
+#[tokio::test]
+async fn leases_are_renewed_while_held() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+    let lock = RedisLock::new(key.as_str()).lease(Duration::from_millis(300));
+    let guard = lock.try_acquire(&mut conn).await.unwrap().unwrap();
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(guard.is_held());
+    assert!(lock.try_acquire(&mut conn).await.unwrap().is_none());
+    let ttl: i64 = conn.pttl(&key).await.unwrap();
+    assert!((1..=300).contains(&ttl), "{ttl}");
+    assert!(guard.release().await.unwrap());
+}
+
+#[tokio::test]
+async fn locks_taken_by_someone_else_are_reported_lost() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let key = random_key();
+    let lock = RedisLock::new(key.as_str()).lease(Duration::from_millis(300));
+    let guard = lock.try_acquire(&mut conn).await.unwrap().unwrap();
+
+    // The lease ran out during a pause and someone else took the lock
+    let _: () = conn.pset_ex(&key, "someone else", 10_000).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(2), guard.lost())
+        .await
+        .unwrap();
+    assert!(!guard.is_held());
+
+    // Releasing leaves the other owner's lock alone
+    assert!(!guard.release().await.unwrap());
+    let owner: String = conn.get(&key).await.unwrap();
+    assert_eq!(owner, "someone else");
+}
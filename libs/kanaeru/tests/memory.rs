@@ -0,0 +1,342 @@
+use std::time::Duration;
+
+use amqprs::channel::{
+    BasicAckArguments, BasicGetArguments, BasicNackArguments, BasicPublishArguments,
+    BasicRejectArguments, Channel, ExchangeDeclareArguments, QueueBindArguments,
+    QueueDeclareArguments,
+};
+use amqprs::connection::Connection;
+use amqprs::{BasicProperties, FieldTable, FieldValue};
+use kanaeru::rabbitmq::confirm::AmqpChannel;
+use kanaeru::rabbitmq::memory::MemoryBroker;
+
+struct Setup {
+    broker: MemoryBroker,
+    connection: Connection,
+    channel: Channel,
+}
+
+async fn setup() -> Setup {
+    let broker = MemoryBroker::start().await.unwrap();
+    let connection = broker.connect().await.unwrap();
+    let channel = connection.open_channel(None).await.unwrap();
+    Setup {
+        broker,
+        connection,
+        channel,
+    }
+}
+
+fn table(fields: &[(&str, FieldValue)]) -> FieldTable {
+    let mut table = FieldTable::new();
+    for (name, value) in fields {
+        table.insert((*name).try_into().unwrap(), value.clone());
+    }
+    table
+}
+
+impl Setup {
+    async fn exchange(&self, exchange: &str, kind: &str) {
+        self.channel
+            .exchange_declare(ExchangeDeclareArguments::new(exchange, kind))
+            .await
+            .unwrap();
+    }
+
+    async fn queue(&self, queue: &str, arguments: FieldTable) {
+        self.channel
+            .queue_declare(
+                QueueDeclareArguments::durable_client_named(queue)
+                    .arguments(arguments)
+                    .finish(),
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn bind(&self, queue: &str, exchange: &str, routing_key: &str, arguments: FieldTable) {
+        self.channel
+            .queue_bind(
+                QueueBindArguments::new(queue, exchange, routing_key)
+                    .arguments(arguments)
+                    .finish(),
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn publish(&self, exchange: &str, routing_key: &str, properties: BasicProperties) {
+        self.channel
+            .basic_publish(
+                properties,
+                vec![0],
+                BasicPublishArguments::new(exchange, routing_key),
+            )
+            .await
+            .unwrap();
+    }
+
+    /// Wait until the broker has handled everything sent on the channel so far
+    async fn sync(&self) {
+        self.channel
+            .queue_declare(QueueDeclareArguments::durable_client_named("test.sync"))
+            .await
+            .unwrap();
+    }
+
+    async fn get(&self, queue: &str) -> (u64, bool) {
+        let (get_ok, _, _) = self
+            .channel
+            .basic_get(BasicGetArguments::new(queue))
+            .await
+            .unwrap()
+            .unwrap();
+        (get_ok.delivery_tag(), get_ok.redelivered())
+    }
+}
+
+async fn eventually(condition: impl Fn() -> bool) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn messages_are_routed_by_exchange_type() {
+    let setup = setup().await;
+    setup.exchange("test.direct", "direct").await;
+    setup.exchange("test.fanout", "fanout").await;
+    setup.exchange("test.topic", "topic").await;
+    setup.exchange("test.headers", "headers").await;
+    for queue in [
+        "direct",
+        "fanout",
+        "topic.all",
+        "topic.orders",
+        "all",
+        "any",
+    ] {
+        setup.queue(queue, FieldTable::new()).await;
+    }
+    setup
+        .bind("direct", "test.direct", "created", FieldTable::new())
+        .await;
+    setup
+        .bind("fanout", "test.fanout", "ignored", FieldTable::new())
+        .await;
+    setup
+        .bind("topic.all", "test.topic", "#", FieldTable::new())
+        .await;
+    setup
+        .bind("topic.orders", "test.topic", "orders.*", FieldTable::new())
+        .await;
+    let region = ("region", FieldValue::from("eu"));
+    let tier = ("tier", FieldValue::from("gold"));
+    setup
+        .bind(
+            "all",
+            "test.headers",
+            "",
+            table(&[("x-match", "all".into()), region.clone(), tier.clone()]),
+        )
+        .await;
+    setup
+        .bind(
+            "any",
+            "test.headers",
+            "",
+            table(&[("x-match", "any".into()), region.clone(), tier.clone()]),
+        )
+        .await;
+
+    setup
+        .publish("test.direct", "created", BasicProperties::default())
+        .await;
+    setup
+        .publish("test.direct", "deleted", BasicProperties::default())
+        .await;
+    setup
+        .publish("test.fanout", "anything", BasicProperties::default())
+        .await;
+    setup
+        .publish("test.topic", "orders.created", BasicProperties::default())
+        .await;
+    setup
+        .publish(
+            "test.topic",
+            "orders.eu.created",
+            BasicProperties::default(),
+        )
+        .await;
+    setup
+        .publish("test.topic", "users.created", BasicProperties::default())
+        .await;
+    let mut properties = BasicProperties::default();
+    properties.with_headers(table(std::slice::from_ref(&region)));
+    setup.publish("test.headers", "", properties).await;
+    let mut properties = BasicProperties::default();
+    properties.with_headers(table(&[region, tier]));
+    setup.publish("test.headers", "", properties).await;
+    // The default exchange routes by queue name
+    setup
+        .publish("", "direct", BasicProperties::default())
+        .await;
+    setup.sync().await;
+
+    let broker = &setup.broker;
+    assert_eq!(broker.queue_len("direct"), Some(2));
+    assert_eq!(broker.queue_len("fanout"), Some(1));
+    assert_eq!(broker.queue_len("topic.all"), Some(3));
+    assert_eq!(broker.queue_len("topic.orders"), Some(1));
+    assert_eq!(broker.queue_len("all"), Some(1));
+    assert_eq!(broker.queue_len("any"), Some(2));
+    assert_eq!(
+        broker.messages("topic.orders")[0].routing_key,
+        "orders.created"
+    );
+}
+
+#[tokio::test]
+async fn nacked_messages_are_requeued_and_rejected_ones_dead_lettered() {
+    let setup = setup().await;
+    setup.queue("dead", FieldTable::new()).await;
+    setup
+        .queue(
+            "work",
+            table(&[
+                ("x-dead-letter-exchange", "".into()),
+                ("x-dead-letter-routing-key", "dead".into()),
+            ]),
+        )
+        .await;
+    setup.publish("", "work", BasicProperties::default()).await;
+    setup.sync().await;
+
+    let (tag, redelivered) = setup.get("work").await;
+    assert!(!redelivered);
+    assert_eq!(setup.broker.unacked_len("work"), 1);
+    setup
+        .channel
+        .basic_nack(BasicNackArguments::new(tag, false, true))
+        .await
+        .unwrap();
+    setup.sync().await;
+    assert_eq!(setup.broker.queue_len("work"), Some(1));
+    assert!(setup.broker.messages("work")[0].redelivered);
+
+    let (tag, redelivered) = setup.get("work").await;
+    assert!(redelivered);
+    setup
+        .channel
+        .basic_reject(BasicRejectArguments::new(tag, false))
+        .await
+        .unwrap();
+    setup.sync().await;
+    assert_eq!(setup.broker.queue_len("work"), Some(0));
+    assert_eq!(setup.broker.unacked_len("work"), 0);
+    let dead = setup.broker.messages("dead");
+    assert_eq!(dead.len(), 1);
+    let headers = dead[0].properties.headers().unwrap();
+    assert!(
+        headers
+            .get(&"x-first-death-reason".try_into().unwrap())
+            .is_some()
+    );
+
+    setup.publish("", "dead", BasicProperties::default()).await;
+    setup.sync().await;
+    let (tag, _) = setup.get("dead").await;
+    setup
+        .channel
+        .basic_ack(BasicAckArguments::new(tag, false))
+        .await
+        .unwrap();
+    setup.sync().await;
+    assert_eq!(setup.broker.queue_len("dead"), Some(1));
+    assert_eq!(setup.broker.unacked_len("dead"), 0);
+}
+
+#[tokio::test]
+async fn expired_messages_are_dead_lettered() {
+    let setup = setup().await;
+    setup.queue("expired", FieldTable::new()).await;
+    setup
+        .queue(
+            "short-lived",
+            table(&[
+                ("x-message-ttl", FieldValue::l(50)),
+                ("x-dead-letter-exchange", "".into()),
+                ("x-dead-letter-routing-key", "expired".into()),
+            ]),
+        )
+        .await;
+    setup.queue("per-message", FieldTable::new()).await;
+
+    setup
+        .publish("", "short-lived", BasicProperties::default())
+        .await;
+    let mut properties = BasicProperties::default();
+    properties.with_expiration("20");
+    setup.publish("", "per-message", properties).await;
+    setup.sync().await;
+    assert_eq!(setup.broker.queue_len("short-lived"), Some(1));
+
+    let broker = &setup.broker;
+    eventually(|| broker.queue_len("expired") == Some(1)).await;
+    assert_eq!(broker.queue_len("short-lived"), Some(0));
+    eventually(|| broker.queue_len("per-message") == Some(0)).await;
+}
+
+#[tokio::test]
+async fn unroutable_mandatory_messages_are_returned() {
+    let setup = setup().await;
+    setup.exchange("test.direct", "direct").await;
+    setup.queue("routed", FieldTable::new()).await;
+    setup
+        .bind("routed", "test.direct", "routed", FieldTable::new())
+        .await;
+    let channel = AmqpChannel::open(&setup.connection).await.unwrap();
+
+    let publish = |routing_key: &'static str, mandatory: bool| {
+        channel.publish(
+            BasicProperties::default(),
+            vec![0],
+            BasicPublishArguments::new("test.direct", routing_key)
+                .mandatory(mandatory)
+                .finish(),
+        )
+    };
+    assert!(matches!(
+        publish("nowhere", true).await,
+        Err(kanaeru::Error::Unroutable(_))
+    ));
+    // Without mandatory the message is dropped and still confirmed
+    publish("nowhere", false).await.unwrap();
+    publish("routed", true).await.unwrap();
+    assert_eq!(setup.broker.queue_len("routed"), Some(1));
+}
+
+#[tokio::test]
+async fn redeclaring_a_queue_with_other_arguments_fails() {
+    let setup = setup().await;
+    setup.queue("work", FieldTable::new()).await;
+    let redeclared = setup
+        .channel
+        .queue_declare(
+            QueueDeclareArguments::durable_client_named("work")
+                .arguments(table(&[("x-message-ttl", FieldValue::l(50))]))
+                .finish(),
+        )
+        .await;
+    assert!(redeclared.is_err());
+    // The channel is closed by the broker, the queue is unchanged
+    let channel = setup.connection.open_channel(None).await.unwrap();
+    channel
+        .queue_declare(QueueDeclareArguments::durable_client_named("work"))
+        .await
+        .unwrap();
+}
@@ -0,0 +1,205 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use amqprs::{BasicProperties, FieldTable, FieldValue};
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::metadata::MessageMetadata;
+use kanaeru::rabbitmq::{
+    AmqpExchangeType, AmqpMessageProcessor, AmqpMessageSend, AmqpPool, AmqpRouting, setup_consumer,
+};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use kanau::processor::Processor;
+use opentelemetry::trace::TracerProvider;
+use tokio::sync::mpsc;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+
+macro_rules! message {
+    ($name:ident, $exchange:literal, $routing_key:literal) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        struct $name(u8);
+
+        impl MessageSer for $name {
+            type SerError = SerializeError;
+
+            fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+                Ok(Box::new([self.0]))
+            }
+        }
+
+        impl MessageDe for $name {
+            type DeError = DeserializeError;
+
+            fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+                match bytes {
+                    [id] => Ok(Self(*id)),
+                    _ => Err(DeserializeError(anyhow::anyhow!("Message is not 1 byte"))),
+                }
+            }
+        }
+
+        impl AmqpRouting for $name {
+            const EXCHANGE: &'static str = $exchange;
+            const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+            const ROUTING_KEY: &'static str = $routing_key;
+        }
+
+        impl AmqpMessageSend for $name {}
+    };
+}
+
+message!(Order, "test.metadata.order", "order.placed");
+message!(Receipt, "test.metadata.receipt", "receipt.issued");
+
+/// Issues a receipt for every order
+struct Cashier {
+    pool: AmqpPool,
+    received: mpsc::UnboundedSender<MessageMetadata>,
+}
+
+impl Processor<Order, Result<(), kanaeru::Error>> for Cashier {
+    async fn process(&self, order: Order) -> Result<(), kanaeru::Error> {
+        Receipt(order.0).send(&self.pool).await
+    }
+}
+
+impl AmqpMessageProcessor<Order> for Cashier {
+    const QUEUE: &'static str = "test.metadata.order.placed";
+
+    async fn process_with_metadata(
+        &self,
+        order: Order,
+        metadata: MessageMetadata,
+    ) -> Result<(), kanaeru::Error> {
+        let _ = self.received.send(metadata);
+        self.process(order).await
+    }
+}
+
+struct Archive(mpsc::UnboundedSender<MessageMetadata>);
+
+impl Processor<Receipt, Result<(), kanaeru::Error>> for Archive {
+    async fn process(&self, _: Receipt) -> Result<(), kanaeru::Error> {
+        Ok(())
+    }
+}
+
+impl AmqpMessageProcessor<Receipt> for Archive {
+    const QUEUE: &'static str = "test.metadata.receipt.issued";
+
+    async fn process_with_metadata(
+        &self,
+        receipt: Receipt,
+        metadata: MessageMetadata,
+    ) -> Result<(), kanaeru::Error> {
+        let _ = self.0.send(metadata);
+        self.process(receipt).await
+    }
+}
+
+fn trace_id(traceparent: &str) -> &str {
+    traceparent.split('-').nth(1).unwrap()
+}
+
+#[test]
+fn metadata_round_trips_through_properties() {
+    let metadata = MessageMetadata {
+        message_id: Some("message".to_string()),
+        correlation_id: Some("correlation".to_string()),
+        timestamp: Some(1_700_000_000),
+        content_type: Some("application/json".to_string()),
+        traceparent: Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_string()),
+        tracestate: Some("vendor=value".to_string()),
+    };
+    let mut headers = FieldTable::new();
+    headers.insert(
+        "x-tenant".try_into().unwrap(),
+        FieldValue::S("acme".try_into().unwrap()),
+    );
+    let mut properties = BasicProperties::default();
+    properties.with_headers(headers);
+
+    metadata.apply(&mut properties);
+    assert_eq!(MessageMetadata::from_properties(&properties), metadata);
+    // Headers already there are kept
+    let headers = properties.headers().unwrap();
+    assert!(headers.get(&"x-tenant".try_into().unwrap()).is_some());
+
+    assert_eq!(
+        MessageMetadata::from_properties(&BasicProperties::default()),
+        MessageMetadata::default()
+    );
+}
+
+#[tokio::test]
+async fn outgoing_metadata_inherits_the_current_correlation() {
+    // Outside of any trace or delivery, a message starts its own correlation
+    let metadata = MessageMetadata::outgoing("application/octet-stream");
+    assert!(metadata.message_id.is_some());
+    assert_eq!(metadata.correlation_id, metadata.message_id);
+    assert_eq!(metadata.traceparent, None);
+    assert!(MessageMetadata::current().is_none());
+
+    let delivery = MessageMetadata {
+        correlation_id: Some("request-1".to_string()),
+        ..MessageMetadata::default()
+    };
+    let metadata = delivery
+        .scope(async {
+            assert!(MessageMetadata::current().is_some());
+            MessageMetadata::outgoing("application/octet-stream")
+        })
+        .await;
+    assert_eq!(metadata.correlation_id.as_deref(), Some("request-1"));
+    assert_ne!(metadata.message_id.as_deref(), Some("request-1"));
+}
+
+#[tokio::test]
+async fn published_messages_inherit_the_delivery_correlation_and_trace() {
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder().build();
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+    let _subscriber = tracing::subscriber::set_default(subscriber);
+
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let (orders_sender, mut orders) = mpsc::unbounded_channel();
+    let (receipts_sender, mut receipts) = mpsc::unbounded_channel();
+    let cashier = Arc::new(Cashier {
+        pool: pool.clone(),
+        received: orders_sender,
+    });
+    let channel = Cashier::ensure_queue(&pool).await.unwrap();
+    let _cashier = setup_consumer::<Order, _>(&channel, cashier).await.unwrap();
+    let channel = Archive::ensure_queue(&pool).await.unwrap();
+    let _archive = setup_consumer::<Receipt, _>(&channel, Arc::new(Archive(receipts_sender)))
+        .await
+        .unwrap();
+
+    Order(1)
+        .send(&pool)
+        .instrument(tracing::info_span!("request"))
+        .await
+        .unwrap();
+    let order = tokio::time::timeout(Duration::from_secs(5), orders.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let receipt = tokio::time::timeout(Duration::from_secs(5), receipts.recv())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // The order starts a correlation named after the trace of the request
+    let order_traceparent = order.traceparent.unwrap();
+    assert_eq!(
+        order.correlation_id.as_deref(),
+        Some(trace_id(&order_traceparent))
+    );
+    assert_eq!(receipt.correlation_id, order.correlation_id);
+    assert_ne!(receipt.message_id, order.message_id);
+    // The receipt is published from the span processing the order, in the same trace
+    let receipt_traceparent = receipt.traceparent.unwrap();
+    assert_eq!(trace_id(&receipt_traceparent), trace_id(&order_traceparent));
+    assert_ne!(receipt_traceparent, order_traceparent);
+}
@@ -0,0 +1,163 @@
+//! Needs a Postgres database with the migrations applied in `KANAERU_TEST_DATABASE_URL`.
+//! The outbox of that database is emptied, so never point it at a database in use.
+
+use std::time::Duration;
+
+use amqprs::FieldTable;
+use amqprs::channel::{ExchangeDeclareArguments, QueueBindArguments, QueueDeclareArguments};
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::metadata::MessageMetadata;
+use kanaeru::rabbitmq::outbox::{OutboxMessage, OutboxRelay};
+use kanaeru::sqlx::DatabaseProcessor;
+
+const EXCHANGE: &str = "test.outbox";
+const QUEUE: &str = "test.outbox.created";
+
+/// Every relay claims from the whole outbox, so the tests take turns
+static OUTBOX: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+async fn database() -> Option<(tokio::sync::MutexGuard<'static, ()>, sqlx::PgPool)> {
+    let Ok(url) = std::env::var("KANAERU_TEST_DATABASE_URL") else {
+        eprintln!("KANAERU_TEST_DATABASE_URL is not set, skipping");
+        return None;
+    };
+    let guard = OUTBOX.lock().await;
+    let db = sqlx::PgPool::connect(&url).await.unwrap();
+    sqlx::query("DELETE FROM kanaeru.outbox")
+        .execute(&db)
+        .await
+        .unwrap();
+    Some((guard, db))
+}
+
+async fn declare(broker: &MemoryBroker, exchange: &str) {
+    let connection = broker.connect().await.unwrap();
+    let channel = connection.open_channel(None).await.unwrap();
+    channel
+        .exchange_declare(ExchangeDeclareArguments::new(exchange, "direct"))
+        .await
+        .unwrap();
+    channel
+        .queue_declare(QueueDeclareArguments::durable_client_named(QUEUE))
+        .await
+        .unwrap();
+    channel
+        .queue_bind(QueueBindArguments::new(QUEUE, exchange, "created"))
+        .await
+        .unwrap();
+}
+
+async fn insert(db: &sqlx::PgPool, exchange: &str, payload: u8) -> i64 {
+    let metadata = MessageMetadata::outgoing("application/octet-stream");
+    OutboxMessage::insert(
+        db,
+        exchange,
+        "created",
+        &FieldTable::new(),
+        &[payload],
+        &metadata,
+    )
+    .await
+    .unwrap()
+}
+
+async fn row(db: &sqlx::PgPool, id: i64) -> (i32, bool, bool) {
+    sqlx::query_as(
+        "SELECT attempts, published_at IS NOT NULL, parked_at IS NOT NULL FROM kanaeru.outbox WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_one(db)
+    .await
+    .unwrap()
+}
+
+async fn make_due(db: &sqlx::PgPool, id: i64) {
+    sqlx::query("UPDATE kanaeru.outbox SET next_attempt_at = NOW() WHERE id = $1")
+        .bind(id)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn failing_messages_back_off_and_are_parked_without_blocking_others() {
+    let Some((_guard, db)) = database().await else {
+        return;
+    };
+    let broker = MemoryBroker::start().await.unwrap();
+    declare(&broker, EXCHANGE).await;
+    let relay = OutboxRelay::new(
+        DatabaseProcessor::new(db.clone()),
+        broker.pool().await.unwrap(),
+    )
+    .max_attempts(2)
+    .claim_timeout(Duration::from_secs(60));
+
+    let first = insert(&db, EXCHANGE, 1).await;
+    let failing = insert(&db, "test.outbox.missing", 2).await;
+    let last = insert(&db, EXCHANGE, 3).await;
+    let corrupt = insert(&db, EXCHANGE, 4).await;
+    sqlx::query("UPDATE kanaeru.outbox SET headers = '\\xff' WHERE id = $1")
+        .bind(corrupt)
+        .execute(&db)
+        .await
+        .unwrap();
+
+    // The failing message does not hold back the one after it
+    assert_eq!(relay.relay_once().await.unwrap(), 2);
+    assert_eq!(broker.queue_len(QUEUE), Some(2));
+    assert_eq!(row(&db, first).await, (1, true, false));
+    assert_eq!(row(&db, last).await, (1, true, false));
+    assert_eq!(row(&db, failing).await, (1, false, false));
+    // Headers that do not decode never will, so the message is parked right away
+    assert_eq!(row(&db, corrupt).await, (1, false, true));
+
+    // Backing off until its next attempt
+    assert_eq!(relay.relay_once().await.unwrap(), 0);
+    assert_eq!(row(&db, failing).await, (1, false, false));
+
+    make_due(&db, failing).await;
+    assert_eq!(relay.relay_once().await.unwrap(), 0);
+    assert_eq!(row(&db, failing).await, (2, false, true));
+    let parked = OutboxMessage::list_parked(&db, 0, 10).await.unwrap();
+    assert_eq!(
+        parked.iter().map(|message| message.id).collect::<Vec<_>>(),
+        [failing, corrupt]
+    );
+
+    // Parked messages stay parked until they are unparked
+    make_due(&db, failing).await;
+    assert_eq!(relay.relay_once().await.unwrap(), 0);
+    declare(&broker, "test.outbox.missing").await;
+    assert!(relay.unpark(failing).await.unwrap());
+    assert!(!relay.unpark(failing).await.unwrap());
+    assert_eq!(relay.relay_once().await.unwrap(), 1);
+    assert_eq!(row(&db, failing).await, (1, true, false));
+    assert_eq!(broker.queue_len(QUEUE), Some(3));
+}
+
+#[tokio::test]
+async fn claimed_messages_are_skipped_until_the_claim_expires() {
+    let Some((_guard, db)) = database().await else {
+        return;
+    };
+    let id = insert(&db, EXCHANGE, 1).await;
+
+    let claimed = OutboxMessage::claim_due(&db, 10, Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_eq!(claimed.len(), 1);
+    assert_eq!(claimed[0].id, id);
+    assert!(
+        OutboxMessage::claim_due(&db, 10, Duration::from_secs(60))
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    make_due(&db, id).await;
+    let reclaimed = OutboxMessage::claim_due(&db, 10, Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_eq!(reclaimed.len(), 1);
+}
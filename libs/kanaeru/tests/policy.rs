@@ -0,0 +1,305 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::policy::{AckAction, AckPolicy, ErrorClass};
+use kanaeru::rabbitmq::quarantine::QuarantinedMessage;
+use kanaeru::rabbitmq::retry::{RetryPolicy, dead_letter_queue_name};
+use kanaeru::rabbitmq::{
+    AmqpExchangeType, AmqpMessageProcessor, AmqpMessageSend, AmqpPool, AmqpRouting, setup_consumer,
+};
+use kanaeru::sqlx::DatabaseProcessor;
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use kanau::processor::Processor;
+
+const CLASSES: [ErrorClass; 6] = [
+    ErrorClass::Transient,
+    ErrorClass::Publish,
+    ErrorClass::Amqp,
+    ErrorClass::Malformed,
+    ErrorClass::Rejected,
+    ErrorClass::BusinessPanic,
+];
+
+fn actions(policy: AckPolicy) -> Vec<AckAction> {
+    CLASSES.iter().map(|class| policy.action(*class)).collect()
+}
+
+#[test]
+fn errors_are_classified() {
+    let classes = [
+        (
+            kanaeru::Error::Io(anyhow::anyhow!("down")),
+            ErrorClass::Transient,
+        ),
+        (kanaeru::Error::PoolTimeout, ErrorClass::Transient),
+        (kanaeru::Error::Conflict(5), ErrorClass::Transient),
+        (
+            kanaeru::Error::RateLimited(Some(Duration::from_secs(1))),
+            ErrorClass::Transient,
+        ),
+        (kanaeru::Error::PublishNacked, ErrorClass::Publish),
+        (
+            kanaeru::Error::Unroutable(String::new()),
+            ErrorClass::Publish,
+        ),
+        (
+            kanaeru::Error::DeserializeError(DeserializeError(anyhow::anyhow!("garbage"))),
+            ErrorClass::Malformed,
+        ),
+        (kanaeru::Error::InvalidInput, ErrorClass::Rejected),
+        (kanaeru::Error::NotFound, ErrorClass::Rejected),
+        (kanaeru::Error::PermissionsDenied, ErrorClass::Rejected),
+        (kanaeru::Error::RateLimited(None), ErrorClass::Rejected),
+        (
+            kanaeru::Error::BusinessPanic(anyhow::anyhow!("bug")),
+            ErrorClass::BusinessPanic,
+        ),
+    ];
+    for (error, class) in classes {
+        assert_eq!(ErrorClass::of(&error), class, "{error:?}");
+    }
+}
+
+#[test]
+fn policies_retry_transient_errors_and_keep_failures_as_named() {
+    use AckAction::*;
+
+    assert_eq!(AckPolicy::default(), AckPolicy::DEFAULT);
+    assert_eq!(
+        actions(AckPolicy::DEFAULT),
+        [RetryLater, RetryLater, RetryLater, Ack, Ack, Ack]
+    );
+    assert_eq!(
+        actions(AckPolicy::QUARANTINE_FAILURES),
+        [
+            RetryLater, RetryLater, RetryLater, Quarantine, Quarantine, Quarantine
+        ]
+    );
+    assert_eq!(
+        actions(AckPolicy::DEAD_LETTER_FAILURES),
+        [
+            RetryLater, RetryLater, RetryLater, DeadLetter, DeadLetter, DeadLetter
+        ]
+    );
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Parcel(u8);
+
+impl MessageSer for Parcel {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(Box::new([self.0]))
+    }
+}
+
+impl MessageDe for Parcel {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        match bytes {
+            [id] => Ok(Self(*id)),
+            _ => Err(DeserializeError(anyhow::anyhow!("Parcel is not 1 byte"))),
+        }
+    }
+}
+
+impl AmqpRouting for Parcel {
+    const EXCHANGE: &'static str = "test.parcel";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "parcel.sent";
+}
+
+impl AmqpMessageSend for Parcel {}
+
+/// Processor refusing the first `refusals` parcels as not found, settling them with `$action`
+macro_rules! refuser {
+    ($name:ident, $queue:literal, $action:expr) => {
+        struct $name {
+            refusals: usize,
+            calls: AtomicUsize,
+            quarantine: Option<DatabaseProcessor>,
+        }
+
+        impl $name {
+            fn new(refusals: usize) -> Arc<Self> {
+                Arc::new(Self {
+                    refusals,
+                    calls: AtomicUsize::new(0),
+                    quarantine: None,
+                })
+            }
+
+            fn calls(&self) -> usize {
+                self.calls.load(Ordering::SeqCst)
+            }
+        }
+
+        impl Processor<Parcel, Result<(), kanaeru::Error>> for $name {
+            async fn process(&self, _: Parcel) -> Result<(), kanaeru::Error> {
+                if self.calls.fetch_add(1, Ordering::SeqCst) < self.refusals {
+                    return Err(kanaeru::Error::NotFound);
+                }
+                Ok(())
+            }
+        }
+
+        impl AmqpMessageProcessor<Parcel> for $name {
+            const QUEUE: &'static str = $queue;
+            const ACK_POLICY: AckPolicy = AckPolicy {
+                rejected: $action,
+                ..AckPolicy::DEFAULT
+            };
+            const RETRY: RetryPolicy = RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(50),
+                max_delay: Duration::from_secs(1),
+            };
+
+            fn quarantine(&self) -> Option<&DatabaseProcessor> {
+                self.quarantine.as_ref()
+            }
+        }
+    };
+}
+
+refuser!(Acks, "test.parcel.acked", AckAction::Ack);
+refuser!(Requeues, "test.parcel.requeued", AckAction::Requeue);
+refuser!(RetriesLater, "test.parcel.retried", AckAction::RetryLater);
+refuser!(
+    DeadLetters,
+    "test.parcel.dead_lettered",
+    AckAction::DeadLetter
+);
+refuser!(
+    Quarantines,
+    "test.parcel.quarantined",
+    AckAction::Quarantine
+);
+
+async fn consume<P>(
+    pool: &AmqpPool,
+    processor: &Arc<P>,
+) -> kanaeru::rabbitmq::consumer::ConsumerHandle
+where
+    P: AmqpMessageProcessor<Parcel> + Send + Sync + 'static,
+{
+    let channel = P::ensure_queue(pool).await.unwrap();
+    setup_consumer::<Parcel, _>(&channel, processor.clone())
+        .await
+        .unwrap()
+}
+
+async fn eventually(condition: impl Fn() -> bool) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+/// Whether `queue` and its dead-letter queue are empty, with nothing unacked
+fn settled(broker: &MemoryBroker, queue: &str, dead: usize) -> bool {
+    broker.queue_len(queue) == Some(0)
+        && broker.unacked_len(queue) == 0
+        && broker.queue_len(&dead_letter_queue_name(queue)) == Some(dead)
+}
+
+#[tokio::test]
+async fn ack_drops_the_message() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let processor = Acks::new(usize::MAX);
+    let _consumer = consume(&pool, &processor).await;
+
+    Parcel(1).send(&pool).await.unwrap();
+    eventually(|| processor.calls() == 1 && settled(&broker, Acks::QUEUE, 0)).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(processor.calls(), 1);
+}
+
+#[tokio::test]
+async fn requeue_redelivers_the_message_at_once() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let processor = Requeues::new(1);
+    let _consumer = consume(&pool, &processor).await;
+
+    Parcel(1).send(&pool).await.unwrap();
+    eventually(|| processor.calls() == 2 && settled(&broker, Requeues::QUEUE, 0)).await;
+}
+
+#[tokio::test]
+async fn retry_later_redelivers_the_message_after_a_delay() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let processor = RetriesLater::new(1);
+    let _consumer = consume(&pool, &processor).await;
+
+    Parcel(1).send(&pool).await.unwrap();
+    eventually(|| processor.calls() == 2 && settled(&broker, RetriesLater::QUEUE, 0)).await;
+}
+
+#[tokio::test]
+async fn dead_letter_moves_the_message_to_the_dead_letter_queue() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let processor = DeadLetters::new(usize::MAX);
+    let _consumer = consume(&pool, &processor).await;
+
+    Parcel(1).send(&pool).await.unwrap();
+    eventually(|| settled(&broker, DeadLetters::QUEUE, 1)).await;
+    assert_eq!(processor.calls(), 1);
+    let dead = &broker.messages(&dead_letter_queue_name(DeadLetters::QUEUE))[0];
+    assert_eq!(dead.content, [1]);
+}
+
+#[tokio::test]
+async fn quarantine_without_a_database_dead_letters_the_message() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let processor = Quarantines::new(usize::MAX);
+    let _consumer = consume(&pool, &processor).await;
+
+    Parcel(1).send(&pool).await.unwrap();
+    eventually(|| settled(&broker, Quarantines::QUEUE, 1)).await;
+    assert_eq!(processor.calls(), 1);
+}
+
+/// Needs a Postgres database with the migrations applied in `KANAERU_TEST_DATABASE_URL`. Only
+/// the quarantined messages of [`Quarantines`] are deleted.
+#[tokio::test]
+async fn quarantine_copies_the_message_to_the_database_and_acks_it() {
+    let Ok(url) = std::env::var("KANAERU_TEST_DATABASE_URL") else {
+        eprintln!("KANAERU_TEST_DATABASE_URL is not set, skipping");
+        return;
+    };
+    let db = sqlx::PgPool::connect(&url).await.unwrap();
+    sqlx::query("DELETE FROM kanaeru.quarantine WHERE processor = $1")
+        .bind(Quarantines::QUEUE)
+        .execute(&db)
+        .await
+        .unwrap();
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let processor = Arc::new(Quarantines {
+        refusals: usize::MAX,
+        calls: AtomicUsize::new(0),
+        quarantine: Some(DatabaseProcessor::from_pool(db.clone())),
+    });
+    let _consumer = consume(&pool, &processor).await;
+
+    Parcel(7).send(&pool).await.unwrap();
+    eventually(|| settled(&broker, Quarantines::QUEUE, 0) && processor.calls() == 1).await;
+    let quarantined = QuarantinedMessage::list(&db, Some(Quarantines::QUEUE), false, 0, 10)
+        .await
+        .unwrap();
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(quarantined[0].payload, [7]);
+    assert_eq!(quarantined[0].error_class, ErrorClass::Rejected.as_str());
+}
@@ -0,0 +1,384 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use kanaeru::pool::{ConnectionManager, Pool, PoolConfig, PoolStats, Pooled, PoolingResult};
+
+/// Connection numbered in the order it was created
+#[derive(Debug)]
+struct Conn(usize);
+
+/// Manager whose connections can be slowed down, invalidated or broken by the test
+#[derive(Default)]
+struct Manager {
+    created: AtomicUsize,
+    create_delay: Mutex<Duration>,
+    validate_delay: Mutex<Duration>,
+    fail_create: AtomicBool,
+    invalid: Mutex<HashSet<usize>>,
+    broken: Mutex<HashSet<usize>>,
+}
+
+impl Manager {
+    fn set(value: &Mutex<Duration>, delay: Duration) {
+        *value.lock().unwrap_or_else(PoisonError::into_inner) = delay;
+    }
+
+    fn get(value: &Mutex<Duration>) -> Duration {
+        *value.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn mark(set: &Mutex<HashSet<usize>>, id: usize) {
+        set.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id);
+    }
+
+    fn is_marked(set: &Mutex<HashSet<usize>>, id: usize) -> bool {
+        set.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(&id)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Refused;
+
+impl From<Refused> for kanaeru::Error {
+    fn from(_: Refused) -> Self {
+        kanaeru::Error::Io(anyhow::anyhow!("Connection refused"))
+    }
+}
+
+impl ConnectionManager for Manager {
+    type Connection = Conn;
+    type Error = Refused;
+
+    async fn create(&self) -> Result<Conn, Refused> {
+        tokio::time::sleep(Manager::get(&self.create_delay)).await;
+        if self.fail_create.load(Ordering::SeqCst) {
+            return Err(Refused);
+        }
+        Ok(Conn(self.created.fetch_add(1, Ordering::SeqCst)))
+    }
+
+    async fn is_valid(&self, conn: &mut Conn) -> Result<(), Refused> {
+        tokio::time::sleep(Manager::get(&self.validate_delay)).await;
+        if Manager::is_marked(&self.invalid, conn.0) {
+            return Err(Refused);
+        }
+        Ok(())
+    }
+
+    fn has_broken(&self, conn: &mut Conn) -> bool {
+        Manager::is_marked(&self.broken, conn.0)
+    }
+}
+
+fn pool(config: PoolConfig) -> Pool<Manager> {
+    Pool::with_config(Manager::default(), config)
+}
+
+fn checked_out(result: PoolingResult<Manager>) -> Pooled<Manager> {
+    match result {
+        PoolingResult::Ok(pooled) => pooled,
+        PoolingResult::Timeout => panic!("checkout timed out"),
+        PoolingResult::Closed => panic!("pool is closed"),
+        PoolingResult::FactoryErr(_) => panic!("connection could not be created"),
+        PoolingResult::SemanticsError => panic!("semaphore error"),
+    }
+}
+
+fn id(pooled: &Pooled<Manager>) -> usize {
+    pooled.get_ref().map(|conn| conn.0).unwrap_or(usize::MAX)
+}
+
+async fn checkout_id(pool: &Pool<Manager>) -> usize {
+    id(&checked_out(pool.get().await))
+}
+
+#[tokio::test(start_paused = true)]
+async fn idle_connections_are_reused() {
+    let pool = pool(PoolConfig::new(2));
+    assert_eq!(checkout_id(&pool).await, 0);
+    assert_eq!(checkout_id(&pool).await, 0);
+    assert_eq!(pool.stats().total_created, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn connections_past_max_lifetime_are_replaced() {
+    let pool = pool(PoolConfig::new(2).max_lifetime(Duration::from_secs(60)));
+    let first = checked_out(pool.get().await);
+    tokio::time::sleep(Duration::from_secs(61)).await;
+    // Too old to be put back
+    drop(first);
+    assert_eq!(pool.idle_len(), 0);
+
+    assert_eq!(checkout_id(&pool).await, 1);
+    tokio::time::sleep(Duration::from_secs(61)).await;
+    // Too old to be reused
+    assert_eq!(checkout_id(&pool).await, 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn connections_idle_for_too_long_are_replaced() {
+    let pool = pool(PoolConfig::new(2).idle_timeout(Duration::from_secs(10)));
+    assert_eq!(checkout_id(&pool).await, 0);
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(checkout_id(&pool).await, 0);
+    tokio::time::sleep(Duration::from_secs(11)).await;
+    assert_eq!(checkout_id(&pool).await, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn reaper_drops_stale_idle_connections() {
+    let pool = pool(
+        PoolConfig::new(2)
+            .idle_timeout(Duration::from_secs(10))
+            .reap_interval(Duration::from_secs(3)),
+    );
+    drop(checked_out(pool.get().await));
+    assert_eq!(pool.idle_len(), 1);
+    tokio::time::sleep(Duration::from_secs(7)).await;
+    assert_eq!(pool.idle_len(), 1);
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    assert_eq!(pool.idle_len(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn invalid_and_broken_connections_are_dropped() {
+    let pool = pool(PoolConfig::new(2));
+    assert_eq!(checkout_id(&pool).await, 0);
+    Manager::mark(&pool.manager().invalid, 0);
+    assert_eq!(checkout_id(&pool).await, 1);
+
+    let pooled = checked_out(pool.get().await);
+    Manager::mark(&pool.manager().broken, 1);
+    drop(pooled);
+    assert_eq!(pool.idle_len(), 0);
+    assert_eq!(checkout_id(&pool).await, 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn connection_is_kept_when_validation_is_cancelled() {
+    let pool = pool(PoolConfig::new(1));
+    drop(checked_out(pool.get().await));
+    Manager::set(&pool.manager().validate_delay, Duration::from_secs(5));
+    assert!(matches!(
+        pool.get_timeout(Duration::from_secs(1)).await,
+        PoolingResult::Timeout
+    ));
+    let stats = pool.stats();
+    assert_eq!((stats.idle, stats.in_use), (1, 0));
+
+    Manager::set(&pool.manager().validate_delay, Duration::ZERO);
+    assert_eq!(checkout_id(&pool).await, 0);
+    assert_eq!(pool.stats().total_created, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn get_timeout_gives_up_waiting_for_capacity() {
+    let pool = pool(PoolConfig::new(1));
+    let held = checked_out(pool.get().await);
+    assert!(matches!(
+        pool.get_timeout(Duration::from_secs(1)).await,
+        PoolingResult::Timeout
+    ));
+    assert!(matches!(pool.try_get().await, PoolingResult::Timeout));
+    assert_eq!(pool.stats().waiters, 0);
+
+    drop(held);
+    assert_eq!(
+        id(&checked_out(pool.get_timeout(Duration::from_secs(1)).await)),
+        0
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn timeouts_and_closed_pools_are_distinct_errors() {
+    let pool = pool(PoolConfig::new(1));
+    let held = checked_out(pool.get().await);
+    let timeout: Result<Pooled<Manager>, kanaeru::Error> = pool.try_get().await.into();
+    let Err(timeout) = timeout else {
+        panic!("checkout did not time out");
+    };
+    assert!(matches!(timeout, kanaeru::Error::PoolTimeout));
+    assert_eq!(
+        tonic::Status::from(&timeout).code(),
+        tonic::Code::DeadlineExceeded
+    );
+
+    drop(held);
+    pool.close(Duration::from_secs(1)).await;
+    let closed: Result<Pooled<Manager>, kanaeru::Error> = pool.get().await.into();
+    let Err(closed) = closed else {
+        panic!("checkout from a closed pool succeeded");
+    };
+    assert!(matches!(closed, kanaeru::Error::PoolClosed));
+    assert_eq!(
+        tonic::Status::from(&closed).code(),
+        tonic::Code::Unavailable
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn cancelled_waiter_does_not_leak_capacity() {
+    let pool = pool(PoolConfig::new(1));
+    let held = checked_out(pool.get().await);
+    let waiter = tokio::spawn({
+        let pool = pool.clone();
+        async move { matches!(pool.get().await, PoolingResult::Ok(_)) }
+    });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(pool.stats().waiters, 1);
+    waiter.abort();
+    let _ = waiter.await;
+    assert_eq!(pool.stats().waiters, 0);
+
+    drop(held);
+    assert_eq!(checkout_id(&pool).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn connection_created_for_a_cancelled_checkout_is_parked() {
+    let pool = pool(PoolConfig::new(1));
+    Manager::set(&pool.manager().create_delay, Duration::from_secs(5));
+    assert!(matches!(
+        pool.get_timeout(Duration::from_secs(1)).await,
+        PoolingResult::Timeout
+    ));
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    let stats = pool.stats();
+    assert_eq!((stats.idle, stats.in_use, stats.total_created), (1, 0, 1));
+    assert_eq!(checkout_id(&pool).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn factory_errors_are_returned() {
+    let pool = pool(PoolConfig::new(1));
+    pool.manager().fail_create.store(true, Ordering::SeqCst);
+    assert!(matches!(
+        pool.get().await,
+        PoolingResult::FactoryErr(Refused)
+    ));
+    // The capacity of the failed checkout is released
+    pool.manager().fail_create.store(false, Ordering::SeqCst);
+    assert_eq!(checkout_id(&pool).await, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn stats_count_connections_and_waits() {
+    let pool = pool(PoolConfig::new(1));
+    let held = checked_out(pool.get().await);
+    let waiter = tokio::spawn({
+        let pool = pool.clone();
+        async move { id(&checked_out(pool.get().await)) }
+    });
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let stats = pool.stats();
+    assert_eq!((stats.in_use, stats.idle, stats.waiters), (1, 0, 1));
+
+    drop(held);
+    assert_eq!(waiter.await.ok(), Some(0));
+    drop(checked_out(pool.try_get().await));
+    pool.manager().fail_create.store(true, Ordering::SeqCst);
+    Manager::mark(&pool.manager().invalid, 0);
+    assert!(matches!(pool.try_get().await, PoolingResult::FactoryErr(_)));
+
+    let stats = pool.stats();
+    assert_eq!(
+        stats,
+        PoolStats {
+            capacity: 1,
+            in_use: 0,
+            idle: 0,
+            waiters: 0,
+            total_created: 1,
+            factory_failures: 1,
+            // 2s over the 2 checkouts with `get`, only one of which waited. Those with `try_get`
+            // do not count.
+            average_wait: Duration::from_secs(1),
+        }
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn min_idle_is_filled_and_refilled() {
+    let pool = pool(PoolConfig::new(4).min_idle(2));
+    assert!(pool.warm_up().await.is_ok());
+    let stats = pool.stats();
+    assert_eq!((stats.idle, stats.total_created), (2, 2));
+
+    let mut pooled = checked_out(pool.get().await);
+    pooled.disconnect();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(pool.idle_len(), 2);
+    assert_eq!(pool.stats().total_created, 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn reaper_refills_min_idle() {
+    let pool = pool(
+        PoolConfig::new(4)
+            .min_idle(2)
+            .max_lifetime(Duration::from_secs(10))
+            .reap_interval(Duration::from_secs(3)),
+    );
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(pool.stats().total_created, 2);
+    tokio::time::sleep(Duration::from_secs(13)).await;
+    let stats = pool.stats();
+    assert_eq!((stats.idle, stats.total_created), (2, 4));
+}
+
+#[tokio::test(start_paused = true)]
+async fn close_waits_for_checked_out_connections() {
+    let pool = pool(PoolConfig::new(2));
+    drop(checked_out(pool.get().await));
+    let held = checked_out(pool.get().await);
+    let closing = tokio::spawn({
+        let pool = pool.clone();
+        async move { pool.close(Duration::from_secs(10)).await }
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(!closing.is_finished());
+    assert!(matches!(pool.get().await, PoolingResult::Closed));
+    assert!(matches!(pool.try_get().await, PoolingResult::Closed));
+
+    drop(held);
+    assert_eq!(closing.await.ok(), Some(true));
+    assert_eq!(pool.idle_len(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn close_gives_up_after_its_timeout() {
+    let pool = pool(PoolConfig::new(2));
+    let held = checked_out(pool.get().await);
+    assert!(!pool.close(Duration::from_secs(1)).await);
+    // Returned too late to be reused
+    drop(held);
+    assert_eq!(pool.stats().idle, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn close_waits_for_connections_being_created() {
+    let pool = pool(PoolConfig::new(2));
+    Manager::set(&pool.manager().create_delay, Duration::from_secs(5));
+    let checkout = tokio::spawn({
+        let pool = pool.clone();
+        async move { matches!(pool.get().await, PoolingResult::Closed) }
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let closing = tokio::spawn({
+        let pool = pool.clone();
+        async move { pool.close(Duration::from_secs(10)).await }
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(!closing.is_finished());
+
+    assert_eq!(closing.await.ok(), Some(true));
+    assert_eq!(checkout.await.ok(), Some(true));
+    let stats = pool.stats();
+    assert_eq!((stats.idle, stats.in_use, stats.total_created), (0, 0, 1));
+}
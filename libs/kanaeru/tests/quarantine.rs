@@ -0,0 +1,168 @@
+//! Needs a Postgres database with the migrations applied in `KANAERU_TEST_DATABASE_URL`.
+//! Only the quarantined messages of the processors of these tests are deleted.
+
+use amqprs::channel::QueueDeclareArguments;
+use amqprs::{BasicProperties, FieldTable, FieldValue};
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::policy::ErrorClass;
+use kanaeru::rabbitmq::quarantine::{QUARANTINE_ID_HEADER, Quarantine, QuarantinedMessage};
+use kanaeru::rabbitmq::retry::{ATTEMPT_HEADER, attempt};
+use kanaeru::sqlx::DatabaseProcessor;
+
+async fn database(processor: &str) -> Option<sqlx::PgPool> {
+    let Ok(url) = std::env::var("KANAERU_TEST_DATABASE_URL") else {
+        eprintln!("KANAERU_TEST_DATABASE_URL is not set, skipping");
+        return None;
+    };
+    let db = sqlx::PgPool::connect(&url).await.unwrap();
+    sqlx::query("DELETE FROM kanaeru.quarantine WHERE processor = $1")
+        .bind(processor)
+        .execute(&db)
+        .await
+        .unwrap();
+    Some(db)
+}
+
+/// Properties of a delivery on its third attempt
+fn delivered() -> BasicProperties {
+    let mut headers = FieldTable::new();
+    headers.insert(
+        "x-tenant".try_into().unwrap(),
+        FieldValue::S("acme".try_into().unwrap()),
+    );
+    headers.insert(ATTEMPT_HEADER.try_into().unwrap(), FieldValue::I(3));
+    let mut properties = BasicProperties::default();
+    properties
+        .with_headers(headers)
+        .with_message_id("order-1")
+        .with_correlation_id("request-1");
+    properties
+}
+
+async fn insert(db: &sqlx::PgPool, processor: &str, payload: &[u8]) -> i64 {
+    QuarantinedMessage::insert(
+        db,
+        processor,
+        "test.order",
+        "order.placed",
+        &delivered(),
+        payload,
+        "Order not found",
+        ErrorClass::Rejected,
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn quarantined_messages_are_listed_and_replayed_to_their_processor() {
+    const PROCESSOR: &str = "test.quarantine.replayed";
+    let Some(db) = database(PROCESSOR).await else {
+        return;
+    };
+    let broker = MemoryBroker::start().await.unwrap();
+    let connection = broker.connect().await.unwrap();
+    let channel = connection.open_channel(None).await.unwrap();
+    channel
+        .queue_declare(QueueDeclareArguments::durable_client_named(PROCESSOR))
+        .await
+        .unwrap();
+    let quarantine = Quarantine::new(
+        DatabaseProcessor::new(db.clone()),
+        broker.pool().await.unwrap(),
+    );
+
+    let id = insert(&db, PROCESSOR, &[1]).await;
+    let listed = quarantine
+        .list(Some(PROCESSOR), false, 0, 10)
+        .await
+        .unwrap();
+    assert_eq!(listed.len(), 1);
+    let message = &listed[0];
+    assert_eq!(message.id, id);
+    assert_eq!(message.exchange, "test.order");
+    assert_eq!(message.routing_key, "order.placed");
+    assert_eq!(message.payload, [1]);
+    assert_eq!(message.error, "Order not found");
+    assert_eq!(message.error_class, "rejected");
+    assert_eq!(message.correlation_id.as_deref(), Some("request-1"));
+    assert_eq!(message.replayed_at, None);
+    let stored = message.headers().unwrap();
+    assert_eq!(
+        stored.get(&ATTEMPT_HEADER.try_into().unwrap()),
+        Some(&FieldValue::I(3))
+    );
+
+    quarantine.edit_payload(id, &[2]).await.unwrap();
+    quarantine.replay(id).await.unwrap();
+
+    let replayed = broker.messages(PROCESSOR);
+    assert_eq!(replayed.len(), 1);
+    let replayed = &replayed[0];
+    assert_eq!(replayed.exchange, "");
+    assert_eq!(replayed.content, [2]);
+    assert_eq!(
+        replayed.properties.message_id().map(String::as_str),
+        Some("order-1")
+    );
+    // Replays start over from the first attempt, and tell where they come from
+    let headers = replayed.properties.headers().unwrap();
+    assert!(headers.get(&ATTEMPT_HEADER.try_into().unwrap()).is_none());
+    assert_eq!(attempt(&replayed.properties), 1);
+    assert!(headers.get(&"x-tenant".try_into().unwrap()).is_some());
+    assert_eq!(
+        headers.get(&QUARANTINE_ID_HEADER.try_into().unwrap()),
+        Some(&FieldValue::l(id))
+    );
+
+    assert!(quarantine.get(id).await.unwrap().replayed_at.is_some());
+    assert!(
+        quarantine
+            .list(Some(PROCESSOR), false, 0, 10)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    assert_eq!(
+        quarantine
+            .list(Some(PROCESSOR), true, 0, 10)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn messages_are_listed_by_page_and_deleted() {
+    const PROCESSOR: &str = "test.quarantine.paged";
+    let Some(db) = database(PROCESSOR).await else {
+        return;
+    };
+    let first = insert(&db, PROCESSOR, &[1]).await;
+    let second = insert(&db, PROCESSOR, &[2]).await;
+
+    let page = QuarantinedMessage::list(&db, Some(PROCESSOR), false, 0, 1)
+        .await
+        .unwrap();
+    assert_eq!(page.iter().map(|m| m.id).collect::<Vec<_>>(), [first]);
+    let page = QuarantinedMessage::list(&db, Some(PROCESSOR), false, first, 10)
+        .await
+        .unwrap();
+    assert_eq!(page.iter().map(|m| m.id).collect::<Vec<_>>(), [second]);
+
+    assert!(QuarantinedMessage::delete(&db, first).await.unwrap());
+    assert!(!QuarantinedMessage::delete(&db, first).await.unwrap());
+    assert!(
+        QuarantinedMessage::find(&db, first)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        QuarantinedMessage::find(&db, second)
+            .await
+            .unwrap()
+            .is_some()
+    );
+}
@@ -0,0 +1,169 @@
+//! Needs a redis server in `KANAERU_TEST_REDIS_URL` for the limiters. Keys are random, so any
+//! instance does.
+
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::Duration;
+
+use kanaeru::redis::{
+    RateLimitKey, RateLimiter, RedisConnection, RedisKey, SlidingWindow, TokenBucket,
+};
+
+async fn connection() -> Option<RedisConnection> {
+    let Ok(url) = std::env::var("KANAERU_TEST_REDIS_URL") else {
+        eprintln!("KANAERU_TEST_REDIS_URL is not set, skipping");
+        return None;
+    };
+    let client = redis::Client::open(url).unwrap();
+    Some(client.get_multiplexed_async_connection().await.unwrap())
+}
+
+fn random_key() -> RateLimitKey {
+    RateLimitKey::new("test").per("run", uuid::Uuid::new_v4())
+}
+
+fn raw(key: RateLimitKey) -> Vec<u8> {
+    RedisKey::from(key).0.into_vec()
+}
+
+#[test]
+fn key_components_are_escaped() {
+    let key = RateLimitKey::new("login")
+        .ip(IpAddr::V6(Ipv6Addr::LOCALHOST))
+        .account("a=b%c");
+    assert_eq!(
+        raw(key),
+        b"rate_limit:login:ip=%3A%3A1:account=a%3Db%25c".to_vec()
+    );
+
+    // Separators in values cannot forge other dimensions or limits
+    assert_ne!(
+        raw(RateLimitKey::new("login").per("ip", "1:account=x")),
+        raw(RateLimitKey::new("login")
+            .ip([0, 0, 0, 1].into())
+            .account("x")),
+    );
+    assert_ne!(
+        raw(RateLimitKey::new("login:ip=1")),
+        raw(RateLimitKey::new("login").per("ip", 1)),
+    );
+}
+
+#[test]
+fn rate_limits_map_to_a_status() {
+    let status = tonic::Status::from(kanaeru::Error::RateLimited(Some(Duration::from_millis(
+        1_500,
+    ))));
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    assert_eq!(status.metadata().get("retry-after").unwrap(), "2");
+
+    // Hits that are never allowed have no time to retry at
+    let status = tonic::Status::from(kanaeru::Error::RateLimited(None));
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    assert!(status.metadata().get("retry-after").is_none());
+}
+
+#[tokio::test]
+async fn sliding_windows_allow_limit_hits_per_window() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let limiter = SlidingWindow::new(3, Duration::from_millis(500));
+    let key = random_key();
+
+    for remaining in [2, 1, 0] {
+        let limit = limiter.check(&mut conn, key.clone()).await.unwrap();
+        assert!(limit.allowed);
+        assert_eq!(limit.remaining, remaining);
+        assert_eq!(limit.retry_after, Some(Duration::ZERO));
+    }
+    let denied = limiter.hit(&mut conn, key.clone(), 1).await.unwrap();
+    assert!(!denied.allowed);
+    assert_eq!(denied.remaining, 0);
+    let retry_after = denied.retry_after.unwrap();
+    assert!(
+        retry_after > Duration::ZERO && retry_after <= Duration::from_millis(500),
+        "{retry_after:?}"
+    );
+    let error = limiter.check(&mut conn, key.clone()).await.unwrap_err();
+    assert!(
+        matches!(error, kanaeru::Error::RateLimited(Some(_))),
+        "{error:?}"
+    );
+
+    tokio::time::sleep(retry_after + Duration::from_millis(20)).await;
+    assert!(limiter.check(&mut conn, key.clone()).await.unwrap().allowed);
+
+    limiter.reset(&mut conn, key.clone()).await.unwrap();
+    let limit = limiter.hit(&mut conn, key, 3).await.unwrap();
+    assert!(limit.allowed);
+    assert_eq!(limit.remaining, 0);
+}
+
+#[tokio::test]
+async fn token_buckets_refill_over_time() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let limiter = TokenBucket::new(2, Duration::from_millis(200));
+    let key = random_key();
+
+    for remaining in [1, 0] {
+        let limit = limiter.check(&mut conn, key.clone()).await.unwrap();
+        assert!(limit.allowed);
+        assert_eq!(limit.remaining, remaining);
+    }
+    let denied = limiter.hit(&mut conn, key.clone(), 1).await.unwrap();
+    assert!(!denied.allowed);
+    let retry_after = denied.retry_after.unwrap();
+    assert!(
+        retry_after > Duration::ZERO && retry_after <= Duration::from_millis(200),
+        "{retry_after:?}"
+    );
+
+    tokio::time::sleep(retry_after + Duration::from_millis(20)).await;
+    assert!(limiter.check(&mut conn, key.clone()).await.unwrap().allowed);
+    assert!(!limiter.hit(&mut conn, key, 1).await.unwrap().allowed);
+}
+
+#[tokio::test]
+async fn hits_over_the_limit_are_never_allowed() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+
+    let error = SlidingWindow::new(0, Duration::from_secs(1))
+        .check(&mut conn, random_key())
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, kanaeru::Error::RateLimited(None)),
+        "{error:?}"
+    );
+    let error = TokenBucket::new(0, Duration::from_secs(1))
+        .check(&mut conn, random_key())
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, kanaeru::Error::RateLimited(None)),
+        "{error:?}"
+    );
+
+    // Denied hits are not counted, the hits left are still reported
+    let key = random_key();
+    let window = SlidingWindow::new(3, Duration::from_secs(10));
+    assert!(window.check(&mut conn, key.clone()).await.unwrap().allowed);
+    let denied = window.hit(&mut conn, key.clone(), 4).await.unwrap();
+    assert!(!denied.allowed);
+    assert_eq!(denied.remaining, 2);
+    assert_eq!(denied.retry_after, None);
+    assert!(window.hit(&mut conn, key, 2).await.unwrap().allowed);
+
+    let key = random_key();
+    let bucket = TokenBucket::new(3, Duration::from_secs(10));
+    assert!(bucket.check(&mut conn, key.clone()).await.unwrap().allowed);
+    let denied = bucket.hit(&mut conn, key.clone(), 4).await.unwrap();
+    assert!(!denied.allowed);
+    assert_eq!(denied.remaining, 2);
+    assert_eq!(denied.retry_after, None);
+    assert!(bucket.hit(&mut conn, key, 2).await.unwrap().allowed);
+}
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use amqprs::channel::{QueueDeclareArguments, QueueDeleteArguments};
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::retry::{ATTEMPT_HEADER, RetryPolicy, dead_letter_queue_name};
+use kanaeru::rabbitmq::{
+    AmqpExchangeType, AmqpMessageProcessor, AmqpMessageSend, AmqpRouting, setup_consumer,
+};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use kanau::processor::Processor;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Job(u8);
+
+impl MessageSer for Job {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(Box::new([self.0]))
+    }
+}
+
+impl MessageDe for Job {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        match bytes {
+            [id] => Ok(Self(*id)),
+            _ => Err(DeserializeError(anyhow::anyhow!("Job is not 1 byte"))),
+        }
+    }
+}
+
+impl AmqpRouting for Job {
+    const EXCHANGE: &'static str = "test.job";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "job.created";
+}
+
+impl AmqpMessageSend for Job {}
+
+/// Fails with a transient error the first `fail_first` times it is called
+struct Flaky {
+    fail_first: u32,
+    calls: AtomicU32,
+    processed: mpsc::UnboundedSender<Job>,
+}
+
+impl Processor<Job, Result<(), kanaeru::Error>> for Flaky {
+    async fn process(&self, job: Job) -> Result<(), kanaeru::Error> {
+        if self.calls.fetch_add(1, Ordering::SeqCst) < self.fail_first {
+            return Err(kanaeru::Error::Io(anyhow::anyhow!("Temporarily down")));
+        }
+        let _ = self.processed.send(job);
+        Ok(())
+    }
+}
+
+impl AmqpMessageProcessor<Job> for Flaky {
+    const QUEUE: &'static str = "test.job.created";
+    const RETRY: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(50),
+        max_delay: Duration::from_secs(1),
+    };
+}
+
+struct Setup {
+    broker: MemoryBroker,
+    pool: kanaeru::rabbitmq::AmqpPool,
+    flaky: Arc<Flaky>,
+    processed: mpsc::UnboundedReceiver<Job>,
+}
+
+async fn setup(fail_first: u32) -> Setup {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let (sender, processed) = mpsc::unbounded_channel();
+    let flaky = Arc::new(Flaky {
+        fail_first,
+        calls: AtomicU32::new(0),
+        processed: sender,
+    });
+    Setup {
+        broker,
+        pool,
+        flaky,
+        processed,
+    }
+}
+
+async fn consume(setup: &Setup) -> kanaeru::rabbitmq::consumer::ConsumerHandle {
+    let channel = Flaky::ensure_queue(&setup.pool).await.unwrap();
+    setup_consumer::<Job, _>(&channel, setup.flaky.clone())
+        .await
+        .unwrap()
+}
+
+async fn eventually(condition: impl Fn() -> bool) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn failed_messages_are_retried_until_they_succeed() {
+    let mut setup = setup(2).await;
+    let _consumer = consume(&setup).await;
+    Job(1).send(&setup.pool).await.unwrap();
+
+    let job = tokio::time::timeout(Duration::from_secs(5), setup.processed.recv()).await;
+    assert_eq!(job.unwrap(), Some(Job(1)));
+    assert_eq!(setup.flaky.calls.load(Ordering::SeqCst), 3);
+    assert_eq!(
+        setup
+            .broker
+            .queue_len(&dead_letter_queue_name(Flaky::QUEUE)),
+        Some(0)
+    );
+}
+
+#[tokio::test]
+async fn messages_out_of_attempts_are_dead_lettered() {
+    let setup = setup(u32::MAX).await;
+    let _consumer = consume(&setup).await;
+    Job(1).send(&setup.pool).await.unwrap();
+
+    let dead_queue = dead_letter_queue_name(Flaky::QUEUE);
+    eventually(|| setup.broker.queue_len(&dead_queue) == Some(1)).await;
+    assert_eq!(setup.flaky.calls.load(Ordering::SeqCst), 3);
+    let dead = &setup.broker.messages(&dead_queue)[0];
+    assert_eq!(dead.content, [1]);
+    assert_eq!(kanaeru::rabbitmq::retry::attempt(&dead.properties), 3);
+    assert!(
+        dead.properties
+            .headers()
+            .is_some_and(|headers| { headers.get(&ATTEMPT_HEADER.try_into().unwrap()).is_some() })
+    );
+}
+
+#[tokio::test]
+async fn message_is_requeued_when_its_retry_can_not_be_published() {
+    let mut setup = setup(1).await;
+    let _consumer = consume(&setup).await;
+    let connection = setup.broker.connect().await.unwrap();
+    let channel = connection.open_channel(None).await.unwrap();
+    channel
+        .queue_delete(QueueDeleteArguments::new("test.job.created.retry.1"))
+        .await
+        .unwrap();
+    Job(1).send(&setup.pool).await.unwrap();
+
+    // The retry queue is gone, so the failed delivery is requeued instead of acked and lost
+    let job = tokio::time::timeout(Duration::from_secs(5), setup.processed.recv()).await;
+    assert_eq!(job.unwrap(), Some(Job(1)));
+    assert_eq!(setup.flaky.calls.load(Ordering::SeqCst), 2);
+    assert_eq!(setup.broker.queue_len(Flaky::QUEUE), Some(0));
+}
+
+#[tokio::test]
+async fn queue_declared_without_dead_letter_arguments_is_kept() {
+    let mut setup = setup(0).await;
+    let connection = setup.broker.connect().await.unwrap();
+    let channel = connection.open_channel(None).await.unwrap();
+    channel
+        .queue_declare(QueueDeclareArguments::durable_client_named(Flaky::QUEUE))
+        .await
+        .unwrap();
+
+    let _consumer = consume(&setup).await;
+    Job(1).send(&setup.pool).await.unwrap();
+    let job = tokio::time::timeout(Duration::from_secs(5), setup.processed.recv()).await;
+    assert_eq!(job.unwrap(), Some(Job(1)));
+}
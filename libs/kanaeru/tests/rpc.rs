@@ -0,0 +1,192 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use kanaeru::rabbitmq::consumer::{ConsumerConfig, ConsumerHandle};
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::rpc::{AmqpRpcClient, AmqpRpcProcessor, AmqpRpcRequest, setup_rpc_server};
+use kanaeru::rabbitmq::{AmqpExchangeType, AmqpMessageSend, AmqpPool, AmqpRouting};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use kanau::processor::Processor;
+
+macro_rules! byte {
+    ($name:ident) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        struct $name(u8);
+
+        impl MessageSer for $name {
+            type SerError = SerializeError;
+
+            fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+                Ok(Box::new([self.0]))
+            }
+        }
+
+        impl MessageDe for $name {
+            type DeError = DeserializeError;
+
+            fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+                match bytes {
+                    [n] => Ok(Self(*n)),
+                    _ => Err(DeserializeError(anyhow::anyhow!("Message is not 1 byte"))),
+                }
+            }
+        }
+    };
+}
+
+byte!(Double);
+byte!(Doubled);
+byte!(Halve);
+
+impl AmqpRouting for Double {
+    const EXCHANGE: &'static str = "test.rpc";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "double";
+}
+
+impl AmqpMessageSend for Double {}
+
+impl AmqpRpcRequest for Double {
+    type Response = Doubled;
+}
+
+impl AmqpRouting for Halve {
+    const EXCHANGE: &'static str = "test.rpc";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "halve";
+}
+
+impl AmqpMessageSend for Halve {}
+
+impl AmqpRpcRequest for Halve {
+    type Response = Halve;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+}
+
+/// Doubles numbers small enough to fit a byte once doubled
+struct Doubler;
+
+impl Processor<Double, Result<Doubled, kanaeru::Error>> for Doubler {
+    async fn process(&self, Double(n): Double) -> Result<Doubled, kanaeru::Error> {
+        let doubled = n.checked_mul(2).ok_or(kanaeru::Error::InvalidInput)?;
+        Ok(Doubled(doubled))
+    }
+}
+
+impl AmqpRpcProcessor<Double> for Doubler {
+    const QUEUE: &'static str = "test.rpc.double";
+}
+
+/// Only declares its queue, no test serves it
+struct Halver;
+
+impl Processor<Halve, Result<Halve, kanaeru::Error>> for Halver {
+    async fn process(&self, Halve(n): Halve) -> Result<Halve, kanaeru::Error> {
+        Ok(Halve(n / 2))
+    }
+}
+
+impl AmqpRpcProcessor<Halve> for Halver {
+    const QUEUE: &'static str = "test.rpc.halve";
+}
+
+async fn serve(pool: &AmqpPool) -> ConsumerHandle {
+    let channel = Doubler::ensure_queue(pool).await.unwrap();
+    setup_rpc_server::<Double, _>(&channel, Arc::new(Doubler), ConsumerConfig::new())
+        .await
+        .unwrap()
+}
+
+async fn eventually(condition: impl Fn() -> bool) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn requests_are_answered() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let _server = serve(&pool).await;
+    let connection = broker.connect().await.unwrap();
+    let client = AmqpRpcClient::connect(&connection).await.unwrap();
+
+    assert_eq!(Double(21).call(&client).await.unwrap(), Doubled(42));
+    assert_eq!(broker.queue_len(Doubler::QUEUE), Some(0));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_requests_get_their_own_replies() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let _server = serve(&pool).await;
+    let connection = broker.connect().await.unwrap();
+    let client = Arc::new(AmqpRpcClient::connect(&connection).await.unwrap());
+
+    let calls: Vec<_> = (0..64u8)
+        .map(|n| {
+            let client = client.clone();
+            tokio::spawn(async move { (n, Double(n).call(&client).await) })
+        })
+        .collect();
+    for call in calls {
+        let (n, doubled) = call.await.unwrap();
+        assert_eq!(doubled.unwrap(), Doubled(n * 2));
+    }
+}
+
+#[tokio::test]
+async fn server_errors_are_sent_back() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let _server = serve(&pool).await;
+    let connection = broker.connect().await.unwrap();
+    let client = AmqpRpcClient::connect(&connection).await.unwrap();
+
+    let overflow = Double(200).call(&client).await;
+    assert!(
+        matches!(overflow, Err(kanaeru::Error::InvalidInput)),
+        "{overflow:?}"
+    );
+    // The server keeps answering
+    assert_eq!(Double(1).call(&client).await.unwrap(), Doubled(2));
+}
+
+#[tokio::test]
+async fn requests_time_out_without_a_server() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    Halver::ensure_queue(&pool).await.unwrap();
+    let connection = broker.connect().await.unwrap();
+    let client = AmqpRpcClient::connect(&connection).await.unwrap();
+
+    let unanswered = Halve(4).call(&client).await;
+    assert!(
+        matches!(&unanswered, Err(kanaeru::Error::Io(e)) if e.to_string().contains("Timed out")),
+        "{unanswered:?}"
+    );
+    // The request expires with its timeout, so a server started later does not answer it
+    eventually(|| broker.queue_len(Halver::QUEUE) == Some(0)).await;
+}
+
+#[tokio::test]
+async fn unroutable_requests_fail_at_once() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    // Declares the exchange, but nothing is bound for halving
+    let _server = serve(&pool).await;
+    let connection = broker.connect().await.unwrap();
+    let client = AmqpRpcClient::connect(&connection).await.unwrap();
+
+    let unroutable = Halve(4).call(&client).await;
+    assert!(
+        matches!(unroutable, Err(kanaeru::Error::Unroutable(_))),
+        "{unroutable:?}"
+    );
+    assert_eq!(Double(2).call(&client).await.unwrap(), Doubled(4));
+}
@@ -0,0 +1,191 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::{
+    AmqpExchangeType, AmqpMessageProcessor, AmqpMessageSend, AmqpRouting, setup_consumer,
+};
+use kanaeru::schema::{
+    self, DEFAULT_CONTENT_TYPE, INITIAL_VERSION, JSON_CONTENT_TYPE, Payload, VERSION_MAGIC,
+};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use kanau::processor::Processor;
+use tokio::sync::mpsc;
+
+#[test]
+fn only_versions_after_the_initial_one_are_prefixed() {
+    let bytes: Box<[u8]> = Box::new([1, 2, 3]);
+    assert_eq!(
+        schema::prefix_version(bytes.clone(), INITIAL_VERSION),
+        bytes
+    );
+    assert_eq!(
+        schema::strip_version(&bytes).unwrap(),
+        (INITIAL_VERSION, &[1, 2, 3][..])
+    );
+
+    let prefixed = schema::prefix_version(bytes, 3);
+    assert!(prefixed.starts_with(&VERSION_MAGIC));
+    assert_eq!(
+        schema::strip_version(&prefixed).unwrap(),
+        (3, &[1, 2, 3][..])
+    );
+    assert!(schema::strip_version(&prefixed[..VERSION_MAGIC.len() + 2]).is_err());
+}
+
+/// Name, stored as bytes in version 2 and as a length-prefixed string in version 1
+#[derive(Debug, PartialEq, Eq)]
+struct Name(String);
+
+impl MessageDe for Name {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        String::from_utf8(bytes.to_vec())
+            .map(Self)
+            .map_err(|e| DeserializeError(e.into()))
+    }
+}
+
+fn upcast_name(payload: Payload<'_>) -> Result<Name, DeserializeError> {
+    match (payload.version, payload.bytes) {
+        (1, [len, rest @ ..]) if usize::from(*len) == rest.len() => Name::from_bytes(rest),
+        _ => Err(payload.unsupported()),
+    }
+}
+
+fn payload<'a>(version: u32, content_type: Option<&'a str>, bytes: &'a [u8]) -> Payload<'a> {
+    Payload {
+        version,
+        content_type,
+        bytes,
+    }
+}
+
+#[test]
+fn payloads_of_other_versions_are_upcast() {
+    let decode = |payload| schema::decode(payload, 2, JSON_CONTENT_TYPE, upcast_name);
+
+    assert_eq!(
+        decode(payload(2, Some(JSON_CONTENT_TYPE), b"ada")).unwrap(),
+        Name("ada".into())
+    );
+    // Without a content type, or with the default one, the payload is taken as current
+    assert_eq!(
+        decode(payload(2, None, b"ada")).unwrap(),
+        Name("ada".into())
+    );
+    assert_eq!(
+        decode(payload(2, Some(DEFAULT_CONTENT_TYPE), b"ada")).unwrap(),
+        Name("ada".into())
+    );
+    assert_eq!(
+        decode(payload(1, None, b"\x03ada")).unwrap(),
+        Name("ada".into())
+    );
+    assert!(matches!(
+        decode(payload(2, Some("application/x-rkyv"), b"ada")),
+        Err(kanaeru::Error::DeserializeError(_))
+    ));
+    assert!(matches!(
+        schema::decode(
+            payload(1, None, b"\x03ada"),
+            2,
+            JSON_CONTENT_TYPE,
+            schema::no_upcast::<Name>
+        ),
+        Err(kanaeru::Error::DeserializeError(_))
+    ));
+}
+
+#[test]
+fn payloads_of_newer_versions_are_retried() {
+    let decoded = schema::decode(payload(3, None, b"ada"), 2, JSON_CONTENT_TYPE, upcast_name);
+    // An io error is retried, so a newer reader can take the message instead
+    assert!(matches!(decoded, Err(kanaeru::Error::Io(_))));
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RenamedV1(String);
+
+impl MessageSer for RenamedV1 {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        let mut bytes = vec![u8::try_from(self.0.len()).map_err(|e| SerializeError(e.into()))?];
+        bytes.extend_from_slice(self.0.as_bytes());
+        Ok(bytes.into_boxed_slice())
+    }
+}
+
+impl AmqpRouting for RenamedV1 {
+    const EXCHANGE: &'static str = "test.user";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "user.renamed";
+}
+
+impl AmqpMessageSend for RenamedV1 {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Renamed(String);
+
+impl MessageSer for Renamed {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(self.0.into_bytes().into_boxed_slice())
+    }
+}
+
+impl MessageDe for Renamed {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        Name::from_bytes(bytes).map(|name| Self(name.0))
+    }
+}
+
+impl AmqpRouting for Renamed {
+    const EXCHANGE: &'static str = "test.user";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "user.renamed";
+}
+
+impl AmqpMessageSend for Renamed {
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn upcast(payload: Payload<'_>) -> Result<Self, DeserializeError> {
+        upcast_name(payload).map(|name| Self(name.0))
+    }
+}
+
+struct Recorder(mpsc::UnboundedSender<Renamed>);
+
+impl Processor<Renamed, Result<(), kanaeru::Error>> for Recorder {
+    async fn process(&self, renamed: Renamed) -> Result<(), kanaeru::Error> {
+        let _ = self.0.send(renamed);
+        Ok(())
+    }
+}
+
+impl AmqpMessageProcessor<Renamed> for Recorder {
+    const QUEUE: &'static str = "test.user.renamed";
+}
+
+#[tokio::test]
+async fn messages_published_with_an_older_schema_are_upcast() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let pool = broker.pool().await.unwrap();
+    let (sender, mut received) = mpsc::unbounded_channel();
+    let channel = Recorder::ensure_queue(&pool).await.unwrap();
+    let _consumer = setup_consumer::<Renamed, _>(&channel, Arc::new(Recorder(sender)))
+        .await
+        .unwrap();
+
+    RenamedV1("ada".into()).send(&pool).await.unwrap();
+    Renamed("grace".into()).send(&pool).await.unwrap();
+    for name in ["ada", "grace"] {
+        let renamed = tokio::time::timeout(Duration::from_secs(5), received.recv()).await;
+        assert_eq!(renamed.unwrap(), Some(Renamed(name.into())));
+    }
+}
@@ -0,0 +1,136 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use amqprs::channel::{ExchangeDeclareArguments, ExchangeDeleteArguments};
+use kanaeru::rabbitmq::consumer::ConsumerConfig;
+use kanaeru::rabbitmq::memory::MemoryBroker;
+use kanaeru::rabbitmq::supervisor::AmqpSupervisor;
+use kanaeru::rabbitmq::{AmqpExchangeType, AmqpMessageProcessor, AmqpMessageSend, AmqpRouting};
+use kanau::message::{DeserializeError, MessageDe, MessageSer, SerializeError};
+use kanau::processor::Processor;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Order(u8);
+
+impl MessageSer for Order {
+    type SerError = SerializeError;
+
+    fn to_bytes(self) -> Result<Box<[u8]>, SerializeError> {
+        Ok(Box::new([self.0]))
+    }
+}
+
+impl MessageDe for Order {
+    type DeError = DeserializeError;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        match bytes {
+            [id] => Ok(Self(*id)),
+            _ => Err(DeserializeError(anyhow::anyhow!("Order is not 1 byte"))),
+        }
+    }
+}
+
+impl AmqpRouting for Order {
+    const EXCHANGE: &'static str = "test.order";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "order.placed";
+}
+
+impl AmqpMessageSend for Order {}
+
+/// Only here for its exchange, declared after the consumer of [`Order`]
+struct Invoice;
+
+impl AmqpRouting for Invoice {
+    const EXCHANGE: &'static str = "test.invoice";
+    const EXCHANGE_TYPE: AmqpExchangeType = AmqpExchangeType::Direct;
+    const ROUTING_KEY: &'static str = "invoice.issued";
+}
+
+struct Recorder(mpsc::UnboundedSender<Order>);
+
+impl Processor<Order, Result<(), kanaeru::Error>> for Recorder {
+    async fn process(&self, order: Order) -> Result<(), kanaeru::Error> {
+        let _ = self.0.send(order);
+        Ok(())
+    }
+}
+
+impl AmqpMessageProcessor<Order> for Recorder {
+    const QUEUE: &'static str = "test.order.placed";
+}
+
+async fn supervise(broker: &MemoryBroker) -> (AmqpSupervisor, mpsc::UnboundedReceiver<Order>) {
+    let supervisor = AmqpSupervisor::connect(broker.connection_arguments())
+        .await
+        .unwrap();
+    let (sender, received) = mpsc::unbounded_channel();
+    supervisor
+        .register_consumer::<Order, _>(Arc::new(Recorder(sender)), ConsumerConfig::default())
+        .await
+        .unwrap();
+    supervisor.register_exchange::<Invoice>().await.unwrap();
+    (supervisor, received)
+}
+
+async fn eventually(condition: impl Fn() -> bool) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+async fn redeclare_invoice_exchange(broker: &MemoryBroker, kind: &str) {
+    let connection = broker.connect().await.unwrap();
+    let channel = connection.open_channel(None).await.unwrap();
+    channel
+        .exchange_delete(ExchangeDeleteArguments::new(Invoice::EXCHANGE))
+        .await
+        .unwrap();
+    channel
+        .exchange_declare(ExchangeDeclareArguments::new(Invoice::EXCHANGE, kind))
+        .await
+        .unwrap();
+    connection.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn consumers_are_started_again_after_reconnecting() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let (supervisor, mut received) = supervise(&broker).await;
+    Order(1).send(supervisor.pool()).await.unwrap();
+    assert_eq!(received.recv().await, Some(Order(1)));
+    eventually(|| broker.unacked_len(Recorder::QUEUE) == 0).await;
+
+    broker.disconnect_all();
+    eventually(|| broker.consumer_count(Recorder::QUEUE) == 1).await;
+    // Channels of the lost connection are dropped by the pool, the send goes out on the new one
+    Order(2).send(supervisor.pool()).await.unwrap();
+    let order = tokio::time::timeout(Duration::from_secs(5), received.recv()).await;
+    assert_eq!(order.unwrap(), Some(Order(2)));
+    assert!(supervisor.shutdown(Duration::from_secs(1)).await);
+}
+
+#[tokio::test]
+async fn consumers_are_shut_down_when_a_later_declaration_fails() {
+    let broker = MemoryBroker::start().await.unwrap();
+    let (supervisor, _received) = supervise(&broker).await;
+
+    // The consumer is declared again first, then the exchange fails to declare
+    redeclare_invoice_exchange(&broker, "fanout").await;
+    broker.disconnect_all();
+    // Attempts run right away, then one and three seconds later
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(broker.consumer_count(Recorder::QUEUE), 0);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(broker.consumer_count(Recorder::QUEUE), 0);
+
+    redeclare_invoice_exchange(&broker, "direct").await;
+    eventually(|| broker.consumer_count(Recorder::QUEUE) == 1).await;
+    supervisor.shutdown(Duration::from_secs(1)).await;
+}
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS "kanaeru"."outbox";
+DROP SCHEMA IF EXISTS "kanaeru";
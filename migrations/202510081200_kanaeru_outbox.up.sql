@@ -0,0 +1,16 @@
+CREATE SCHEMA IF NOT EXISTS "kanaeru";
+
+CREATE TABLE IF NOT EXISTS "kanaeru"."outbox"
+(
+    id           BIGSERIAL   PRIMARY KEY,
+    exchange     TEXT        NOT NULL,
+    routing_key  TEXT        NOT NULL,
+    payload      BYTEA       NOT NULL,
+    attempts     INTEGER     NOT NULL DEFAULT 0,
+    last_error   TEXT,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    published_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS "kanaeru-outbox_pending_idx" ON "kanaeru"."outbox" ("id") WHERE "published_at" IS NULL;
+CREATE INDEX IF NOT EXISTS "kanaeru-outbox_published_idx" ON "kanaeru"."outbox" ("published_at") WHERE "published_at" IS NOT NULL;
@@ -0,0 +1,6 @@
+ALTER TABLE "kanaeru"."outbox"
+    DROP COLUMN IF EXISTS message_id,
+    DROP COLUMN IF EXISTS correlation_id,
+    DROP COLUMN IF EXISTS content_type,
+    DROP COLUMN IF EXISTS traceparent,
+    DROP COLUMN IF EXISTS tracestate;
@@ -0,0 +1,6 @@
+ALTER TABLE "kanaeru"."outbox"
+    ADD COLUMN IF NOT EXISTS message_id     TEXT,
+    ADD COLUMN IF NOT EXISTS correlation_id TEXT,
+    ADD COLUMN IF NOT EXISTS content_type   TEXT,
+    ADD COLUMN IF NOT EXISTS traceparent    TEXT,
+    ADD COLUMN IF NOT EXISTS tracestate     TEXT;
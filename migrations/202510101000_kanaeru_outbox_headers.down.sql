@@ -0,0 +1,2 @@
+ALTER TABLE "kanaeru"."outbox"
+    DROP COLUMN IF EXISTS headers;
@@ -0,0 +1,3 @@
+-- AMQP field table of the headers a message is published with, encoded as on the wire
+ALTER TABLE "kanaeru"."outbox"
+    ADD COLUMN IF NOT EXISTS headers BYTEA;
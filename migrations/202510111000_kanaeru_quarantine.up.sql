@@ -0,0 +1,21 @@
+-- Messages processors gave up on, kept with their error until they are replayed
+CREATE TABLE IF NOT EXISTS "kanaeru"."quarantine"
+(
+    id             BIGSERIAL   PRIMARY KEY,
+    processor      TEXT        NOT NULL,
+    exchange       TEXT        NOT NULL,
+    routing_key    TEXT        NOT NULL,
+    payload        BYTEA       NOT NULL,
+    headers        BYTEA,
+    message_id     TEXT,
+    correlation_id TEXT,
+    content_type   TEXT,
+    traceparent    TEXT,
+    tracestate     TEXT,
+    error          TEXT        NOT NULL,
+    error_class    TEXT        NOT NULL,
+    quarantined_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    replayed_at    TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS "kanaeru-quarantine_pending_idx" ON "kanaeru"."quarantine" ("processor", "id") WHERE "replayed_at" IS NULL;
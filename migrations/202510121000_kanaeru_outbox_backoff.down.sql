@@ -0,0 +1,7 @@
+DROP INDEX IF EXISTS "kanaeru"."kanaeru-outbox_parked_idx";
+DROP INDEX IF EXISTS "kanaeru"."kanaeru-outbox_pending_idx";
+CREATE INDEX IF NOT EXISTS "kanaeru-outbox_pending_idx" ON "kanaeru"."outbox" ("id") WHERE "published_at" IS NULL;
+
+ALTER TABLE "kanaeru"."outbox"
+    DROP COLUMN IF EXISTS parked_at,
+    DROP COLUMN IF EXISTS next_attempt_at;
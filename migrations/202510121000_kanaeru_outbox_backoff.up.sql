@@ -0,0 +1,9 @@
+-- Failed messages back off until next_attempt_at, which also leases claimed messages to a relay.
+-- Messages that keep failing are parked until they are unparked by hand.
+ALTER TABLE "kanaeru"."outbox"
+    ADD COLUMN IF NOT EXISTS next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ADD COLUMN IF NOT EXISTS parked_at       TIMESTAMPTZ;
+
+DROP INDEX IF EXISTS "kanaeru"."kanaeru-outbox_pending_idx";
+CREATE INDEX IF NOT EXISTS "kanaeru-outbox_pending_idx" ON "kanaeru"."outbox" ("id") WHERE "published_at" IS NULL AND "parked_at" IS NULL;
+CREATE INDEX IF NOT EXISTS "kanaeru-outbox_parked_idx" ON "kanaeru"."outbox" ("id") WHERE "parked_at" IS NOT NULL;
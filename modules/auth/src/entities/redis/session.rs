@@ -1,4 +1,5 @@
 use kanaeru::redis::{KeyValue, KeyValueRead, KeyValueWrite, RedisKey};
+use kanaeru::schema::RKYV_CONTENT_TYPE;
 use kanau::{RkyvMessageDe, RkyvMessageSer};
 use uuid::Uuid;
 
@@ -20,7 +21,18 @@ pub struct Session {
     pub last_refreshed: u64,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    rkyv::Archive,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+    RkyvMessageSer,
+    RkyvMessageDe,
+)]
 pub struct SessionId(pub Uuid);
 
 impl From<SessionId> for RedisKey {
@@ -34,6 +46,8 @@ impl KeyValue for Session {
     type Key = SessionId;
     type Value = Self;
 
+    const CONTENT_TYPE: &'static str = RKYV_CONTENT_TYPE;
+
     fn key(&self) -> Self::Key {
         self.id
     }
@@ -1,22 +1,43 @@
-use kanaeru::redis::{KeyValue, KeyValueRead, KeyValueWrite, RedisKey};
+use std::sync::LazyLock;
+
+use kanaeru::redis::{KeyValue, KeyValueRead, RedisCollection, RedisKey, RedisSet};
+use kanau::message::MessageSer;
 use kanau::{RkyvMessageDe, RkyvMessageSer};
+use redis::AsyncCommands;
 use uuid::Uuid;
 
-#[derive(
-    Debug,
-    Clone,
-    PartialEq,
-    Eq,
-    rkyv::Archive,
-    rkyv::Serialize,
-    rkyv::Deserialize,
-    RkyvMessageDe,
-    RkyvMessageSer,
-)]
-pub struct UserSessions {
-    pub user_id: Uuid,
-    pub session_ids: Vec<Uuid>,
-}
+use super::session::SessionId;
+
+/// Prefix of the keys of [`UserSessions`]
+const KEY_PREFIX: &str = "user_sessions_list:";
+
+/// Replace the legacy blob in `KEYS[1]` by a set of the members in `ARGV`, keeping its TTL.
+/// Returns 0 without touching the key if it is not a blob anymore.
+static MIGRATE_LEGACY: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r#"
+        if redis.call("TYPE", KEYS[1])["ok"] ~= "string" then
+            return 0
+        end
+        local ttl = redis.call("PTTL", KEYS[1])
+        redis.call("DEL", KEYS[1])
+        if #ARGV > 0 then
+            redis.call("SADD", KEYS[1], unpack(ARGV))
+            if ttl > 0 then
+                redis.call("PEXPIRE", KEYS[1], ttl)
+            end
+        end
+        return 1
+        "#,
+    )
+});
+
+/// Ids of the sessions of a user, as a redis set so sessions are added and removed atomically.
+///
+/// Sessions used to be stored as a single [`LegacyUserSessions`] blob under the same key, which
+/// redis refuses set commands on. Convert them with [`UserSessions::migrate_all_legacy`] on
+/// deploy, or with [`UserSessions::migrate_legacy`] before touching the sessions of a user.
+pub struct UserSessions;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
 pub struct UserIdIndex(pub Uuid);
@@ -29,12 +50,101 @@ impl From<Uuid> for UserIdIndex {
 
 impl From<UserIdIndex> for RedisKey {
     fn from(v: UserIdIndex) -> Self {
-        let string = format!("user_sessions_list:{}", v.0);
+        let string = format!("{KEY_PREFIX}{}", v.0);
         Self::from(string)
     }
 }
 
-impl KeyValue for UserSessions {
+impl RedisCollection for UserSessions {
+    type Key = UserIdIndex;
+}
+
+impl RedisSet for UserSessions {
+    type Member = SessionId;
+}
+
+impl UserSessions {
+    /// Convert the sessions of `user` from a [`LegacyUserSessions`] blob into the set, keeping
+    /// its TTL. Returns false if they were not stored as a blob.
+    ///
+    /// The blob is decoded here, then replaced by the set in a single script that first checks it
+    /// is still a blob, so sessions added after a concurrent migration are not overwritten.
+    pub async fn migrate_legacy(
+        conn: &mut kanaeru::redis::RedisConnection,
+        user: UserIdIndex,
+    ) -> Result<bool, kanaeru::Error> {
+        let key: RedisKey = user.into();
+        let kind: String = redis::cmd("TYPE").arg(&key).query_async(conn).await?;
+        if kind != "string" {
+            return Ok(false);
+        }
+        let legacy = match LegacyUserSessions::read(conn, user).await {
+            Ok(Some(legacy)) => legacy,
+            Ok(None) => return Ok(false),
+            // Converted by someone else since
+            Err(kanaeru::Error::RedisError(e)) if e.code() == Some("WRONGTYPE") => {
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut invocation = MIGRATE_LEGACY.key(&key);
+        for id in legacy.session_ids {
+            let member = SessionId(id)
+                .to_bytes()
+                .map_err(|e| kanaeru::Error::SerializeError(e.into()))?;
+            invocation.arg(member.as_ref());
+        }
+        let migrated: bool = invocation.invoke_async(conn).await?;
+        Ok(migrated)
+    }
+
+    /// Convert the sessions of every user still stored as a [`LegacyUserSessions`] blob. Returns
+    /// how many were converted.
+    pub async fn migrate_all_legacy(
+        conn: &mut kanaeru::redis::RedisConnection,
+    ) -> Result<usize, kanaeru::Error> {
+        let mut users = Vec::new();
+        {
+            let mut keys = conn
+                .scan_match::<_, String>(format!("{KEY_PREFIX}*"))
+                .await?;
+            while let Some(key) = keys.next_item().await {
+                let user = key
+                    .strip_prefix(KEY_PREFIX)
+                    .and_then(|id| Uuid::parse_str(id).ok());
+                users.extend(user);
+            }
+        }
+        let mut migrated = 0;
+        for user in users {
+            if Self::migrate_legacy(conn, UserIdIndex(user)).await? {
+                migrated += 1;
+            }
+        }
+        Ok(migrated)
+    }
+}
+
+/// Sessions of a user as they were stored before [`UserSessions`] became a set. Only read to
+/// migrate them.
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    rkyv::Archive,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+    RkyvMessageDe,
+    RkyvMessageSer,
+)]
+pub struct LegacyUserSessions {
+    pub user_id: Uuid,
+    pub session_ids: Vec<Uuid>,
+}
+
+impl KeyValue for LegacyUserSessions {
     type Key = UserIdIndex;
     type Value = Self;
 
@@ -56,5 +166,4 @@ impl KeyValue for UserSessions {
     }
 }
 
-impl KeyValueRead for UserSessions {}
-impl KeyValueWrite for UserSessions {}
+impl KeyValueRead for LegacyUserSessions {}
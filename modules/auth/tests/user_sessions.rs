@@ -0,0 +1,121 @@
+//! Needs a redis server in `KANAERU_TEST_REDIS_URL`. Users are random, so any instance does,
+//! though migrating every user converts the legacy sessions of other users found there as well.
+
+use auth::entities::redis::session::SessionId;
+use auth::entities::redis::user_session_list::{LegacyUserSessions, UserIdIndex, UserSessions};
+use kanaeru::redis::{RedisConnection, RedisKey, RedisSet};
+use kanau::message::MessageSer;
+use redis::AsyncCommands;
+use uuid::Uuid;
+
+async fn connection() -> Option<RedisConnection> {
+    let Ok(url) = std::env::var("KANAERU_TEST_REDIS_URL") else {
+        eprintln!("KANAERU_TEST_REDIS_URL is not set, skipping");
+        return None;
+    };
+    let client = redis::Client::open(url).unwrap();
+    Some(client.get_multiplexed_async_connection().await.unwrap())
+}
+
+/// Store the sessions of a new user the way they were before [`UserSessions`], expiring in
+/// `ttl` milliseconds if given
+async fn legacy_user(
+    conn: &mut RedisConnection,
+    sessions: &[Uuid],
+    ttl: Option<u64>,
+) -> UserIdIndex {
+    let user = UserIdIndex(Uuid::new_v4());
+    let legacy = LegacyUserSessions {
+        user_id: user.0,
+        session_ids: sessions.to_vec(),
+    };
+    let key: RedisKey = user.into();
+    let bytes = legacy.to_bytes().unwrap();
+    let _: () = match ttl {
+        Some(ttl) => conn.pset_ex(&key, bytes.as_ref(), ttl).await.unwrap(),
+        None => conn.set(&key, bytes.as_ref()).await.unwrap(),
+    };
+    user
+}
+
+async fn sessions(conn: &mut RedisConnection, user: UserIdIndex) -> Vec<Uuid> {
+    let mut sessions: Vec<Uuid> = UserSessions::members(conn, user)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|session| session.0)
+        .collect();
+    sessions.sort();
+    sessions
+}
+
+#[tokio::test]
+async fn legacy_sessions_are_converted_into_a_set_keeping_their_ttl() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let mut ids = vec![Uuid::new_v4(), Uuid::new_v4()];
+    ids.sort();
+    let user = legacy_user(&mut conn, &ids, Some(60_000)).await;
+
+    assert!(UserSessions::migrate_legacy(&mut conn, user).await.unwrap());
+    assert_eq!(sessions(&mut conn, user).await, ids);
+    let key: RedisKey = user.into();
+    let ttl: i64 = conn.pttl(&key).await.unwrap();
+    assert!((1..=60_000).contains(&ttl), "{ttl}");
+
+    // A set is left as it is, with the sessions added since
+    let added = Uuid::new_v4();
+    assert!(
+        UserSessions::add(&mut conn, user, SessionId(added))
+            .await
+            .unwrap()
+    );
+    assert!(!UserSessions::migrate_legacy(&mut conn, user).await.unwrap());
+    assert_eq!(UserSessions::len(&mut conn, user).await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn legacy_sessions_without_ttl_or_sessions_are_converted() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let id = Uuid::new_v4();
+    let user = legacy_user(&mut conn, &[id], None).await;
+    assert!(UserSessions::migrate_legacy(&mut conn, user).await.unwrap());
+    assert_eq!(sessions(&mut conn, user).await, [id]);
+    let key: RedisKey = user.into();
+    let ttl: i64 = conn.pttl(&key).await.unwrap();
+    assert_eq!(ttl, -1);
+
+    // Redis has no empty sets, so no sessions is no key
+    let user = legacy_user(&mut conn, &[], Some(60_000)).await;
+    assert!(UserSessions::migrate_legacy(&mut conn, user).await.unwrap());
+    let key: RedisKey = user.into();
+    let exists: bool = conn.exists(&key).await.unwrap();
+    assert!(!exists);
+
+    // Nor is a user without sessions
+    let user = UserIdIndex(Uuid::new_v4());
+    assert!(!UserSessions::migrate_legacy(&mut conn, user).await.unwrap());
+}
+
+#[tokio::test]
+async fn every_legacy_user_is_migrated() {
+    let Some(mut conn) = connection().await else {
+        return;
+    };
+    let first_id = Uuid::new_v4();
+    let first = legacy_user(&mut conn, &[first_id], None).await;
+    let second_id = Uuid::new_v4();
+    let second = legacy_user(&mut conn, &[second_id], Some(60_000)).await;
+    let migrated = UserIdIndex(Uuid::new_v4());
+    UserSessions::add(&mut conn, migrated, SessionId(Uuid::new_v4()))
+        .await
+        .unwrap();
+
+    assert!(UserSessions::migrate_all_legacy(&mut conn).await.unwrap() >= 2);
+    assert_eq!(sessions(&mut conn, first).await, [first_id]);
+    assert_eq!(sessions(&mut conn, second).await, [second_id]);
+    assert_eq!(UserSessions::len(&mut conn, migrated).await.unwrap(), 1);
+}